@@ -6,6 +6,24 @@ pub struct Cli {
     /// Select a LLM model
     #[clap(short, long)]
     pub model: Option<String>,
+    /// Let the model think before answering (low, medium, high or a token budget)
+    #[clap(long, value_name = "low|medium|high|N")]
+    pub think: Option<String>,
+    /// Tag the requests, e.g. `user=alice`, sent to the providers that accept metadata
+    #[clap(long, value_name = "KEY=VALUE")]
+    pub tag: Vec<String>,
+    /// Answer in JSON conforming to a JSON schema, given as a file or inline
+    #[clap(long, value_name = "FILE|JSON")]
+    pub schema: Option<String>,
+    /// Send up to N requests at once to each provider
+    #[clap(long, value_name = "N")]
+    pub concurrency: Option<usize>,
+    /// Limit the requests per minute to each provider
+    #[clap(long, value_name = "N")]
+    pub rpm: Option<u64>,
+    /// Limit the input tokens per minute to each provider
+    #[clap(long, value_name = "N")]
+    pub tpm: Option<u64>,
     /// Use the system prompt
     #[clap(long)]
     pub prompt: Option<String>,
@@ -21,6 +39,33 @@ pub struct Cli {
     /// Serve all LLMs via an OpenAI-compatible API
     #[clap(long, value_name = "ADDRESS")]
     pub serve: Option<Option<String>>,
+    /// Run the test prompts and assertions of an eval file
+    #[clap(long, value_name = "FILE")]
+    pub eval: Option<String>,
+    /// Write the eval results as JSON to a file
+    #[clap(long, value_name = "FILE", requires = "eval")]
+    pub eval_report: Option<String>,
+    /// Compare the outputs of two roles over the inputs of --ab-inputs
+    #[clap(long, num_args = 2, value_names = ["ROLE_A", "ROLE_B"])]
+    pub ab: Vec<String>,
+    /// JSONL file of inputs for --ab, each line a string or {"input": ...}
+    #[clap(long, value_name = "FILE", requires = "ab")]
+    pub ab_inputs: Option<String>,
+    /// Let a model judge which output of --ab is better
+    #[clap(long, value_name = "MODEL", requires = "ab")]
+    pub ab_judge: Option<Option<String>>,
+    /// Run a REPL command before sending the input, can be repeated
+    #[clap(long, value_name = "COMMAND")]
+    pub cmd: Vec<String>,
+    /// Print the embedding of the text and of each file, one JSON array per line
+    #[clap(long, conflicts_with_all = ["execute", "code", "cmd"])]
+    pub embed: bool,
+    /// Run the REPL lines of a file, then exit
+    #[clap(long, value_name = "FILE", conflicts_with = "text")]
+    pub script: Option<String>,
+    /// Start a full-screen chat UI instead of the REPL
+    #[clap(long, conflicts_with_all = ["text", "script"])]
+    pub tui: bool,
     /// Execute commands in natural language
     #[clap(short = 'e', long)]
     pub execute: bool,
@@ -36,18 +81,36 @@ pub struct Cli {
     /// Turns off stream mode
     #[clap(short = 'S', long)]
     pub no_stream: bool,
+    /// Write a streamed reply that is piped per token, per line, or once complete
+    #[clap(long, value_name = "token|line|off")]
+    pub stream_flush: Option<String>,
     /// Control text wrapping (no, auto, <max-width>)
     #[clap(short = 'w', long)]
     pub wrap: Option<String>,
     /// Use light theme
     #[clap(long)]
     pub light_theme: bool,
-    /// Display the message without sending it
+    /// Don't print the welcome text of the REPL
+    #[clap(long)]
+    pub no_banner: bool,
+    /// Only use local models (ollama, llama.cpp, ...) and turn off the web tools
+    #[clap(long)]
+    pub offline: bool,
+    /// Display the request without sending it
     #[clap(long)]
     pub dry_run: bool,
+    /// Log the requests and the raw responses to debug.log, secrets redacted
+    #[clap(long)]
+    pub debug: bool,
     /// Display information
     #[clap(long)]
     pub info: bool,
+    /// Display the tokens and cost of all the requests so far, per client
+    #[clap(long)]
+    pub stats: bool,
+    /// Check the config, dirs, clipboard, editor, terminal and clients, with how to fix them
+    #[clap(long)]
+    pub doctor: bool,
     /// List all available models
     #[clap(long)]
     pub list_models: bool,
@@ -57,6 +120,12 @@ pub struct Cli {
     /// List all available sessions
     #[clap(long)]
     pub list_sessions: bool,
+    /// Delete the sessions beyond `session_retention`, only list them with --dry-run
+    #[clap(long)]
+    pub prune_sessions: bool,
+    /// Import the conversations of chatgpt (export.zip), sgpt (chat_cache) or ollama (Modelfile) as sessions
+    #[clap(long, num_args = 2, value_names = ["FORMAT", "FILE"])]
+    pub import: Vec<String>,
     /// Input text
     #[clap(trailing_var_arg = true)]
     text: Vec<String>,
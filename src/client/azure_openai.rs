@@ -1,4 +1,4 @@
-use super::openai::openai_build_body;
+use super::openai::{openai_build_body, openai_set_tags};
 use super::{
     AzureOpenAIClient, ExtraConfig, Model, ModelConfig, PromptAction, PromptKind, SendData,
 };
@@ -32,11 +32,17 @@ impl AzureOpenAIClient {
         ),
     ];
 
+    pub fn api_base(config: &AzureOpenAIConfig) -> Option<String> {
+        config.api_base.clone()
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_base = self.get_api_base()?;
         let api_key = self.get_api_key()?;
 
+        let tags = data.tags.clone();
         let mut body = openai_build_body(data, &self.model);
+        openai_set_tags(&mut body, &tags);
         self.model.merge_extra_fields(&mut body);
 
         let url = format!(
@@ -1,6 +1,9 @@
-use super::claude::{claude_build_body, claude_extract_completion};
+use super::claude::{
+    claude_build_body, claude_extract_completion, claude_finish_blocks, claude_handle_event,
+    ClaudeStreamBlocks,
+};
 use super::{
-    catch_error, generate_prompt, BedrockClient, Client, CompletionDetails, ExtraConfig, Model,
+    send_request, generate_prompt, BedrockClient, Client, CompletionDetails, ExtraConfig, Model,
     ModelConfig, PromptAction, PromptFormat, PromptKind, SendData, SseHandler,
     LLAMA3_PROMPT_FORMAT, MISTRAL_PROMPT_FORMAT,
 };
@@ -58,6 +61,15 @@ impl Client for BedrockClient {
         let builder = self.request_builder(client, data, &model_category)?;
         send_message_streaming(builder, handler, &model_category).await
     }
+
+    async fn request_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<RequestBuilder> {
+        let model_category = ModelCategory::from_str(&self.model.name)?;
+        self.request_builder(client, data, &model_category)
+    }
 }
 
 impl BedrockClient {
@@ -81,6 +93,11 @@ impl BedrockClient {
         ("region", "AWS Region", true, PromptKind::String),
     ];
 
+    pub fn api_base(config: &BedrockConfig) -> Option<String> {
+        let region = config.region.as_deref()?;
+        Some(format!("https://bedrock-runtime.{region}.amazonaws.com"))
+    }
+
     fn request_builder(
         &self,
         client: &ReqwestClient,
@@ -130,14 +147,9 @@ async fn send_message(
     builder: RequestBuilder,
     model_category: &ModelCategory,
 ) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
 
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
-
     match model_category {
         ModelCategory::Anthropic => claude_extract_completion(&data),
         ModelCategory::MetaLlama3 => llama_extract_completion(&data),
@@ -150,16 +162,11 @@ async fn send_message_streaming(
     handler: &mut SseHandler,
     model_category: &ModelCategory,
 ) -> Result<()> {
-    let res = builder.send().await?;
-    let status = res.status();
-    if !status.is_success() {
-        let data: Value = res.json().await?;
-        catch_error(&data, status.as_u16())?;
-        bail!("Invalid response data: {data}");
-    }
+    let res = send_request(builder).await?;
     let mut stream = res.bytes_stream();
     let mut buffer = BytesMut::new();
     let mut decoder = MessageFrameDecoder::new();
+    let mut blocks = ClaudeStreamBlocks::default();
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         buffer.extend_from_slice(&chunk);
@@ -175,13 +182,7 @@ async fn send_message_streaming(
                     debug!("bedrock chunk: {data}");
                     match model_category {
                         ModelCategory::Anthropic => {
-                            if let Some(typ) = data["type"].as_str() {
-                                if typ == "content_block_delta" {
-                                    if let Some(text) = data["delta"]["text"].as_str() {
-                                        handler.text(text)?;
-                                    }
-                                }
-                            }
+                            claude_handle_event(&data, handler, &mut blocks)?;
                         }
                         ModelCategory::MetaLlama3 => {
                             if let Some(text) = data["generation"].as_str() {
@@ -207,7 +208,7 @@ async fn send_message_streaming(
             }
         }
     }
-    Ok(())
+    claude_finish_blocks(blocks, handler)
 }
 
 fn build_body(data: SendData, model: &Model, model_category: &ModelCategory) -> Result<Value> {
@@ -230,6 +231,16 @@ fn meta_llama_build_body(data: SendData, model: &Model, pt: PromptFormat) -> Res
         messages,
         temperature,
         top_p,
+        seed: _,
+        frequency_penalty: _,
+        presence_penalty: _,
+        stop: _,
+        logprobs: _,
+        functions: _,
+        tool_rounds: _,
+        thinking: _,
+        tags: _,
+        schema: _,
         stream: _,
     } = data;
     let prompt = generate_prompt(&messages, pt)?;
@@ -253,6 +264,16 @@ fn mistral_build_body(data: SendData, model: &Model) -> Result<Value> {
         messages,
         temperature,
         top_p,
+        seed: _,
+        frequency_penalty: _,
+        presence_penalty: _,
+        stop,
+        logprobs: _,
+        functions: _,
+        tool_rounds: _,
+        thinking: _,
+        tags: _,
+        schema: _,
         stream: _,
     } = data;
     let prompt = generate_prompt(&messages, MISTRAL_PROMPT_FORMAT)?;
@@ -267,6 +288,9 @@ fn mistral_build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if !stop.is_empty() {
+        body["stop"] = stop.into();
+    }
 
     Ok(body)
 }
@@ -279,6 +303,7 @@ fn llama_extract_completion(data: &Value) -> Result<(String, CompletionDetails)>
         id: None,
         input_tokens: data["prompt_token_count"].as_u64(),
         output_tokens: data["generation_token_count"].as_u64(),
+        ..Default::default()
     };
     Ok((text.to_string(), details))
 }
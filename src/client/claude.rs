@@ -1,9 +1,11 @@
 use super::{
-    catch_error, extract_system_message, sse_stream, ClaudeClient, CompletionDetails, ExtraConfig,
+    send_request, extract_system_message, sse_stream, ClaudeClient, CompletionDetails, ExtraConfig,
     ImageUrl, MessageContent, MessageContentPart, Model, ModelConfig, PromptAction, PromptKind,
-    SendData, SsMmessage, SseHandler,
+    ResponseMeta, SendData, SsMmessage, SseHandler,
 };
 
+use crate::function::{ToolCall, ToolResult, ToolRound};
+
 use anyhow::{anyhow, bail, Result};
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
@@ -11,11 +13,19 @@ use serde_json::{json, Value};
 
 const API_BASE: &str = "https://api.anthropic.com/v1/messages";
 
+/// The tool forced on Claude to get an answer conforming to a JSON schema.
+const RESPONSE_TOOL: &str = "respond";
+
+/// Claude allows 4 cache breakpoints per request.
+const MAX_CACHE_BREAKPOINTS: usize = 4;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClaudeConfig {
     pub name: Option<String>,
     pub api_key: Option<String>,
     #[serde(default)]
+    pub prompt_caching: bool,
+    #[serde(default)]
     pub models: Vec<ModelConfig>,
     pub extra: Option<ExtraConfig>,
 }
@@ -26,10 +36,23 @@ impl ClaudeClient {
     pub const PROMPTS: [PromptAction<'static>; 1] =
         [("api_key", "API Key:", true, PromptKind::String)];
 
+    pub fn api_base(_config: &ClaudeConfig) -> Option<String> {
+        Some(API_BASE.into())
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_key = self.get_api_key().ok();
 
-        let body = claude_build_body(data, &self.model)?;
+        let user = data.tags.get("user").cloned();
+        let mut body = claude_build_body(data, &self.model)?;
+        if self.config.prompt_caching {
+            claude_add_cache_control(&mut body);
+        }
+        // Only the tag `user` has a place in the metadata of Claude
+        if let Some(user) = user {
+            body["metadata"] = json!({ "user_id": user });
+        }
+        self.model.merge_extra_fields(&mut body);
 
         let url = API_BASE;
 
@@ -52,12 +75,8 @@ impl_client_trait!(
 );
 
 pub async fn claude_send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
     claude_extract_completion(&data)
 }
 
@@ -65,19 +84,89 @@ pub async fn claude_send_message_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
+    let mut blocks = ClaudeStreamBlocks::default();
     let handle = |message: SsMmessage| -> Result<bool> {
         let data: Value = serde_json::from_str(&message.data)?;
-        if let Some(typ) = data["type"].as_str() {
-            if typ == "content_block_delta" {
-                if let Some(text) = data["delta"]["text"].as_str() {
-                    handler.text(text)?;
+        claude_handle_event(&data, handler, &mut blocks)?;
+        Ok(false)
+    };
+
+    sse_stream(builder, handle).await?;
+    claude_finish_blocks(blocks, handler)
+}
+
+/// The blocks of a streamed reply that come in pieces: the tool calls as (id, name, arguments)
+/// and the thinking with its signature.
+#[derive(Debug, Default)]
+pub struct ClaudeStreamBlocks {
+    tool_calls: Vec<(String, String, String)>,
+    thinking: Vec<Value>,
+}
+
+/// Handle a streamed event, collecting the blocks that come in pieces.
+pub fn claude_handle_event(
+    data: &Value,
+    handler: &mut SseHandler,
+    blocks: &mut ClaudeStreamBlocks,
+) -> Result<()> {
+    match data["type"].as_str() {
+        Some("content_block_start") => {
+            let block = &data["content_block"];
+            match block["type"].as_str() {
+                Some("tool_use") => blocks.tool_calls.push((
+                    block["id"].as_str().unwrap_or_default().to_string(),
+                    block["name"].as_str().unwrap_or_default().to_string(),
+                    String::new(),
+                )),
+                Some("thinking") | Some("redacted_thinking") => {
+                    blocks.thinking.push(block.clone())
                 }
+                _ => {}
             }
         }
-        Ok(false)
-    };
+        Some("message_start") => {
+            let usage = &data["message"]["usage"];
+            handler.usage(claude_input_tokens(usage), None)?;
+            handler.meta(claude_extract_meta(usage))?;
+        }
+        Some("message_delta") => {
+            handler.usage(None, data["usage"]["output_tokens"].as_u64())?;
+        }
+        Some("content_block_delta") => {
+            let delta = &data["delta"];
+            if let Some(text) = delta["text"].as_str() {
+                handler.text(text)?;
+            } else if let Some(text) = delta["thinking"].as_str() {
+                handler.thinking(text)?;
+                if let Some(block) = blocks.thinking.last_mut() {
+                    let thinking = block["thinking"].as_str().unwrap_or_default();
+                    block["thinking"] = format!("{thinking}{text}").into();
+                }
+            } else if let Some(signature) = delta["signature"].as_str() {
+                if let Some(block) = blocks.thinking.last_mut() {
+                    block["signature"] = signature.into();
+                }
+            } else if let Some(json) = delta["partial_json"].as_str() {
+                if let Some((_, _, arguments)) = blocks.tool_calls.last_mut() {
+                    arguments.push_str(json);
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
 
-    sse_stream(builder, handle).await
+pub fn claude_finish_blocks(blocks: ClaudeStreamBlocks, handler: &mut SseHandler) -> Result<()> {
+    handler.set_thinking_blocks(blocks.thinking);
+    for (id, name, arguments) in blocks.tool_calls {
+        if name == RESPONSE_TOOL {
+            handler.text(&arguments)?;
+        } else {
+            handler.tool_call(ToolCall::new(id, name, &arguments)?);
+        }
+    }
+    Ok(())
 }
 
 pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
@@ -85,13 +174,22 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
         mut messages,
         temperature,
         top_p,
+        seed: _,
+        frequency_penalty: _,
+        presence_penalty: _,
+        stop,
+        logprobs: _,
+        functions,
+        tool_rounds,
+        thinking,
+        tags: _,
+        schema,
         stream,
     } = data;
 
     let system_message = extract_system_message(&mut messages);
 
-    let mut network_image_urls = vec![];
-    let messages: Vec<Value> = messages
+    let mut messages: Vec<Value> = messages
         .into_iter()
         .map(|message| {
             let role = message.role;
@@ -117,8 +215,10 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
                                     }
                                 })
                             } else {
-                                network_image_urls.push(url.clone());
-                                json!({ "url": url })
+                                json!({
+                                    "type": "image",
+                                    "source": { "type": "url", "url": url }
+                                })
                             }
                         }
                     })
@@ -128,12 +228,7 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
         })
         .collect();
 
-    if !network_image_urls.is_empty() {
-        bail!(
-            "The model does not support network images: {:?}",
-            network_image_urls
-        );
-    }
+    messages.extend(tool_rounds.iter().flat_map(claude_tool_messages));
 
     let mut body = json!({
         "model": &model.name,
@@ -145,27 +240,366 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = model.max_output_tokens {
         body["max_tokens"] = v.into();
     }
-    if let Some(v) = temperature {
-        body["temperature"] = v.into();
+    match thinking {
+        Some(thinking) => {
+            // Thinking counts towards max_tokens and doesn't allow changing the sampling
+            let budget_tokens = thinking.budget_tokens();
+            let max_tokens = body["max_tokens"].as_u64().unwrap_or_default();
+            body["max_tokens"] = max_tokens.max(budget_tokens + 1024).into();
+            body["thinking"] = json!({ "type": "enabled", "budget_tokens": budget_tokens });
+        }
+        None => {
+            if let Some(v) = temperature {
+                body["temperature"] = v.into();
+            }
+            if let Some(v) = top_p {
+                body["top_p"] = v.into();
+            }
+        }
     }
-    if let Some(v) = top_p {
-        body["top_p"] = v.into();
+    if !stop.is_empty() {
+        body["stop_sequences"] = stop.into();
     }
     if stream {
         body["stream"] = true.into();
     }
+    if let Some(functions) = functions {
+        body["tools"] = functions
+            .iter()
+            .map(|v| {
+                json!({
+                    "name": v.name,
+                    "description": v.description,
+                    "input_schema": v.parameters,
+                })
+            })
+            .collect();
+    }
+    if let Some(schema) = schema {
+        // Claude has no JSON mode, force a tool taking the schema as input instead
+        let mut tools = body["tools"].as_array().cloned().unwrap_or_default();
+        tools.push(json!({
+            "name": RESPONSE_TOOL,
+            "description": "Respond with the structured output",
+            "input_schema": schema,
+        }));
+        body["tools"] = tools.into();
+        // Thinking only allows the model to choose the tools by itself
+        body["tool_choice"] = match body.get("thinking") {
+            Some(_) => json!({ "type": "auto" }),
+            None => json!({ "type": "tool", "name": RESPONSE_TOOL }),
+        };
+    }
     Ok(body)
 }
 
+/// Mark the tools, the system prompt and the latest prefixes of the messages as cached, so that
+/// the next requests of a session reuse them.
+///
+/// A breakpoint caches all the content before it: the tools come first, then the system prompt,
+/// then the messages. The end of the latest user message writes the whole conversation to the
+/// cache, the ends of the previous ones read what the last request wrote.
+pub fn claude_add_cache_control(body: &mut Value) {
+    let cache_control = json!({ "type": "ephemeral" });
+    let mut breakpoints = MAX_CACHE_BREAKPOINTS;
+    if let Some(tool) = body["tools"].as_array_mut().and_then(|v| v.last_mut()) {
+        tool["cache_control"] = cache_control.clone();
+        breakpoints -= 1;
+    }
+    if let Some(system) = body["system"].as_str() {
+        body["system"] = json!([{
+            "type": "text",
+            "text": system,
+            "cache_control": cache_control,
+        }]);
+        breakpoints -= 1;
+    }
+    let Some(messages) = body["messages"].as_array_mut() else {
+        return;
+    };
+    // The assistant messages may end with thinking, which can't be cached on its own
+    let blocks = messages
+        .iter_mut()
+        .rev()
+        .filter(|message| message["role"] == "user")
+        .filter_map(|message| message["content"].as_array_mut()?.last_mut())
+        .take(breakpoints);
+    for block in blocks {
+        block["cache_control"] = cache_control.clone();
+    }
+}
+
+/// Replay a round of tool calls as one message of the assistant, its thinking, its text and its
+/// `tool_use` blocks, followed by one of the user with the `tool_result` blocks.
+fn claude_tool_messages(round: &ToolRound) -> [Value; 2] {
+    let ToolRound {
+        text,
+        thinking,
+        results,
+    } = round;
+    let mut content = thinking.clone();
+    // Claude refuses the text blocks without text
+    if !text.trim().is_empty() {
+        content.push(json!({ "type": "text", "text": text }));
+    }
+    content.extend(results.iter().map(|ToolResult { call, .. }| {
+        json!({
+            "type": "tool_use",
+            "id": call.id,
+            "name": call.name,
+            "input": call.arguments,
+        })
+    }));
+    let tool_results: Vec<Value> = results
+        .iter()
+        .map(|ToolResult { call, output }| {
+            json!({
+                "type": "tool_result",
+                "tool_use_id": call.id,
+                "content": output.to_string(),
+            })
+        })
+        .collect();
+    [
+        json!({ "role": "assistant", "content": content }),
+        json!({ "role": "user", "content": tool_results }),
+    ]
+}
+
 pub fn claude_extract_completion(data: &Value) -> Result<(String, CompletionDetails)> {
-    let text = data["content"][0]["text"]
-        .as_str()
+    let blocks = data["content"]
+        .as_array()
         .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
+    // Thinking blocks come before the text, tool_use blocks after it
+    let mut text = None;
+    let mut tool_calls = vec![];
+    let mut thinking = vec![];
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("thinking") | Some("redacted_thinking") => thinking.push(block.clone()),
+            Some("text") => {
+                text.get_or_insert_with(String::new)
+                    .push_str(block["text"].as_str().unwrap_or_default());
+            }
+            Some("tool_use") if block["name"].as_str() == Some(RESPONSE_TOOL) => {
+                text.get_or_insert_with(String::new)
+                    .push_str(&block["input"].to_string());
+            }
+            Some("tool_use") => tool_calls.push(ToolCall {
+                id: block["id"].as_str().unwrap_or_default().to_string(),
+                name: block["name"].as_str().unwrap_or_default().to_string(),
+                arguments: block["input"].clone(),
+            }),
+            _ => {}
+        }
+    }
+    let text = match text {
+        Some(text) => text,
+        None if !tool_calls.is_empty() => String::new(),
+        None => bail!("Invalid response data: {data}"),
+    };
 
     let details = CompletionDetails {
         id: data["id"].as_str().map(|v| v.to_string()),
-        input_tokens: data["usage"]["input_tokens"].as_u64(),
+        input_tokens: claude_input_tokens(&data["usage"]),
         output_tokens: data["usage"]["output_tokens"].as_u64(),
+        tool_calls,
+        thinking,
+        meta: claude_extract_meta(&data["usage"]),
+        ..Default::default()
     };
-    Ok((text.to_string(), details))
+    Ok((text, details))
+}
+
+/// All the input tokens, Claude counts the ones read from and written to the cache apart.
+fn claude_input_tokens(usage: &Value) -> Option<u64> {
+    let input_tokens = usage["input_tokens"].as_u64()?;
+    Some(
+        input_tokens
+            + usage["cache_creation_input_tokens"].as_u64().unwrap_or_default()
+            + usage["cache_read_input_tokens"].as_u64().unwrap_or_default(),
+    )
+}
+
+/// The tokens read from and written to the prompt cache, see `prompt_caching`.
+fn claude_extract_meta(usage: &Value) -> ResponseMeta {
+    ResponseMeta {
+        cached_tokens: usage["cache_read_input_tokens"].as_u64(),
+        cache_write_tokens: usage["cache_creation_input_tokens"].as_u64(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{test_send_data, Message, MessageRole};
+    use crate::utils::create_abort_signal;
+
+    #[test]
+    fn test_claude_extract_completion() {
+        let data = json!({
+            "id": "msg_1",
+            "content": [
+                { "type": "thinking", "thinking": "..." },
+                { "type": "text", "text": "Let me compute it." },
+                { "type": "tool_use", "id": "toolu_1", "name": "calculator", "input": { "expression": "1+1" } }
+            ],
+            "usage": {
+                "input_tokens": 10,
+                "cache_creation_input_tokens": 2000,
+                "cache_read_input_tokens": 3000,
+                "output_tokens": 5
+            }
+        });
+        let (text, details) = claude_extract_completion(&data).unwrap();
+        assert_eq!(text, "Let me compute it.");
+        assert_eq!(details.input_tokens, Some(5010));
+        assert_eq!(details.meta.cached_tokens, Some(3000));
+        assert_eq!(details.meta.cache_write_tokens, Some(2000));
+        assert_eq!(
+            details.tool_calls,
+            vec![ToolCall {
+                id: "toolu_1".into(),
+                name: "calculator".into(),
+                arguments: json!({ "expression": "1+1" }),
+            }]
+        );
+        assert!(claude_extract_completion(&json!({ "content": [] })).is_err());
+
+        let data = json!({
+            "content": [
+                { "type": "tool_use", "id": "toolu_2", "name": RESPONSE_TOOL, "input": { "answer": 2 } }
+            ]
+        });
+        let (text, details) = claude_extract_completion(&data).unwrap();
+        assert_eq!(text, r#"{"answer":2}"#);
+        assert!(details.tool_calls.is_empty());
+    }
+
+    #[test]
+    fn test_claude_build_body_images() {
+        let image = |url: &str| MessageContentPart::ImageUrl {
+            image_url: ImageUrl { url: url.into() },
+        };
+        let data = SendData {
+            messages: vec![Message {
+                role: MessageRole::User,
+                content: MessageContent::Array(vec![
+                    MessageContentPart::Text {
+                        text: "Compare them".into(),
+                    },
+                    image("data:image/png;base64,AAAA"),
+                    image("https://example.com/cat.jpg"),
+                ]),
+            }],
+            ..test_send_data("")
+        };
+        let body = claude_build_body(data, &Model::new("claude", "claude-3-haiku")).unwrap();
+        assert_eq!(
+            body["messages"][0]["content"],
+            json!([
+                { "type": "text", "text": "Compare them" },
+                { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "AAAA" } },
+                { "type": "image", "source": { "type": "url", "url": "https://example.com/cat.jpg" } }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_claude_tool_messages() {
+        let result = |id: &str, city: &str| ToolResult {
+            call: ToolCall {
+                id: id.into(),
+                name: "get_weather".into(),
+                arguments: json!({ "city": city }),
+            },
+            output: json!("sunny"),
+        };
+        let thinking = json!({ "type": "thinking", "thinking": "Both", "signature": "sig" });
+        let data = SendData {
+            tool_rounds: vec![ToolRound {
+                text: "Checking.".into(),
+                thinking: vec![thinking.clone()],
+                results: vec![result("toolu_1", "Paris"), result("toolu_2", "Rome")],
+            }],
+            ..test_send_data("Weather in Paris and Rome?")
+        };
+        let body = claude_build_body(data, &Model::new("claude", "claude-3-haiku")).unwrap();
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[1],
+            json!({ "role": "assistant", "content": [
+                thinking,
+                { "type": "text", "text": "Checking." },
+                { "type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": { "city": "Paris" } },
+                { "type": "tool_use", "id": "toolu_2", "name": "get_weather", "input": { "city": "Rome" } },
+            ] })
+        );
+        assert_eq!(
+            messages[2],
+            json!({ "role": "user", "content": [
+                { "type": "tool_result", "tool_use_id": "toolu_1", "content": "\"sunny\"" },
+                { "type": "tool_result", "tool_use_id": "toolu_2", "content": "\"sunny\"" },
+            ] })
+        );
+    }
+
+    #[test]
+    fn test_claude_handle_event() {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, create_abort_signal());
+        let mut blocks = ClaudeStreamBlocks::default();
+        let events = [
+            json!({ "type": "content_block_start", "content_block": { "type": "thinking", "thinking": "" } }),
+            json!({ "type": "content_block_delta", "delta": { "type": "thinking_delta", "thinking": "Look " } }),
+            json!({ "type": "content_block_delta", "delta": { "type": "thinking_delta", "thinking": "it up" } }),
+            json!({ "type": "content_block_delta", "delta": { "type": "signature_delta", "signature": "sig" } }),
+            json!({ "type": "content_block_start", "content_block": { "type": "tool_use", "id": "toolu_1", "name": "get_weather" } }),
+            json!({ "type": "content_block_delta", "delta": { "type": "input_json_delta", "partial_json": "{\"city\":" } }),
+            json!({ "type": "content_block_delta", "delta": { "type": "input_json_delta", "partial_json": "\"Paris\"}" } }),
+        ];
+        for event in &events {
+            claude_handle_event(event, &mut handler, &mut blocks).unwrap();
+        }
+        claude_finish_blocks(blocks, &mut handler).unwrap();
+        let details = handler.completion_details();
+        assert_eq!(
+            details.thinking,
+            [json!({ "type": "thinking", "thinking": "Look it up", "signature": "sig" })]
+        );
+        assert_eq!(details.tool_calls[0].arguments, json!({ "city": "Paris" }));
+    }
+
+    #[test]
+    fn test_claude_add_cache_control() {
+        let mut body = json!({
+            "system": "Be brief",
+            "tools": [{ "name": "get_weather" }, { "name": "web_search" }],
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "Read it" }] },
+                { "role": "assistant", "content": [{ "type": "text", "text": "ok" }] },
+                { "role": "user", "content": [{ "type": "text", "text": "And this" }] },
+                { "role": "assistant", "content": [{ "type": "text", "text": "ok" }] },
+                { "role": "user", "content": [
+                    { "type": "image", "source": { "type": "url", "url": "https://example.com/a.png" } },
+                    { "type": "text", "text": "Summarize them" },
+                ] },
+            ],
+        });
+        claude_add_cache_control(&mut body);
+        let cache = json!({ "type": "ephemeral" });
+        assert_eq!(body["tools"][1]["cache_control"], cache);
+        assert!(body["tools"][0].get("cache_control").is_none());
+        assert_eq!(body["system"][0]["text"], "Be brief");
+        assert_eq!(body["system"][0]["cache_control"], cache);
+        let messages = &body["messages"];
+        assert_eq!(messages[4]["content"][1]["cache_control"], cache);
+        assert!(messages[4]["content"][0].get("cache_control").is_none());
+        assert_eq!(messages[2]["content"][0]["cache_control"], cache);
+        assert!(messages[3]["content"][0].get("cache_control").is_none());
+        // Out of breakpoints for the oldest message
+        assert!(messages[0]["content"][0].get("cache_control").is_none());
+    }
 }
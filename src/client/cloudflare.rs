@@ -1,5 +1,5 @@
 use super::{
-    catch_error, sse_stream, CloudflareClient, CompletionDetails, ExtraConfig, Model, ModelConfig,
+    send_request, sse_stream, CloudflareClient, CompletionDetails, ExtraConfig, Model, ModelConfig,
     PromptAction, PromptKind, SendData, SsMmessage, SseHandler,
 };
 
@@ -29,11 +29,16 @@ impl CloudflareClient {
         ("api_key", "API Key:", true, PromptKind::String),
     ];
 
+    pub fn api_base(_config: &CloudflareConfig) -> Option<String> {
+        Some(API_BASE.into())
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let account_id = self.get_account_id()?;
         let api_key = self.get_api_key()?;
 
-        let body = build_body(data, &self.model)?;
+        let mut body = build_body(data, &self.model)?;
+        self.model.merge_extra_fields(&mut body);
 
         let url = format!(
             "{API_BASE}/accounts/{account_id}/ai/run/{}",
@@ -51,12 +56,8 @@ impl CloudflareClient {
 impl_client_trait!(CloudflareClient, send_message, send_message_streaming);
 
 async fn send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
 
     extract_completion(&data)
 }
@@ -80,6 +81,16 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
         messages,
         temperature,
         top_p,
+        seed,
+        frequency_penalty,
+        presence_penalty,
+        stop: _,
+        logprobs: _,
+        functions: _,
+        tool_rounds: _,
+        thinking: _,
+        tags: _,
+        schema: _,
         stream,
     } = data;
 
@@ -97,6 +108,15 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["seed"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["frequency_penalty"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        body["presence_penalty"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
@@ -1,6 +1,7 @@
 use super::{
-    catch_error, extract_system_message, json_stream, message::*, CohereClient, CompletionDetails,
-    ExtraConfig, Model, ModelConfig, PromptAction, PromptKind, SendData, SseHandler,
+    send_request, extract_system_message, extract_vectors, json_stream, message::*, CohereClient,
+    CompletionDetails, EmbeddingsData, EmbeddingsOutput, ExtraConfig, Model, ModelConfig,
+    PromptAction, PromptKind, SendData, SseHandler,
 };
 
 use anyhow::{anyhow, bail, Result};
@@ -9,6 +10,7 @@ use serde::Deserialize;
 use serde_json::{json, Value};
 
 const API_URL: &str = "https://api.cohere.ai/v1/chat";
+const EMBED_API_URL: &str = "https://api.cohere.ai/v1/embed";
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct CohereConfig {
@@ -25,10 +27,15 @@ impl CohereClient {
     pub const PROMPTS: [PromptAction<'static>; 1] =
         [("api_key", "API Key:", true, PromptKind::String)];
 
+    pub fn api_base(_config: &CohereConfig) -> Option<String> {
+        Some(API_URL.into())
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_key = self.get_api_key()?;
 
-        let body = build_body(data, &self.model)?;
+        let mut body = build_body(data, &self.model)?;
+        self.model.merge_extra_fields(&mut body);
 
         let url = API_URL;
 
@@ -38,39 +45,76 @@ impl CohereClient {
 
         Ok(builder)
     }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        let input_type = match data.query {
+            true => "search_query",
+            false => "search_document",
+        };
+        let body = json!({
+            "model": &self.model.name,
+            "texts": data.texts,
+            "input_type": input_type,
+        });
+
+        let url = EMBED_API_URL;
+
+        debug!("Cohere Embeddings Request: {url} {body}");
+
+        let builder = client.post(url).bearer_auth(api_key).json(&body);
+
+        Ok(builder)
+    }
 }
 
-impl_client_trait!(CohereClient, send_message, send_message_streaming);
+impl_client_trait!(
+    CohereClient,
+    send_message,
+    send_message_streaming,
+    embeddings
+);
+
+async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    Ok(EmbeddingsOutput {
+        vectors: extract_vectors(&data["embeddings"], "")?,
+        input_tokens: data["meta"]["billed_units"]["input_tokens"].as_u64(),
+    })
+}
 
 async fn send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
 
     extract_completion(&data)
 }
 
 async fn send_message_streaming(builder: RequestBuilder, handler: &mut SseHandler) -> Result<()> {
-    let res = builder.send().await?;
-    let status = res.status();
-    if !status.is_success() {
-        let data: Value = res.json().await?;
-        catch_error(&data, status.as_u16())?;
-    } else {
-        let handle = |data: &str| -> Result<()> {
-            let data: Value = serde_json::from_str(data)?;
-            if let Some("text-generation") = data["event_type"].as_str() {
+    let res = send_request(builder).await?;
+    let handle = |data: &str| -> Result<()> {
+        let data: Value = serde_json::from_str(data)?;
+        match data["event_type"].as_str() {
+            Some("text-generation") => {
                 if let Some(text) = data["text"].as_str() {
                     handler.text(text)?;
                 }
             }
-            Ok(())
-        };
-        json_stream(res.bytes_stream(), handle).await?;
-    }
+            Some("stream-end") => {
+                let units = &data["response"]["meta"]["billed_units"];
+                handler.usage(units["input_tokens"].as_u64(), units["output_tokens"].as_u64())?;
+            }
+            _ => {}
+        }
+        Ok(())
+    };
+    json_stream(res.bytes_stream(), handle).await?;
     Ok(())
 }
 
@@ -79,6 +123,16 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
         mut messages,
         temperature,
         top_p,
+        seed,
+        frequency_penalty,
+        presence_penalty,
+        stop,
+        logprobs: _,
+        functions: _,
+        tool_rounds: _,
+        thinking: _,
+        tags: _,
+        schema: _,
         stream,
     } = data;
 
@@ -144,6 +198,18 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         body["p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["seed"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["frequency_penalty"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        body["presence_penalty"] = v.into();
+    }
+    if !stop.is_empty() {
+        body["stop_sequences"] = stop.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
@@ -160,6 +226,7 @@ fn extract_completion(data: &Value) -> Result<(String, CompletionDetails)> {
         id: data["generation_id"].as_str().map(|v| v.to_string()),
         input_tokens: data["meta"]["billed_units"]["input_tokens"].as_u64(),
         output_tokens: data["meta"]["billed_units"]["output_tokens"].as_u64(),
+        ..Default::default()
     };
     Ok((text.to_string(), details))
 }
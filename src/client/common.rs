@@ -1,27 +1,111 @@
-use super::{openai::OpenAIConfig, BuiltinModels, ClientConfig, Message, Model, SseHandler};
+use super::{
+    init_client, is_retryable, openai::OpenAIConfig, BuiltinModels, ClientConfig, Message,
+    Middlewares, Model, RateLimiter, RetryConfig, SseEvent, SseHandler,
+};
 
 use crate::{
     config::{GlobalConfig, Input},
+    function::{eval_tool_calls, FunctionDeclaration, ToolCall, ToolResult, ToolRound},
     render::{render_error, render_stream},
-    utils::{prompt_input_integer, prompt_input_string, tokenize, AbortSignal, PromptKind},
+    utils::{
+        extract_block, prompt_input_integer, prompt_input_string, tokenize, validate_schema,
+        AbortSignal, PromptKind,
+    },
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use async_trait::async_trait;
 use futures_util::{Stream, StreamExt};
-use lazy_static::lazy_static;
-use reqwest::{Client as ReqwestClient, ClientBuilder, Proxy, RequestBuilder};
+use nu_ansi_term::Color;
+use parking_lot::RwLock;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client as ReqwestClient, ClientBuilder, NoProxy, Proxy, Request, RequestBuilder, Response,
+};
 use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::{env, future::Future, time::Duration};
-use tokio::{sync::mpsc::unbounded_channel, time::sleep};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    io::{stdout, IsTerminal},
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        OwnedSemaphorePermit,
+    },
+    time::sleep,
+};
 
 const MODELS_YAML: &str = include_str!("../../models.yaml");
+pub(crate) const MAX_TOOL_ROUNDS: usize = 8;
+
+static ALL_CLIENT_MODELS: OnceLock<Vec<BuiltinModels>> = OnceLock::new();
 
-lazy_static! {
-    pub static ref ALL_CLIENT_MODELS: Vec<BuiltinModels> =
-        serde_yaml::from_str(MODELS_YAML).unwrap();
+/// The models of the platforms, from the embedded `models.yaml` and the user's overrides.
+pub fn all_client_models() -> &'static [BuiltinModels] {
+    ALL_CLIENT_MODELS.get_or_init(|| serde_yaml::from_str(MODELS_YAML).unwrap())
+}
+
+/// Merge the user's `models.yaml` into the embedded one: the fields of a model override the same
+/// fields of the model with the same platform and name, new platforms and models are added.
+pub fn init_client_models(overrides_path: &Path) -> Result<()> {
+    if !overrides_path.exists() {
+        return Ok(());
+    }
+    let err = || format!("Invalid models at {}", overrides_path.display());
+    let content = std::fs::read_to_string(overrides_path).with_context(err)?;
+    let overrides: serde_yaml::Value = serde_yaml::from_str(&content).with_context(err)?;
+    let mut models: serde_yaml::Value = serde_yaml::from_str(MODELS_YAML)?;
+    merge_models(&mut models, overrides).with_context(err)?;
+    let models = serde_yaml::from_value(models).with_context(err)?;
+    let _ = ALL_CLIENT_MODELS.set(models);
+    Ok(())
+}
+
+fn merge_models(models: &mut serde_yaml::Value, overrides: serde_yaml::Value) -> Result<()> {
+    let (Some(platforms), serde_yaml::Value::Sequence(overrides)) =
+        (models.as_sequence_mut(), overrides)
+    else {
+        bail!("Expect a list of platforms");
+    };
+    for item in overrides {
+        let Some(platform) = platforms
+            .iter_mut()
+            .find(|v| v["platform"] == item["platform"])
+        else {
+            platforms.push(item);
+            continue;
+        };
+        let (Some(list), Some(override_list)) = (
+            platform["models"].as_sequence_mut(),
+            item["models"].as_sequence(),
+        ) else {
+            continue;
+        };
+        for model in override_list {
+            match (
+                list.iter_mut()
+                    .find(|v| v["name"] == model["name"])
+                    .and_then(|v| v.as_mapping_mut()),
+                model.as_mapping(),
+            ) {
+                (Some(found), Some(fields)) => {
+                    for (key, value) in fields {
+                        found.insert(key.clone(), value.clone());
+                    }
+                }
+                _ => list.push(model.clone()),
+            }
+        }
+    }
+    Ok(())
 }
 
 #[macro_export]
@@ -47,6 +131,7 @@ macro_rules! register_client {
             Unknown,
         }
 
+        #[allow(dead_code)]
         #[derive(Debug, Clone, serde::Deserialize)]
         #[serde(tag = "type")]
         pub enum ClientModel {
@@ -71,7 +156,7 @@ macro_rules! register_client {
                 pub const NAME: &'static str = $name;
 
                 pub fn init(global_config: &$crate::config::GlobalConfig) -> Option<Box<dyn Client>> {
-                    let model = global_config.read().model.clone();
+                    let mut model = global_config.read().model.clone();
                     let config = global_config.read().clients.iter().find_map(|client_config| {
                         if let ClientConfig::$config(c) = client_config {
                             if Self::name(c) == &model.client_name {
@@ -80,6 +165,10 @@ macro_rules! register_client {
                         }
                         None
                     })?;
+                    model.merge_extra_body(
+                        config.extra.as_ref().and_then(|v| v.extra_body.as_ref()),
+                        &global_config.read().extra_body,
+                    );
 
                     Some(Box::new(Self {
                         global_config: global_config.clone(),
@@ -90,16 +179,22 @@ macro_rules! register_client {
 
                 pub fn list_models(local_config: &$config) -> Vec<Model> {
                     let client_name = Self::name(local_config);
-                    if local_config.models.is_empty() {
-                        if let Some(client_models) = $crate::client::ALL_CLIENT_MODELS.iter().find(|v| {
+                    let mut models = if local_config.models.is_empty() {
+                        match $crate::client::all_client_models().iter().find(|v| {
                             v.platform == $name || ($name == "openai-compatible" && local_config.name.as_deref() == Some(&v.platform))
                         }) {
-                            return Model::from_config(client_name, &client_models.models);
+                            Some(client_models) => Model::from_config(client_name, &client_models.models),
+                            None => vec![],
                         }
-                        vec![]
                     } else {
                         Model::from_config(client_name, &local_config.models)
+                    };
+                    if !$crate::client::TOOL_CLIENT_TYPES.contains(&$name) {
+                        for model in models.iter_mut() {
+                            model.set_supports_function_calling(false);
+                        }
                     }
+                    models
                 }
 
                 pub fn name(config: &$config) -> &str {
@@ -110,6 +205,10 @@ macro_rules! register_client {
         )+
 
         pub fn init_client(config: &$crate::config::GlobalConfig) -> anyhow::Result<Box<dyn Client>> {
+            {
+                let config = config.read();
+                config.ensure_offline_model(&config.model)?;
+            }
             None
             $(.or_else(|| $client::init(config)))+
             .ok_or_else(|| {
@@ -131,6 +230,20 @@ macro_rules! register_client {
             Ok(())
         }
 
+        pub fn find_client_extra_config(config: &$crate::config::Config) -> Option<$crate::client::ExtraConfig> {
+            config.clients.iter().find_map(|client_config| match client_config {
+                $(ClientConfig::$config(c) if $client::name(c) == config.model.client_name => c.extra.clone(),)+
+                _ => None,
+            })
+        }
+
+        pub fn find_client_endpoint(config: &$crate::config::Config, client_name: &str) -> Option<(&'static str, Option<String>)> {
+            config.clients.iter().find_map(|client_config| match client_config {
+                $(ClientConfig::$config(c) if $client::name(c) == client_name => Some(($client::NAME, $client::api_base(c))),)+
+                _ => None,
+            })
+        }
+
         pub fn list_client_types() -> Vec<&'static str> {
             let mut client_types: Vec<_> = vec![$($client::NAME,)+];
             client_types.extend($crate::client::OPENAI_COMPATIBLE_PLATFORMS.iter().map(|(name, _)| *name));
@@ -149,21 +262,51 @@ macro_rules! register_client {
             anyhow::bail!("Unknown client '{}'", client)
         }
 
-        static mut ALL_CLIENTS: Option<Vec<$crate::client::Model>> = None;
-
-        pub fn list_models(config: &$crate::config::Config) -> Vec<&$crate::client::Model> {
-            if unsafe { ALL_CLIENTS.is_none() } {
-                let models: Vec<_> = config
+        /// Fetch the models of the clients that list them, the clients failing to are skipped.
+        pub async fn fetch_models(config: &$crate::config::GlobalConfig) -> Vec<$crate::client::Model> {
+            let clients: Vec<Box<dyn Client>> = {
+                let global_config = config.read();
+                global_config
                     .clients
                     .iter()
-                    .flat_map(|v| match v {
-                        $(ClientConfig::$config(c) => $client::list_models(c),)+
-                        ClientConfig::Unknown => vec![],
+                    .filter_map(|client_config| match client_config {
+                        $(ClientConfig::$config(c) => Some(Box::new($client {
+                            global_config: config.clone(),
+                            config: c.clone(),
+                            model: $crate::client::Model::new($client::name(c), ""),
+                        }) as Box<dyn Client>),)+
+                        ClientConfig::Unknown => None,
                     })
-                    .collect();
-                unsafe { ALL_CLIENTS = Some(models) };
+                    .filter(|v| !global_config.offline_mode || global_config.is_local_model(v.model()))
+                    .collect()
+            };
+            let results = futures_util::future::join_all(clients.iter().map(|v| v.fetch_models())).await;
+            let mut models = vec![];
+            for (client, result) in clients.iter().zip(results) {
+                match result {
+                    Ok(list) => models.extend(list),
+                    Err(err) => debug!("Skip the models of '{}': {err:?}", client.model().client_name),
+                }
             }
-            unsafe { ALL_CLIENTS.as_ref().unwrap().iter().collect() }
+            models
+        }
+
+        static ALL_CLIENTS: std::sync::OnceLock<Vec<$crate::client::Model>> = std::sync::OnceLock::new();
+
+        pub fn list_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
+            ALL_CLIENTS
+                .get_or_init(|| {
+                    config
+                        .clients
+                        .iter()
+                        .flat_map(|v| match v {
+                            $(ClientConfig::$config(c) => $client::list_models(c),)+
+                            ClientConfig::Unknown => vec![],
+                        })
+                        .collect()
+                })
+                .iter()
+                .collect()
         }
     };
 }
@@ -200,7 +343,7 @@ macro_rules! client_common_fns {
 
 #[macro_export]
 macro_rules! impl_client_trait {
-    ($client:ident, $send_message:path, $send_message_streaming:path) => {
+    ($client:ident, $send_message:path, $send_message_streaming:path $(, $embeddings:path)? $(; models = $models:path)?) => {
         #[async_trait::async_trait]
         impl $crate::client::Client for $crate::client::$client {
             client_common_fns!();
@@ -223,6 +366,35 @@ macro_rules! impl_client_trait {
                 let builder = self.request_builder(client, data)?;
                 $send_message_streaming(builder, handler).await
             }
+
+            async fn request_inner(
+                &self,
+                client: &reqwest::Client,
+                data: $crate::client::SendData,
+            ) -> anyhow::Result<reqwest::RequestBuilder> {
+                self.request_builder(client, data)
+            }
+
+            $(
+                async fn embeddings_inner(
+                    &self,
+                    client: &reqwest::Client,
+                    data: $crate::client::EmbeddingsData,
+                ) -> anyhow::Result<$crate::client::EmbeddingsOutput> {
+                    let builder = self.embeddings_builder(client, data)?;
+                    $embeddings(builder).await
+                }
+            )?
+
+            $(
+                async fn fetch_models_inner(
+                    &self,
+                    client: &reqwest::Client,
+                ) -> anyhow::Result<Vec<$crate::client::Model>> {
+                    let builder = self.models_builder(client)?;
+                    $models(builder, &self.model.client_name).await
+                }
+            )?
         }
     };
 }
@@ -272,8 +444,37 @@ pub trait Client: Sync + Send {
             .as_ref()
             .and_then(|v| v.connect_timeout)
             .unwrap_or(10);
-        let proxy = options.as_ref().and_then(|v| v.proxy.clone());
-        builder = set_proxy(builder, &proxy)?;
+        let (proxy, no_proxy) = {
+            let global_config = self.config().0.read();
+            (
+                options
+                    .as_ref()
+                    .and_then(|v| v.proxy.clone())
+                    .or_else(|| global_config.proxy.clone()),
+                options
+                    .as_ref()
+                    .and_then(|v| v.no_proxy.clone())
+                    .or_else(|| global_config.no_proxy.clone()),
+            )
+        };
+        builder = set_proxy(builder, &proxy, &no_proxy)?;
+        let user_agent = options
+            .as_ref()
+            .and_then(|v| v.user_agent.clone())
+            .or_else(|| self.config().0.read().user_agent.clone())
+            .unwrap_or_else(|| {
+                format!("{}/{}", env!("CARGO_CRATE_NAME"), env!("CARGO_PKG_VERSION"))
+            });
+        builder = builder.user_agent(user_agent);
+        if let Some(headers) = options.as_ref().and_then(|v| v.extra_headers.as_ref()) {
+            builder = builder.default_headers(build_headers(headers)?);
+        }
+        if let Some(timeout) = options.as_ref().and_then(|v| v.timeout) {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = options.as_ref().and_then(|v| v.read_timeout) {
+            builder = builder.read_timeout(Duration::from_secs(timeout));
+        }
         let client = builder
             .connect_timeout(Duration::from_secs(timeout))
             .build()
@@ -284,32 +485,44 @@ pub trait Client: Sync + Send {
     async fn send_message(&self, input: Input) -> Result<(String, CompletionDetails)> {
         let global_config = self.config().0;
         if global_config.read().dry_run {
-            let content = global_config.read().echo_messages(&input);
+            let content = self.dry_run(&input, false).await?;
             return Ok((content, CompletionDetails::default()));
         }
         let client = self.build_client()?;
-        let data = global_config.read().prepare_send_data(&input, false)?;
-        self.send_message_inner(&client, data)
-            .await
-            .with_context(|| "Failed to get answer")
-    }
-
-    async fn send_message_streaming(&self, input: &Input, handler: &mut SseHandler) -> Result<()> {
-        async fn watch_abort(abort: AbortSignal) {
-            loop {
-                if abort.aborted() {
-                    break;
+        let mut input = input;
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let data = global_config.read().prepare_send_data(&input, false)?;
+            let schema = data.schema.clone();
+            let ret = match self.send_data(&client, data).await {
+                Err(err) => self.fail_over(&input, err).await,
+                ret => ret,
+            };
+            let (text, mut details) = ret.with_context(|| "Failed to get answer")?;
+            global_config.write().add_session_usage(&details);
+            let tool_calls = std::mem::take(&mut details.tool_calls);
+            if tool_calls.is_empty() {
+                if let Some(schema) = &schema {
+                    check_schema(&text, schema)?;
                 }
-                sleep(Duration::from_millis(100)).await;
+                return Ok((text, details));
             }
+            input.add_tool_round(ToolRound {
+                text,
+                thinking: std::mem::take(&mut details.thinking),
+                results: eval_tool_calls(global_config, tool_calls).await,
+            });
         }
+        bail!("Too many rounds of tool calls")
+    }
+
+    async fn send_message_streaming(&self, input: &Input, handler: &mut SseHandler) -> Result<()> {
         let abort = handler.get_abort();
         let input = input.clone();
         tokio::select! {
             ret = async {
                 let global_config = self.config().0;
                 if global_config.read().dry_run {
-                    let content = global_config.read().echo_messages(&input);
+                    let content = self.dry_run(&input, true).await?;
                     let tokens = tokenize(&content);
                     for token in tokens {
                         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -319,10 +532,24 @@ pub trait Client: Sync + Send {
                 }
                 let client = self.build_client()?;
                 let data = global_config.read().prepare_send_data(&input, true)?;
-                self.send_message_streaming_inner(&client, handler, data).await
+                match self.send_data_streaming(&client, handler, data).await {
+                    // Another model can't take over a reply that has started showing
+                    Err(err) if handler.get_buffer().is_empty() && handler.tool_calls().is_empty() => {
+                        self.fail_over_streaming(&input, handler, err).await
+                    }
+                    ret => ret,
+                }
             } => {
+                let ret = ret.with_context(|| "Failed to get answer");
+                match &ret {
+                    Ok(()) => {
+                        let details = handler.completion_details();
+                        self.config().0.write().add_session_usage(&details);
+                    }
+                    Err(err) => handler.error(err)?,
+                }
                 handler.done()?;
-                ret.with_context(|| "Failed to get answer")
+                ret
             }
             _ = watch_abort(abort.clone()) => {
                 handler.done()?;
@@ -331,6 +558,172 @@ pub trait Client: Sync + Send {
         }
     }
 
+    /// Send the data through the middlewares, once it fits in the rate limits.
+    async fn send_data(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<(String, CompletionDetails)> {
+        let retry = self.config().1.as_ref().and_then(|v| v.retry.as_ref());
+        let middlewares = Middlewares::init(&self.config().0.read().middlewares, retry)?;
+        let _permit = self.acquire_rate_limit(&data).await;
+        middlewares.send_message(self, client, data).await
+    }
+
+    async fn send_data_streaming(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: SendData,
+    ) -> Result<()> {
+        let retry = self.config().1.as_ref().and_then(|v| v.retry.as_ref());
+        let middlewares = Arc::new(Middlewares::init(
+            &self.config().0.read().middlewares,
+            retry,
+        )?);
+        handler.set_middlewares(middlewares.clone());
+        let _permit = self.acquire_rate_limit(&data).await;
+        middlewares
+            .send_message_streaming(self, client, handler, data)
+            .await
+    }
+
+    /// The clients of the models in `fallback_models` that can answer the input, in order.
+    fn fallback_clients(&self, input: &Input) -> Result<Vec<Box<dyn Client>>> {
+        let config = self.config().0.read();
+        let capabilities = input.required_capabilities();
+        let mut clients = vec![];
+        for model_id in &config.fallback_models {
+            if model_id == &self.model().id() {
+                continue;
+            }
+            let fallback_config = config.fallback_config(model_id)?;
+            if !fallback_config.model.capabilities.contains(capabilities) {
+                continue;
+            }
+            clients.push(init_client(&Arc::new(RwLock::new(fallback_config)))?);
+        }
+        Ok(clients)
+    }
+
+    /// Send the input to the fallback models in turn after `err`, the error of the last one
+    /// if they all fail. Only an outage or a rate limit fails over, the other errors would fail
+    /// the same with another provider, e.g. a too long input.
+    async fn fail_over(&self, input: &Input, err: Error) -> Result<(String, CompletionDetails)> {
+        let mut err = err;
+        let mut model_id = self.model().id();
+        for fallback in self.fallback_clients(input)? {
+            if !is_retryable(&err) {
+                break;
+            }
+            let notice = failover_notice(&model_id, &fallback.model().id(), &err);
+            self.config().0.read().print_warning(&notice);
+            let ret = async {
+                let client = fallback.build_client()?;
+                let data = fallback.config().0.read().prepare_send_data(input, false)?;
+                fallback.send_data(&client, data).await
+            }
+            .await;
+            match ret {
+                Ok(ret) => return Ok(ret),
+                Err(fallback_err) => err = fallback_err,
+            }
+            model_id = fallback.model().id();
+        }
+        Err(err)
+    }
+
+    async fn fail_over_streaming(
+        &self,
+        input: &Input,
+        handler: &mut SseHandler,
+        err: Error,
+    ) -> Result<()> {
+        let mut err = err;
+        let mut model_id = self.model().id();
+        for fallback in self.fallback_clients(input)? {
+            if handler.get_abort().aborted() || !is_retryable(&err) {
+                break;
+            }
+            handler.notice(&failover_notice(&model_id, &fallback.model().id(), &err))?;
+            let ret = async {
+                let client = fallback.build_client()?;
+                let data = fallback.config().0.read().prepare_send_data(input, true)?;
+                fallback.send_data_streaming(&client, handler, data).await
+            }
+            .await;
+            match ret {
+                Ok(()) => return Ok(()),
+                Err(fallback_err) => err = fallback_err,
+            }
+            if !handler.get_buffer().is_empty() || !handler.tool_calls().is_empty() {
+                break;
+            }
+            model_id = fallback.model().id();
+        }
+        Err(err)
+    }
+
+    /// The request that would be sent for the input, secrets redacted.
+    async fn dry_run(&self, input: &Input, stream: bool) -> Result<String> {
+        let global_config = self.config().0;
+        let client = self.build_client()?;
+        let mut data = global_config.read().prepare_send_data(input, stream)?;
+        let middlewares = Middlewares::init(&global_config.read().middlewares, None)?;
+        middlewares.on_request(self.model(), &mut data)?;
+        let mut request = self.request_inner(&client, data).await?.build()?;
+        // The client adds them when sending, after the ones of the request
+        if let Some(headers) = self
+            .config()
+            .1
+            .as_ref()
+            .and_then(|v| v.extra_headers.as_ref())
+        {
+            for (name, value) in build_headers(headers)? {
+                if let Some(name) = name {
+                    request.headers_mut().entry(name).or_insert(value);
+                }
+            }
+        }
+        Ok(describe_request(&request))
+    }
+
+    /// The limiter shared by the requests to the provider of the client.
+    fn rate_limiter(&self) -> Arc<RateLimiter> {
+        let limits = self.config().0.read().rate_limits();
+        RateLimiter::for_client(&self.model().client_name, limits)
+    }
+
+    /// Wait until the request fits in the rate limits of the provider.
+    async fn acquire_rate_limit(&self, data: &SendData) -> Option<OwnedSemaphorePermit> {
+        let tokens = self.model().count_tokens(&data.messages) as u64;
+        self.rate_limiter().acquire(tokens).await
+    }
+
+    /// Embed the texts with the current model, which must be an embedding model.
+    async fn embeddings(&self, data: EmbeddingsData) -> Result<EmbeddingsOutput> {
+        let client = self.build_client()?;
+        let retry = self.config().1.as_ref().and_then(|v| v.retry.as_ref());
+        let middlewares = Middlewares::init(&self.config().0.read().middlewares, retry)?;
+        let tokens = data.texts.iter().map(|v| tokenize(v).len() as u64).sum();
+        let _permit = self.rate_limiter().acquire(tokens).await;
+        let output = middlewares
+            .embeddings(self, &client, data)
+            .await
+            .with_context(|| "Failed to get embeddings")?;
+        Ok(output)
+    }
+
+    /// The models the provider serves now, including the ones missing from `models.yaml`.
+    async fn fetch_models(&self) -> Result<Vec<Model>> {
+        let client = self.build_client()?;
+        let models = self
+            .fetch_models_inner(&client)
+            .await
+            .with_context(|| "Failed to fetch models")?;
+        Ok(models)
+    }
+
     async fn send_message_inner(
         &self,
         client: &ReqwestClient,
@@ -343,6 +736,36 @@ pub trait Client: Sync + Send {
         handler: &mut SseHandler,
         data: SendData,
     ) -> Result<()>;
+
+    /// The request of `send_message_inner`, without sending it.
+    async fn request_inner(
+        &self,
+        _client: &ReqwestClient,
+        _data: SendData,
+    ) -> Result<RequestBuilder> {
+        bail!(
+            "The client '{}' doesn't support dry runs",
+            self.model().client_name
+        )
+    }
+
+    async fn embeddings_inner(
+        &self,
+        _client: &ReqwestClient,
+        _data: EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        bail!(
+            "The client '{}' doesn't support embeddings",
+            self.model().client_name
+        )
+    }
+
+    async fn fetch_models_inner(&self, _client: &ReqwestClient) -> Result<Vec<Model>> {
+        bail!(
+            "The client '{}' doesn't list its models",
+            self.model().client_name
+        )
+    }
 }
 
 impl Default for ClientConfig {
@@ -354,22 +777,220 @@ impl Default for ClientConfig {
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct ExtraConfig {
     pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
     pub connect_timeout: Option<u64>,
+    /// Seconds for a whole request, until the reply is complete
+    pub timeout: Option<u64>,
+    /// Seconds without receiving anything before giving up on a request
+    pub read_timeout: Option<u64>,
+    /// Seconds to wait for a streamed reply to start, the reply itself may take longer
+    pub first_token_timeout: Option<u64>,
+    pub user_agent: Option<String>,
+    pub extra_body: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Headers added to every request of the client, the ones it sets itself take precedence
+    pub extra_headers: Option<HashMap<String, String>>,
+    pub retry: Option<RetryConfig>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SendData {
     pub messages: Vec<Message>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub frequency_penalty: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    /// Sequences at which the model stops generating.
+    pub stop: Vec<String>,
+    /// Return the log probabilities of the output tokens with this many top alternatives.
+    pub logprobs: Option<u64>,
+    pub functions: Option<Vec<FunctionDeclaration>>,
+    /// The rounds of tool calls made so far while answering, with their results.
+    pub tool_rounds: Vec<ToolRound>,
+    pub thinking: Option<Thinking>,
+    /// Attribute the request, sent to the providers that accept metadata.
+    pub tags: HashMap<String, String>,
+    /// The JSON schema the answer must conform to.
+    pub schema: Option<Value>,
     pub stream: bool,
 }
 
+impl SendData {
+    /// The results of all the rounds of tool calls.
+    pub fn tool_results(&self) -> impl Iterator<Item = &ToolResult> {
+        self.tool_rounds.iter().flat_map(|v| &v.results)
+    }
+}
+
+/// A request with a single user message and nothing else set, for the tests of the clients.
+#[cfg(test)]
+pub fn test_send_data(text: &str) -> SendData {
+    SendData {
+        messages: vec![Message {
+            role: super::MessageRole::User,
+            content: super::MessageContent::Text(text.into()),
+        }],
+        temperature: None,
+        top_p: None,
+        seed: None,
+        frequency_penalty: None,
+        presence_penalty: None,
+        stop: vec![],
+        logprobs: None,
+        functions: None,
+        tool_rounds: vec![],
+        thinking: None,
+        tags: Default::default(),
+        schema: None,
+        stream: false,
+    }
+}
+
+/// How much a model may think before answering, mapped to the controls of each provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Thinking {
+    Low,
+    Medium,
+    High,
+    Budget(u64),
+}
+
+impl Thinking {
+    pub fn budget_tokens(&self) -> u64 {
+        match self {
+            Thinking::Low => 1024,
+            Thinking::Medium => 4096,
+            Thinking::High => 16384,
+            Thinking::Budget(v) => *v,
+        }
+    }
+
+    pub fn effort(&self) -> &'static str {
+        match self {
+            Thinking::Low => "low",
+            Thinking::Medium => "medium",
+            Thinking::High => "high",
+            Thinking::Budget(v) if *v <= 2048 => "low",
+            Thinking::Budget(v) if *v <= 8192 => "medium",
+            Thinking::Budget(_) => "high",
+        }
+    }
+}
+
+impl std::str::FromStr for Thinking {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "low" => Ok(Thinking::Low),
+            "medium" => Ok(Thinking::Medium),
+            "high" => Ok(Thinking::High),
+            _ => match s.parse() {
+                Ok(v) if v > 0 => Ok(Thinking::Budget(v)),
+                _ => {
+                    bail!("Invalid thinking '{s}', expect low, medium, high or a number of tokens")
+                }
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Thinking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Thinking::Budget(v) => write!(f, "{v}"),
+            _ => write!(f, "{}", self.effort()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Thinking {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let text = match &value {
+            Value::String(v) => v.clone(),
+            v => v.to_string(),
+        };
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EmbeddingsData {
+    pub texts: Vec<String>,
+    /// The texts are search queries rather than documents, some providers embed them differently.
+    pub query: bool,
+}
+
+impl EmbeddingsData {
+    pub fn new(texts: Vec<String>, query: bool) -> Self {
+        Self { texts, query }
+    }
+}
+
+/// A vector by text, in the order of the texts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EmbeddingsOutput {
+    pub vectors: Vec<Vec<f32>>,
+    pub input_tokens: Option<u64>,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct CompletionDetails {
     pub id: Option<String>,
     pub input_tokens: Option<u64>,
     pub output_tokens: Option<u64>,
+    pub tool_calls: Vec<ToolCall>,
+    /// The blocks of thinking to send back with the tool results, see [`ToolRound`]
+    pub thinking: Vec<Value>,
+    /// The `logprobs.content` of OpenAI-compatible APIs, one item per output token.
+    pub logprobs: Option<Value>,
+    pub meta: ResponseMeta,
+    /// In USD, by the prices of the model and the tokens reported by the provider
+    pub cost: Option<f64>,
+}
+
+/// How the request was served, as far as the provider, or a gateway such as OpenRouter in
+/// front of it, tells. It explains the latency that varies from one reply to the next.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResponseMeta {
+    /// The upstream provider the gateway routed the request to
+    pub provider: Option<String>,
+    /// How long the request waited in the queue of the provider
+    pub queue_time: Option<Duration>,
+    /// The input tokens read from the prompt cache
+    pub cached_tokens: Option<u64>,
+    /// The input tokens written to the prompt cache
+    pub cache_write_tokens: Option<u64>,
+}
+
+impl ResponseMeta {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Fill in the values that the pieces of a streamed reply tell one at a time.
+    pub fn merge(&mut self, other: ResponseMeta) {
+        self.provider = other.provider.or(self.provider.take());
+        self.queue_time = other.queue_time.or(self.queue_time);
+        self.cached_tokens = other.cached_tokens.or(self.cached_tokens);
+        self.cache_write_tokens = other.cache_write_tokens.or(self.cache_write_tokens);
+    }
+
+    /// e.g. `provider: Groq, queue: 12ms, cached: 1024 tokens`
+    pub fn summary(&self) -> String {
+        let mut parts = vec![];
+        if let Some(provider) = &self.provider {
+            parts.push(format!("provider: {provider}"));
+        }
+        if let Some(queue_time) = self.queue_time {
+            parts.push(format!("queue: {}ms", queue_time.as_millis()));
+        }
+        if let Some(cached_tokens) = self.cached_tokens {
+            parts.push(format!("cached: {cached_tokens} tokens"));
+        }
+        parts.join(", ")
+    }
 }
 
 pub type PromptAction<'a> = (&'a str, &'a str, bool, PromptKind);
@@ -396,7 +1017,7 @@ pub fn create_openai_compatible_client_config(client: &str) -> Result<Option<(St
                 "name": name,
                 "api_base": api_base,
             });
-            let prompts = if ALL_CLIENT_MODELS.iter().any(|v| &v.platform == name) {
+            let prompts = if all_client_models().iter().any(|v| &v.platform == name) {
                 vec![("api_key", "API Key:", false, PromptKind::String)]
             } else {
                 vec![
@@ -418,35 +1039,140 @@ pub fn create_openai_compatible_client_config(client: &str) -> Result<Option<(St
     }
 }
 
+/// Resolve once the abort signal is set, used to race against a running request.
+pub async fn watch_abort(abort: AbortSignal) {
+    loop {
+        if abort.aborted() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
 pub async fn send_stream(
     input: &Input,
     client: &dyn Client,
     config: &GlobalConfig,
     abort: AbortSignal,
 ) -> Result<String> {
-    let (tx, rx) = unbounded_channel();
-    let mut stream_handler = SseHandler::new(tx, abort.clone());
+    send_stream_with(input, client, config, abort, None, |calls| {
+        eval_tool_calls(config, calls)
+    })
+    .await
+}
 
-    let (send_ret, rend_ret) = tokio::join!(
-        client.send_message_streaming(input, &mut stream_handler),
-        render_stream(rx, config, abort.clone()),
-    );
-    if let Err(err) = rend_ret {
-        render_error(err, config.read().highlight);
-    }
-    let output = stream_handler.get_buffer().to_string();
-    match send_ret {
-        Ok(_) => {
-            println!();
-            Ok(output)
+/// Stream the answer with its rounds of tool calls.
+///
+/// The events are rendered on the terminal, or forwarded to `events` if it is set. The tool
+/// calls of each round are run by `eval_tools`.
+pub async fn send_stream_with<F, Fut>(
+    input: &Input,
+    client: &dyn Client,
+    config: &GlobalConfig,
+    abort: AbortSignal,
+    events: Option<UnboundedSender<SseEvent>>,
+    mut eval_tools: F,
+) -> Result<String>
+where
+    F: FnMut(Vec<ToolCall>) -> Fut,
+    Fut: Future<Output = Vec<ToolResult>>,
+{
+    let mut input = input.clone();
+    let mut outputs = vec![];
+    let mut meta = ResponseMeta::default();
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let mut stream_handler;
+        let send_ret = match &events {
+            Some(tx) => {
+                stream_handler = SseHandler::new(tx.clone(), abort.clone());
+                client
+                    .send_message_streaming(&input, &mut stream_handler)
+                    .await
+            }
+            None => {
+                let (tx, rx) = unbounded_channel();
+                stream_handler = SseHandler::new(tx, abort.clone());
+                let (send_ret, rend_ret) = tokio::join!(
+                    client.send_message_streaming(&input, &mut stream_handler),
+                    render_stream(rx, config, abort.clone()),
+                );
+                if let Err(err) = rend_ret {
+                    render_error(err, config.read().highlight);
+                }
+                send_ret
+            }
+        };
+        let output = stream_handler.get_buffer().to_string();
+        if let Err(err) = send_ret {
+            if !output.is_empty() && events.is_none() {
+                println!();
+            }
+            return Err(err);
         }
-        Err(err) => {
-            if !output.is_empty() {
+        if !output.is_empty() {
+            if events.is_none() {
                 println!();
             }
-            Err(err)
+            outputs.push(output);
         }
+        let details = stream_handler.completion_details();
+        meta.merge(details.meta);
+        // The tool calls run after the round is rendered, so that they can ask for confirmation.
+        let tool_calls = stream_handler.take_tool_calls();
+        if tool_calls.is_empty() || abort.aborted() {
+            if events.is_none() {
+                print_response_meta(&meta, config);
+            }
+            if let (Some(schema), false) = (config.read().response_schema(&input), abort.aborted())
+            {
+                check_schema(
+                    outputs.last().map(|v| v.as_str()).unwrap_or_default(),
+                    &schema,
+                )?;
+            }
+            return Ok(outputs.join("\n\n"));
+        }
+        if events.is_none() {
+            for call in &tool_calls {
+                println!(
+                    "{}",
+                    dimmed_text(&format!("Call {} {}", call.name, call.arguments), config)
+                );
+            }
+        }
+        input.add_tool_round(ToolRound {
+            text: stream_handler.get_buffer().to_string(),
+            thinking: details.thinking,
+            results: eval_tools(tool_calls).await,
+        });
     }
+    bail!("Too many rounds of tool calls")
+}
+
+/// Tell how the reply was served below it, e.g. the provider OpenRouter routed it to.
+///
+/// Only on the terminal, so that the reply can be piped on its own.
+pub fn print_response_meta(meta: &ResponseMeta, config: &GlobalConfig) {
+    if meta.is_empty() || !stdout().is_terminal() {
+        return;
+    }
+    println!("{}", dimmed_text(&meta.summary(), config));
+}
+
+fn dimmed_text(text: &str, config: &GlobalConfig) -> String {
+    if !config.read().highlight {
+        return text.to_string();
+    }
+    let color = if config.read().light_theme {
+        Color::LightGray
+    } else {
+        Color::DarkGray
+    };
+    color.paint(text).to_string()
+}
+
+fn failover_notice(model_id: &str, fallback_id: &str, err: &Error) -> String {
+    format!("'{model_id}' failed ({err}), trying '{fallback_id}'")
 }
 
 #[allow(unused)]
@@ -466,20 +1192,252 @@ where
     Ok(())
 }
 
+/// Read the vector at `pointer` of each item of the list, `""` when the items are the vectors.
+pub fn extract_vectors(list: &Value, pointer: &str) -> Result<Vec<Vec<f32>>> {
+    let items = list
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid embeddings data: {list}"))?;
+    items
+        .iter()
+        .map(|item| {
+            item.pointer(pointer)
+                .and_then(|v| v.as_array())
+                .and_then(|v| {
+                    v.iter()
+                        .map(|v| v.as_f64().map(|v| v as f32))
+                        .collect::<Option<Vec<f32>>>()
+                })
+                .ok_or_else(|| anyhow!("Invalid embeddings data: {item}"))
+        })
+        .collect()
+}
+
+/// A response with an error status, its message is the error the API gave.
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    /// How long the `Retry-After` header asks to wait before sending again.
+    pub retry_after: Option<Duration>,
+    pub(crate) message: String,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// A streamed reply didn't start within the `first_token_timeout` of the client.
+#[derive(Debug)]
+pub struct FirstTokenTimeout(pub Duration);
+
+impl std::fmt::Display for FirstTokenTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No reply started within {:?}", self.0)
+    }
+}
+
+impl std::error::Error for FirstTokenTimeout {}
+
+/// The answer of the model doesn't conform to the JSON schema of the request.
+#[derive(Debug)]
+pub struct SchemaError {
+    pub output: String,
+    pub(crate) message: String,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The answer doesn't conform to the schema, {}",
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// Check the answer against the JSON schema, a fenced code block around the JSON is accepted.
+pub fn check_schema(output: &str, schema: &Value) -> Result<()> {
+    let text = match output.trim_start().starts_with("```") {
+        true => extract_block(output),
+        false => output.trim().to_string(),
+    };
+    let message = match serde_json::from_str::<Value>(&text) {
+        Ok(value) => {
+            let errors = validate_schema(schema, &value, "$");
+            if errors.is_empty() {
+                return Ok(());
+            }
+            errors.join("; ")
+        }
+        Err(err) => format!("invalid JSON, {err}"),
+    };
+    Err(SchemaError {
+        output: output.to_string(),
+        message,
+    }
+    .into())
+}
+
+/// Send the request, a response with an error status fails with an [`HttpError`].
+pub async fn send_request(builder: RequestBuilder) -> Result<Response> {
+    let res = trace_request(builder)?.send().await?;
+    if !res.status().is_success() {
+        return Err(response_error(res).await);
+    }
+    Ok(trace_response(res))
+}
+
+/// Log the request, with `--debug`.
+fn trace_request(builder: RequestBuilder) -> Result<RequestBuilder> {
+    if !log_enabled!(log::Level::Trace) {
+        return Ok(builder);
+    }
+    let (client, request) = builder.build_split();
+    let request = request?;
+    trace!("Request {}", describe_request(&request));
+    Ok(RequestBuilder::from_parts(client, request))
+}
+
+/// Log the chunks of the response as they are read, with `--debug`.
+fn trace_response(res: Response) -> Response {
+    if !log_enabled!(log::Level::Trace) {
+        return res;
+    }
+    trace!("Response {}", res.status());
+    let (status, version, headers) = (res.status(), res.version(), res.headers().clone());
+    let stream = res.bytes_stream().inspect(|chunk| {
+        if let Ok(chunk) = chunk {
+            trace!("Response chunk {}", String::from_utf8_lossy(chunk));
+        }
+    });
+    let mut res = http::Response::new(reqwest::Body::wrap_stream(stream));
+    *res.status_mut() = status;
+    *res.version_mut() = version;
+    *res.headers_mut() = headers;
+    res.into()
+}
+
+/// The method, URL, headers and body of the request, with the credentials redacted.
+pub fn describe_request(request: &Request) -> String {
+    let mut url = request.url().clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| match is_secret(&name) {
+            true => (name.to_string(), REDACTED.to_string()),
+            false => (name.to_string(), value.to_string()),
+        })
+        .collect();
+    if !pairs.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    let mut output = format!("{} {url}\n", request.method());
+    for (name, value) in request.headers() {
+        let value = match is_secret(name.as_str()) {
+            true => REDACTED,
+            false => value.to_str().unwrap_or_default(),
+        };
+        output.push_str(&format!("{name}: {value}\n"));
+    }
+    let body = request
+        .body()
+        .and_then(|v| v.as_bytes())
+        .unwrap_or_default();
+    let body = match serde_json::from_slice::<Value>(body) {
+        Ok(data) => format!(
+            "```json\n{}\n```",
+            serde_json::to_string_pretty(&data).unwrap_or_default()
+        ),
+        Err(_) => String::from_utf8_lossy(body).to_string(),
+    };
+    if !body.is_empty() {
+        output.push_str(&format!("\n{body}\n"));
+    }
+    output
+}
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Headers and query parameters that carry credentials, e.g. `authorization`, `x-api-key` or
+/// `key`. By their whole name, `max_tokens` or `x-request-key-id` are worth seeing.
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase().replace('_', "-");
+    [
+        "authorization",
+        "proxy-authorization",
+        "api-key",
+        "apikey",
+        "key",
+        "token",
+        "cookie",
+        "set-cookie",
+    ]
+    .contains(&name.as_str())
+        || ["-api-key", "-token", "-secret"]
+            .iter()
+            .any(|v| name.ends_with(v))
+}
+
+async fn response_error(res: Response) -> anyhow::Error {
+    let status = res.status().as_u16();
+    let retry_after = res
+        .headers()
+        .get("retry-after")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    let text = match res.text().await {
+        Ok(text) => text,
+        Err(err) => return err.into(),
+    };
+    trace!("Response {status} {text}");
+    let message = match text.parse::<Value>() {
+        Ok(data) => catch_error(&data, status)
+            .err()
+            .map(|err| err.to_string())
+            .unwrap_or_default(),
+        Err(_) => format!("Invalid response data: {text} (status: {status})"),
+    };
+    HttpError {
+        status,
+        retry_after,
+        message,
+    }
+    .into()
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let millis = (date.timestamp_millis() - chrono::Utc::now().timestamp_millis()).max(0);
+    Some(Duration::from_millis(millis as u64))
+}
+
 pub fn catch_error(data: &Value, status: u16) -> Result<()> {
     if (200..300).contains(&status) {
         return Ok(());
     }
     debug!("Invalid response, status: {status}, data: {data}");
-    if let Some(error) = data["error"].as_object() {
+    if let Some(error) = data.get("error").filter(|v| v.is_object()) {
         if let (Some(typ), Some(message)) = (error["type"].as_str(), error["message"].as_str()) {
             bail!("{message} (type: {typ})");
         }
-    } else if let Some(error) = data["errors"][0].as_object() {
+        // Azure, e.g. GitHub Models
+        if let (Some(code), Some(message)) = (error["code"].as_str(), error["message"].as_str()) {
+            bail!("{message} (code: {code})");
+        }
+    } else if let Some(error) = Some(&data["errors"][0]).filter(|v| v.is_object()) {
         if let (Some(code), Some(message)) = (error["code"].as_u64(), error["message"].as_str()) {
             bail!("{message} (status: {code})")
         }
-    } else if let Some(error) = data[0]["error"].as_object() {
+    } else if let Some(error) = Some(&data[0]["error"]).filter(|v| v.is_object()) {
         if let (Some(status), Some(message)) = (error["status"].as_str(), error["message"].as_str())
         {
             bail!("{message} (status: {status})")
@@ -518,11 +1476,12 @@ pub async fn sse_stream<F>(builder: RequestBuilder, mut handle: F) -> Result<()>
 where
     F: FnMut(SsMmessage) -> Result<bool>,
 {
-    let mut es = builder.eventsource()?;
+    let mut es = trace_request(builder)?.eventsource()?;
     while let Some(event) = es.next().await {
         match event {
             Ok(Event::Open) => {}
             Ok(Event::Message(message)) => {
+                trace!("Response event {} {}", message.event, message.data);
                 let message = SsMmessage {
                     event: message.event,
                     data: message.data,
@@ -534,19 +1493,11 @@ where
             Err(err) => {
                 match err {
                     EventSourceError::StreamEnded => {}
-                    EventSourceError::InvalidStatusCode(status, res) => {
-                        let text = res.text().await?;
-                        let data: Value = match text.parse() {
-                            Ok(data) => data,
-                            Err(_) => {
-                                bail!(
-                                    "Invalid response data: {text} (status: {})",
-                                    status.as_u16()
-                                );
-                            }
-                        };
-                        catch_error(&data, status.as_u16())?;
+                    EventSourceError::InvalidStatusCode(_, res) => {
+                        return Err(response_error(res).await);
                     }
+                    // Kept as is, so that timeouts can be retried
+                    EventSourceError::Transport(err) => return Err(err.into()),
                     EventSourceError::InvalidContentType(header_value, res) => {
                         let text = res.text().await?;
                         bail!(
@@ -604,10 +1555,8 @@ where
                     }
                     balances.push(ch);
                 }
-                '[' => {
-                    if start != 0 {
-                        balances.push(ch);
-                    }
+                '[' if start != 0 => {
+                    balances.push(ch);
                 }
                 '}' => {
                     balances.pop();
@@ -691,21 +1640,280 @@ fn to_json(kind: &PromptKind, value: &str) -> Value {
     }
 }
 
-fn set_proxy(builder: ClientBuilder, proxy: &Option<String>) -> Result<ClientBuilder> {
-    let proxy = if let Some(proxy) = proxy {
+pub fn resolve_proxy(proxy: &Option<String>) -> Option<String> {
+    if let Some(proxy) = proxy {
         if proxy.is_empty() || proxy == "-" {
-            return Ok(builder);
+            return None;
         }
-        proxy.clone()
-    } else if let Some(proxy) = ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
-        .into_iter()
-        .find_map(|v| env::var(v).ok())
-    {
-        proxy
+        Some(proxy.clone())
     } else {
-        return Ok(builder);
+        ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+            .into_iter()
+            .find_map(|v| env::var(v).ok())
+    }
+}
+
+/// The hosts that bypass the proxy, comma separated, e.g. `localhost,.corp.example.com`.
+pub fn resolve_no_proxy(no_proxy: &Option<String>) -> Option<String> {
+    if let Some(no_proxy) = no_proxy {
+        if no_proxy.is_empty() || no_proxy == "-" {
+            return None;
+        }
+        Some(no_proxy.clone())
+    } else {
+        ["NO_PROXY", "no_proxy"]
+            .into_iter()
+            .find_map(|v| env::var(v).ok())
+    }
+}
+
+/// Whether the API base is on this machine or the local network, e.g. ollama or llama.cpp.
+pub fn is_local_url(url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        Ok(IpAddr::V6(ip)) => {
+            ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00
+        }
+        Err(_) => {
+            let host = host.to_lowercase();
+            host == "localhost" || host.ends_with(".localhost") || host.ends_with(".local")
+        }
+    }
+}
+
+pub fn set_proxy(
+    builder: ClientBuilder,
+    proxy: &Option<String>,
+    no_proxy: &Option<String>,
+) -> Result<ClientBuilder> {
+    let proxy = match resolve_proxy(proxy) {
+        Some(proxy) => proxy,
+        None => return Ok(builder),
     };
-    let builder =
-        builder.proxy(Proxy::all(&proxy).with_context(|| format!("Invalid proxy `{proxy}`"))?);
+    let no_proxy = resolve_no_proxy(no_proxy).and_then(|v| NoProxy::from_string(&v));
+    let builder = builder.proxy(
+        Proxy::all(&proxy)
+            .with_context(|| format!("Invalid proxy `{proxy}`"))?
+            .no_proxy(no_proxy),
+    );
     Ok(builder)
 }
+
+fn build_headers(headers: &HashMap<String, String>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid extra header name `{name}`"))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value of the extra header `{name}`"))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_headers() {
+        let headers = HashMap::from([
+            (
+                "HTTP-Referer".to_string(),
+                "https://example.com".to_string(),
+            ),
+            ("X-Title".to_string(), "aichat".to_string()),
+        ]);
+        let map = build_headers(&headers).unwrap();
+        assert_eq!(map["http-referer"], "https://example.com");
+        assert_eq!(map["x-title"], "aichat");
+        let headers = HashMap::from([("Bad Name".to_string(), "v".to_string())]);
+        assert!(build_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn test_is_secret() {
+        for name in [
+            "Authorization",
+            "x-api-key",
+            "api-key",
+            "key",
+            "access_token",
+            "Cookie",
+        ] {
+            assert!(is_secret(name), "{name}");
+        }
+        for name in [
+            "max_tokens",
+            "keep_alive",
+            "author",
+            "x-request-key-id",
+            "alt",
+        ] {
+            assert!(!is_secret(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_describe_request() {
+        let request = ReqwestClient::new()
+            .post("https://example.com/v1/models/m:streamGenerateContent?alt=sse&key=abc")
+            .bearer_auth("sk-1")
+            .header("x-api-key", "sk-2")
+            .header("x-request-key-id", "req-1")
+            .json(&json!({ "model": "m" }))
+            .build()
+            .unwrap();
+        assert_eq!(
+            describe_request(&request),
+            "POST https://example.com/v1/models/m:streamGenerateContent?alt=sse&key=%5BREDACTED%5D
+authorization: [REDACTED]
+x-api-key: [REDACTED]
+x-request-key-id: req-1
+content-type: application/json
+
+```json
+{
+  \"model\": \"m\"
+}
+```
+"
+        );
+    }
+
+    #[test]
+    fn test_response_meta() {
+        use crate::client::openai::openai_extract_meta;
+
+        let mut meta = openai_extract_meta(&json!({ "provider": "Fireworks", "choices": [] }));
+        meta.merge(openai_extract_meta(&json!({
+            "choices": [],
+            "x_groq": { "usage": { "prompt_tokens": 40, "queue_time": 0.0125 } }
+        })));
+        meta.merge(openai_extract_meta(&json!({
+            "usage": { "prompt_tokens_details": { "cached_tokens": 1024 } }
+        })));
+        assert_eq!(
+            meta.summary(),
+            "provider: Fireworks, queue: 12ms, cached: 1024 tokens"
+        );
+        assert!(openai_extract_meta(&json!({ "usage": {} })).is_empty());
+    }
+
+    #[test]
+    fn test_extract_vectors() {
+        let data = json!([{ "embedding": [0.5, -1] }, { "embedding": [0.25, 2.0] }]);
+        assert_eq!(
+            extract_vectors(&data, "/embedding").unwrap(),
+            vec![vec![0.5, -1.0], vec![0.25, 2.0]]
+        );
+        assert_eq!(
+            extract_vectors(&json!([[1.0, 2.0]]), "").unwrap(),
+            vec![vec![1.0, 2.0]]
+        );
+        assert!(extract_vectors(&json!([{ "embedding": ["x"] }]), "/embedding").is_err());
+        assert!(extract_vectors(&json!(null), "").is_err());
+    }
+
+    #[test]
+    fn test_resolve_proxy() {
+        assert_eq!(resolve_proxy(&Some("-".into())), None);
+        assert_eq!(
+            resolve_proxy(&Some("socks5://127.0.0.1:1080".into())),
+            Some("socks5://127.0.0.1:1080".into())
+        );
+        assert_eq!(resolve_no_proxy(&Some("".into())), None);
+        let client = set_proxy(
+            ReqwestClient::builder(),
+            &Some("http://127.0.0.1:3128".into()),
+            &Some("localhost,.corp.example.com".into()),
+        );
+        assert!(client.unwrap().build().is_ok());
+        assert!(set_proxy(ReqwestClient::builder(), &Some("://".into()), &None).is_err());
+    }
+
+    #[test]
+    fn test_merge_models() {
+        let mut models: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+- platform: openai
+  models:
+    - name: gpt-4o
+      max_input_tokens: 128000
+      input_price: 5
+"#,
+        )
+        .unwrap();
+        let overrides = serde_yaml::from_str(
+            r#"
+- platform: openai
+  models:
+    - name: gpt-4o
+      input_price: 2.5
+    - name: gpt-4o-mini
+- platform: acme
+  models: []
+"#,
+        )
+        .unwrap();
+        merge_models(&mut models, overrides).unwrap();
+        let models: Vec<BuiltinModels> = serde_yaml::from_value(models).unwrap();
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].models[0].max_input_tokens, Some(128000));
+        assert_eq!(models[0].models[0].input_price, Some(2.5));
+        assert_eq!(models[0].models[1].name, "gpt-4o-mini");
+        assert!(merge_models(&mut serde_yaml::Value::Null, Default::default()).is_err());
+    }
+
+    #[test]
+    fn test_is_local_url() {
+        assert!(is_local_url("http://localhost:11434"));
+        assert!(is_local_url("http://127.0.0.1:8080/v1"));
+        assert!(is_local_url("http://192.168.1.20:11434"));
+        assert!(is_local_url("http://[::1]:8080/v1"));
+        assert!(!is_local_url("https://api.openai.com/v1"));
+        assert!(!is_local_url("http://8.8.8.8"));
+        assert!(!is_local_url("not a url"));
+    }
+
+    #[test]
+    fn test_catch_error() {
+        let data = json!({ "error": { "code": "RateLimitReached", "message": "Slow down" } });
+        assert_eq!(
+            catch_error(&data, 429).unwrap_err().to_string(),
+            "Slow down (code: RateLimitReached)"
+        );
+        let data = json!({ "error": { "message": "Oops" } });
+        assert!(catch_error(&data, 500).is_err());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_parse_thinking() {
+        assert_eq!("high".parse::<Thinking>().unwrap(), Thinking::High);
+        assert_eq!("3000".parse::<Thinking>().unwrap(), Thinking::Budget(3000));
+        assert_eq!(Thinking::Budget(3000).effort(), "medium");
+        assert_eq!(Thinking::Low.budget_tokens(), 1024);
+        assert!("0".parse::<Thinking>().is_err());
+        assert!("max".parse::<Thinking>().is_err());
+        let value: Thinking = serde_yaml::from_str("8192").unwrap();
+        assert_eq!(value, Thinking::Budget(8192));
+    }
+}
@@ -0,0 +1,68 @@
+use super::openai::{openai_include_usage, openai_remove_reasoning_effort, OpenAILikeRequests};
+use super::{
+    DeepSeekClient, ExtraConfig, Model, ModelConfig, PromptAction, PromptKind, SendData,
+};
+
+use anyhow::Result;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+
+const API_BASE: &str = "https://api.deepseek.com";
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct DeepSeekConfig {
+    pub name: Option<String>,
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl DeepSeekClient {
+    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_base, get_api_base);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("api_key", "API Key:", true, PromptKind::String)];
+
+    pub fn api_base(config: &DeepSeekConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
+    fn requests(&self) -> Result<OpenAILikeRequests> {
+        let api_key = self.get_api_key()?;
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+        Ok(OpenAILikeRequests::bearer("DeepSeek", api_base, Some(api_key)))
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        self.requests()?
+            .chat(client, &self.model, data, deepseek_filter_body)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        self.requests()?.models(client)
+    }
+}
+
+impl_client_trait!(
+    DeepSeekClient,
+    crate::client::openai::openai_send_message,
+    crate::client::openai::openai_send_message_streaming;
+    models = crate::client::openai::openai_models
+);
+
+/// The reasoner always thinks, streaming its thinking as `reasoning_content`, and rejects
+/// `logprobs`.
+fn deepseek_filter_body(body: &mut Value, model: &Model) {
+    openai_include_usage(body);
+    openai_remove_reasoning_effort(body, model);
+    if model.name.contains("reasoner") {
+        if let Some(body) = body.as_object_mut() {
+            body.remove("logprobs");
+            body.remove("top_logprobs");
+        }
+    }
+}
@@ -6,6 +6,8 @@ use super::{
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use chrono::Utc;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
@@ -14,7 +16,9 @@ use std::env;
 const API_BASE: &str = "https://aip.baidubce.com/rpc/2.0/ai_custom/v1";
 const ACCESS_TOKEN_URL: &str = "https://aip.baidubce.com/oauth/2.0/token";
 
-static mut ACCESS_TOKEN: (String, i64) = (String::new(), 0);
+lazy_static! {
+    static ref ACCESS_TOKEN: RwLock<(String, i64)> = RwLock::new((String::new(), 0));
+}
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct ErnieConfig {
@@ -32,13 +36,18 @@ impl ErnieClient {
         ("secret_key", "Secret Key:", true, PromptKind::String),
     ];
 
+    pub fn api_base(_config: &ErnieConfig) -> Option<String> {
+        Some(API_BASE.into())
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let body = build_body(data, &self.model);
+        let mut body = build_body(data, &self.model);
+        self.model.merge_extra_fields(&mut body);
 
         let url = format!(
             "{API_BASE}/wenxinworkshop/chat/{}?access_token={}",
             &self.model.name,
-            unsafe { &ACCESS_TOKEN.0 }
+            ACCESS_TOKEN.read().0
         );
 
         debug!("Ernie Request: {url} {body}");
@@ -49,7 +58,11 @@ impl ErnieClient {
     }
 
     async fn prepare_access_token(&self) -> Result<()> {
-        if unsafe { ACCESS_TOKEN.0.is_empty() || Utc::now().timestamp() > ACCESS_TOKEN.1 } {
+        let expired = {
+            let access_token = ACCESS_TOKEN.read();
+            access_token.0.is_empty() || Utc::now().timestamp() > access_token.1
+        };
+        if expired {
             let env_prefix = Self::name(&self.config).to_uppercase();
             let api_key = self.config.api_key.clone();
             let api_key = api_key
@@ -65,7 +78,7 @@ impl ErnieClient {
             let token = fetch_access_token(&client, &api_key, &secret_key)
                 .await
                 .with_context(|| "Failed to fetch access token")?;
-            unsafe { ACCESS_TOKEN = (token, 86400) };
+            *ACCESS_TOKEN.write() = (token, 86400);
         }
         Ok(())
     }
@@ -95,6 +108,15 @@ impl Client for ErnieClient {
         let builder = self.request_builder(client, data)?;
         send_message_streaming(builder, handler).await
     }
+
+    async fn request_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<RequestBuilder> {
+        self.prepare_access_token().await?;
+        self.request_builder(client, data)
+    }
 }
 
 async fn send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
@@ -109,6 +131,10 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut SseHandle
         if let Some(text) = data["result"].as_str() {
             handler.text(text)?;
         }
+        handler.usage(
+            data["usage"]["prompt_tokens"].as_u64(),
+            data["usage"]["completion_tokens"].as_u64(),
+        )?;
         Ok(false)
     };
 
@@ -120,6 +146,16 @@ fn build_body(data: SendData, model: &Model) -> Value {
         mut messages,
         temperature,
         top_p,
+        seed: _,
+        frequency_penalty: _,
+        presence_penalty: _,
+        stop,
+        logprobs: _,
+        functions: _,
+        tool_rounds: _,
+        thinking: _,
+        tags: _,
+        schema: _,
         stream,
     } = data;
 
@@ -138,6 +174,9 @@ fn build_body(data: SendData, model: &Model) -> Value {
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if !stop.is_empty() {
+        body["stop"] = stop.into();
+    }
 
     if stream {
         body["stream"] = true.into();
@@ -154,6 +193,7 @@ fn extract_completion_text(data: &Value) -> Result<(String, CompletionDetails)>
         id: data["id"].as_str().map(|v| v.to_string()),
         input_tokens: data["usage"]["prompt_tokens"].as_u64(),
         output_tokens: data["usage"]["completion_tokens"].as_u64(),
+        ..Default::default()
     };
     Ok((text.to_string(), details))
 }
@@ -1,9 +1,13 @@
 use super::vertexai::gemini_build_body;
-use super::{ExtraConfig, GeminiClient, Model, ModelConfig, PromptAction, PromptKind, SendData};
+use super::{
+    send_request, extract_vectors, EmbeddingsData, EmbeddingsOutput, ExtraConfig, GeminiClient,
+    Model, ModelConfig, PromptAction, PromptKind, SendData,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
+use serde_json::{json, Value};
 
 const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models/";
 
@@ -23,6 +27,10 @@ impl GeminiClient {
     pub const PROMPTS: [PromptAction<'static>; 1] =
         [("api_key", "API Key:", true, PromptKind::String)];
 
+    pub fn api_base(_config: &GeminiConfig) -> Option<String> {
+        Some(API_BASE.into())
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_key = self.get_api_key()?;
 
@@ -33,7 +41,8 @@ impl GeminiClient {
 
         let block_threshold = self.config.block_threshold.clone();
 
-        let body = gemini_build_body(data, &self.model, block_threshold)?;
+        let mut body = gemini_build_body(data, &self.model, block_threshold)?;
+        self.model.merge_extra_fields(&mut body);
 
         let model = &self.model.name;
 
@@ -45,10 +54,99 @@ impl GeminiClient {
 
         Ok(builder)
     }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        let model = &self.model.name;
+        let task_type = match data.query {
+            true => "RETRIEVAL_QUERY",
+            false => "RETRIEVAL_DOCUMENT",
+        };
+        let requests: Vec<Value> = data
+            .texts
+            .into_iter()
+            .map(|text| {
+                json!({
+                    "model": format!("models/{model}"),
+                    "content": { "parts": [{ "text": text }] },
+                    "taskType": task_type,
+                })
+            })
+            .collect();
+        let body = json!({ "requests": requests });
+
+        let url = format!("{API_BASE}{model}:batchEmbedContents?key={api_key}");
+
+        debug!("Gemini Embeddings Request: {url} {body}");
+
+        let builder = client.post(url).json(&body);
+
+        Ok(builder)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+
+        let url = format!(
+            "{}?key={api_key}&pageSize=1000",
+            API_BASE.trim_end_matches('/')
+        );
+
+        debug!("Gemini Models Request: {}", API_BASE.trim_end_matches('/'));
+
+        Ok(client.get(url))
+    }
 }
 
 impl_client_trait!(
     GeminiClient,
     crate::client::vertexai::gemini_send_message,
-    crate::client::vertexai::gemini_send_message_streaming
+    crate::client::vertexai::gemini_send_message_streaming,
+    embeddings;
+    models = models
 );
+
+async fn models(builder: RequestBuilder, client_name: &str) -> Result<Vec<Model>> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    let list = data["models"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid models data: {data}"))?;
+    let mut models = vec![];
+    for item in list {
+        let Some(name) = item["name"].as_str() else {
+            continue;
+        };
+        let supports = |method: &str| {
+            item["supportedGenerationMethods"]
+                .as_array()
+                .is_some_and(|v| v.iter().any(|v| v == method))
+        };
+        let mut model = Model::new(client_name, name.trim_start_matches("models/"));
+        if supports("generateContent") {
+            model
+                .set_max_input_tokens(item["inputTokenLimit"].as_u64().map(|v| v as usize))
+                .set_max_output_tokens(item["outputTokenLimit"].as_i64().map(|v| v as isize));
+        } else if supports("embedContent") {
+            model.set_supports_embedding(true);
+        } else {
+            continue;
+        }
+        models.push(model);
+    }
+    Ok(models)
+}
+
+async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    Ok(EmbeddingsOutput {
+        vectors: extract_vectors(&data["embeddings"], "/values")?,
+        input_tokens: None,
+    })
+}
@@ -0,0 +1,164 @@
+use super::openai::{
+    openai_embeddings, openai_embeddings_body, openai_send_message, openai_send_message_streaming,
+    OpenAILikeRequests,
+};
+use super::{
+    CompletionDetails, EmbeddingsData, EmbeddingsOutput, ExtraConfig, GitHubClient, HttpError,
+    Model, ModelConfig, PromptAction, PromptKind, SendData, SseHandler, send_request,
+};
+
+use anyhow::{anyhow, Error, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+
+const API_BASE: &str = "https://models.inference.ai.azure.com";
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct GitHubConfig {
+    pub name: Option<String>,
+    /// A GitHub token, `$GITHUB_TOKEN` if not set
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl GitHubClient {
+    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_base, get_api_base);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("api_key", "GitHub Token:", true, PromptKind::String)];
+
+    pub fn api_base(config: &GitHubConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
+    fn get_token(&self) -> Result<String> {
+        self.get_api_key()
+            .or_else(|_| std::env::var("GITHUB_TOKEN"))
+            .map_err(|_| anyhow!("Miss 'api_key' in client configuration, or $GITHUB_TOKEN"))
+    }
+
+    fn requests(&self) -> Result<OpenAILikeRequests> {
+        let token = self.get_token()?;
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+        Ok(OpenAILikeRequests::bearer("GitHub", api_base, Some(token)))
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        self.requests()?.chat(client, &self.model, data, |body, model| {
+            body["model"] = github_model_name(&model.name).into();
+        })
+    }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let body = openai_embeddings_body(github_model_name(&self.model.name), data);
+        self.requests()?.embeddings(client, body)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        self.requests()?.models(client)
+    }
+}
+
+impl_client_trait!(
+    GitHubClient,
+    github_send_message,
+    github_send_message_streaming,
+    github_embeddings;
+    models = github_models
+);
+
+async fn github_send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
+    openai_send_message(builder).await.map_err(rate_limit_error)
+}
+
+async fn github_send_message_streaming(
+    builder: RequestBuilder,
+    handler: &mut SseHandler,
+) -> Result<()> {
+    openai_send_message_streaming(builder, handler)
+        .await
+        .map_err(rate_limit_error)
+}
+
+async fn github_embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
+    openai_embeddings(builder).await.map_err(rate_limit_error)
+}
+
+/// The models are an array, with the chat models for the task `chat-completion`.
+async fn github_models(builder: RequestBuilder, client_name: &str) -> Result<Vec<Model>> {
+    let res = send_request(builder).await.map_err(rate_limit_error)?;
+    let data: Value = res.json().await?;
+    let list = data
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid models data: {data}"))?;
+    let models = list
+        .iter()
+        .filter_map(|v| Some((v["name"].as_str()?, v["task"].as_str().unwrap_or_default())))
+        .filter(|(_, task)| ["chat-completion", "embeddings"].contains(task))
+        .map(|(name, task)| {
+            let mut model = Model::new(client_name, name);
+            model.set_supports_embedding(task == "embeddings");
+            model
+        })
+        .collect();
+    Ok(models)
+}
+
+/// The names of the marketplace have the publisher first, e.g. `openai/gpt-4o`, the API takes
+/// the name alone.
+fn github_model_name(name: &str) -> &str {
+    name.rsplit_once('/').map(|(_, v)| v).unwrap_or(name)
+}
+
+/// The free tier allows a few requests per minute and per day for each model, explain the limit
+/// that was hit. The retry middleware still sees the status and the `Retry-After` header.
+fn rate_limit_error(err: Error) -> Error {
+    let Some(http_error) = err.chain().find_map(|v| v.downcast_ref::<HttpError>()) else {
+        return err;
+    };
+    if http_error.status != 429 {
+        return err;
+    }
+    let wait = match http_error.retry_after {
+        Some(v) => format!(", retry in {}s", v.as_secs()),
+        None => String::new(),
+    };
+    err.context(format!(
+        "Reached the rate limit of GitHub Models{wait}, see https://docs.github.com/en/github-models/prototyping-with-ai-models#rate-limits"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_github_model_name() {
+        assert_eq!(github_model_name("openai/gpt-4o"), "gpt-4o");
+        assert_eq!(github_model_name("Phi-3.5-mini-instruct"), "Phi-3.5-mini-instruct");
+    }
+
+    #[test]
+    fn test_rate_limit_error() {
+        let err = rate_limit_error(
+            HttpError {
+                status: 429,
+                retry_after: Some(Duration::from_secs(42)),
+                message: "Rate limit of 15 per 60s exceeded".into(),
+            }
+            .into(),
+        );
+        assert!(format!("{err:#}").starts_with("Reached the rate limit of GitHub Models, retry in 42s"));
+        assert!(err.chain().any(|v| v.is::<HttpError>()));
+    }
+}
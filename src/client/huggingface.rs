@@ -0,0 +1,115 @@
+use super::openai::{
+    openai_collect_tool_calls, openai_remove_reasoning_effort, openai_send_message,
+    OpenAILikeRequests,
+};
+use super::{
+    sse_stream, ExtraConfig, HuggingFaceClient, Model, ModelConfig, PromptAction, PromptKind,
+    SendData, SsMmessage, SseHandler,
+};
+
+use crate::function::ToolCall;
+
+use anyhow::{bail, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const API_BASE: &str = "https://api-inference.huggingface.co/v1";
+
+/// Serverless Inference by default, or an Inference Endpoint or a self-hosted Text Generation
+/// Inference with `api_base`, e.g. `http://localhost:8080/v1`.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct HuggingFaceConfig {
+    pub name: Option<String>,
+    /// A Hugging Face token, `$HF_TOKEN` if not set, a self-hosted server may not need one
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl HuggingFaceClient {
+    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_base, get_api_base);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("api_key", "Hugging Face Token:", false, PromptKind::String)];
+
+    pub fn api_base(config: &HuggingFaceConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
+    fn get_token(&self) -> Option<String> {
+        self.get_api_key()
+            .ok()
+            .or_else(|| std::env::var("HF_TOKEN").ok())
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+        OpenAILikeRequests::bearer("HuggingFace", api_base, self.get_token()).chat(
+            client,
+            &self.model,
+            data,
+            huggingface_filter_body,
+        )
+    }
+}
+
+impl_client_trait!(
+    HuggingFaceClient,
+    openai_send_message,
+    huggingface_send_message_streaming
+);
+
+/// The Messages API of TGI streams the chunks of OpenAI, yet it reports errors as events of their
+/// own and older versions end the stream without `[DONE]`.
+async fn huggingface_send_message_streaming(
+    builder: RequestBuilder,
+    handler: &mut SseHandler,
+) -> Result<()> {
+    let mut tool_calls = vec![];
+    let handle = |message: SsMmessage| -> Result<bool> {
+        if message.data == "[DONE]" {
+            return Ok(true);
+        }
+        let data: Value = serde_json::from_str(&message.data)?;
+        if let Some(error) = data["error"].as_str() {
+            match data["error_type"].as_str() {
+                Some(typ) => bail!("{error} (type: {typ})"),
+                None => bail!("{error}"),
+            }
+        }
+        if let Some(text) = data["choices"][0]["delta"]["content"].as_str() {
+            handler.text(text)?;
+        }
+        // The last chunk has the usage since TGI 2.2
+        handler.usage(
+            data["usage"]["prompt_tokens"].as_u64(),
+            data["usage"]["completion_tokens"].as_u64(),
+        )?;
+        openai_collect_tool_calls(&data, &mut tool_calls);
+        Ok(false)
+    };
+    sse_stream(builder, handle).await?;
+    for (id, name, arguments) in tool_calls {
+        handler.tool_call(ToolCall::new(id, name, &arguments)?);
+    }
+    Ok(())
+}
+
+/// TGI serves a single model and ignores its name, it has no reasoning effort and takes a JSON
+/// schema as a grammar.
+fn huggingface_filter_body(body: &mut Value, model: &Model) {
+    openai_remove_reasoning_effort(body, model);
+    if let Some(body) = body.as_object_mut() {
+        if let Some(format) = body.remove("response_format") {
+            let schema = format["json_schema"]["schema"].clone();
+            body.insert(
+                "response_format".into(),
+                json!({ "type": "json_object", "value": schema }),
+            );
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use super::openai::{
+    openai_embeddings, openai_embeddings_body, openai_models, openai_remove_reasoning_effort,
+    openai_send_message, openai_send_message_streaming, OpenAILikeRequests,
+};
+use super::{
+    generate_prompt, send_request, smart_prompt_format, sse_stream, Client, CompletionDetails,
+    EmbeddingsData, EmbeddingsOutput, ExtraConfig, LlamaCppClient, Model, ModelConfig,
+    PromptAction, PromptKind, SendData, SsMmessage, SseHandler,
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+const API_BASE: &str = "http://localhost:8080";
+
+/// The server of llama.cpp, `llama-server`. Its options go along with every request.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct LlamaCppConfig {
+    pub name: Option<String>,
+    /// The `--api-key` of the server, if it has one
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    /// Send a raw prompt, formatted for the model name, to `/completion` instead of the chat
+    /// messages to `/v1/chat/completions`
+    #[serde(default)]
+    pub completion: bool,
+    /// A GBNF grammar that constrains the output, it takes precedence over a JSON schema
+    pub grammar: Option<String>,
+    /// A file with the GBNF grammar
+    pub grammar_file: Option<String>,
+    /// Reuse the KV cache of the prompt shared with the previous request
+    pub cache_prompt: Option<bool>,
+    /// The slot of the server that takes the requests, -1 for an idle one
+    pub id_slot: Option<i64>,
+    /// The tokens of the prompt kept when the context is full, -1 for all
+    pub n_keep: Option<i64>,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl LlamaCppClient {
+    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_base, get_api_base);
+
+    pub const PROMPTS: [PromptAction<'static>; 3] = [
+        ("api_base", "API Base:", true, PromptKind::String),
+        ("api_key", "API Key:", false, PromptKind::String),
+        ("models[].name", "Model Name:", true, PromptKind::String),
+    ];
+
+    pub fn api_base(config: &LlamaCppConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
+    fn api_base_url(&self) -> String {
+        self.get_api_base().unwrap_or_else(|_| API_BASE.to_string())
+    }
+
+    /// The OpenAI API of the server is under `/v1`.
+    fn requests(&self) -> OpenAILikeRequests {
+        let api_base = format!("{}/v1", self.api_base_url());
+        OpenAILikeRequests::bearer("LlamaCpp", api_base, self.get_api_key().ok())
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        let options = self.server_options()?;
+        if !self.config.completion {
+            return self.requests().chat(client, &self.model, data, |body, model| {
+                openai_remove_reasoning_effort(body, model);
+                set_server_options(body, options);
+            });
+        }
+
+        let mut body = llamacpp_build_completion_body(data, &self.model)?;
+        set_server_options(&mut body, options);
+        self.model.merge_extra_fields(&mut body);
+
+        let url = format!("{}/completion", self.api_base_url());
+
+        debug!("LlamaCpp Request: {url} {body}");
+
+        (self.requests().auth)(client.post(url).json(&body))
+    }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let body = openai_embeddings_body(&self.model.name, data);
+        self.requests().embeddings(client, body)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        self.requests().models(client)
+    }
+
+    /// The options of the server that the OpenAI API has no fields for.
+    fn server_options(&self) -> Result<Map<String, Value>> {
+        let mut options = Map::new();
+        let grammar = match (&self.config.grammar, &self.config.grammar_file) {
+            (Some(grammar), _) => Some(grammar.clone()),
+            (None, Some(path)) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read the grammar '{path}'"))?,
+            ),
+            (None, None) => None,
+        };
+        if let Some(grammar) = grammar {
+            options.insert("grammar".into(), grammar.into());
+        }
+        if let Some(v) = self.config.cache_prompt {
+            options.insert("cache_prompt".into(), v.into());
+        }
+        if let Some(v) = self.config.id_slot {
+            options.insert("id_slot".into(), v.into());
+        }
+        if let Some(v) = self.config.n_keep {
+            options.insert("n_keep".into(), v.into());
+        }
+        Ok(options)
+    }
+}
+
+#[async_trait]
+impl Client for LlamaCppClient {
+    client_common_fns!();
+
+    async fn send_message_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<(String, CompletionDetails)> {
+        let builder = self.request_builder(client, data)?;
+        if self.config.completion {
+            llamacpp_send_completion(builder).await
+        } else {
+            openai_send_message(builder).await
+        }
+    }
+
+    async fn send_message_streaming_inner(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: SendData,
+    ) -> Result<()> {
+        let builder = self.request_builder(client, data)?;
+        if self.config.completion {
+            llamacpp_send_completion_streaming(builder, handler).await
+        } else {
+            openai_send_message_streaming(builder, handler).await
+        }
+    }
+
+    async fn request_inner(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        self.request_builder(client, data)
+    }
+
+    async fn embeddings_inner(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let builder = self.embeddings_builder(client, data)?;
+        openai_embeddings(builder).await
+    }
+
+    async fn fetch_models_inner(&self, client: &ReqwestClient) -> Result<Vec<Model>> {
+        let builder = self.models_builder(client)?;
+        openai_models(builder, &self.model.client_name).await
+    }
+}
+
+/// A grammar takes precedence over a JSON schema.
+fn set_server_options(body: &mut Value, options: Map<String, Value>) {
+    if let Some(body) = body.as_object_mut() {
+        if options.contains_key("grammar") {
+            body.remove("response_format");
+            body.remove("json_schema");
+        }
+        body.extend(options);
+    }
+}
+
+async fn llamacpp_send_completion(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    let text = data["content"].as_str().unwrap_or_default();
+    let details = CompletionDetails {
+        input_tokens: data["tokens_evaluated"].as_u64(),
+        output_tokens: data["tokens_predicted"].as_u64(),
+        ..Default::default()
+    };
+    Ok((text.to_string(), details))
+}
+
+/// The chunks have the `content`, the last one has `stop` and the token counts.
+async fn llamacpp_send_completion_streaming(
+    builder: RequestBuilder,
+    handler: &mut SseHandler,
+) -> Result<()> {
+    let handle = |message: SsMmessage| -> Result<bool> {
+        let data: Value = serde_json::from_str(&message.data)?;
+        if let Some(text) = data["content"].as_str() {
+            handler.text(text)?;
+        }
+        if data["stop"].as_bool().unwrap_or_default() {
+            handler.usage(
+                data["tokens_evaluated"].as_u64(),
+                data["tokens_predicted"].as_u64(),
+            )?;
+            return Ok(true);
+        }
+        Ok(false)
+    };
+    sse_stream(builder, handle).await
+}
+
+fn llamacpp_build_completion_body(data: SendData, model: &Model) -> Result<Value> {
+    let SendData {
+        messages,
+        temperature,
+        top_p,
+        seed,
+        frequency_penalty,
+        presence_penalty,
+        stop,
+        logprobs,
+        functions,
+        tool_rounds,
+        thinking: _,
+        tags: _,
+        schema,
+        stream,
+    } = data;
+
+    if functions.is_some() || !tool_rounds.is_empty() {
+        bail!("The completion mode of llama.cpp has no tools, turn off `completion` to use them");
+    }
+
+    let prompt = generate_prompt(&messages, smart_prompt_format(&model.name))?;
+
+    let mut body = json!({ "prompt": prompt });
+
+    if let Some(v) = model.max_output_tokens {
+        body["n_predict"] = v.into();
+    }
+    if let Some(v) = temperature {
+        body["temperature"] = v.into();
+    }
+    if let Some(v) = top_p {
+        body["top_p"] = v.into();
+    }
+    if let Some(v) = seed {
+        body["seed"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["frequency_penalty"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        body["presence_penalty"] = v.into();
+    }
+    if !stop.is_empty() {
+        body["stop"] = stop.into();
+    }
+    if let Some(v) = logprobs {
+        body["n_probs"] = v.into();
+    }
+    if let Some(schema) = schema {
+        body["json_schema"] = schema;
+    }
+    if stream {
+        body["stream"] = true.into();
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_send_data;
+
+    #[test]
+    fn test_llamacpp_build_completion_body() {
+        let data = SendData {
+            temperature: Some(0.5),
+            stop: vec!["END".into()],
+            schema: Some(json!({ "type": "object" })),
+            stream: true,
+            ..test_send_data("Hi")
+        };
+        let model = Model::new("llamacpp", "llama3");
+        let body = llamacpp_build_completion_body(data.clone(), &model).unwrap();
+        assert_eq!(
+            body["prompt"],
+            "<|begin_of_text|><|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["stop"], json!(["END"]));
+        assert_eq!(body["json_schema"], json!({ "type": "object" }));
+        assert_eq!(body["stream"], true);
+
+        let data = SendData {
+            functions: Some(vec![]),
+            ..data
+        };
+        assert!(llamacpp_build_completion_body(data, &model).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::config::Input;
+use crate::function::ToolResult;
 
 use serde::{Deserialize, Serialize};
 
@@ -100,6 +101,27 @@ impl MessageContent {
             }
         }
     }
+
+    /// Add the content of the next message, the texts are separated by a blank line.
+    pub fn append(&mut self, other: MessageContent) {
+        match (&mut *self, other) {
+            (MessageContent::Text(text), MessageContent::Text(other)) => {
+                *text = format!("{text}\n\n{other}")
+            }
+            (this, other) => {
+                let mut list = std::mem::replace(this, MessageContent::Array(vec![])).into_parts();
+                list.extend(other.into_parts());
+                *this = MessageContent::Array(list);
+            }
+        }
+    }
+
+    fn into_parts(self) -> Vec<MessageContentPart> {
+        match self {
+            MessageContent::Text(text) => vec![MessageContentPart::Text { text }],
+            MessageContent::Array(list) => list,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -114,19 +136,68 @@ pub struct ImageUrl {
     pub url: String,
 }
 
+/// Merge the system message into the first message, for the models without system messages.
+/// With images, the text goes to the first text part.
 pub fn patch_system_message(messages: &mut Vec<Message>) {
     if messages[0].role.is_system() {
         let system_message = messages.remove(0);
         if let (Some(message), MessageContent::Text(system_text)) =
             (messages.get_mut(0), system_message.content)
         {
-            if let MessageContent::Text(text) = message.content.clone() {
-                message.content = MessageContent::Text(format!("{}\n\n{}", system_text, text))
+            match &mut message.content {
+                MessageContent::Text(text) => *text = format!("{}\n\n{}", system_text, text),
+                MessageContent::Array(list) => match list.iter_mut().find_map(|v| match v {
+                    MessageContentPart::Text { text } => Some(text),
+                    _ => None,
+                }) {
+                    Some(text) => *text = format!("{}\n\n{}", system_text, text),
+                    None => list.insert(0, MessageContentPart::Text { text: system_text }),
+                },
             }
         }
     }
 }
 
+/// Merge the consecutive messages of a role, and open the conversation with a user message, for
+/// the chat templates that require the roles to alternate.
+pub fn alternate_roles(messages: &mut Vec<Message>) {
+    let mut list: Vec<Message> = vec![];
+    for message in messages.drain(..) {
+        match list.last_mut() {
+            Some(last) if last.role == message.role => last.content.append(message.content),
+            _ => list.push(message),
+        }
+    }
+    if let Some(index) = list.iter().position(|v| !v.role.is_system()) {
+        if list[index].role.is_assistant() {
+            list.insert(
+                index,
+                Message {
+                    role: MessageRole::User,
+                    content: MessageContent::Text(String::new()),
+                },
+            );
+        }
+    }
+    *messages = list;
+}
+
+/// Replay a tool call and its result as an assistant and a user message, for the chat templates
+/// without tool messages.
+pub fn tool_result_messages(result: &ToolResult) -> [Message; 2] {
+    let ToolResult { call, output } = result;
+    [
+        Message {
+            role: MessageRole::Assistant,
+            content: MessageContent::Text(format!("Call {} {}", call.name, call.arguments)),
+        },
+        Message {
+            role: MessageRole::User,
+            content: MessageContent::Text(format!("The result of {}: {output}", call.name)),
+        },
+    ]
+}
+
 pub fn extract_system_message(messages: &mut Vec<Message>) -> Option<String> {
     if messages[0].role.is_system() {
         let system_message = messages.remove(0);
@@ -139,6 +210,7 @@ pub fn extract_system_message(messages: &mut Vec<Message>) -> Option<String> {
 mod tests {
     use super::*;
     use crate::config::InputContext;
+    use crate::function::ToolCall;
 
     #[test]
     fn test_serde() {
@@ -151,4 +223,64 @@ mod tests {
             "{\"role\":\"user\",\"content\":\"Hello World\"}"
         );
     }
+
+    #[test]
+    fn test_patch_system_message() {
+        let image = MessageContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: "data:image/png;base64,AAAA".into(),
+            },
+        };
+        let mut messages = vec![
+            Message {
+                role: MessageRole::System,
+                content: MessageContent::Text("Be brief.".into()),
+            },
+            Message {
+                role: MessageRole::User,
+                content: MessageContent::Array(vec![image.clone()]),
+            },
+        ];
+        patch_system_message(&mut messages);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&messages[0].content).unwrap(),
+            serde_json::json!([
+                { "type": "text", "text": "Be brief." },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_alternate_roles() {
+        let message = |role, text: &str| Message {
+            role,
+            content: MessageContent::Text(text.into()),
+        };
+        let mut messages = vec![
+            message(MessageRole::System, "Be brief."),
+            message(MessageRole::Assistant, "Hi."),
+            message(MessageRole::User, "Who are you?"),
+            message(MessageRole::User, "And where?"),
+        ];
+        let result = ToolResult {
+            call: ToolCall::new("1".into(), "calculator".into(), r#"{"expression":"1+1"}"#)
+                .unwrap(),
+            output: serde_json::json!({ "result": "2" }),
+        };
+        messages.extend(tool_result_messages(&result));
+        alternate_roles(&mut messages);
+        assert_eq!(
+            serde_json::to_value(&messages).unwrap(),
+            serde_json::json!([
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "" },
+                { "role": "assistant", "content": "Hi." },
+                { "role": "user", "content": "Who are you?\n\nAnd where?" },
+                { "role": "assistant", "content": "Call calculator {\"expression\":\"1+1\"}" },
+                { "role": "user", "content": "The result of calculator: {\"result\":\"2\"}" },
+            ])
+        );
+    }
 }
@@ -0,0 +1,1271 @@
+use super::{
+    record_usage, Client, CompletionDetails, EmbeddingsData, EmbeddingsOutput, FirstTokenTimeout,
+    HttpError, Message, MessageContent, MessageContentPart, Model, SendData, SseHandler,
+};
+
+use crate::utils::{now, sha256};
+
+use anyhow::{bail, Context, Error, Result};
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::Notify, time::sleep};
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<String, CacheEntry>> = Mutex::new(HashMap::new());
+}
+
+struct CacheEntry {
+    time: Instant,
+    used: Instant,
+    text: String,
+}
+
+/// A step of the request pipeline of the clients.
+///
+/// The requests go through the middlewares in the order of `middlewares` in the config, the
+/// responses in reverse order.
+pub trait Middleware: Send + Sync {
+    /// Change the request before it's sent, or answer it without sending it.
+    fn on_request(&self, _model: &Model, _data: &mut SendData) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Change a streamed chunk of the reply before it's shown, a part of it may be held back.
+    fn on_stream_chunk(&self, _chunk: &mut String) {}
+
+    /// The text held back by `on_stream_chunk`, once the stream has ended.
+    fn on_stream_end(&self) -> String {
+        String::new()
+    }
+
+    /// Change the complete reply, after streaming it's the text that was shown.
+    fn on_response(
+        &self,
+        _model: &Model,
+        _data: &SendData,
+        _text: &mut String,
+        _details: &CompletionDetails,
+    ) {
+    }
+
+    /// How long to wait before sending the request again, `None` gives up.
+    fn on_error(&self, _err: &Error, _attempt: usize) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MiddlewareConfig {
+    Retry(RetryConfig),
+    Log,
+    Cache(CacheConfig),
+    Redact(RedactConfig),
+    Archive(ArchiveConfig),
+    Normalize(NormalizeConfig),
+}
+
+/// Retry failed requests that may succeed later, e.g. rate limits, with exponential backoff.
+/// A client may have its own in its `extra` config.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_retries: usize,
+    pub backoff_ms: u64,
+    /// The longest wait, a `Retry-After` beyond it gives up.
+    pub max_backoff_ms: u64,
+    /// Wait a random time between half and all of the backoff, so that clients hitting the same
+    /// limit don't retry in lockstep.
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff_ms: 1000,
+            max_backoff_ms: 60000,
+            jitter: true,
+        }
+    }
+}
+
+/// Answer repeated requests from memory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub ttl: u64,
+    /// The most replies kept, the least recently used are dropped first.
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: 3600,
+            max_entries: 1000,
+        }
+    }
+}
+
+/// Replace the matches of the patterns in the messages sent and in the replies.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RedactConfig {
+    pub patterns: Vec<String>,
+    pub replacement: String,
+    /// The longest match, a streamed reply is held back by up to this many bytes so that a
+    /// match split between chunks is still redacted.
+    pub max_match_len: usize,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        Self {
+            patterns: vec![],
+            replacement: "[REDACTED]".into(),
+            max_match_len: 64,
+        }
+    }
+}
+
+/// Keep a record of every request and its reply, with the patterns redacted, for audits.
+/// The records are appended to a JSONL file per day, never rewritten, and/or posted to a URL.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub dir: Option<String>,
+    pub url: Option<String>,
+    #[serde(flatten)]
+    pub redact: RedactConfig,
+}
+
+/// Tidy the replies, whose small inconsistencies break pasted code, before they're rendered,
+/// copied and saved. The steps run in order. A streamed reply is shown a line at a time, and a
+/// code block once it's complete.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NormalizeConfig {
+    pub steps: Vec<NormalizeStep>,
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> Self {
+        Self {
+            steps: vec![
+                NormalizeStep::TrailingSpaces,
+                NormalizeStep::CodeQuotes,
+                NormalizeStep::DedentCode,
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalizeStep {
+    /// Strip the spaces and tabs at the end of the lines
+    TrailingSpaces,
+    /// Convert the smart quotes in fenced code blocks to ASCII quotes
+    CodeQuotes,
+    /// Remove the indentation shared by all lines of fenced code blocks
+    DedentCode,
+    /// End the reply with a newline, for pasting it into files
+    FinalNewline,
+}
+
+/// The middlewares of a single request, some keep state between its request and response.
+#[derive(Default)]
+pub struct Middlewares(Vec<Box<dyn Middleware>>);
+
+impl Middlewares {
+    /// `retry` is the retry config of the client, it takes the place of the one in `configs`.
+    pub fn init(configs: &[MiddlewareConfig], retry: Option<&RetryConfig>) -> Result<Self> {
+        let mut list: Vec<Box<dyn Middleware>> = vec![];
+        if let Some(retry) = retry {
+            list.push(Box::new(retry.clone()));
+        }
+        for config in configs {
+            match config {
+                MiddlewareConfig::Retry(config) => {
+                    if retry.is_none() {
+                        list.push(Box::new(config.clone()))
+                    }
+                }
+                MiddlewareConfig::Log => list.push(Box::new(LogMiddleware)),
+                MiddlewareConfig::Cache(config) => list.push(Box::new(CacheMiddleware {
+                    ttl: Duration::from_secs(config.ttl),
+                    max_entries: config.max_entries,
+                    key: Mutex::new(None),
+                })),
+                MiddlewareConfig::Redact(config) => {
+                    list.push(Box::new(RedactMiddleware::new(config)?))
+                }
+                MiddlewareConfig::Archive(config) => {
+                    list.push(Box::new(ArchiveMiddleware::new(config)?))
+                }
+                MiddlewareConfig::Normalize(config) => {
+                    list.push(Box::new(NormalizeMiddleware::new(config)))
+                }
+            }
+        }
+        Ok(Self(list))
+    }
+
+    #[cfg(test)]
+    fn new(list: Vec<Box<dyn Middleware>>) -> Self {
+        Self(list)
+    }
+
+    pub async fn send_message<C: Client + ?Sized>(
+        &self,
+        client: &C,
+        http_client: &ReqwestClient,
+        mut data: SendData,
+    ) -> Result<(String, CompletionDetails)> {
+        let model = client.model();
+        let (count, cached) = self.on_request(model, &mut data)?;
+        let (mut text, mut details) = match cached {
+            Some(text) => (text, CompletionDetails::default()),
+            None => {
+                let mut attempt = 0;
+                loop {
+                    attempt += 1;
+                    match client.send_message_inner(http_client, data.clone()).await {
+                        Ok(ret) => break ret,
+                        Err(err) => match self.on_error(&err, attempt) {
+                            Some(delay) => sleep(delay).await,
+                            None => return Err(err),
+                        },
+                    }
+                }
+            }
+        };
+        details.cost = completion_cost(model, &details);
+        record_usage(&model.client_name, &details).await;
+        self.on_response(count, model, &data, &mut text, &details);
+        Ok((text, details))
+    }
+
+    /// Only the retries and the usage apply to embeddings, the other middlewares are for chats.
+    pub async fn embeddings<C: Client + ?Sized>(
+        &self,
+        client: &C,
+        http_client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        let model = client.model();
+        let mut attempt = 0;
+        let output = loop {
+            attempt += 1;
+            match client.embeddings_inner(http_client, data.clone()).await {
+                Ok(output) => break output,
+                Err(err) => match self.on_error(&err, attempt) {
+                    Some(delay) => sleep(delay).await,
+                    None => return Err(err),
+                },
+            }
+        };
+        let mut details = CompletionDetails {
+            input_tokens: output.input_tokens,
+            ..Default::default()
+        };
+        details.cost = completion_cost(model, &details);
+        record_usage(&model.client_name, &details).await;
+        Ok(output)
+    }
+
+    pub async fn send_message_streaming<C: Client + ?Sized>(
+        &self,
+        client: &C,
+        http_client: &ReqwestClient,
+        handler: &mut SseHandler,
+        mut data: SendData,
+    ) -> Result<()> {
+        let model = client.model();
+        let (count, cached) = self.on_request(model, &mut data)?;
+        match cached {
+            Some(text) => handler.text(&text)?,
+            None => {
+                let mut attempt = 0;
+                let first_token_timeout = client
+                    .config()
+                    .1
+                    .as_ref()
+                    .and_then(|v| v.first_token_timeout)
+                    .map(Duration::from_secs);
+                loop {
+                    attempt += 1;
+                    let started = handler.started();
+                    let ret = tokio::select! {
+                        ret = client.send_message_streaming_inner(http_client, handler, data.clone()) => ret,
+                        err = watch_first_token(started, first_token_timeout) => Err(err.into()),
+                    };
+                    let err = match ret {
+                        Ok(()) => break,
+                        Err(err) => err,
+                    };
+                    // Once a part of the reply is shown, sending again would repeat it
+                    let shown =
+                        !handler.get_buffer().is_empty() || !handler.tool_calls().is_empty();
+                    match self.on_error(&err, attempt) {
+                        Some(delay) if !shown => {
+                            // The text held back of the failed attempt is sent again
+                            let _ = self.on_stream_end();
+                            sleep(delay).await
+                        }
+                        _ => return Err(err),
+                    }
+                }
+            }
+        }
+        handler.end_text()?;
+        let mut details = handler.completion_details();
+        details.cost = completion_cost(model, &details);
+        handler.set_cost(details.cost);
+        record_usage(&model.client_name, &details).await;
+        let mut text = handler.get_buffer().to_string();
+        self.on_response(count, model, &data, &mut text, &details);
+        handler.set_buffer(text);
+        Ok(())
+    }
+
+    pub fn on_stream_chunk(&self, chunk: &mut String) {
+        for middleware in &self.0 {
+            middleware.on_stream_chunk(chunk);
+        }
+    }
+
+    /// The held back text, it goes through the middlewares after the one that held it.
+    pub fn on_stream_end(&self) -> String {
+        let mut rest = String::new();
+        for middleware in &self.0 {
+            middleware.on_stream_chunk(&mut rest);
+            rest.push_str(&middleware.on_stream_end());
+        }
+        rest
+    }
+
+    /// Returns the number of middlewares that saw the request, and the answer of the one that
+    /// answered it.
+    pub fn on_request(
+        &self,
+        model: &Model,
+        data: &mut SendData,
+    ) -> Result<(usize, Option<String>)> {
+        for (i, middleware) in self.0.iter().enumerate() {
+            if let Some(text) = middleware.on_request(model, data)? {
+                return Ok((i, Some(text)));
+            }
+        }
+        Ok((self.0.len(), None))
+    }
+
+    fn on_response(
+        &self,
+        count: usize,
+        model: &Model,
+        data: &SendData,
+        text: &mut String,
+        details: &CompletionDetails,
+    ) {
+        for middleware in self.0[..count].iter().rev() {
+            middleware.on_response(model, data, text, details);
+        }
+    }
+
+    /// Every middleware sees the error, the first delay wins.
+    fn on_error(&self, err: &Error, attempt: usize) -> Option<Duration> {
+        self.0.iter().fold(None, |delay, middleware| {
+            let value = middleware.on_error(err, attempt);
+            delay.or(value)
+        })
+    }
+}
+
+impl Middleware for RetryConfig {
+    fn on_error(&self, err: &Error, attempt: usize) -> Option<Duration> {
+        if attempt > self.max_retries || !is_retryable(err) {
+            return None;
+        }
+        let retry_after = err
+            .chain()
+            .find_map(|v| v.downcast_ref::<HttpError>())
+            .and_then(|v| v.retry_after)
+            .map(|v| v.as_millis() as u64);
+        let delay = match retry_after {
+            Some(delay) if delay > self.max_backoff_ms => return None,
+            Some(delay) => delay,
+            None => {
+                let delay = self
+                    .backoff_ms
+                    .saturating_mul(1 << (attempt - 1).min(16))
+                    .min(self.max_backoff_ms);
+                if self.jitter {
+                    let random = chrono::Utc::now().timestamp_subsec_nanos() as u64;
+                    delay / 2 + random % (delay / 2 + 1)
+                } else {
+                    delay
+                }
+            }
+        };
+        debug!("Retry the request in {delay}ms, attempt {attempt}, {err}");
+        Some(Duration::from_millis(delay))
+    }
+}
+
+struct LogMiddleware;
+
+impl Middleware for LogMiddleware {
+    fn on_request(&self, model: &Model, data: &mut SendData) -> Result<Option<String>> {
+        info!(
+            "Request {}, {} messages, {} tool results, stream: {}",
+            model.id(),
+            data.messages.len(),
+            data.tool_results().count(),
+            data.stream
+        );
+        Ok(None)
+    }
+
+    fn on_response(
+        &self,
+        model: &Model,
+        _data: &SendData,
+        text: &mut String,
+        details: &CompletionDetails,
+    ) {
+        let mut message = format!(
+            "Response {}, {} chars, {} tool calls",
+            model.id(),
+            text.chars().count(),
+            details.tool_calls.len()
+        );
+        if let (Some(input_tokens), Some(output_tokens)) =
+            (details.input_tokens, details.output_tokens)
+        {
+            message.push_str(&format!(", tokens: {input_tokens} in, {output_tokens} out"));
+        }
+        if let Some(cost) = details.cost {
+            message.push_str(&format!(", cost: ${cost:.6}"));
+        }
+        if !details.meta.is_empty() {
+            message.push_str(&format!(", {}", details.meta.summary()));
+        }
+        info!("{message}");
+    }
+
+    fn on_error(&self, err: &Error, attempt: usize) -> Option<Duration> {
+        warn!("Request failed, attempt {attempt}, {err:#}");
+        None
+    }
+}
+
+struct CacheMiddleware {
+    ttl: Duration,
+    max_entries: usize,
+    key: Mutex<Option<String>>,
+}
+
+impl Middleware for CacheMiddleware {
+    fn on_request(&self, model: &Model, data: &mut SendData) -> Result<Option<String>> {
+        // The results of tool calls depend on when they ran
+        if !data.tool_rounds.is_empty() {
+            return Ok(None);
+        }
+        let key = sha256(
+            &json!({
+                "model": model.id(),
+                "messages": data.messages,
+                "temperature": data.temperature,
+                "top_p": data.top_p,
+                "seed": data.seed,
+                "frequency_penalty": data.frequency_penalty,
+                "presence_penalty": data.presence_penalty,
+                "stop": data.stop,
+                "logprobs": data.logprobs,
+                "functions": data.functions,
+                "thinking": data.thinking.map(|v| v.to_string()),
+                "schema": data.schema,
+                "tags": data.tags,
+            })
+            .to_string(),
+        );
+        if let Some(entry) = CACHE.lock().get_mut(&key) {
+            if entry.time.elapsed() < self.ttl {
+                debug!("Answer the request from the cache");
+                entry.used = Instant::now();
+                return Ok(Some(entry.text.clone()));
+            }
+        }
+        *self.key.lock() = Some(key);
+        Ok(None)
+    }
+
+    fn on_response(
+        &self,
+        _model: &Model,
+        _data: &SendData,
+        text: &mut String,
+        details: &CompletionDetails,
+    ) {
+        if let Some(key) = self.key.lock().take() {
+            if details.tool_calls.is_empty() && !text.is_empty() {
+                let mut cache = CACHE.lock();
+                cache.retain(|_, v| v.time.elapsed() < self.ttl);
+                while cache.len() >= self.max_entries.max(1) {
+                    let Some(oldest) = cache
+                        .iter()
+                        .min_by_key(|(_, v)| v.used)
+                        .map(|(k, _)| k.clone())
+                    else {
+                        break;
+                    };
+                    cache.remove(&oldest);
+                }
+                let now = Instant::now();
+                let entry = CacheEntry {
+                    time: now,
+                    used: now,
+                    text: text.clone(),
+                };
+                cache.insert(key, entry);
+            }
+        }
+    }
+}
+
+struct RedactMiddleware {
+    patterns: Vec<Regex>,
+    replacement: String,
+    max_match_len: usize,
+    pending: Mutex<String>,
+}
+
+impl RedactMiddleware {
+    fn new(config: &RedactConfig) -> Result<Self> {
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|v| Regex::new(v).with_context(|| format!("Invalid redact pattern '{v}'")))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            patterns,
+            replacement: config.replacement.clone(),
+            max_match_len: config.max_match_len,
+            pending: Mutex::new(String::new()),
+        })
+    }
+
+    fn redact_messages(&self, messages: &mut [Message]) {
+        for message in messages {
+            match &mut message.content {
+                MessageContent::Text(text) => self.redact(text),
+                MessageContent::Array(list) => {
+                    for part in list {
+                        if let MessageContentPart::Text { text } = part {
+                            self.redact(text)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The length of the text that can be redacted and shown, the rest may be the start of a
+    /// match that continues in the next chunk.
+    fn redactable_len(&self, text: &str) -> usize {
+        let mut len = text.len().saturating_sub(self.max_match_len);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        for pattern in &self.patterns {
+            for m in pattern.find_iter(text).flatten() {
+                if m.start() < len && m.end() > len {
+                    len = m.start();
+                }
+            }
+        }
+        len
+    }
+
+    fn redact(&self, text: &mut String) {
+        for pattern in &self.patterns {
+            if let std::borrow::Cow::Owned(value) =
+                pattern.replace_all(text, self.replacement.as_str())
+            {
+                *text = value;
+            }
+        }
+    }
+}
+
+impl Middleware for RedactMiddleware {
+    fn on_request(&self, _model: &Model, data: &mut SendData) -> Result<Option<String>> {
+        self.redact_messages(&mut data.messages);
+        Ok(None)
+    }
+
+    fn on_stream_chunk(&self, chunk: &mut String) {
+        if self.patterns.is_empty() {
+            return;
+        }
+        let mut pending = self.pending.lock();
+        pending.push_str(chunk);
+        let len = self.redactable_len(&pending);
+        *chunk = pending.drain(..len).collect();
+        self.redact(chunk)
+    }
+
+    fn on_stream_end(&self) -> String {
+        let mut rest = std::mem::take(&mut *self.pending.lock());
+        self.redact(&mut rest);
+        rest
+    }
+
+    fn on_response(
+        &self,
+        _model: &Model,
+        _data: &SendData,
+        text: &mut String,
+        _details: &CompletionDetails,
+    ) {
+        self.redact(text)
+    }
+}
+
+struct ArchiveMiddleware {
+    dir: Option<PathBuf>,
+    url: Option<String>,
+    redact: RedactMiddleware,
+    request: Mutex<Option<(String, Value)>>,
+}
+
+impl ArchiveMiddleware {
+    fn new(config: &ArchiveConfig) -> Result<Self> {
+        if config.dir.is_none() && config.url.is_none() {
+            bail!("The archive middleware needs a `dir` or a `url`");
+        }
+        Ok(Self {
+            dir: config.dir.as_ref().map(PathBuf::from),
+            url: config.url.clone(),
+            redact: RedactMiddleware::new(&config.redact)?,
+            request: Mutex::new(None),
+        })
+    }
+
+    /// Store the record in the background, the reply doesn't wait for it.
+    fn archive(&self, record: Value) {
+        let (dir, url) = (self.dir.clone(), self.url.clone());
+        let store = move || {
+            if let Err(err) = store_record(dir.as_deref(), url.as_deref(), &record) {
+                warn!("{err:#}");
+                eprintln!("⚠️  Failed to archive the request: {err:#}");
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(store);
+            }
+            Err(_) => store(),
+        }
+    }
+}
+
+fn store_record(dir: Option<&Path>, url: Option<&str>, record: &Value) -> Result<()> {
+    if let Some(dir) = dir {
+        create_dir_all(dir).with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        let date = chrono::Local::now().format("%Y-%m-%d");
+        let path = dir.join(format!("{date}.jsonl"));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open '{}'", path.display()))?;
+        writeln!(file, "{record}")
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    }
+    if let Some(url) = url {
+        // The runtime of the client may be single-threaded, the request runs on its own
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime
+            .block_on(async {
+                ReqwestClient::builder()
+                    .timeout(Duration::from_secs(10))
+                    .build()?
+                    .post(url)
+                    .json(record)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                anyhow::Ok(())
+            })
+            .with_context(|| format!("Failed to post the record to '{url}'"))?;
+    }
+    Ok(())
+}
+
+impl Middleware for ArchiveMiddleware {
+    fn on_request(&self, model: &Model, data: &mut SendData) -> Result<Option<String>> {
+        let mut messages = data.messages.clone();
+        self.redact.redact_messages(&mut messages);
+        let request = json!({
+            "messages": messages,
+            "temperature": data.temperature,
+            "top_p": data.top_p,
+            "seed": data.seed,
+            "frequency_penalty": data.frequency_penalty,
+            "presence_penalty": data.presence_penalty,
+            "stop": data.stop,
+            "logprobs": data.logprobs,
+            "functions": data.functions.as_ref().map(|v| v.len()),
+            "tool_results": data.tool_results().count(),
+            "thinking": data.thinking.map(|v| v.to_string()),
+            "tags": data.tags,
+            "stream": data.stream,
+        });
+        *self.request.lock() = Some((model.id(), request));
+        Ok(None)
+    }
+
+    fn on_response(
+        &self,
+        _model: &Model,
+        _data: &SendData,
+        text: &mut String,
+        details: &CompletionDetails,
+    ) {
+        let Some((model_id, request)) = self.request.lock().take() else {
+            return;
+        };
+        let mut reply = text.clone();
+        self.redact.redact(&mut reply);
+        let tool_calls: Vec<_> = details
+            .tool_calls
+            .iter()
+            .map(|v| {
+                let mut arguments = v.arguments.to_string();
+                self.redact.redact(&mut arguments);
+                json!({ "name": v.name, "arguments": arguments })
+            })
+            .collect();
+        let record = json!({
+            "time": now(),
+            "model": model_id,
+            "request": request,
+            "response": {
+                "text": reply,
+                "tool_calls": tool_calls,
+                "input_tokens": details.input_tokens,
+                "output_tokens": details.output_tokens,
+                "provider": details.meta.provider,
+                "queue_time_ms": details.meta.queue_time.map(|v| v.as_millis() as u64),
+                "cached_tokens": details.meta.cached_tokens,
+            },
+        });
+        self.archive(record);
+    }
+
+    /// Every failed attempt is recorded, the request is kept for the next one.
+    fn on_error(&self, err: &Error, attempt: usize) -> Option<Duration> {
+        if let Some((model_id, request)) = &*self.request.lock() {
+            let mut error = format!("{err:#}");
+            self.redact.redact(&mut error);
+            self.archive(json!({
+                "time": now(),
+                "model": model_id,
+                "request": request,
+                "error": error,
+                "attempt": attempt,
+            }));
+        }
+        None
+    }
+}
+
+/// The cost of the tokens the provider has reported, none if it reported none or the model has
+/// no price.
+fn completion_cost(model: &Model, details: &CompletionDetails) -> Option<f64> {
+    if details.input_tokens.is_none() && details.output_tokens.is_none() {
+        return None;
+    }
+    model.estimate_cached_cost(
+        details.input_tokens.unwrap_or_default(),
+        details.meta.cached_tokens.unwrap_or_default(),
+        details.meta.cache_write_tokens.unwrap_or_default(),
+        details.output_tokens.unwrap_or_default(),
+    )
+}
+
+/// Fails when the reply hasn't started in time, never returns once it has.
+async fn watch_first_token(started: Arc<Notify>, timeout: Option<Duration>) -> FirstTokenTimeout {
+    if let Some(timeout) = timeout {
+        if tokio::time::timeout(timeout, started.notified())
+            .await
+            .is_err()
+        {
+            return FirstTokenTimeout(timeout);
+        }
+    }
+    std::future::pending().await
+}
+
+struct NormalizeMiddleware {
+    steps: Vec<NormalizeStep>,
+    /// The streamed text that can't be normalized yet
+    pending: Mutex<String>,
+}
+
+impl NormalizeMiddleware {
+    fn new(config: &NormalizeConfig) -> Self {
+        Self {
+            steps: config.steps.clone(),
+            pending: Mutex::new(String::new()),
+        }
+    }
+
+    fn normalize(&self, text: &str, final_part: bool) -> String {
+        let mut text = text.to_string();
+        for step in &self.steps {
+            if *step != NormalizeStep::FinalNewline || final_part {
+                text = normalize(&text, *step);
+            }
+        }
+        text
+    }
+}
+
+impl Middleware for NormalizeMiddleware {
+    fn on_stream_chunk(&self, chunk: &mut String) {
+        let mut pending = self.pending.lock();
+        pending.push_str(chunk);
+        let len = normalizable_len(&pending);
+        let ready: String = pending.drain(..len).collect();
+        *chunk = self.normalize(&ready, false);
+    }
+
+    fn on_stream_end(&self) -> String {
+        let rest = std::mem::take(&mut *self.pending.lock());
+        self.normalize(&rest, true)
+    }
+
+    fn on_response(
+        &self,
+        _model: &Model,
+        _data: &SendData,
+        text: &mut String,
+        _details: &CompletionDetails,
+    ) {
+        *text = self.normalize(text, true);
+    }
+}
+
+/// The length of the start of the streamed text that is normalized the same as the whole: the
+/// complete lines outside of the code blocks and the complete code blocks.
+fn normalizable_len(text: &str) -> usize {
+    let mut len = 0;
+    let mut offset = 0;
+    let mut fence: Option<&str> = None;
+    for line in text.split_inclusive('\n') {
+        if !line.ends_with('\n') {
+            break;
+        }
+        offset += line.len();
+        let trimmed = line.trim_start();
+        match fence {
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                fence = Some(&trimmed[..3]);
+            }
+            None => len = offset,
+            Some(marker) => {
+                if trimmed.starts_with(marker) && trimmed[3..].trim().is_empty() {
+                    fence = None;
+                    len = offset;
+                }
+            }
+        }
+    }
+    len
+}
+
+fn normalize(text: &str, step: NormalizeStep) -> String {
+    match step {
+        NormalizeStep::TrailingSpaces => text
+            .split('\n')
+            .map(|v| v.trim_end_matches([' ', '\t']))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        NormalizeStep::CodeQuotes => map_code_blocks(text, |lines| {
+            lines
+                .iter()
+                .map(|v| {
+                    v.replace(['\u{2018}', '\u{2019}', '\u{201A}', '\u{201B}'], "'")
+                        .replace(['\u{201C}', '\u{201D}', '\u{201E}', '\u{201F}'], "\"")
+                })
+                .collect()
+        }),
+        NormalizeStep::DedentCode => map_code_blocks(text, |lines| {
+            // Only the body, the fences stay where they are, e.g. in a list item
+            let (open, close) = (lines[0], lines[lines.len() - 1]);
+            let body = &lines[1..lines.len() - 1];
+            let fence_indent = indent_len(open);
+            let indent = body
+                .iter()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| indent_len(v))
+                .min()
+                .unwrap_or_default();
+            if indent <= fence_indent {
+                return lines.iter().map(|v| v.to_string()).collect();
+            }
+            let mut output = vec![open.to_string()];
+            output.extend(body.iter().map(|v| match v.trim().is_empty() {
+                true => String::new(),
+                false => format!("{}{}", &open[..fence_indent], &v[indent..]),
+            }));
+            output.push(close.to_string());
+            output
+        }),
+        NormalizeStep::FinalNewline => {
+            if text.is_empty() || text.ends_with('\n') {
+                text.to_string()
+            } else {
+                format!("{text}\n")
+            }
+        }
+    }
+}
+
+fn indent_len(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// Change the lines of each fenced code block of the markdown, its fences included.
+fn map_code_blocks(text: &str, f: impl Fn(&[&str]) -> Vec<String>) -> String {
+    let mut output: Vec<String> = vec![];
+    let mut block: Vec<&str> = vec![];
+    let mut fence: Option<&str> = None;
+    for line in text.split('\n') {
+        let trimmed = line.trim_start();
+        match fence {
+            None => {
+                if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                    fence = Some(&trimmed[..3]);
+                    block.push(line);
+                } else {
+                    output.push(line.to_string());
+                }
+            }
+            Some(marker) => {
+                block.push(line);
+                if trimmed.starts_with(marker) && trimmed[3..].trim().is_empty() {
+                    output.extend(f(&block));
+                    block.clear();
+                    fence = None;
+                }
+            }
+        }
+    }
+    // A block without its closing fence may be cut off, it's left as is
+    output.extend(block.iter().map(|v| v.to_string()));
+    output.join("\n")
+}
+
+/// Connection errors, timeouts, rate limits and server errors.
+pub fn is_retryable(err: &Error) -> bool {
+    for cause in err.chain() {
+        if cause.is::<FirstTokenTimeout>() {
+            return true;
+        }
+        if let Some(err) = cause.downcast_ref::<HttpError>() {
+            return err.status == 429 || err.status >= 500;
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            if err.is_connect() || err.is_timeout() {
+                return true;
+            }
+            if let Some(status) = err.status() {
+                return status.as_u16() == 429 || status.is_server_error();
+            }
+        }
+    }
+    let message = err.to_string().to_lowercase();
+    ["status: 429", "status: 5", "rate limit", "overloaded"]
+        .iter()
+        .any(|v| message.contains(v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_send_data;
+    use crate::utils::TestDir;
+    use anyhow::anyhow;
+
+    struct Suffix(&'static str);
+
+    impl Middleware for Suffix {
+        fn on_request(&self, _model: &Model, data: &mut SendData) -> Result<Option<String>> {
+            if let MessageContent::Text(text) = &mut data.messages[0].content {
+                text.push_str(self.0);
+            }
+            Ok(None)
+        }
+
+        fn on_response(
+            &self,
+            _model: &Model,
+            _data: &SendData,
+            text: &mut String,
+            _details: &CompletionDetails,
+        ) {
+            text.push_str(self.0);
+        }
+    }
+
+    #[test]
+    fn test_order() {
+        let middlewares = Middlewares::new(vec![Box::new(Suffix("1")), Box::new(Suffix("2"))]);
+        let model = Model::default();
+        let mut data = test_send_data("a");
+        let (count, cached) = middlewares.on_request(&model, &mut data).unwrap();
+        assert_eq!((count, cached), (2, None));
+        assert_eq!(data.messages[0].content.to_text(), "a12");
+        let mut text = "b".to_string();
+        middlewares.on_response(count, &model, &data, &mut text, &Default::default());
+        assert_eq!(text, "b21");
+    }
+
+    #[test]
+    fn test_cache() {
+        let config = [MiddlewareConfig::Cache(CacheConfig::default())];
+        let model = Model::default();
+        let middlewares = Middlewares::init(&config, None).unwrap();
+        let mut data = test_send_data("test_cache");
+        assert_eq!(
+            middlewares.on_request(&model, &mut data).unwrap(),
+            (1, None)
+        );
+        middlewares.on_response(1, &model, &data, &mut "hi".into(), &Default::default());
+        let middlewares = Middlewares::init(&config, None).unwrap();
+        assert_eq!(
+            middlewares.on_request(&model, &mut data).unwrap(),
+            (0, Some("hi".into()))
+        );
+
+        // A reply of the schema is not one for free-form text, and the other way around
+        let mut schema_data = SendData {
+            schema: Some(json!({ "type": "object" })),
+            ..test_send_data("test_cache")
+        };
+        assert_eq!(
+            middlewares.on_request(&model, &mut schema_data).unwrap(),
+            (1, None)
+        );
+        middlewares.on_response(
+            1,
+            &model,
+            &schema_data,
+            &mut "{}".into(),
+            &Default::default(),
+        );
+        let middlewares = Middlewares::init(&config, None).unwrap();
+        assert_eq!(
+            middlewares.on_request(&model, &mut data).unwrap(),
+            (0, Some("hi".into()))
+        );
+        assert_eq!(
+            middlewares.on_request(&model, &mut schema_data).unwrap(),
+            (0, Some("{}".into()))
+        );
+    }
+
+    #[test]
+    fn test_redact() {
+        let config = [MiddlewareConfig::Redact(RedactConfig {
+            patterns: vec![r"sk-\w+".into()],
+            ..Default::default()
+        })];
+        let middlewares = Middlewares::init(&config, None).unwrap();
+        let mut data = test_send_data("key sk-abc1 and sk-def2");
+        middlewares
+            .on_request(&Model::default(), &mut data)
+            .unwrap();
+        assert_eq!(
+            data.messages[0].content.to_text(),
+            "key [REDACTED] and [REDACTED]"
+        );
+        let mut streamed = String::new();
+        for chunk in ["ok s", "k-x", "yz done"] {
+            let mut chunk = chunk.to_string();
+            middlewares.on_stream_chunk(&mut chunk);
+            streamed.push_str(&chunk);
+        }
+        streamed.push_str(&middlewares.on_stream_end());
+        assert_eq!(streamed, "ok [REDACTED] done");
+
+        let config = [MiddlewareConfig::Redact(RedactConfig {
+            patterns: vec![r"sk-\w+".into()],
+            max_match_len: 4,
+            ..Default::default()
+        })];
+        let middlewares = Middlewares::init(&config, None).unwrap();
+        let mut chunk = "a sk-123456 b c d e".to_string();
+        middlewares.on_stream_chunk(&mut chunk);
+        assert_eq!(chunk, "a [REDACTED] b c");
+        assert_eq!(middlewares.on_stream_end(), " d e");
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = TestDir::new("archive");
+        let config: Vec<MiddlewareConfig> = serde_yaml::from_str(&format!(
+            "- type: archive\n  dir: {}\n  patterns: ['sk-\\w+']",
+            dir.display()
+        ))
+        .unwrap();
+        let middlewares = Middlewares::init(&config, None).unwrap();
+        let model = Model::new("openai", "gpt-4-turbo");
+        for (input, output) in [("key sk-abc1", "ok sk-def2"), ("again", "done")] {
+            let mut data = test_send_data(input);
+            let (count, _) = middlewares.on_request(&model, &mut data).unwrap();
+            assert_eq!(data.messages[0].content.to_text(), input);
+            let mut text = output.to_string();
+            middlewares.on_response(count, &model, &data, &mut text, &Default::default());
+            assert_eq!(text, output);
+        }
+        let date = chrono::Local::now().format("%Y-%m-%d");
+        let content = std::fs::read_to_string(dir.join(format!("{date}.jsonl"))).unwrap();
+        let records: Vec<Value> = content
+            .lines()
+            .map(|v| serde_json::from_str(v).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["model"], "openai:gpt-4-turbo");
+        assert_eq!(
+            records[0]["request"]["messages"][0]["content"],
+            "key [REDACTED]"
+        );
+        assert_eq!(records[0]["response"]["text"], "ok [REDACTED]");
+
+        let mut data = test_send_data("failing sk-abc3");
+        middlewares.on_request(&model, &mut data).unwrap();
+        let err = anyhow::anyhow!("Invalid key sk-abc3");
+        assert_eq!(middlewares.on_error(&err, 1), None);
+        let content = std::fs::read_to_string(dir.join(format!("{date}.jsonl"))).unwrap();
+        let record: Value = serde_json::from_str(content.lines().last().unwrap()).unwrap();
+        assert_eq!(record["error"], "Invalid key [REDACTED]");
+        assert_eq!(record["attempt"], 1);
+
+        let config = [MiddlewareConfig::Archive(ArchiveConfig::default())];
+        assert!(Middlewares::init(&config, None).is_err());
+    }
+
+    #[test]
+    fn test_retry() {
+        let retry = RetryConfig {
+            max_retries: 3,
+            backoff_ms: 100,
+            max_backoff_ms: 300,
+            jitter: false,
+        };
+        let err = anyhow!("Too many requests (status: 429)");
+        assert_eq!(retry.on_error(&err, 1), Some(Duration::from_millis(100)));
+        assert_eq!(retry.on_error(&err, 2), Some(Duration::from_millis(200)));
+        assert_eq!(retry.on_error(&err, 3), Some(Duration::from_millis(300)));
+        assert_eq!(retry.on_error(&err, 4), None);
+        assert_eq!(retry.on_error(&anyhow!("Invalid api key"), 1), None);
+
+        let http_error = |retry_after| {
+            anyhow::Error::from(HttpError {
+                status: 503,
+                retry_after,
+                message: "Service unavailable".into(),
+            })
+        };
+        let err = http_error(Some(Duration::from_millis(250)));
+        assert_eq!(retry.on_error(&err, 1), Some(Duration::from_millis(250)));
+        assert_eq!(
+            retry.on_error(&http_error(Some(Duration::from_secs(1))), 1),
+            None
+        );
+
+        let retry = RetryConfig {
+            jitter: true,
+            ..retry
+        };
+        let delay = retry.on_error(&http_error(None), 2).unwrap();
+        assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+
+        let err = anyhow::Error::from(FirstTokenTimeout(Duration::from_secs(5)))
+            .context("Failed to get answer");
+        assert!(retry.on_error(&err, 1).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_watch_first_token() {
+        let started = Arc::new(Notify::new());
+        let timeout = Some(Duration::from_millis(10));
+        let err = watch_first_token(started.clone(), timeout).await;
+        assert_eq!(err.to_string(), "No reply started within 10ms");
+
+        started.notify_one();
+        let ret = tokio::time::timeout(
+            Duration::from_millis(50),
+            watch_first_token(started, timeout),
+        )
+        .await;
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let config: Vec<MiddlewareConfig> = serde_yaml::from_str(
+            "- type: normalize\n  steps: [trailing_spaces, code_quotes, dedent_code, final_newline]",
+        )
+        .unwrap();
+        let middlewares = Middlewares::init(&config, None).unwrap();
+        let model = Model::default();
+        let data = test_send_data("");
+        let reply = "Run:  \n  ```py\n    print(“hi”)  \n      x = ‘a’\n  ```\nSaid “ok”";
+        let normalized = "Run:\n  ```py\n  print(\"hi\")\n    x = 'a'\n  ```\nSaid “ok”\n";
+        let mut text = reply.to_string();
+        middlewares.on_response(1, &model, &data, &mut text, &Default::default());
+        assert_eq!(text, normalized);
+
+        let mut streamed = String::new();
+        for c in reply.chars() {
+            let mut chunk = c.to_string();
+            middlewares.on_stream_chunk(&mut chunk);
+            streamed.push_str(&chunk);
+            // The code shows once it's normalized
+            assert!(!streamed.contains("“hi”"));
+        }
+        streamed.push_str(&middlewares.on_stream_end());
+        assert_eq!(streamed, normalized);
+
+        let item = "1. List:\n   ```sh\n   ls\n\n   pwd\n   ```";
+        assert_eq!(normalize(item, NormalizeStep::DedentCode), item);
+        let mut text = String::new();
+        middlewares.on_response(1, &model, &data, &mut text, &Default::default());
+        assert_eq!(text, "");
+        assert_eq!(
+            normalize("```\n  “a”\n", NormalizeStep::CodeQuotes),
+            "```\n  “a”\n"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use super::openai::{openai_embeddings_body, OpenAILikeRequests};
+use super::{
+    EmbeddingsData, ExtraConfig, MistralClient, Model, ModelConfig, PromptAction, PromptKind,
+    SendData,
+};
+
+use anyhow::Result;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+
+const API_BASE: &str = "https://api.mistral.ai/v1";
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct MistralConfig {
+    pub name: Option<String>,
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    /// Prepend Mistral's guardrail prompt to the conversation
+    #[serde(default)]
+    pub safe_prompt: bool,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl MistralClient {
+    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_base, get_api_base);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("api_key", "API Key:", true, PromptKind::String)];
+
+    pub fn api_base(config: &MistralConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
+    fn requests(&self) -> Result<OpenAILikeRequests> {
+        let api_key = self.get_api_key()?;
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+        Ok(OpenAILikeRequests::bearer("Mistral", api_base, Some(api_key)))
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        let safe_prompt = self.config.safe_prompt;
+        self.requests()?
+            .chat(client, &self.model, data, |body, model| {
+                mistral_filter_body(body, model);
+                if safe_prompt {
+                    body["safe_prompt"] = true.into();
+                }
+            })
+    }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let body = openai_embeddings_body(&self.model.name, data);
+        self.requests()?.embeddings(client, body)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        self.requests()?.models(client)
+    }
+}
+
+impl_client_trait!(
+    MistralClient,
+    crate::client::openai::openai_send_message,
+    crate::client::openai::openai_send_message_streaming,
+    crate::client::openai::openai_embeddings;
+    models = crate::client::openai::openai_models
+);
+
+/// Remove the fields of OpenAI that Mistral rejects, it names the seed `random_seed`.
+/// It always reports the usage at the end of the stream.
+fn mistral_filter_body(body: &mut Value, _model: &Model) {
+    if let Some(body) = body.as_object_mut() {
+        if let Some(seed) = body.remove("seed") {
+            body.insert("random_seed".into(), seed);
+        }
+        for key in ["logprobs", "top_logprobs", "reasoning_effort"] {
+            body.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{openai::openai_build_body, test_send_data};
+    use serde_json::json;
+
+    #[test]
+    fn test_mistral_filter_body() {
+        let data = SendData {
+            seed: Some(42),
+            logprobs: Some(2),
+            stream: true,
+            ..test_send_data("Hi")
+        };
+        let model = Model::new("mistral", "mistral-large-latest");
+        let mut body = openai_build_body(data, &model);
+        mistral_filter_body(&mut body, &model);
+        assert_eq!(
+            body,
+            json!({
+                "model": "mistral-large-latest",
+                "messages": [{ "role": "user", "content": "Hi" }],
+                "random_seed": 42,
+                "stream": true,
+            })
+        );
+    }
+}
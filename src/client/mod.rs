@@ -1,16 +1,22 @@
 #[macro_use]
 mod common;
 mod message;
+mod middleware;
 mod model;
 mod prompt_format;
+mod rate_limit;
 mod sse_handler;
+mod usage;
 
 pub use crate::utils::PromptKind;
 pub use common::*;
 pub use message::*;
+pub use middleware::*;
 pub use model::*;
 pub use prompt_format::*;
+pub use rate_limit::*;
 pub use sse_handler::*;
+pub use usage::*;
 
 register_client!(
     (openai, "openai", OpenAIConfig, OpenAIClient),
@@ -22,8 +28,20 @@ register_client!(
     ),
     (gemini, "gemini", GeminiConfig, GeminiClient),
     (claude, "claude", ClaudeConfig, ClaudeClient),
+    (mistral, "mistral", MistralConfig, MistralClient),
+    (deepseek, "deepseek", DeepSeekConfig, DeepSeekClient),
+    (xai, "xai", XAIConfig, XAIClient),
+    (github, "github", GitHubConfig, GitHubClient),
+    (
+        huggingface,
+        "huggingface",
+        HuggingFaceConfig,
+        HuggingFaceClient
+    ),
+    (nvidia, "nvidia", NvidiaConfig, NvidiaClient),
     (cohere, "cohere", CohereConfig, CohereClient),
     (ollama, "ollama", OllamaConfig, OllamaClient),
+    (llamacpp, "llamacpp", LlamaCppConfig, LlamaCppClient),
     (
         azure_openai,
         "azure-openai",
@@ -38,12 +56,33 @@ register_client!(
     (qianwen, "qianwen", QianwenConfig, QianwenClient),
 );
 
+/// The local platforms that need no config, their client is added when none has their name.
+pub const IMPLICIT_PLATFORMS: [&str; 1] = ["lmstudio"];
+
+/// The client types that send the functions of a role as tools.
+pub const TOOL_CLIENT_TYPES: [&str; 14] = [
+    "openai",
+    "openai-compatible",
+    "azure-openai",
+    "claude",
+    "mistral",
+    "deepseek",
+    "xai",
+    "github",
+    "nvidia",
+    "huggingface",
+    "llamacpp",
+    "gemini",
+    "vertexai",
+    "ollama",
+];
+
 pub const OPENAI_COMPATIBLE_PLATFORMS: [(&str, &str); 10] = [
     ("anyscale", "https://api.endpoints.anyscale.com/v1"),
     ("deepinfra", "https://api.deepinfra.com/v1/openai"),
     ("fireworks", "https://api.fireworks.ai/inference/v1"),
     ("groq", "https://api.groq.com/openai/v1"),
-    ("mistral", "https://api.mistral.ai/v1"),
+    ("lmstudio", "http://localhost:1234/v1"),
     ("moonshot", "https://api.moonshot.cn/v1"),
     ("openrouter", "https://openrouter.ai/api/v1"),
     ("octoai", "https://text.octoai.run/v1"),
@@ -1,6 +1,6 @@
-use super::message::{Message, MessageContent};
+use super::message::{Message, MessageContent, MessageContentPart};
 
-use crate::utils::{count_tokens, format_option_value};
+use crate::utils::{format_option_value, Encoding};
 
 use anyhow::{bail, Result};
 use serde::Deserialize;
@@ -17,8 +17,22 @@ pub struct Model {
     pub ref_max_output_tokens: Option<isize>,
     pub input_price: Option<f64>,
     pub output_price: Option<f64>,
+    /// The price of the input tokens read from the prompt cache, the input price if not set
+    pub cached_input_price: Option<f64>,
+    /// The price of the input tokens written to the prompt cache, the input price if not set
+    pub cache_write_price: Option<f64>,
     pub extra_fields: Option<serde_json::Map<String, serde_json::Value>>,
     pub capabilities: ModelCapabilities,
+    pub knowledge_cutoff: Option<String>,
+    pub deprecation: Option<Deprecation>,
+    pub tokenizer: Option<Encoding>,
+}
+
+/// The provider deprecated or removed the model, the replacement is a model of the same client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deprecation {
+    pub removed: bool,
+    pub replacement: Option<String>,
 }
 
 impl Default for Model {
@@ -38,7 +52,12 @@ impl Model {
             ref_max_output_tokens: None,
             input_price: None,
             output_price: None,
-            capabilities: ModelCapabilities::Text,
+            cached_input_price: None,
+            cache_write_price: None,
+            capabilities: ModelCapabilities::Text | ModelCapabilities::FunctionCalling,
+            knowledge_cutoff: None,
+            deprecation: None,
+            tokenizer: None,
         }
     }
 
@@ -54,7 +73,14 @@ impl Model {
                     .set_input_price(v.input_price)
                     .set_output_price(v.output_price)
                     .set_supports_vision(v.supports_vision)
-                    .set_extra_fields(&v.extra_fields);
+                    .set_supports_function_calling(v.supports_function_calling.unwrap_or(true))
+                    .set_supports_embedding(v.supports_embedding)
+                    .set_extra_fields(&v.extra_fields)
+                    .set_knowledge_cutoff(&v.knowledge_cutoff)
+                    .set_deprecation(v.deprecated, v.removed, &v.replacement)
+                    .set_tokenizer(v.tokenizer);
+                model.cached_input_price = v.cached_input_price;
+                model.cache_write_price = v.cache_write_price;
                 model
             })
             .collect()
@@ -79,6 +105,9 @@ impl Model {
                 } else if let Some(found) = models.iter().find(|v| v.client_name == client_name) {
                     let mut found = (*found).clone();
                     found.name = model_name.to_string();
+                    found.knowledge_cutoff = None;
+                    found.deprecation = None;
+                    found.tokenizer = None;
                     model = Some(found)
                 }
             }
@@ -100,7 +129,9 @@ impl Model {
         let max_output_tokens = format_option_value(&self.show_max_output_tokens());
         let input_price = format_option_value(&self.input_price);
         let output_price = format_option_value(&self.output_price);
-        let vision = if self.capabilities.contains(ModelCapabilities::Vision) {
+        let vision = if self.capabilities.contains(ModelCapabilities::Embedding) {
+            "embedding"
+        } else if self.capabilities.contains(ModelCapabilities::Vision) {
             "👁"
         } else {
             ""
@@ -164,6 +195,55 @@ impl Model {
         self
     }
 
+    pub fn set_supports_function_calling(&mut self, supports_function_calling: bool) -> &mut Self {
+        if supports_function_calling {
+            self.capabilities |= ModelCapabilities::FunctionCalling;
+        } else {
+            self.capabilities &= !ModelCapabilities::FunctionCalling;
+        }
+        self
+    }
+
+    /// An embedding model only embeds, it can't be chatted with.
+    pub fn set_supports_embedding(&mut self, supports_embedding: bool) -> &mut Self {
+        if supports_embedding {
+            self.capabilities = ModelCapabilities::Embedding;
+        }
+        self
+    }
+
+    /// The cost in USD of the tokens by the prices of the model, none if it has no price.
+    pub fn estimate_cost(&self, input_tokens: u64, output_tokens: u64) -> Option<f64> {
+        self.estimate_cached_cost(input_tokens, 0, 0, output_tokens)
+    }
+
+    /// The cost in USD of the tokens, the input tokens include those read from and written to
+    /// the prompt cache.
+    pub fn estimate_cached_cost(
+        &self,
+        input_tokens: u64,
+        cache_read_tokens: u64,
+        cache_write_tokens: u64,
+        output_tokens: u64,
+    ) -> Option<f64> {
+        if self.input_price.is_none() && self.output_price.is_none() {
+            return None;
+        }
+        let cost = |price: Option<f64>, tokens: u64| price.unwrap_or_default() * tokens as f64;
+        let uncached_tokens = input_tokens.saturating_sub(cache_read_tokens + cache_write_tokens);
+        let total = cost(self.input_price, uncached_tokens)
+            + cost(
+                self.cached_input_price.or(self.input_price),
+                cache_read_tokens,
+            )
+            + cost(
+                self.cache_write_price.or(self.input_price),
+                cache_write_tokens,
+            )
+            + cost(self.output_price, output_tokens);
+        Some(total / 1e6)
+    }
+
     pub fn set_extra_fields(
         &mut self,
         extra_fields: &Option<serde_json::Map<String, serde_json::Value>>,
@@ -172,19 +252,91 @@ impl Model {
         self
     }
 
+    /// Layer the fields of the client under those of the model, and the fields set with
+    /// `.set extra_body.<name>` over them.
+    pub fn merge_extra_body(
+        &mut self,
+        client_body: Option<&serde_json::Map<String, serde_json::Value>>,
+        global_body: &serde_json::Map<String, serde_json::Value>,
+    ) -> &mut Self {
+        let mut fields = client_body.cloned().unwrap_or_default();
+        fields.extend(self.extra_fields.take().unwrap_or_default());
+        fields.extend(global_body.clone());
+        if !fields.is_empty() {
+            self.extra_fields = Some(fields);
+        }
+        self
+    }
+
+    pub fn set_knowledge_cutoff(&mut self, knowledge_cutoff: &Option<String>) -> &mut Self {
+        self.knowledge_cutoff.clone_from(knowledge_cutoff);
+        self
+    }
+
+    pub fn set_tokenizer(&mut self, tokenizer: Option<Encoding>) -> &mut Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    pub fn set_deprecation(
+        &mut self,
+        deprecated: bool,
+        removed: bool,
+        replacement: &Option<String>,
+    ) -> &mut Self {
+        self.deprecation = if deprecated || removed {
+            Some(Deprecation {
+                removed,
+                replacement: replacement.clone(),
+            })
+        } else {
+            None
+        };
+        self
+    }
+
+    pub fn deprecation_warning(&self) -> Option<String> {
+        let deprecation = self.deprecation.as_ref()?;
+        let state = if deprecation.removed {
+            "was removed by the provider"
+        } else {
+            "is deprecated"
+        };
+        let mut warning = format!("Model '{}' {state}", self.id());
+        if let Some(replacement) = &deprecation.replacement {
+            warning.push_str(&format!(
+                ", use '{}:{replacement}' instead",
+                self.client_name
+            ));
+        }
+        Some(warning)
+    }
+
+    /// The encoding configured by `tokenizer`, else the one of the model name.
+    pub fn encoding(&self) -> Encoding {
+        self.tokenizer
+            .unwrap_or_else(|| Encoding::for_model(&self.name))
+    }
+
     pub fn messages_tokens(&self, messages: &[Message]) -> usize {
+        let encoding = self.encoding();
         messages
             .iter()
-            .map(|v| {
-                match &v.content {
-                    MessageContent::Text(text) => count_tokens(text),
-                    MessageContent::Array(_) => 0, // TODO
-                }
+            .map(|v| match &v.content {
+                MessageContent::Text(text) => encoding.count_tokens(text),
+                MessageContent::Array(list) => list
+                    .iter()
+                    .map(|part| match part {
+                        MessageContentPart::Text { text } => encoding.count_tokens(text),
+                        MessageContentPart::ImageUrl { .. } => 0,
+                    })
+                    .sum(),
             })
             .sum()
     }
 
-    pub fn total_tokens(&self, messages: &[Message]) -> usize {
+    /// The tokens the messages take in the prompt, with the tokens framing each message.
+    pub fn count_tokens(&self, messages: &[Message]) -> usize {
         if messages.is_empty() {
             return 0;
         }
@@ -198,7 +350,7 @@ impl Model {
     }
 
     pub fn max_input_tokens_limit(&self, messages: &[Message]) -> Result<()> {
-        let total_tokens = self.total_tokens(messages) + BASIS_TOKENS;
+        let total_tokens = self.count_tokens(messages) + BASIS_TOKENS;
         if let Some(max_input_tokens) = self.max_input_tokens {
             if total_tokens >= max_input_tokens {
                 bail!("Exceed max input tokens limit")
@@ -237,9 +389,22 @@ pub struct ModelConfig {
     pub ref_max_output_tokens: Option<isize>,
     pub input_price: Option<f64>,
     pub output_price: Option<f64>,
+    pub cached_input_price: Option<f64>,
+    pub cache_write_price: Option<f64>,
     #[serde(default)]
     pub supports_vision: bool,
+    pub supports_function_calling: Option<bool>,
+    #[serde(default)]
+    pub supports_embedding: bool,
+    #[serde(alias = "extra_body")]
     pub extra_fields: Option<serde_json::Map<String, serde_json::Value>>,
+    pub knowledge_cutoff: Option<String>,
+    #[serde(default)]
+    pub deprecated: bool,
+    #[serde(default)]
+    pub removed: bool,
+    pub replacement: Option<String>,
+    pub tokenizer: Option<Encoding>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -253,5 +418,86 @@ bitflags::bitflags! {
     pub struct ModelCapabilities: u32 {
         const Text = 0b00000001;
         const Vision = 0b00000010;
+        const FunctionCalling = 0b00000100;
+        const Embedding = 0b00001000;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_extra_body() {
+        let map = |v: serde_json::Value| v.as_object().unwrap().clone();
+        let mut model = Model::new("openai", "gpt-4-turbo");
+        model.set_extra_fields(&Some(map(serde_json::json!({ "seed": 1, "user": "a" }))));
+        model.merge_extra_body(
+            Some(&map(serde_json::json!({ "seed": 0, "store": true }))),
+            &map(serde_json::json!({ "user": "b" })),
+        );
+        assert_eq!(
+            serde_json::Value::Object(model.extra_fields.clone().unwrap()),
+            serde_json::json!({ "seed": 1, "store": true, "user": "b" })
+        );
+
+        let mut model = Model::new("openai", "gpt-4-turbo");
+        model.merge_extra_body(None, &Default::default());
+        assert_eq!(model.extra_fields, None);
+    }
+
+    #[test]
+    fn test_deprecation_warning() {
+        let mut model = Model::new("openai", "gpt-4-vision-preview");
+        assert_eq!(model.deprecation_warning(), None);
+        model.set_deprecation(true, false, &None);
+        assert_eq!(
+            model.deprecation_warning().unwrap(),
+            "Model 'openai:gpt-4-vision-preview' is deprecated"
+        );
+        model.set_deprecation(false, true, &Some("gpt-4-turbo".into()));
+        assert_eq!(
+            model.deprecation_warning().unwrap(),
+            "Model 'openai:gpt-4-vision-preview' was removed by the provider, use 'openai:gpt-4-turbo' instead"
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let models: Vec<ModelConfig> = serde_yaml::from_str(
+            r#"
+- name: gpt-4o
+  supports_vision: true
+  input_price: 2.5
+  output_price: 10
+- name: o1-mini
+  supports_function_calling: false
+- name: text-embedding-3-small
+  supports_embedding: true
+"#,
+        )
+        .unwrap();
+        let models = Model::from_config("openai", &models);
+        assert_eq!(
+            models[0].capabilities,
+            ModelCapabilities::Text
+                | ModelCapabilities::Vision
+                | ModelCapabilities::FunctionCalling
+        );
+        assert_eq!(models[1].capabilities, ModelCapabilities::Text);
+        assert_eq!(models[2].capabilities, ModelCapabilities::Embedding);
+        assert_eq!(models[0].estimate_cost(1_000_000, 100_000), Some(3.5));
+        assert_eq!(models[1].estimate_cost(1000, 1000), None);
+
+        let mut model = models[0].clone();
+        model.cached_input_price = Some(1.25);
+        assert_eq!(
+            model.estimate_cached_cost(1_000_000, 800_000, 0, 0),
+            Some(0.2 * 2.5 + 0.8 * 1.25)
+        );
+        assert_eq!(
+            model.estimate_cached_cost(1_000_000, 0, 1_000_000, 0),
+            Some(2.5)
+        );
     }
 }
@@ -0,0 +1,153 @@
+use super::openai::{openai_remove_reasoning_effort, OpenAILikeRequests};
+use super::{
+    EmbeddingsData, ExtraConfig, Model, ModelConfig, NvidiaClient, PromptAction, PromptKind,
+    SendData,
+};
+
+use anyhow::{bail, Result};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const API_BASE: &str = "https://integrate.api.nvidia.com/v1";
+
+/// The API catalog of build.nvidia.com, or a self-hosted NIM with its `api_base`.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct NvidiaConfig {
+    pub name: Option<String>,
+    /// An `nvapi-` key, a self-hosted NIM needs none
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl NvidiaClient {
+    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_base, get_api_base);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("api_key", "API Key:", true, PromptKind::String)];
+
+    pub fn api_base(config: &NvidiaConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
+    /// The API catalog requires the key, a self-hosted NIM takes one if it is set.
+    fn requests(&self) -> Result<OpenAILikeRequests> {
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+        let api_key = match self.get_api_key() {
+            Ok(api_key) => Some(api_key),
+            Err(_) if api_base != API_BASE => None,
+            Err(err) => bail!("{err}, get an `nvapi-` key at https://build.nvidia.com"),
+        };
+        Ok(OpenAILikeRequests::bearer("Nvidia", api_base, api_key))
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        self.requests()?
+            .chat(client, &self.model, data, openai_remove_reasoning_effort)
+    }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let body = nvidia_embeddings_body(&self.model.name, data);
+        self.requests()?.embeddings(client, body)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        self.requests()?.models(client)
+    }
+}
+
+impl_client_trait!(
+    NvidiaClient,
+    crate::client::openai::openai_send_message,
+    crate::client::openai::openai_send_message_streaming,
+    crate::client::openai::openai_embeddings;
+    models = crate::client::openai::openai_models
+);
+
+/// The retrieval embedding models of NIM embed queries and passages differently.
+fn nvidia_embeddings_body(model_name: &str, data: EmbeddingsData) -> Value {
+    let input_type = match data.query {
+        true => "query",
+        false => "passage",
+    };
+    json!({
+        "model": model_name,
+        "input": data.texts,
+        "input_type": input_type,
+        "encoding_format": "float",
+        "truncate": "END",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{openai::openai_build_body, test_send_data, Thinking};
+    use crate::config::Config;
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    fn client(api_key: Option<&str>, api_base: Option<&str>) -> NvidiaClient {
+        let config = NvidiaConfig {
+            api_key: api_key.map(|v| v.into()),
+            api_base: api_base.map(|v| v.into()),
+            ..Default::default()
+        };
+        let global_config = Arc::new(RwLock::new(Config::default()));
+        NvidiaClient {
+            global_config,
+            config,
+            model: Model::new("nvidia", "meta/llama-3.1-8b-instruct"),
+        }
+    }
+
+    #[test]
+    fn test_nvidia_auth() {
+        let http_client = ReqwestClient::new();
+        let request = client(Some("nvapi-x"), None)
+            .models_builder(&http_client)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().as_str(), "https://integrate.api.nvidia.com/v1/models");
+        assert_eq!(request.headers()["authorization"], "Bearer nvapi-x");
+        let request = client(None, Some("http://localhost:8000/v1"))
+            .models_builder(&http_client)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(!request.headers().contains_key("authorization"));
+        assert!(client(None, None).models_builder(&http_client).is_err());
+    }
+
+    #[test]
+    fn test_nvidia_bodies() {
+        let model = Model::new("nvidia", "deepseek-ai/deepseek-r1");
+        let data = SendData {
+            thinking: Some(Thinking::High),
+            ..test_send_data("Hi")
+        };
+        let mut body = openai_build_body(data, &model);
+        openai_remove_reasoning_effort(&mut body, &model);
+        assert!(body.get("reasoning_effort").is_none());
+        let data = EmbeddingsData::new(vec!["Hi".into()], true);
+        assert_eq!(
+            nvidia_embeddings_body("nvidia/nv-embedqa-e5-v5", data),
+            json!({
+                "model": "nvidia/nv-embedqa-e5-v5",
+                "input": ["Hi"],
+                "input_type": "query",
+                "encoding_format": "float",
+                "truncate": "END",
+            })
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use super::{
-    catch_error, message::*, CompletionDetails, ExtraConfig, Model, ModelConfig, OllamaClient,
-    PromptAction, PromptKind, SendData, SseHandler,
+    send_request, extract_vectors, message::*, CompletionDetails, EmbeddingsData, EmbeddingsOutput,
+    ExtraConfig, Model, ModelConfig, OllamaClient, PromptAction, PromptKind, SendData, SseHandler,
 };
 
+use crate::function::{ToolCall, ToolResult};
+
 use anyhow::{anyhow, bail, Result};
 use futures_util::StreamExt;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
@@ -35,6 +37,10 @@ impl OllamaClient {
         ),
     ];
 
+    pub fn api_base(config: &OllamaConfig) -> Option<String> {
+        config.api_base.clone()
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_base = self.get_api_base()?;
         let api_auth = self.get_api_auth().ok();
@@ -55,44 +61,140 @@ impl OllamaClient {
 
         Ok(builder)
     }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let api_base = self.get_api_base()?;
+        let api_auth = self.get_api_auth().ok();
+
+        let body = json!({
+            "model": &self.model.name,
+            "input": data.texts,
+        });
+
+        let url = format!("{api_base}/api/embed");
+
+        debug!("Ollama Embeddings Request: {url} {body}");
+
+        let mut builder = client.post(url).json(&body);
+        if let Some(api_auth) = api_auth {
+            builder = builder.header("Authorization", api_auth)
+        }
+
+        Ok(builder)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_base = self.get_api_base()?;
+        let api_auth = self.get_api_auth().ok();
+
+        let url = format!("{api_base}/api/tags");
+
+        debug!("Ollama Models Request: {url}");
+
+        let mut builder = client.get(url);
+        if let Some(api_auth) = api_auth {
+            builder = builder.header("Authorization", api_auth)
+        }
+
+        Ok(builder)
+    }
 }
 
-impl_client_trait!(OllamaClient, send_message, send_message_streaming);
+impl_client_trait!(
+    OllamaClient,
+    send_message,
+    send_message_streaming,
+    embeddings;
+    models = models
+);
+
+async fn models(builder: RequestBuilder, client_name: &str) -> Result<Vec<Model>> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    let list = data["models"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid models data: {data}"))?;
+    let models = list
+        .iter()
+        .filter_map(|v| v["name"].as_str())
+        .map(|name| {
+            let mut model = Model::new(client_name, name);
+            model.set_supports_embedding(name.contains("embed"));
+            model
+        })
+        .collect();
+    Ok(models)
+}
+
+async fn embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    Ok(EmbeddingsOutput {
+        vectors: extract_vectors(&data["embeddings"], "")?,
+        input_tokens: data["prompt_eval_count"].as_u64(),
+    })
+}
 
 async fn send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
-    let data = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
     let text = data["message"]["content"]
         .as_str()
         .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
-    Ok((text.to_string(), CompletionDetails::default()))
+    let details = CompletionDetails {
+        tool_calls: extract_tool_calls(&data),
+        ..Default::default()
+    };
+    Ok((text.to_string(), details))
+}
+
+/// Ollama gives the arguments of a tool call as an object and no id, the name is used instead.
+fn extract_tool_calls(data: &Value) -> Vec<ToolCall> {
+    data["message"]["tool_calls"]
+        .as_array()
+        .map(|calls| {
+            calls
+                .iter()
+                .map(|call| {
+                    let name = call["function"]["name"].as_str().unwrap_or_default();
+                    ToolCall {
+                        id: name.to_string(),
+                        name: name.to_string(),
+                        arguments: call["function"]["arguments"].clone(),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 async fn send_message_streaming(builder: RequestBuilder, handler: &mut SseHandler) -> Result<()> {
-    let res = builder.send().await?;
-    let status = res.status();
-    if !status.is_success() {
-        let data = res.json().await?;
-        catch_error(&data, status.as_u16())?;
-    } else {
-        let mut stream = res.bytes_stream();
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            if chunk.is_empty() {
-                continue;
+    let res = send_request(builder).await?;
+    let mut stream = res.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if chunk.is_empty() {
+            continue;
+        }
+        let data: Value = serde_json::from_slice(&chunk)?;
+        if data["done"].is_boolean() {
+            if let Some(text) = data["message"]["content"].as_str() {
+                handler.text(text)?;
             }
-            let data: Value = serde_json::from_slice(&chunk)?;
-            if data["done"].is_boolean() {
-                if let Some(text) = data["message"]["content"].as_str() {
-                    handler.text(text)?;
-                }
-            } else {
-                bail!("Invalid response data: {data}")
+            for call in extract_tool_calls(&data) {
+                handler.tool_call(call);
             }
+            // The last chunk has the counts of the prompt and the answer
+            handler.usage(
+                data["prompt_eval_count"].as_u64(),
+                data["eval_count"].as_u64(),
+            )?;
+        } else {
+            bail!("Invalid response data: {data}")
         }
     }
     Ok(())
@@ -103,11 +205,21 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
         messages,
         temperature,
         top_p,
+        seed,
+        frequency_penalty,
+        presence_penalty,
+        stop,
+        logprobs: _,
+        functions,
+        tool_rounds,
+        thinking: _,
+        tags: _,
+        schema,
         stream,
     } = data;
 
     let mut network_image_urls = vec![];
-    let messages: Vec<Value> = messages
+    let mut messages: Vec<Value> = messages
         .into_iter()
         .map(|message| {
             let role = message.role;
@@ -152,6 +264,13 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
         );
     }
 
+    messages.extend(
+        tool_rounds
+            .iter()
+            .flat_map(|v| &v.results)
+            .flat_map(tool_messages),
+    );
+
     let mut body = json!({
         "model": &model.name,
         "messages": messages,
@@ -168,6 +287,44 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         body["options"]["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["options"]["seed"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["options"]["frequency_penalty"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        body["options"]["presence_penalty"] = v.into();
+    }
+    if !stop.is_empty() {
+        body["options"]["stop"] = stop.into();
+    }
+    if let Some(functions) = functions {
+        body["tools"] = functions
+            .iter()
+            .map(|v| json!({ "type": "function", "function": v }))
+            .collect();
+    }
+    if let Some(schema) = schema {
+        body["format"] = schema;
+    }
 
     Ok(body)
 }
+
+fn tool_messages(result: &ToolResult) -> [Value; 2] {
+    let ToolResult { call, output } = result;
+    [
+        json!({
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [{
+                "function": { "name": call.name, "arguments": call.arguments }
+            }]
+        }),
+        json!({
+            "role": "tool",
+            "content": output.to_string(),
+        }),
+    ]
+}
@@ -1,15 +1,31 @@
 use super::{
-    catch_error, sse_stream, CompletionDetails, ExtraConfig, Model, ModelConfig, OpenAIClient,
-    PromptAction, PromptKind, SendData, SsMmessage, SseHandler,
+    send_request, extract_vectors, sse_stream, CompletionDetails, EmbeddingsData, EmbeddingsOutput,
+    ExtraConfig, Model, ModelConfig, OpenAIClient, PromptAction, PromptKind, ResponseMeta, SendData,
+    SsMmessage, SseHandler,
 };
 
+use crate::function::{ToolCall, ToolResult};
+
 use anyhow::{anyhow, Result};
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
+use std::{collections::HashMap, time::Duration};
 
 const API_BASE: &str = "https://api.openai.com/v1";
 
+/// Models of `/models` that can't chat nor embed, e.g. `whisper-1` or `dall-e-3`.
+const NON_CHAT_MODELS: [&str; 8] = [
+    "whisper",
+    "dall-e",
+    "tts",
+    "davinci",
+    "babbage",
+    "moderation",
+    "gpt-image",
+    "transcribe",
+];
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct OpenAIConfig {
     pub name: Option<String>,
@@ -28,11 +44,19 @@ impl OpenAIClient {
     pub const PROMPTS: [PromptAction<'static>; 1] =
         [("api_key", "API Key:", true, PromptKind::String)];
 
+    pub fn api_base(config: &OpenAIConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_key = self.get_api_key()?;
         let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
 
-        let body = openai_build_body(data, &self.model);
+        let tags = data.tags.clone();
+        let mut body = openai_build_body(data, &self.model);
+        openai_include_usage(&mut body);
+        openai_set_tags(&mut body, &tags);
+        self.model.merge_extra_fields(&mut body);
 
         let url = format!("{api_base}/chat/completions");
 
@@ -46,15 +70,82 @@ impl OpenAIClient {
 
         Ok(builder)
     }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+
+        let body = json!({
+            "model": &self.model.name,
+            "input": data.texts,
+        });
+
+        let url = format!("{api_base}/embeddings");
+
+        debug!("OpenAI Embeddings Request: {url} {body}");
+
+        let mut builder = client.post(url).bearer_auth(api_key).json(&body);
+
+        if let Some(organization_id) = &self.config.organization_id {
+            builder = builder.header("OpenAI-Organization", organization_id);
+        }
+
+        Ok(builder)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+
+        let url = format!("{api_base}/models");
+
+        debug!("OpenAI Models Request: {url}");
+
+        let mut builder = client.get(url).bearer_auth(api_key);
+
+        if let Some(organization_id) = &self.config.organization_id {
+            builder = builder.header("OpenAI-Organization", organization_id);
+        }
+
+        Ok(builder)
+    }
+}
+
+pub async fn openai_models(builder: RequestBuilder, client_name: &str) -> Result<Vec<Model>> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    let list = data["data"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid models data: {data}"))?;
+    let models = list
+        .iter()
+        .filter_map(|v| v["id"].as_str())
+        .filter(|id| !NON_CHAT_MODELS.iter().any(|v| id.contains(v)))
+        .map(|id| {
+            let mut model = Model::new(client_name, id);
+            model.set_supports_embedding(id.contains("embed"));
+            model
+        })
+        .collect();
+    Ok(models)
+}
+
+pub async fn openai_embeddings(builder: RequestBuilder) -> Result<EmbeddingsOutput> {
+    let res = send_request(builder).await?;
+    let data: Value = res.json().await?;
+    Ok(EmbeddingsOutput {
+        vectors: extract_vectors(&data["data"], "/embedding")?,
+        input_tokens: data["usage"]["prompt_tokens"].as_u64(),
+    })
 }
 
 pub async fn openai_send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
 
     openai_extract_completion(&data)
 }
@@ -63,18 +154,158 @@ pub async fn openai_send_message_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
+    // (id, name, arguments) of the tool calls, whose arguments are streamed in pieces
+    let mut tool_calls: Vec<(String, String, String)> = vec![];
     let handle = |message: SsMmessage| -> Result<bool> {
         if message.data == "[DONE]" {
             return Ok(true);
         }
         let data: Value = serde_json::from_str(&message.data)?;
+        // Some compatible APIs stream the thinking of reasoning models as `reasoning_content`
+        if let Some(text) = data["choices"][0]["delta"]["reasoning_content"].as_str() {
+            handler.thinking(text)?;
+        }
         if let Some(text) = data["choices"][0]["delta"]["content"].as_str() {
             handler.text(text)?;
         }
+        if let Some(content) = data["choices"][0]["logprobs"]["content"].as_array() {
+            if !content.is_empty() {
+                handler.logprobs(json!(content))?;
+            }
+        }
+        // The last chunk has the usage when the API is asked for it or sends it anyway
+        let usage = openai_usage(&data);
+        handler.usage(
+            usage["prompt_tokens"].as_u64(),
+            usage["completion_tokens"].as_u64(),
+        )?;
+        handler.meta(openai_extract_meta(&data))?;
+        openai_collect_tool_calls(&data, &mut tool_calls);
         Ok(false)
     };
 
-    sse_stream(builder, handle).await
+    sse_stream(builder, handle).await?;
+    for (id, name, arguments) in tool_calls {
+        handler.tool_call(ToolCall::new(id, name, &arguments)?);
+    }
+    Ok(())
+}
+
+/// Add the pieces of the tool calls in a streamed chunk to the (id, name, arguments) so far.
+pub fn openai_collect_tool_calls(data: &Value, tool_calls: &mut Vec<(String, String, String)>) {
+    let Some(calls) = data["choices"][0]["delta"]["tool_calls"].as_array() else {
+        return;
+    };
+    for call in calls {
+        let index = call["index"].as_u64().unwrap_or_default() as usize;
+        if tool_calls.len() <= index {
+            tool_calls.resize(index + 1, Default::default());
+        }
+        let (id, name, arguments) = &mut tool_calls[index];
+        if let Some(v) = call["id"].as_str() {
+            id.push_str(v);
+        }
+        if let Some(v) = call["function"]["name"].as_str() {
+            name.push_str(v);
+        }
+        if let Some(v) = call["function"]["arguments"].as_str() {
+            arguments.push_str(v);
+        }
+    }
+}
+
+/// The tag `user` goes to the `user` field, all tags go to `metadata`, which is only accepted
+/// for the stored completions.
+pub fn openai_set_tags(body: &mut Value, tags: &HashMap<String, String>) {
+    if tags.is_empty() {
+        return;
+    }
+    if let Some(user) = tags.get("user") {
+        body["user"] = user.clone().into();
+    }
+    body["store"] = true.into();
+    body["metadata"] = json!(tags);
+}
+
+/// The requests of a platform that serves the API of OpenAI at its own base URL.
+pub struct OpenAILikeRequests {
+    /// The platform in the debug log, e.g. `Mistral`
+    pub name: &'static str,
+    pub api_base: String,
+    /// Authorize a request, e.g. with a bearer token
+    pub auth: Box<dyn Fn(RequestBuilder) -> Result<RequestBuilder>>,
+}
+
+impl OpenAILikeRequests {
+    /// Authorized with the token if there is one.
+    pub fn bearer(name: &'static str, api_base: String, token: Option<String>) -> Self {
+        Self {
+            name,
+            api_base,
+            auth: Box::new(move |builder| match &token {
+                Some(token) => Ok(builder.bearer_auth(token)),
+                None => Ok(builder),
+            }),
+        }
+    }
+
+    /// The body of OpenAI, changed by `filter_body` for the platform.
+    pub fn chat(
+        &self,
+        client: &ReqwestClient,
+        model: &Model,
+        data: SendData,
+        filter_body: impl FnOnce(&mut Value, &Model),
+    ) -> Result<RequestBuilder> {
+        let mut body = openai_build_body(data, model);
+        filter_body(&mut body, model);
+        model.merge_extra_fields(&mut body);
+
+        let url = format!("{}/chat/completions", self.api_base);
+
+        debug!("{} Request: {url} {body}", self.name);
+
+        (self.auth)(client.post(url).json(&body))
+    }
+
+    pub fn embeddings(&self, client: &ReqwestClient, body: Value) -> Result<RequestBuilder> {
+        let url = format!("{}/embeddings", self.api_base);
+
+        debug!("{} Embeddings Request: {url} {body}", self.name);
+
+        (self.auth)(client.post(url).json(&body))
+    }
+
+    pub fn models(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let url = format!("{}/models", self.api_base);
+
+        debug!("{} Models Request: {url}", self.name);
+
+        (self.auth)(client.get(url))
+    }
+}
+
+/// The embeddings body of OpenAI.
+pub fn openai_embeddings_body(model_name: &str, data: EmbeddingsData) -> Value {
+    json!({
+        "model": model_name,
+        "input": data.texts,
+    })
+}
+
+/// For the platforms whose reasoning models think without an effort.
+pub fn openai_remove_reasoning_effort(body: &mut Value, _model: &Model) {
+    if let Some(body) = body.as_object_mut() {
+        body.remove("reasoning_effort");
+    }
+}
+
+/// Ask for the usage in a last chunk of the stream, an opt-in since some OpenAI-compatible
+/// servers reject `stream_options`.
+pub fn openai_include_usage(body: &mut Value) {
+    if body["stream"].as_bool().unwrap_or_default() {
+        body["stream_options"] = json!({ "include_usage": true });
+    }
 }
 
 pub fn openai_build_body(data: SendData, model: &Model) -> Value {
@@ -82,9 +313,27 @@ pub fn openai_build_body(data: SendData, model: &Model) -> Value {
         messages,
         temperature,
         top_p,
+        seed,
+        frequency_penalty,
+        presence_penalty,
+        stop,
+        logprobs,
+        functions,
+        tool_rounds,
+        thinking,
+        tags: _,
+        schema,
         stream,
     } = data;
 
+    let mut messages: Vec<Value> = messages.into_iter().map(|v| json!(v)).collect();
+    messages.extend(
+        tool_rounds
+            .iter()
+            .flat_map(|v| &v.results)
+            .flat_map(openai_tool_messages),
+    );
+
     let mut body = json!({
         "model": &model.name,
         "messages": messages,
@@ -99,26 +348,135 @@ pub fn openai_build_body(data: SendData, model: &Model) -> Value {
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["seed"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["frequency_penalty"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        body["presence_penalty"] = v.into();
+    }
+    if !stop.is_empty() {
+        body["stop"] = stop.into();
+    }
+    if let Some(v) = logprobs {
+        body["logprobs"] = true.into();
+        if v > 0 {
+            body["top_logprobs"] = v.into();
+        }
+    }
+    if let Some(thinking) = thinking {
+        body["reasoning_effort"] = thinking.effort().into();
+    }
     if stream {
         body["stream"] = true.into();
     }
+    if let Some(functions) = functions {
+        body["tools"] = functions
+            .iter()
+            .map(|v| json!({ "type": "function", "function": v }))
+            .collect();
+    }
+    if let Some(schema) = schema {
+        body["response_format"] = json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema },
+        });
+    }
     body
 }
 
+/// Replay a tool call and its result as an assistant message followed by a tool message.
+fn openai_tool_messages(result: &ToolResult) -> [Value; 2] {
+    let ToolResult { call, output } = result;
+    [
+        json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": call.id,
+                "type": "function",
+                "function": {
+                    "name": call.name,
+                    "arguments": call.arguments.to_string(),
+                }
+            }]
+        }),
+        json!({
+            "role": "tool",
+            "tool_call_id": call.id,
+            "content": output.to_string(),
+        }),
+    ]
+}
+
 pub fn openai_extract_completion(data: &Value) -> Result<(String, CompletionDetails)> {
-    let text = data["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
+    let message = &data["choices"][0]["message"];
+    let mut tool_calls = vec![];
+    if let Some(calls) = message["tool_calls"].as_array() {
+        for call in calls {
+            // TGI sends the arguments as an object rather than a string
+            let arguments = match &call["function"]["arguments"] {
+                Value::String(v) => v.clone(),
+                Value::Object(_) => call["function"]["arguments"].to_string(),
+                _ => String::new(),
+            };
+            tool_calls.push(ToolCall::new(
+                call["id"].as_str().unwrap_or_default().to_string(),
+                call["function"]["name"].as_str().unwrap_or_default().to_string(),
+                &arguments,
+            )?);
+        }
+    }
+    let text = match message["content"].as_str() {
+        Some(text) => text,
+        None if !tool_calls.is_empty() => "",
+        None => return Err(anyhow!("Invalid response data: {data}")),
+    };
     let details = CompletionDetails {
         id: data["id"].as_str().map(|v| v.to_string()),
         input_tokens: data["usage"]["prompt_tokens"].as_u64(),
         output_tokens: data["usage"]["completion_tokens"].as_u64(),
+        tool_calls,
+        logprobs: data["choices"][0]["logprobs"]
+            .get("content")
+            .filter(|v| v.is_array())
+            .cloned(),
+        meta: openai_extract_meta(data),
+        ..Default::default()
     };
     Ok((text.to_string(), details))
 }
 
+/// Groq puts the usage of a stream in `x_groq` rather than in the last chunk.
+fn openai_usage(data: &Value) -> &Value {
+    match data["usage"].is_object() {
+        true => &data["usage"],
+        false => &data["x_groq"]["usage"],
+    }
+}
+
+/// The provider picked by OpenRouter, the queue time of Groq, and the cached tokens of OpenAI
+/// or DeepSeek.
+pub fn openai_extract_meta(data: &Value) -> ResponseMeta {
+    let usage = openai_usage(data);
+    ResponseMeta {
+        provider: data["provider"].as_str().map(|v| v.to_string()),
+        queue_time: usage["queue_time"]
+            .as_f64()
+            .and_then(|v| Duration::try_from_secs_f64(v).ok()),
+        cached_tokens: usage["prompt_tokens_details"]["cached_tokens"]
+            .as_u64()
+            .or_else(|| usage["prompt_cache_hit_tokens"].as_u64()),
+        cache_write_tokens: None,
+    }
+}
+
 impl_client_trait!(
     OpenAIClient,
     openai_send_message,
-    openai_send_message_streaming
+    openai_send_message_streaming,
+    openai_embeddings;
+    models = openai_models
 );
@@ -1,8 +1,9 @@
 use crate::client::OPENAI_COMPATIBLE_PLATFORMS;
 
-use super::openai::openai_build_body;
+use super::openai::{openai_build_body, openai_include_usage};
 use super::{
-    ExtraConfig, Model, ModelConfig, OpenAICompatibleClient, PromptAction, PromptKind, SendData,
+    alternate_roles, patch_system_message, tool_result_messages, ExtraConfig, Model, ModelConfig,
+    OpenAICompatibleClient, PromptAction, PromptKind, SendData,
 };
 
 use anyhow::Result;
@@ -17,6 +18,15 @@ pub struct OpenAICompatibleConfig {
     pub chat_endpoint: Option<String>,
     #[serde(default)]
     pub models: Vec<ModelConfig>,
+    /// For the servers that reject `system` messages
+    #[serde(default)]
+    pub merge_system_into_user: bool,
+    /// For the servers whose chat templates require user and assistant messages to alternate
+    #[serde(default)]
+    pub enforce_role_alternation: bool,
+    /// For the servers that report the usage of a stream with `stream_options`
+    #[serde(default)]
+    pub include_usage: bool,
     pub extra: Option<ExtraConfig>,
 }
 
@@ -37,27 +47,42 @@ impl OpenAICompatibleClient {
         ),
     ];
 
-    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let api_base = match self.get_api_base() {
-            Ok(v) => v,
-            Err(err) => {
-                match OPENAI_COMPATIBLE_PLATFORMS
-                    .into_iter()
-                    .find_map(|(name, api_base)| {
-                        if name == self.model.client_name {
-                            Some(api_base.to_string())
-                        } else {
-                            None
-                        }
-                    }) {
-                    Some(v) => v,
-                    None => return Err(err),
-                }
-            }
-        };
+    pub fn api_base(config: &OpenAICompatibleConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| {
+            let name = config.name.as_deref()?;
+            OPENAI_COMPATIBLE_PLATFORMS
+                .iter()
+                .find(|(v, _)| *v == name)
+                .map(|(_, api_base)| api_base.to_string())
+        })
+    }
+
+    fn request_builder(
+        &self,
+        client: &ReqwestClient,
+        mut data: SendData,
+    ) -> Result<RequestBuilder> {
+        let api_base = self.resolve_api_base()?;
         let api_key = self.get_api_key().ok();
 
+        if self.config.merge_system_into_user {
+            patch_system_message(&mut data.messages);
+        }
+        if self.config.enforce_role_alternation {
+            // The templates that require the roles to alternate have no tool messages
+            let tool_rounds = std::mem::take(&mut data.tool_rounds);
+            data.messages.extend(
+                tool_rounds
+                    .iter()
+                    .flat_map(|v| &v.results)
+                    .flat_map(tool_result_messages),
+            );
+            alternate_roles(&mut data.messages);
+        }
         let mut body = openai_build_body(data, &self.model);
+        if self.config.include_usage {
+            openai_include_usage(&mut body);
+        }
         self.model.merge_extra_fields(&mut body);
 
         let chat_endpoint = self
@@ -77,10 +102,44 @@ impl OpenAICompatibleClient {
 
         Ok(builder)
     }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        let api_base = self.resolve_api_base()?;
+        let api_key = self.get_api_key().ok();
+
+        let url = format!("{api_base}/models");
+
+        debug!("OpenAICompatible Models Request: {url}");
+
+        let mut builder = client.get(url);
+        if let Some(api_key) = api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        Ok(builder)
+    }
+
+    /// The configured API base, or the one of the known platform of the client name.
+    fn resolve_api_base(&self) -> Result<String> {
+        match self.get_api_base() {
+            Ok(v) => Ok(v),
+            Err(err) => OPENAI_COMPATIBLE_PLATFORMS
+                .into_iter()
+                .find_map(|(name, api_base)| {
+                    if name == self.model.client_name {
+                        Some(api_base.to_string())
+                    } else {
+                        None
+                    }
+                })
+                .ok_or(err),
+        }
+    }
 }
 
 impl_client_trait!(
     OpenAICompatibleClient,
     crate::client::openai::openai_send_message,
-    crate::client::openai::openai_send_message_streaming
+    crate::client::openai::openai_send_message_streaming;
+    models = crate::client::openai::openai_models
 );
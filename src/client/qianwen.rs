@@ -36,6 +36,10 @@ impl QianwenClient {
     pub const PROMPTS: [PromptAction<'static>; 1] =
         [("api_key", "API Key:", true, PromptKind::String)];
 
+    pub fn api_base(_config: &QianwenConfig) -> Option<String> {
+        Some(API_URL.into())
+    }
+
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
         let api_key = self.get_api_key()?;
 
@@ -46,7 +50,8 @@ impl QianwenClient {
             true => API_URL_VL,
             false => API_URL,
         };
-        let (body, has_upload) = build_body(data, &self.model, is_vl)?;
+        let (mut body, has_upload) = build_body(data, &self.model, is_vl)?;
+        self.model.merge_extra_fields(&mut body);
 
         debug!("Qianwen Request: {url} {body}");
 
@@ -92,6 +97,15 @@ impl Client for QianwenClient {
         let builder = self.request_builder(client, data)?;
         send_message_streaming(builder, handler, self.is_vl()).await
     }
+
+    async fn request_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<RequestBuilder> {
+        // The images are only uploaded when sending
+        self.request_builder(client, data)
+    }
 }
 
 async fn send_message(builder: RequestBuilder, is_vl: bool) -> Result<(String, CompletionDetails)> {
@@ -118,6 +132,10 @@ async fn send_message_streaming(
         } else if let Some(text) = data["output"]["text"].as_str() {
             handler.text(text)?;
         }
+        handler.usage(
+            data["usage"]["input_tokens"].as_u64(),
+            data["usage"]["output_tokens"].as_u64(),
+        )?;
         Ok(false)
     };
 
@@ -129,6 +147,16 @@ fn build_body(data: SendData, model: &Model, is_vl: bool) -> Result<(Value, bool
         messages,
         temperature,
         top_p,
+        seed,
+        frequency_penalty: _,
+        presence_penalty,
+        stop,
+        logprobs: _,
+        functions: _,
+        tool_rounds: _,
+        thinking: _,
+        tags: _,
+        schema: _,
         stream,
     } = data;
 
@@ -182,6 +210,15 @@ fn build_body(data: SendData, model: &Model, is_vl: bool) -> Result<(Value, bool
     if let Some(v) = top_p {
         parameters["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        parameters["seed"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        parameters["presence_penalty"] = v.into();
+    }
+    if !stop.is_empty() {
+        parameters["stop"] = stop.into();
+    }
 
     let body = json!({
         "model": &model.name,
@@ -205,6 +242,7 @@ fn extract_completion_text(data: &Value, is_vl: bool) -> Result<(String, Complet
         id: data["request_id"].as_str().map(|v| v.to_string()),
         input_tokens: data["usage"]["input_tokens"].as_u64(),
         output_tokens: data["usage"]["output_tokens"].as_u64(),
+        ..Default::default()
     };
 
     Ok((text.to_string(), details))
@@ -0,0 +1,143 @@
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref LIMITERS: Mutex<HashMap<String, Arc<RateLimiter>>> = Mutex::new(HashMap::new());
+}
+
+/// Limits of the requests to a provider, from `concurrency`, `rpm` and `tpm` of the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Requests in flight at once
+    pub concurrency: Option<usize>,
+    /// Requests per minute
+    pub rpm: Option<u64>,
+    /// Input tokens per minute
+    pub tpm: Option<u64>,
+}
+
+/// Throttle the requests to a provider, shared by all the tasks sending to it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    semaphore: Option<Arc<Semaphore>>,
+    requests: Option<Window>,
+    tokens: Option<Window>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            semaphore: limits
+                .concurrency
+                .map(|v| Arc::new(Semaphore::new(v.max(1)))),
+            requests: limits.rpm.map(Window::new),
+            tokens: limits.tpm.map(Window::new),
+        }
+    }
+
+    /// The limiter of the client, created or replaced when the limits have changed.
+    pub fn for_client(name: &str, limits: RateLimits) -> Arc<Self> {
+        let mut limiters = LIMITERS.lock();
+        match limiters.get(name) {
+            Some(limiter) if limiter.limits == limits => limiter.clone(),
+            _ => {
+                let limiter = Arc::new(Self::new(limits));
+                limiters.insert(name.to_string(), limiter.clone());
+                limiter
+            }
+        }
+    }
+
+    /// Wait until a request of `tokens` input tokens fits in the limits. The request counts
+    /// against `concurrency` until the returned permit is dropped.
+    pub async fn acquire(&self, tokens: u64) -> Option<OwnedSemaphorePermit> {
+        let permit = match &self.semaphore {
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        if let Some(requests) = &self.requests {
+            requests.acquire(1).await;
+        }
+        if let Some(window) = &self.tokens {
+            window.acquire(tokens).await;
+        }
+        permit
+    }
+}
+
+/// A sliding window of a minute holding the costs spent in it.
+#[derive(Debug)]
+struct Window {
+    limit: u64,
+    spent: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl Window {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit: limit.max(1),
+            spent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    async fn acquire(&self, cost: u64) {
+        // A cost over the limit would never fit, it waits for an empty window instead
+        let cost = cost.min(self.limit);
+        loop {
+            match self.try_acquire(cost, Instant::now()) {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    /// Spend the cost if it fits, otherwise how long to wait for the oldest cost to expire.
+    fn try_acquire(&self, cost: u64, now: Instant) -> Option<Duration> {
+        let mut spent = self.spent.lock();
+        while spent
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) >= WINDOW)
+        {
+            spent.pop_front();
+        }
+        let total: u64 = spent.iter().map(|(_, v)| v).sum();
+        if total + cost <= self.limit {
+            spent.push_back((now, cost));
+            return None;
+        }
+        let (oldest, _) = spent.front()?;
+        Some(WINDOW.saturating_sub(now.duration_since(*oldest)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let window = Window::new(100);
+        let now = Instant::now();
+        assert_eq!(window.try_acquire(60, now), None);
+        assert_eq!(window.try_acquire(40, now + Duration::from_secs(10)), None);
+        assert_eq!(
+            window.try_acquire(1, now + Duration::from_secs(20)),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(window.try_acquire(60, now + Duration::from_secs(60)), None);
+        assert_eq!(
+            window.try_acquire(10, now + Duration::from_secs(61)),
+            Some(Duration::from_secs(9))
+        );
+    }
+}
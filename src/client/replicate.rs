@@ -1,7 +1,7 @@
 use std::time::Duration;
 
 use super::{
-    catch_error, generate_prompt, smart_prompt_format, sse_stream, Client, CompletionDetails,
+    send_request, generate_prompt, smart_prompt_format, sse_stream, Client, CompletionDetails,
     ExtraConfig, Model, ModelConfig, PromptAction, PromptKind, ReplicateClient, SendData,
     SsMmessage, SseHandler,
 };
@@ -29,13 +29,18 @@ impl ReplicateClient {
     pub const PROMPTS: [PromptAction<'static>; 1] =
         [("api_key", "API Key:", true, PromptKind::String)];
 
+    pub fn api_base(_config: &ReplicateConfig) -> Option<String> {
+        Some(API_BASE.into())
+    }
+
     fn request_builder(
         &self,
         client: &ReqwestClient,
         data: SendData,
         api_key: &str,
     ) -> Result<RequestBuilder> {
-        let body = build_body(data, &self.model)?;
+        let mut body = build_body(data, &self.model)?;
+        self.model.merge_extra_fields(&mut body);
 
         let url = format!("{API_BASE}/models/{}/predictions", self.model.name);
 
@@ -71,6 +76,15 @@ impl Client for ReplicateClient {
         let builder = self.request_builder(client, data, &api_key)?;
         send_message_streaming(client, builder, handler).await
     }
+
+    async fn request_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<RequestBuilder> {
+        let api_key = self.get_api_key()?;
+        self.request_builder(client, data, &api_key)
+    }
 }
 
 async fn send_message(
@@ -78,12 +92,8 @@ async fn send_message(
     builder: RequestBuilder,
     api_key: &str,
 ) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
     let prediction_url = data["urls"]["get"]
         .as_str()
         .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
@@ -111,12 +121,8 @@ async fn send_message_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
     let stream_url = data["urls"]["stream"]
         .as_str()
         .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
@@ -138,6 +144,16 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
         messages,
         temperature,
         top_p,
+        seed: _,
+        frequency_penalty,
+        presence_penalty,
+        stop,
+        logprobs: _,
+        functions: _,
+        tool_rounds: _,
+        thinking: _,
+        tags: _,
+        schema: _,
         stream,
     } = data;
 
@@ -158,6 +174,15 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         input["top_p"] = v.into();
     }
+    if let Some(v) = frequency_penalty {
+        input["frequency_penalty"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        input["presence_penalty"] = v.into();
+    }
+    if !stop.is_empty() {
+        input["stop_sequences"] = stop.join(",").into();
+    }
 
     let mut body = json!({
         "input": input,
@@ -186,6 +211,7 @@ fn extract_completion(data: &Value) -> Result<(String, CompletionDetails)> {
         id: data["id"].as_str().map(|v| v.to_string()),
         input_tokens: data["metrics"]["input_token_count"].as_u64(),
         output_tokens: data["metrics"]["output_token_count"].as_u64(),
+        ..Default::default()
     };
 
     Ok((text.to_string(), details))
@@ -1,11 +1,24 @@
-use crate::utils::AbortSignal;
+use super::{CompletionDetails, Middlewares, ResponseMeta};
+
+use crate::{function::ToolCall, utils::AbortSignal};
 
 use anyhow::{Context, Result};
-use tokio::sync::mpsc::UnboundedSender;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::{mpsc::UnboundedSender, Notify};
 
 pub struct SseHandler {
     sender: UnboundedSender<SseEvent>,
     buffer: String,
+    tool_calls: Vec<ToolCall>,
+    thinking: Vec<Value>,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
+    meta: ResponseMeta,
+    cost: Option<f64>,
+    /// Notified on the first piece of the reply, the thinking or a tool call
+    started: Arc<Notify>,
+    middlewares: Arc<Middlewares>,
     abort: AbortSignal,
 }
 
@@ -15,6 +28,14 @@ impl SseHandler {
             sender,
             abort,
             buffer: String::new(),
+            tool_calls: vec![],
+            thinking: vec![],
+            input_tokens: None,
+            output_tokens: None,
+            meta: ResponseMeta::default(),
+            cost: None,
+            started: Default::default(),
+            middlewares: Default::default(),
         }
     }
 
@@ -23,29 +44,138 @@ impl SseHandler {
         if text.is_empty() {
             return Ok(());
         }
-        self.buffer.push_str(text);
-        let ret = self
-            .sender
-            .send(SseEvent::Text(text.to_string()))
-            .with_context(|| "Failed to send ReplyEvent:Text");
-        self.safe_ret(ret)?;
-        Ok(())
+        self.started.notify_one();
+        let mut text = text.to_string();
+        self.middlewares.on_stream_chunk(&mut text);
+        self.buffer.push_str(&text);
+        self.send(SseEvent::TextDelta(text))
+    }
+
+    /// Show the text that the middlewares held back, at the end of the reply.
+    pub fn end_text(&mut self) -> Result<()> {
+        let text = self.middlewares.on_stream_end();
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.buffer.push_str(&text);
+        self.send(SseEvent::TextDelta(text))
+    }
+
+    pub fn set_middlewares(&mut self, middlewares: Arc<Middlewares>) {
+        self.middlewares = middlewares;
+    }
+
+    /// The thinking of the model, which is not kept in the reply.
+    pub fn thinking(&mut self, text: &str) -> Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.started.notify_one();
+        self.send(SseEvent::ReasoningDelta(text.to_string()))
+    }
+
+    /// The blocks of thinking to send back with the tool results, once they are complete.
+    pub fn set_thinking_blocks(&mut self, blocks: Vec<Value>) {
+        self.thinking = blocks;
+    }
+
+    pub fn tool_call(&mut self, call: ToolCall) {
+        // Only the caller gets to run it, the event just lets the frontends show it
+        self.started.notify_one();
+        let _ = self.send(SseEvent::ToolCall(call.clone()));
+        self.tool_calls.push(call);
+    }
+
+    pub fn usage(&mut self, input_tokens: Option<u64>, output_tokens: Option<u64>) -> Result<()> {
+        if input_tokens.is_none() && output_tokens.is_none() {
+            return Ok(());
+        }
+        self.input_tokens = input_tokens.or(self.input_tokens);
+        self.output_tokens = output_tokens.or(self.output_tokens);
+        self.send(SseEvent::Usage {
+            input_tokens,
+            output_tokens,
+        })
+    }
+
+    /// How the request was served, some of it may only come with the last chunk.
+    pub fn meta(&mut self, meta: ResponseMeta) -> Result<()> {
+        // Gateways repeat it in every chunk
+        let mut merged = self.meta.clone();
+        merged.merge(meta);
+        if merged == self.meta {
+            return Ok(());
+        }
+        self.meta = merged;
+        self.send(SseEvent::Meta(self.meta.clone()))
+    }
+
+    /// The cost of the reply, known once it's complete.
+    pub fn set_cost(&mut self, cost: Option<f64>) {
+        self.cost = cost;
+    }
+
+    /// The log probabilities of the tokens of a chunk.
+    pub fn logprobs(&mut self, content: Value) -> Result<()> {
+        self.send(SseEvent::Logprobs(content))
+    }
+
+    /// A message for the user about the request, not a part of the reply.
+    pub fn notice(&mut self, text: &str) -> Result<()> {
+        self.send(SseEvent::Notice(text.to_string()))
+    }
+
+    pub fn error(&mut self, err: &anyhow::Error) -> Result<()> {
+        self.send(SseEvent::Error(format!("{err:?}")))
+    }
+
+    pub fn tool_calls(&self) -> &[ToolCall] {
+        &self.tool_calls
+    }
+
+    pub fn take_tool_calls(&mut self) -> Vec<ToolCall> {
+        std::mem::take(&mut self.tool_calls)
     }
 
     pub fn done(&mut self) -> Result<()> {
-        // debug!("ReplyDone");
+        self.send(SseEvent::Done)
+    }
+
+    fn send(&mut self, event: SseEvent) -> Result<()> {
         let ret = self
             .sender
-            .send(SseEvent::Done)
-            .with_context(|| "Failed to send ReplyEvent::Done");
-        self.safe_ret(ret)?;
-        Ok(())
+            .send(event)
+            .with_context(|| "Failed to send the reply event");
+        self.safe_ret(ret)
     }
 
     pub fn get_buffer(&self) -> &str {
         &self.buffer
     }
 
+    /// Replace the reply, without changing what was shown.
+    pub fn set_buffer(&mut self, buffer: String) {
+        self.buffer = buffer;
+    }
+
+    /// The usage and the metadata reported while streaming, and the tool calls.
+    pub fn completion_details(&self) -> CompletionDetails {
+        CompletionDetails {
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens,
+            tool_calls: self.tool_calls.clone(),
+            thinking: self.thinking.clone(),
+            meta: self.meta.clone(),
+            cost: self.cost,
+            ..Default::default()
+        }
+    }
+
+    /// Wait for the reply to start.
+    pub fn started(&self) -> Arc<Notify> {
+        self.started.clone()
+    }
+
     pub fn get_abort(&self) -> AbortSignal {
         self.abort.clone()
     }
@@ -58,8 +188,27 @@ impl SseHandler {
     }
 }
 
-#[derive(Debug)]
+/// A streamed reply as typed events, so that the frontends don't have to parse printed text.
+#[derive(Debug, Clone, PartialEq)]
 pub enum SseEvent {
-    Text(String),
+    /// A piece of the reply
+    TextDelta(String),
+    /// A piece of the thinking of the model
+    ReasoningDelta(String),
+    /// A tool call of the model, run once the reply is complete
+    ToolCall(ToolCall),
+    /// The tokens spent so far, as reported by the provider
+    Usage {
+        input_tokens: Option<u64>,
+        output_tokens: Option<u64>,
+    },
+    /// How the request was served so far, e.g. the provider picked by a gateway
+    Meta(ResponseMeta),
+    /// The log probabilities of the tokens of a piece of the reply
+    Logprobs(Value),
+    /// A message for the user about the request, e.g. a failover to another model
+    Notice(String),
+    /// The reply has failed, the error is also returned to the caller
+    Error(String),
     Done,
 }
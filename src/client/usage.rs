@@ -0,0 +1,207 @@
+use super::CompletionDetails;
+
+use crate::config::Config;
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+const USAGE_RECORDS_FILE_NAME: &str = "usage.jsonl";
+
+lazy_static! {
+    static ref RUN_USAGE: Mutex<BTreeMap<String, Usage>> = Mutex::new(BTreeMap::new());
+    static ref WRITING_USAGE: Mutex<()> = Mutex::new(());
+}
+
+/// Tokens and cost of completions, the cost in USD of those whose model has a price.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Usage {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+}
+
+impl Usage {
+    pub fn is_empty(&self) -> bool {
+        self.requests == 0
+    }
+
+    /// Count the completion, unless the provider has reported no tokens, e.g. for a cached
+    /// reply.
+    pub fn add(&mut self, details: &CompletionDetails) {
+        if !has_usage(details) {
+            return;
+        }
+        self.requests += 1;
+        self.input_tokens += details.input_tokens.unwrap_or_default();
+        self.output_tokens += details.output_tokens.unwrap_or_default();
+        self.cost += details.cost.unwrap_or_default();
+    }
+
+    pub fn merge(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cost += other.cost;
+    }
+}
+
+/// The usage since the first completion. The completions are appended to `usage.jsonl` of the
+/// config dir, a line at a time so that the running aichat don't lose each other's counts.
+///
+/// The file is never compacted, it grows by about 100 bytes per completion and is read whole
+/// by `.cost`. Removing it, or moving it away, starts the counts over.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UsageStats {
+    /// When the first completion was counted
+    pub since: Option<String>,
+    pub total: Usage,
+    pub clients: BTreeMap<String, Usage>,
+}
+
+/// A completion in `usage.jsonl`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct UsageRecord {
+    date: String,
+    client: String,
+    input_tokens: u64,
+    output_tokens: u64,
+    cost: f64,
+}
+
+impl UsageRecord {
+    fn new(client_name: &str, details: &CompletionDetails) -> Self {
+        Self {
+            date: chrono::Local::now().format("%Y-%m-%d").to_string(),
+            client: client_name.to_string(),
+            input_tokens: details.input_tokens.unwrap_or_default(),
+            output_tokens: details.output_tokens.unwrap_or_default(),
+            cost: details.cost.unwrap_or_default(),
+        }
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            requests: 1,
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens,
+            cost: self.cost,
+        }
+    }
+}
+
+impl UsageStats {
+    pub fn load() -> Result<Self> {
+        let mut stats = Self::default();
+        let path = Config::local_path(USAGE_RECORDS_FILE_NAME)?;
+        if path.exists() {
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read usage at {}", path.display()))?;
+            // A line cut off by a crash is skipped, the others still count
+            for record in content
+                .lines()
+                .filter_map(|line| serde_json::from_str::<UsageRecord>(line).ok())
+            {
+                stats.add(&record);
+            }
+        }
+        Ok(stats)
+    }
+
+    fn add(&mut self, record: &UsageRecord) {
+        if self.since.is_none() {
+            self.since = Some(record.date.clone());
+        }
+        let usage = record.usage();
+        self.total.merge(&usage);
+        self.clients
+            .entry(record.client.clone())
+            .or_default()
+            .merge(&usage);
+    }
+}
+
+/// Count the completion in the usage of this run and in the lifetime one.
+pub async fn record_usage(client_name: &str, details: &CompletionDetails) {
+    if !has_usage(details) {
+        return;
+    }
+    RUN_USAGE
+        .lock()
+        .entry(client_name.to_string())
+        .or_default()
+        .add(details);
+    let record = UsageRecord::new(client_name, details);
+    let ret = tokio::task::spawn_blocking(move || append_usage_record(&record)).await;
+    if let Err(err) = ret.map_err(|err| anyhow!(err)).and_then(|v| v) {
+        warn!("{err:#}");
+    }
+}
+
+/// One write of a whole line with `O_APPEND`, which doesn't interleave with the writes of the
+/// other processes.
+fn append_usage_record(record: &UsageRecord) -> Result<()> {
+    let _guard = WRITING_USAGE.lock();
+    let path = Config::local_path(USAGE_RECORDS_FILE_NAME)?;
+    let line = format!("{}\n", serde_json::to_string(record)?);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Failed to save usage to {}", path.display()))
+}
+
+/// Wait for the usage being recorded to be saved.
+pub fn flush_usage() {
+    drop(WRITING_USAGE.lock());
+}
+
+fn has_usage(details: &CompletionDetails) -> bool {
+    details.input_tokens.is_some() || details.output_tokens.is_some()
+}
+
+/// The usage of this run per client.
+pub fn run_usage() -> BTreeMap<String, Usage> {
+    RUN_USAGE.lock().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_stats() {
+        let details = CompletionDetails {
+            input_tokens: Some(100),
+            output_tokens: Some(20),
+            cost: Some(0.5),
+            ..Default::default()
+        };
+        let record = UsageRecord::new("openai", &details);
+        let line = serde_json::to_string(&record).unwrap();
+        let record: UsageRecord = serde_json::from_str(&line).unwrap();
+        let mut stats = UsageStats::default();
+        stats.add(&record);
+        stats.add(&record);
+        assert!(stats.since.is_some());
+        assert_eq!(
+            stats.clients["openai"],
+            Usage {
+                requests: 2,
+                input_tokens: 200,
+                output_tokens: 40,
+                cost: 1.0,
+            }
+        );
+        assert_eq!(stats.total.requests, 2);
+        assert_eq!(stats.total.cost, 1.0);
+    }
+}
@@ -1,19 +1,25 @@
 use super::claude::{claude_build_body, claude_send_message, claude_send_message_streaming};
 use super::{
-    catch_error, json_stream, message::*, patch_system_message, Client, CompletionDetails,
-    ExtraConfig, Model, ModelConfig, PromptAction, PromptKind, SendData, SseHandler,
-    VertexAIClient,
+    send_request, extract_vectors, json_stream, message::*, patch_system_message, Client,
+    CompletionDetails, EmbeddingsData, EmbeddingsOutput, ExtraConfig, Model, ModelConfig,
+    PromptAction, PromptKind, ResponseMeta, SendData, SseHandler, VertexAIClient,
 };
 
+use crate::function::{ToolCall, ToolResult};
+
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use chrono::{Duration, Utc};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::{path::PathBuf, str::FromStr};
 
-static mut ACCESS_TOKEN: (String, i64) = (String::new(), 0); // safe under linear operation
+lazy_static! {
+    static ref ACCESS_TOKEN: RwLock<(String, i64)> = RwLock::new((String::new(), 0));
+}
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct VertexAIConfig {
@@ -36,6 +42,14 @@ impl VertexAIClient {
         ("location", "Location", true, PromptKind::String),
     ];
 
+    pub fn api_base(config: &VertexAIConfig) -> Option<String> {
+        let location = config.location.as_deref()?;
+        let project_id = config.project_id.as_deref()?;
+        Some(format!(
+            "https://{location}-aiplatform.googleapis.com/v1/projects/{project_id}/locations/{location}/publishers"
+        ))
+    }
+
     fn request_builder(
         &self,
         client: &ReqwestClient,
@@ -49,20 +63,57 @@ impl VertexAIClient {
         let url = build_url(&base_url, &self.model.name, model_category, data.stream)?;
 
         let block_threshold = self.config.block_threshold.clone();
-        let body = build_body(data, &self.model, model_category, block_threshold)?;
+        let mut body = build_body(data, &self.model, model_category, block_threshold)?;
+        self.model.merge_extra_fields(&mut body);
 
         debug!("VertexAI Request: {url} {body}");
 
         let builder = client
             .post(url)
-            .bearer_auth(unsafe { &ACCESS_TOKEN.0 })
+            .bearer_auth(&ACCESS_TOKEN.read().0)
+            .json(&body);
+
+        Ok(builder)
+    }
+
+    fn embeddings_builder(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<RequestBuilder> {
+        let project_id = self.get_project_id()?;
+        let location = self.get_location()?;
+
+        let base_url = format!("https://{location}-aiplatform.googleapis.com/v1/projects/{project_id}/locations/{location}/publishers");
+        let url = format!("{base_url}/google/models/{}:predict", self.model.name);
+
+        let task_type = match data.query {
+            true => "RETRIEVAL_QUERY",
+            false => "RETRIEVAL_DOCUMENT",
+        };
+        let instances: Vec<Value> = data
+            .texts
+            .into_iter()
+            .map(|text| json!({ "content": text, "task_type": task_type }))
+            .collect();
+        let body = json!({ "instances": instances });
+
+        debug!("VertexAI Embeddings Request: {url} {body}");
+
+        let builder = client
+            .post(url)
+            .bearer_auth(&ACCESS_TOKEN.read().0)
             .json(&body);
 
         Ok(builder)
     }
 
     async fn prepare_access_token(&self) -> Result<()> {
-        if unsafe { ACCESS_TOKEN.0.is_empty() || Utc::now().timestamp() > ACCESS_TOKEN.1 } {
+        let expired = {
+            let access_token = ACCESS_TOKEN.read();
+            access_token.0.is_empty() || Utc::now().timestamp() > access_token.1
+        };
+        if expired {
             let client = self.build_client()?;
             let (token, expires_in) = fetch_access_token(&client, &self.config.adc_file)
                 .await
@@ -70,7 +121,7 @@ impl VertexAIClient {
             let expires_at = Utc::now()
                 + Duration::try_seconds(expires_in)
                     .ok_or_else(|| anyhow!("Failed to parse expires_in of access_token"))?;
-            unsafe { ACCESS_TOKEN = (token, expires_at.timestamp()) };
+            *ACCESS_TOKEN.write() = (token, expires_at.timestamp());
         }
         Ok(())
     }
@@ -108,15 +159,42 @@ impl Client for VertexAIClient {
             ModelCategory::Claude => claude_send_message_streaming(builder, handler).await,
         }
     }
+
+    async fn request_inner(
+        &self,
+        client: &ReqwestClient,
+        data: SendData,
+    ) -> Result<RequestBuilder> {
+        let model_category = ModelCategory::from_str(&self.model.name)?;
+        self.prepare_access_token().await?;
+        self.request_builder(client, data, &model_category)
+    }
+
+    async fn embeddings_inner(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        self.prepare_access_token().await?;
+        let builder = self.embeddings_builder(client, data)?;
+        let res = send_request(builder).await?;
+        let data: Value = res.json().await?;
+        let predictions = &data["predictions"];
+        let input_tokens = predictions.as_array().map(|list| {
+            list.iter()
+                .filter_map(|v| v["embeddings"]["statistics"]["token_count"].as_u64())
+                .sum()
+        });
+        Ok(EmbeddingsOutput {
+            vectors: extract_vectors(predictions, "/embeddings/values")?,
+            input_tokens,
+        })
+    }
 }
 
 pub async fn gemini_send_message(builder: RequestBuilder) -> Result<(String, CompletionDetails)> {
-    let res = builder.send().await?;
-    let status = res.status();
+    let res = send_request(builder).await?;
     let data: Value = res.json().await?;
-    if !status.is_success() {
-        catch_error(&data, status.as_u16())?;
-    }
     gemini_extract_completion_text(&data)
 }
 
@@ -124,35 +202,95 @@ pub async fn gemini_send_message_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let res = builder.send().await?;
-    let status = res.status();
-    if !status.is_success() {
-        let data: Value = res.json().await?;
-        catch_error(&data, status.as_u16())?;
-    } else {
-        let handle = |value: &str| -> Result<()> {
-            let value: Value = serde_json::from_str(value)?;
-            handler.text(gemini_extract_text(&value)?)?;
-            Ok(())
-        };
-        json_stream(res.bytes_stream(), handle).await?;
-    }
+    let res = send_request(builder).await?;
+    let handle = |value: &str| -> Result<()> {
+        let value: Value = serde_json::from_str(value)?;
+        let thoughts = gemini_extract_thoughts(&value);
+        if !thoughts.is_empty() {
+            handler.thinking(&thoughts)?;
+        }
+        handler.text(&gemini_extract_text(&value)?)?;
+        for call in gemini_extract_tool_calls(&value) {
+            handler.tool_call(call);
+        }
+        // Each chunk has the counts so far
+        let usage = &value["usageMetadata"];
+        handler.usage(
+            usage["promptTokenCount"].as_u64(),
+            usage["candidatesTokenCount"].as_u64(),
+        )?;
+        handler.meta(gemini_extract_meta(usage))?;
+        Ok(())
+    };
+    json_stream(res.bytes_stream(), handle).await?;
     Ok(())
 }
 
+fn gemini_extract_thoughts(data: &Value) -> String {
+    data["candidates"][0]["content"]["parts"]
+        .as_array()
+        .map(|parts| {
+            parts
+                .iter()
+                .filter(|v| v["thought"] == Value::Bool(true))
+                .filter_map(|v| v["text"].as_str())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 fn gemini_extract_completion_text(data: &Value) -> Result<(String, CompletionDetails)> {
     let text = gemini_extract_text(data)?;
     let details = CompletionDetails {
         id: None,
         input_tokens: data["usageMetadata"]["promptTokenCount"].as_u64(),
         output_tokens: data["usageMetadata"]["candidatesTokenCount"].as_u64(),
+        tool_calls: gemini_extract_tool_calls(data),
+        meta: gemini_extract_meta(&data["usageMetadata"]),
+        ..Default::default()
     };
-    Ok((text.to_string(), details))
+    Ok((text, details))
 }
 
-fn gemini_extract_text(data: &Value) -> Result<&str> {
-    match data["candidates"][0]["content"]["parts"][0]["text"].as_str() {
-        Some(text) => Ok(text),
+/// The tokens read from the context cache, implicit or explicit.
+fn gemini_extract_meta(usage: &Value) -> ResponseMeta {
+    ResponseMeta {
+        cached_tokens: usage["cachedContentTokenCount"].as_u64(),
+        ..Default::default()
+    }
+}
+
+/// The `functionCall` parts, which come whole rather than in pieces when streaming.
+/// Gemini may leave out the ids, the name is used instead.
+fn gemini_extract_tool_calls(data: &Value) -> Vec<ToolCall> {
+    data["candidates"][0]["content"]["parts"]
+        .as_array()
+        .map(|parts| {
+            parts
+                .iter()
+                .filter_map(|v| v.get("functionCall"))
+                .map(|call| {
+                    let name = call["name"].as_str().unwrap_or_default().to_string();
+                    ToolCall {
+                        id: call["id"].as_str().unwrap_or(&name).to_string(),
+                        name,
+                        arguments: call["args"].clone(),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The text of the parts that are not thoughts.
+fn gemini_extract_text(data: &Value) -> Result<String> {
+    let parts = data["candidates"][0]["content"]["parts"].as_array();
+    match parts.filter(|v| !v.is_empty()) {
+        Some(parts) => Ok(parts
+            .iter()
+            .filter(|v| v["thought"] != Value::Bool(true))
+            .filter_map(|v| v["text"].as_str())
+            .collect()),
         None => {
             if let Some("SAFETY") = data["promptFeedback"]["blockReason"]
                 .as_str()
@@ -215,13 +353,23 @@ pub(crate) fn gemini_build_body(
         mut messages,
         temperature,
         top_p,
+        seed,
+        frequency_penalty,
+        presence_penalty,
+        stop,
+        logprobs: _,
+        functions,
+        tool_rounds,
+        thinking,
+        tags: _,
+        schema,
         stream: _,
     } = data;
 
     patch_system_message(&mut messages);
 
     let mut network_image_urls = vec![];
-    let contents: Vec<Value> = messages
+    let mut contents: Vec<Value> = messages
         .into_iter()
         .map(|message| {
             let role = match message.role {
@@ -261,6 +409,13 @@ pub(crate) fn gemini_build_body(
         );
     }
 
+    contents.extend(
+        tool_rounds
+            .iter()
+            .flat_map(|v| &v.results)
+            .flat_map(gemini_tool_contents),
+    );
+
     let mut body = json!({ "contents": contents, "generationConfig": {} });
 
     if let Some(block_threshold) = block_threshold {
@@ -281,10 +436,78 @@ pub(crate) fn gemini_build_body(
     if let Some(v) = top_p {
         body["generationConfig"]["topP"] = v.into();
     }
+    if let Some(v) = seed {
+        body["generationConfig"]["seed"] = v.into();
+    }
+    if let Some(v) = frequency_penalty {
+        body["generationConfig"]["frequencyPenalty"] = v.into();
+    }
+    if let Some(v) = presence_penalty {
+        body["generationConfig"]["presencePenalty"] = v.into();
+    }
+    if !stop.is_empty() {
+        body["generationConfig"]["stopSequences"] = stop.into();
+    }
+    if let Some(thinking) = thinking {
+        body["generationConfig"]["thinkingConfig"] = json!({
+            "thinkingBudget": thinking.budget_tokens(),
+            "includeThoughts": true,
+        });
+    }
+    if let Some(functions) = functions {
+        body["tools"] = json!([{ "functionDeclarations": functions }]);
+    }
+    if let Some(mut schema) = schema {
+        strip_schema_keywords(&mut schema);
+        body["generationConfig"]["responseMimeType"] = "application/json".into();
+        body["generationConfig"]["responseSchema"] = schema;
+    }
 
     Ok(body)
 }
 
+/// The `responseSchema` of Gemini is a subset of OpenAPI, rejecting the JSON schema keywords
+/// it doesn't know.
+fn strip_schema_keywords(schema: &mut Value) {
+    match schema {
+        Value::Object(obj) => {
+            for key in ["$schema", "$id", "additionalProperties"] {
+                obj.remove(key);
+            }
+            if let Some(Value::Object(properties)) = obj.get_mut("properties") {
+                properties.values_mut().for_each(strip_schema_keywords);
+            }
+            for key in ["items", "anyOf"] {
+                if let Some(value) = obj.get_mut(key) {
+                    strip_schema_keywords(value);
+                }
+            }
+        }
+        Value::Array(list) => list.iter_mut().for_each(strip_schema_keywords),
+        _ => {}
+    }
+}
+
+/// Replay a tool call as a `functionCall` part of the model and its result as a
+/// `functionResponse` part, whose response has to be an object.
+fn gemini_tool_contents(result: &ToolResult) -> [Value; 2] {
+    let ToolResult { call, output } = result;
+    let response = match output {
+        Value::Object(_) => output.clone(),
+        _ => json!({ "content": output }),
+    };
+    [
+        json!({
+            "role": "model",
+            "parts": [{ "functionCall": { "name": call.name, "args": call.arguments } }]
+        }),
+        json!({
+            "role": "user",
+            "parts": [{ "functionResponse": { "name": call.name, "response": response } }]
+        }),
+    ]
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ModelCategory {
     Gemini,
@@ -369,3 +592,57 @@ fn default_adc_file() -> Option<PathBuf> {
     path.push("application_default_credentials.json");
     Some(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gemini_extract_completion() {
+        let data = json!({
+            "candidates": [{
+                "content": {
+                    "role": "model",
+                    "parts": [
+                        { "functionCall": { "name": "calculator", "args": { "expression": "1+1" } } }
+                    ]
+                }
+            }]
+        });
+        let (text, details) = gemini_extract_completion_text(&data).unwrap();
+        assert_eq!(text, "");
+        assert_eq!(
+            details.tool_calls,
+            vec![ToolCall {
+                id: "calculator".into(),
+                name: "calculator".into(),
+                arguments: json!({ "expression": "1+1" }),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_strip_schema_keywords() {
+        let mut schema = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "tags": {
+                    "type": "array",
+                    "items": { "type": "object", "additionalProperties": false }
+                }
+            },
+            "additionalProperties": false
+        });
+        strip_schema_keywords(&mut schema);
+        assert_eq!(
+            schema,
+            json!({
+                "type": "object",
+                "properties": {
+                    "tags": { "type": "array", "items": { "type": "object" } }
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use super::openai::{openai_include_usage, OpenAILikeRequests};
+use super::{ExtraConfig, Model, ModelConfig, PromptAction, PromptKind, SendData, XAIClient};
+
+use anyhow::Result;
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+
+const API_BASE: &str = "https://api.x.ai/v1";
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct XAIConfig {
+    pub name: Option<String>,
+    pub api_key: Option<String>,
+    pub api_base: Option<String>,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+    pub extra: Option<ExtraConfig>,
+}
+
+impl XAIClient {
+    config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_base, get_api_base);
+
+    pub const PROMPTS: [PromptAction<'static>; 1] =
+        [("api_key", "API Key:", true, PromptKind::String)];
+
+    pub fn api_base(config: &XAIConfig) -> Option<String> {
+        config.api_base.clone().or_else(|| Some(API_BASE.into()))
+    }
+
+    fn requests(&self) -> Result<OpenAILikeRequests> {
+        let api_key = self.get_api_key()?;
+        let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
+        Ok(OpenAILikeRequests::bearer("XAI", api_base, Some(api_key)))
+    }
+
+    fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
+        self.requests()?
+            .chat(client, &self.model, data, xai_filter_body)
+    }
+
+    fn models_builder(&self, client: &ReqwestClient) -> Result<RequestBuilder> {
+        self.requests()?.models(client)
+    }
+}
+
+impl_client_trait!(
+    XAIClient,
+    crate::client::openai::openai_send_message,
+    crate::client::openai::openai_send_message_streaming;
+    models = crate::client::openai::openai_models
+);
+
+/// Only the mini models of Grok take `reasoning_effort`, low or high, and stream their thinking
+/// as `reasoning_content`. Grok 4 always thinks, and rejects the penalties and the stop
+/// sequences.
+fn xai_filter_body(body: &mut Value, model: &Model) {
+    openai_include_usage(body);
+    if let Some(body) = body.as_object_mut() {
+        if model.name.contains("mini") {
+            if let Some(effort) = body.get_mut("reasoning_effort") {
+                if effort == "medium" {
+                    *effort = "high".into();
+                }
+            }
+        } else {
+            body.remove("reasoning_effort");
+        }
+        if model.name.starts_with("grok-4") {
+            for key in ["presence_penalty", "frequency_penalty", "stop"] {
+                body.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{openai::openai_build_body, test_send_data, Thinking};
+    use serde_json::json;
+
+    fn build_body(model_name: &str) -> Value {
+        let data = SendData {
+            presence_penalty: Some(0.5),
+            stop: vec!["END".into()],
+            thinking: Some(Thinking::Medium),
+            ..test_send_data("Hi")
+        };
+        let model = Model::new("xai", model_name);
+        let mut body = openai_build_body(data, &model);
+        xai_filter_body(&mut body, &model);
+        body
+    }
+
+    #[test]
+    fn test_xai_filter_body() {
+        let body = build_body("grok-3-mini");
+        assert_eq!(body["reasoning_effort"], "high");
+        assert_eq!(body["stop"], json!(["END"]));
+        let body = build_body("grok-4");
+        assert_eq!(
+            body,
+            json!({
+                "model": "grok-4",
+                "messages": [{ "role": "user", "content": "Hi" }],
+            })
+        );
+    }
+}
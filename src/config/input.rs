@@ -1,8 +1,10 @@
 use super::role::Role;
 use super::session::Session;
+use super::PromptCompression;
 
 use crate::client::{ImageUrl, MessageContent, MessageContentPart, ModelCapabilities};
-use crate::utils::{base64_encode, sha256};
+use crate::function::ToolRound;
+use crate::utils::{base64_encode, prune_text, sha256, Encoding};
 
 use anyhow::{bail, Context, Result};
 use fancy_regex::Regex;
@@ -18,8 +20,16 @@ use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 const IMAGE_EXTS: [&str; 5] = ["png", "jpeg", "jpg", "webp", "gif"];
 
+/// The files whose words all count, they are never pruned.
+const CODE_EXTS: [&str; 40] = [
+    "c", "cc", "cpp", "cs", "css", "dart", "ex", "go", "h", "hpp", "html", "java", "js", "json",
+    "jsx", "kt", "lua", "m", "php", "pl", "proto", "ps1", "py", "r", "rb", "rs", "scala", "scss",
+    "sh", "sql", "swift", "toml", "ts", "tsx", "vue", "xml", "yaml", "yml", "zig", "zsh",
+];
+
 lazy_static! {
     static ref URL_RE: Regex = Regex::new(r"^[A-Za-z0-9_-]{2,}:/").unwrap();
+    static ref VARIABLE_RE: Regex = Regex::new(r"%\{([A-Za-z_][A-Za-z0-9_-]*)\}").unwrap();
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +37,7 @@ pub struct Input {
     text: String,
     medias: Vec<String>,
     data_urls: HashMap<String, String>,
+    tool_rounds: Vec<ToolRound>,
     context: InputContext,
 }
 
@@ -36,6 +47,7 @@ impl Input {
             text: text.to_string(),
             medias: Default::default(),
             data_urls: Default::default(),
+            tool_rounds: vec![],
             context,
         }
     }
@@ -44,10 +56,7 @@ impl Input {
         let mut texts = vec![text.to_string()];
         let mut medias = vec![];
         let mut data_urls = HashMap::new();
-        let files: Vec<_> = files
-            .iter()
-            .map(|f| (f, is_image_ext(Path::new(f))))
-            .collect();
+        let files: Vec<_> = files.iter().map(|f| (f, is_image(f))).collect();
         let include_filepath = files.iter().filter(|(_, is_image)| !*is_image).count() > 1;
         for (file_item, is_image) in files {
             match resolve_local_file(file_item) {
@@ -60,6 +69,7 @@ impl Input {
                     } else {
                         let text = read_file(&file_path)
                             .with_context(|| format!("Unable to read file '{file_item}'"))?;
+                        let text = context.compress(&file_path, text);
                         if include_filepath {
                             texts.push(format!("`{file_item}`:\n~~~~~~\n{text}\n~~~~~~"));
                         } else {
@@ -81,6 +91,7 @@ impl Input {
             text: texts.join("\n"),
             medias,
             data_urls,
+            tool_rounds: vec![],
             context,
         })
     }
@@ -101,6 +112,36 @@ impl Input {
         self.text = text;
     }
 
+    /// Names of `%{name}` placeholders used in the text or the role prompt
+    pub fn variable_names(&self) -> Vec<String> {
+        let mut names = vec![];
+        let prompt = self.context.role.as_ref().map(|v| v.prompt.as_str());
+        for text in [Some(self.text.as_str()), prompt].into_iter().flatten() {
+            for name in extract_variable_names(text) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    pub fn fill_variables(&mut self, variables: &HashMap<String, String>) {
+        self.text = fill_variables(&self.text, variables);
+        if let Some(role) = self.context.role.as_mut() {
+            role.prompt = fill_variables(&role.prompt, variables);
+        }
+    }
+
+    /// The rounds of tool calls made so far while answering this input, with their results.
+    pub fn tool_rounds(&self) -> &[ToolRound] {
+        &self.tool_rounds
+    }
+
+    pub fn add_tool_round(&mut self, round: ToolRound) {
+        self.tool_rounds.push(round);
+    }
+
     pub fn role(&self) -> Option<&Role> {
         self.context.role.as_ref()
     }
@@ -188,11 +229,15 @@ impl Input {
     }
 
     pub fn required_capabilities(&self) -> ModelCapabilities {
-        if !self.medias.is_empty() {
+        let mut capabilities = if !self.medias.is_empty() {
             ModelCapabilities::Vision
         } else {
             ModelCapabilities::Text
+        };
+        if self.role().is_some_and(|v| !v.functions.is_empty()) {
+            capabilities |= ModelCapabilities::FunctionCalling;
         }
+        capabilities
     }
 }
 
@@ -200,11 +245,35 @@ impl Input {
 pub struct InputContext {
     role: Option<Role>,
     in_session: bool,
+    prompt_compression: PromptCompression,
 }
 
 impl InputContext {
     pub fn new(role: Option<Role>, in_session: bool) -> Self {
-        Self { role, in_session }
+        Self {
+            role,
+            in_session,
+            prompt_compression: Default::default(),
+        }
+    }
+
+    pub fn with_prompt_compression(mut self, prompt_compression: PromptCompression) -> Self {
+        self.prompt_compression = prompt_compression;
+        self
+    }
+
+    /// Prune a large file content down to the configured ratio of its words, unless it's code.
+    fn compress(&self, path: &Path, text: String) -> String {
+        let PromptCompression { ratio, min_tokens } = self.prompt_compression;
+        if has_ext(path, &CODE_EXTS) {
+            return text;
+        }
+        match ratio {
+            Some(ratio) if Encoding::Cl100kBase.count_tokens(&text) > min_tokens => {
+                prune_text(&text, ratio)
+            }
+            _ => text,
+        }
     }
 }
 
@@ -220,6 +289,24 @@ pub fn resolve_data_url(data_urls: &HashMap<String, String>, data_url: String) -
     }
 }
 
+fn extract_variable_names(text: &str) -> Vec<String> {
+    VARIABLE_RE
+        .captures_iter(text)
+        .filter_map(|cap| cap.ok()?.get(1).map(|v| v.as_str().to_string()))
+        .collect()
+}
+
+fn fill_variables(text: &str, variables: &HashMap<String, String>) -> String {
+    VARIABLE_RE
+        .replace_all(text, |caps: &fancy_regex::Captures| {
+            variables
+                .get(&caps[1])
+                .cloned()
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+        .to_string()
+}
+
 fn resolve_local_file(file: &str) -> Option<PathBuf> {
     if let Ok(true) = URL_RE.is_match(file) {
         return None;
@@ -232,11 +319,19 @@ fn resolve_local_file(file: &str) -> Option<PathBuf> {
     Some(path)
 }
 
-fn is_image_ext(path: &Path) -> bool {
+/// Whether the file or URL is an image by its extension, the query of a URL aside.
+fn is_image(file: &str) -> bool {
+    let path = match URL_RE.is_match(file) {
+        Ok(true) => file.split(['?', '#']).next().unwrap_or(file),
+        _ => file,
+    };
+    has_ext(Path::new(path), &IMAGE_EXTS)
+}
+
+fn has_ext(path: &Path, exts: &[&str]) -> bool {
     path.extension()
         .map(|v| {
-            IMAGE_EXTS
-                .iter()
+            exts.iter()
                 .any(|ext| *ext == v.to_string_lossy().to_lowercase())
         })
         .unwrap_or_default()
@@ -264,3 +359,45 @@ fn read_file<P: AsRef<Path>>(file_path: P) -> Result<String> {
     file.read_to_string(&mut text)?;
     Ok(text)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_variables() {
+        let variables: HashMap<String, String> = [("lang".to_string(), "Rust".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            extract_variable_names("Write %{lang} code for %{task}"),
+            ["lang", "task"]
+        );
+        assert_eq!(
+            fill_variables("Write %{lang} code for %{task}", &variables),
+            "Write Rust code for %{task}"
+        );
+    }
+
+    #[test]
+    fn test_is_image() {
+        assert!(is_image("photo.PNG"));
+        assert!(is_image("https://example.com/cat.jpg?size=large"));
+        assert!(!is_image("notes.md"));
+        assert!(!is_image("https://example.com/image"));
+    }
+
+    #[test]
+    fn test_compress() {
+        let context = InputContext::default().with_prompt_compression(PromptCompression {
+            ratio: Some(0.5),
+            min_tokens: 0,
+        });
+        let text = "The quick brown fox jumps over the lazy dog\n".repeat(20);
+        assert!(context.compress(Path::new("notes.md"), text.clone()).len() < text.len());
+        assert_eq!(
+            context.compress(Path::new("src/main.RS"), text.clone()),
+            text
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use anyhow::{anyhow, bail, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use indexmap::IndexMap;
+use serde::{de, Deserialize, Deserializer};
+use std::str::FromStr;
+
+/// The keybinding style of the REPL, and the keys remapped by the user.
+///
+/// Either a style, `keybindings: vi`, or a table of the style and the actions to remap:
+///
+/// ```yaml
+/// keybindings:
+///   mode: emacs
+///   newline: [ctrl-enter, alt-enter]
+///   editor: ctrl-e
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Keybindings {
+    pub mode: KeybindingsMode,
+    bindings: IndexMap<KeyAction, Vec<KeyCombo>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum KeybindingsMode {
+    #[serde(rename = "emacs")]
+    #[default]
+    Emacs,
+    #[serde(rename = "vi")]
+    Vi,
+}
+
+impl Keybindings {
+    pub fn is_vi(&self) -> bool {
+        matches!(self.mode, KeybindingsMode::Vi)
+    }
+
+    pub fn stringify(&self) -> &str {
+        match self.mode {
+            KeybindingsMode::Emacs => "emacs",
+            KeybindingsMode::Vi => "vi",
+        }
+    }
+
+    /// The actions remapped by the user, with their keys.
+    pub fn bindings(&self) -> impl Iterator<Item = (KeyAction, &[KeyCombo])> {
+        self.bindings.iter().map(|(k, v)| (*k, v.as_slice()))
+    }
+
+    /// The keys of the action, the remapped ones or the defaults.
+    pub fn keys(&self, action: KeyAction) -> Vec<KeyCombo> {
+        match self.bindings.get(&action) {
+            Some(keys) => keys.clone(),
+            None => action.default_keys(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Keybindings {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Mode(String),
+            Table(IndexMap<String, KeyList>),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum KeyList {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let parse_mode = |value: &str| match value {
+            "emacs" => Ok(KeybindingsMode::Emacs),
+            "vi" => Ok(KeybindingsMode::Vi),
+            _ => Err(de::Error::custom(format!(
+                "Invalid keybindings '{value}', expect emacs or vi"
+            ))),
+        };
+        let mut keybindings = Keybindings::default();
+        match Raw::deserialize(deserializer)? {
+            Raw::Mode(value) => keybindings.mode = parse_mode(&value)?,
+            Raw::Table(table) => {
+                for (name, keys) in table {
+                    let keys = match keys {
+                        KeyList::One(key) => vec![key],
+                        KeyList::Many(keys) => keys,
+                    };
+                    if name == "mode" {
+                        keybindings.mode = parse_mode(keys.first().map_or("", |v| v.as_str()))?;
+                        continue;
+                    }
+                    let action = name.parse::<KeyAction>().map_err(de::Error::custom)?;
+                    let keys = keys
+                        .iter()
+                        .map(|v| v.parse::<KeyCombo>())
+                        .collect::<Result<Vec<_>>>()
+                        .map_err(|err| de::Error::custom(format!("{err} of `{name}`")))?;
+                    keybindings.bindings.insert(action, keys);
+                }
+            }
+        }
+        Ok(keybindings)
+    }
+}
+
+/// The REPL actions that can be bound to other keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyAction {
+    Submit,
+    Newline,
+    Editor,
+    Abort,
+    MenuNext,
+    MenuPrevious,
+    HistoryMenu,
+}
+
+impl KeyAction {
+    const ALL: [(KeyAction, &'static str); 7] = [
+        (KeyAction::Submit, "submit"),
+        (KeyAction::Newline, "newline"),
+        (KeyAction::Editor, "editor"),
+        (KeyAction::Abort, "abort"),
+        (KeyAction::MenuNext, "menu_next"),
+        (KeyAction::MenuPrevious, "menu_previous"),
+        (KeyAction::HistoryMenu, "history_menu"),
+    ];
+
+    pub fn default_keys(&self) -> Vec<KeyCombo> {
+        let key = |modifiers, code| KeyCombo { modifiers, code };
+        match self {
+            KeyAction::Submit => vec![key(KeyModifiers::NONE, KeyCode::Enter)],
+            KeyAction::Newline => vec![key(KeyModifiers::CONTROL, KeyCode::Enter)],
+            KeyAction::Editor => vec![key(KeyModifiers::CONTROL, KeyCode::Char('o'))],
+            KeyAction::Abort => vec![key(KeyModifiers::CONTROL, KeyCode::Char('c'))],
+            KeyAction::MenuNext => vec![key(KeyModifiers::NONE, KeyCode::Tab)],
+            KeyAction::MenuPrevious => vec![key(KeyModifiers::SHIFT, KeyCode::BackTab)],
+            KeyAction::HistoryMenu => vec![key(KeyModifiers::CONTROL, KeyCode::Char('r'))],
+        }
+    }
+}
+
+impl FromStr for KeyAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        KeyAction::ALL
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(action, _)| *action)
+            .ok_or_else(|| {
+                let names: Vec<_> = KeyAction::ALL.iter().map(|(_, name)| *name).collect();
+                anyhow!(
+                    "Unknown key action '{s}', expect one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// What Ctrl+C or Ctrl+D does in the REPL.
+///
+/// While a reply is streaming, both keys abort it first, then do the rest of their action.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalAction {
+    /// Abort the reply, nothing more.
+    Abort,
+    /// Abort the reply, at the prompt clear the line and tell how to exit.
+    ClearLine,
+    /// End the current session and stay in the REPL.
+    ExitSession,
+    /// Leave the REPL, ending the session like `.exit`.
+    Exit,
+}
+
+impl SignalAction {
+    const ALL: [(SignalAction, &'static str); 4] = [
+        (SignalAction::Abort, "abort"),
+        (SignalAction::ClearLine, "clear_line"),
+        (SignalAction::ExitSession, "exit_session"),
+        (SignalAction::Exit, "exit"),
+    ];
+
+    pub fn names() -> Vec<&'static str> {
+        SignalAction::ALL.iter().map(|(_, name)| *name).collect()
+    }
+
+    pub fn stringify(&self) -> &'static str {
+        SignalAction::ALL
+            .iter()
+            .find(|(action, _)| action == self)
+            .map(|(_, name)| *name)
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for SignalAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        SignalAction::ALL
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(action, _)| *action)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid value '{s}', expect one of {}",
+                    SignalAction::names().join(", ")
+                )
+            })
+    }
+}
+
+/// A key with its modifiers, written like `ctrl-alt-x`, `shift-tab` or `f2`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyCombo {
+    pub modifiers: KeyModifiers,
+    pub code: KeyCode,
+}
+
+impl KeyCombo {
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        self.code == event.code && self.modifiers == event.modifiers
+    }
+}
+
+impl FromStr for KeyCombo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let value = s.trim().to_lowercase();
+        let (modifier_names, key) = match value.rsplit_once(['-', '+']) {
+            Some((modifiers, "")) => (modifiers.to_string(), "-".to_string()),
+            Some((modifiers, key)) => (modifiers.to_string(), key.to_string()),
+            None => (String::new(), value.clone()),
+        };
+        let mut modifiers = KeyModifiers::NONE;
+        for name in modifier_names.split(['-', '+']).filter(|v| !v.is_empty()) {
+            modifiers |= match name {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => bail!("Invalid modifier '{name}' in key '{s}'"),
+            };
+        }
+        let code = match key.as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "tab" if modifiers.contains(KeyModifiers::SHIFT) => KeyCode::BackTab,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "esc" | "escape" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            _ => {
+                let mut chars = key.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    (Some('f'), Some(_)) => match key[1..].parse::<u8>() {
+                        Ok(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                        _ => bail!("Invalid key '{s}'"),
+                    },
+                    _ => bail!("Invalid key '{s}'"),
+                }
+            }
+        };
+        Ok(Self { modifiers, code })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_combo() {
+        let parse = |v: &str| v.parse::<KeyCombo>().map(|v| (v.modifiers, v.code));
+        assert_eq!(
+            parse("ctrl-alt-x").unwrap(),
+            (
+                KeyModifiers::CONTROL | KeyModifiers::ALT,
+                KeyCode::Char('x')
+            )
+        );
+        assert_eq!(
+            parse("Shift+Tab").unwrap(),
+            (KeyModifiers::SHIFT, KeyCode::BackTab)
+        );
+        assert_eq!(parse("f2").unwrap(), (KeyModifiers::NONE, KeyCode::F(2)));
+        assert_eq!(
+            parse("ctrl--").unwrap(),
+            (KeyModifiers::CONTROL, KeyCode::Char('-'))
+        );
+        assert!(parse("hyper-x").is_err());
+        assert!(parse("f13").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_keybindings() {
+        let keybindings: Keybindings = serde_yaml::from_str("vi").unwrap();
+        assert!(keybindings.is_vi());
+
+        let keybindings: Keybindings =
+            serde_yaml::from_str("mode: vi\nnewline: [alt-enter, ctrl-j]\neditor: ctrl-e").unwrap();
+        assert!(keybindings.is_vi());
+        assert_eq!(keybindings.keys(KeyAction::Newline).len(), 2);
+        assert_eq!(
+            keybindings.keys(KeyAction::Editor),
+            vec![KeyCombo {
+                modifiers: KeyModifiers::CONTROL,
+                code: KeyCode::Char('e')
+            }]
+        );
+        assert_eq!(
+            keybindings.keys(KeyAction::Submit),
+            KeyAction::Submit.default_keys()
+        );
+
+        let err = serde_yaml::from_str::<Keybindings>("jump: ctrl-j").unwrap_err();
+        assert!(err.to_string().starts_with("Unknown key action 'jump'"));
+        let err = serde_yaml::from_str::<Keybindings>("editor: hyper-e").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid modifier 'hyper' in key 'hyper-e' of `editor`"));
+    }
+
+    #[test]
+    fn test_signal_action() {
+        let action: SignalAction = serde_yaml::from_str("exit_session").unwrap();
+        assert_eq!(action, SignalAction::ExitSession);
+        assert_eq!(
+            "clear_line".parse::<SignalAction>().unwrap(),
+            SignalAction::ClearLine
+        );
+        assert_eq!(SignalAction::Exit.stringify(), "exit");
+        assert!("quit".parse::<SignalAction>().is_err());
+    }
+}
@@ -1,26 +1,41 @@
 mod input;
+mod keybindings;
+mod obsidian;
 mod role;
 mod session;
 
 pub use self::input::{Input, InputContext};
+pub use self::keybindings::{KeyAction, KeyCombo, Keybindings, KeybindingsMode, SignalAction};
+use self::obsidian::ObsidianConfig;
 use self::role::Role;
 pub use self::role::{CODE_ROLE, EXPLAIN_ROLE, SHELL_ROLE};
-use self::session::{Session, TEMP_SESSION_NAME};
+pub use self::session::Session;
+use self::session::{
+    is_session_locked, lock_session, prunable_sessions, remove_session_files, unlock_session,
+    SessionFile, TEMP_SESSION_NAME,
+};
 
 use crate::client::{
-    create_client_config, list_client_types, list_models, ClientConfig, Message, Model, SendData,
-    OPENAI_COMPATIBLE_PLATFORMS,
+    create_client_config, find_client_endpoint, find_client_extra_config, init_client_models,
+    is_local_url, list_client_types, list_models, resolve_no_proxy, resolve_proxy, run_usage,
+    ClientConfig, CompletionDetails, Message, MessageContent, MessageRole, MiddlewareConfig, Model,
+    ModelCapabilities, RateLimits, SendData, Thinking, Usage, UsageStats, IMPLICIT_PLATFORMS,
+    OPENAI_COMPATIBLE_PLATFORMS, TOOL_CLIENT_TYPES,
 };
+use crate::function::{declare_functions, BUILTIN_FUNCTIONS, WEB_FUNCTIONS};
 use crate::render::{MarkdownRender, RenderOptions};
 use crate::utils::{
-    format_option_value, fuzzy_match, get_env_name, light_theme_from_colorfgbg, now, render_prompt,
-    set_text,
+    backup_paths, format_option_value, fuzzy_match, get_env_name, is_dumb_terminal,
+    is_network_unreachable, is_no_color, light_theme_from_colorfgbg, now, render_prompt, set_text,
+    write_file_atomic, ColorDepth, Locale,
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use inquire::{Confirm, Select, Text};
 use is_terminal::IsTerminal;
-use parking_lot::RwLock;
+use lazy_static::lazy_static;
+use nu_ansi_term::Color;
+use parking_lot::{Mutex, RwLock};
 use serde::Deserialize;
 use serde_json::json;
 use std::collections::{HashMap, HashSet};
@@ -31,6 +46,7 @@ use std::{
     path::{Path, PathBuf},
     process::exit,
     sync::Arc,
+    time::SystemTime,
 };
 use syntect::highlighting::ThemeSet;
 
@@ -41,24 +57,62 @@ const LIGHT_THEME: &[u8] = include_bytes!("../../assets/monokai-extended-light.t
 const CONFIG_FILE_NAME: &str = "config.yaml";
 const ROLES_FILE_NAME: &str = "roles.yaml";
 const MESSAGES_FILE_NAME: &str = "messages.md";
+const MODELS_FILE_NAME: &str = "models.yaml";
+const HISTORY_FILE_NAME: &str = "history.txt";
+const REPLRC_FILE_NAME: &str = "replrc";
 const SESSIONS_DIR_NAME: &str = "sessions";
+const WEB_SESSION_PREFIX: &str = "web-";
+const MAX_TOP_LOGPROBS: u64 = 20;
+const SESSION_BACKUPS: usize = 3;
 
 const CLIENTS_FIELD: &str = "clients";
 
 const SUMMARIZE_PROMPT: &str =
     "Summarize the discussion briefly in 200 words or less to use as a prompt for future context.";
 const SUMMARY_PROMPT: &str = "This is a summary of the chat history as a recap: ";
+const FOLLOWUP_PROMPT: &str = r#"Based on the question and answer below, suggest 3 short follow-up questions the user is likely to ask next.
+Output one question per line, without numbering or any other text.
+
+Question:
+__QUESTION__
+
+Answer:
+__ANSWER__"#;
+const MAX_FOLLOWUPS: usize = 3;
+const PREFETCH_PROMPT: &str = r#"Answer the follow-up question of the conversation below.
+
+Conversation:
+__CONVERSATION__
+
+Follow-up question:
+__FOLLOWUP__"#;
+
+lazy_static! {
+    static ref WARNED_MODELS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
 const LEFT_PROMPT: &str = "{color.green}{?session {session}{?role /}}{role}{color.cyan}{?session )}{!session >}{color.reset} ";
-const RIGHT_PROMPT: &str = "{color.purple}{?session {?consume_tokens {consume_tokens}({consume_percent}%)}{!consume_tokens {consume_tokens}}}{color.reset}";
+const RIGHT_PROMPT: &str = "{color.purple}{?session {consume_tokens}{?consume_percent ({consume_percent}%)} }{color.dark_gray}{model}{color.reset}";
+const BANNER: &str = "Welcome to aichat {version}\nType \".help\" for additional help.";
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct Config {
     #[serde(rename(serialize = "model", deserialize = "model"))]
     pub model_id: Option<String>,
+    pub fallback_models: Vec<String>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub thinking: Option<Thinking>,
+    pub seed: Option<u64>,
+    pub frequency_penalty: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub stop: Vec<String>,
+    pub logprobs: Option<u64>,
     pub dry_run: bool,
+    pub stream: bool,
+    pub stream_chars_per_sec: Option<usize>,
+    pub show_thinking: bool,
+    pub stream_flush: StreamFlush,
     pub save: bool,
     pub save_session: Option<bool>,
     pub highlight: bool,
@@ -66,14 +120,55 @@ pub struct Config {
     pub wrap: Option<String>,
     pub wrap_code: bool,
     pub auto_copy: bool,
+    pub pager: bool,
+    pub include_datetime: bool,
+    pub run_code: bool,
+    pub run_code_timeout: u64,
+    pub run_code_network: bool,
+    pub fs_roots: Vec<String>,
+    pub fetch: FetchConfig,
+    pub prompt_compression: PromptCompression,
+    pub obsidian: ObsidianConfig,
     pub keybindings: Keybindings,
+    pub ctrl_c: SignalAction,
+    pub ctrl_d: SignalAction,
     pub prelude: Option<String>,
     pub buffer_editor: Option<String>,
+    pub history_file: Option<String>,
+    pub history_max_entries: usize,
+    pub history_ignore_space: bool,
     pub compress_threshold: usize,
+    pub compress_target_tokens: Option<usize>,
+    pub compress_keep_turns: usize,
     pub summarize_prompt: Option<String>,
     pub summary_prompt: Option<String>,
+    pub repeat_guard: RepeatGuard,
+    pub session_conflict: SessionConflict,
+    pub session_backups: usize,
+    pub session_retention: SessionRetention,
+    pub suggest_followups: bool,
+    pub followup_model: Option<String>,
+    pub prefetch_followups: bool,
     pub left_prompt: Option<String>,
     pub right_prompt: Option<String>,
+    pub banner: Option<String>,
+    pub variables: HashMap<String, String>,
+    pub locale: Option<String>,
+    pub currency: Option<String>,
+    pub exchange_rates: HashMap<String, f64>,
+    pub serve: ServeConfig,
+    pub repl_theme: ReplTheme,
+    pub offline: Option<bool>,
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub user_agent: Option<String>,
+    pub tags: HashMap<String, String>,
+    pub concurrency: Option<usize>,
+    pub rpm: Option<u64>,
+    pub tpm: Option<u64>,
+    pub extra_body: serde_json::Map<String, serde_json::Value>,
+    pub model_overrides: HashMap<String, ModelOverride>,
+    pub middlewares: Vec<MiddlewareConfig>,
     pub clients: Vec<ClientConfig>,
     #[serde(skip)]
     pub roles: Vec<Role>,
@@ -87,14 +182,35 @@ pub struct Config {
     pub working_mode: WorkingMode,
     #[serde(skip)]
     pub last_message: Option<(Input, String)>,
+    #[serde(skip)]
+    pub followups: Vec<String>,
+    #[serde(skip)]
+    pub session_lock: Option<PathBuf>,
+    #[serde(skip)]
+    pub offline_mode: bool,
+    #[serde(skip)]
+    pub fetched_models: Vec<Model>,
+    #[serde(skip)]
+    pub schema: Option<serde_json::Value>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             model_id: None,
+            fallback_models: vec![],
             temperature: None,
             top_p: None,
+            thinking: None,
+            seed: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            stop: vec![],
+            logprobs: None,
+            stream: true,
+            stream_chars_per_sec: None,
+            show_thinking: true,
+            stream_flush: StreamFlush::Token,
             save: false,
             save_session: None,
             highlight: true,
@@ -103,14 +219,55 @@ impl Default for Config {
             wrap: None,
             wrap_code: false,
             auto_copy: false,
+            pager: true,
+            include_datetime: false,
+            run_code: false,
+            run_code_timeout: 10,
+            run_code_network: false,
+            fs_roots: vec![],
+            fetch: Default::default(),
+            prompt_compression: Default::default(),
+            obsidian: Default::default(),
             keybindings: Default::default(),
+            ctrl_c: SignalAction::ClearLine,
+            ctrl_d: SignalAction::Exit,
             prelude: None,
             buffer_editor: None,
+            history_file: None,
+            history_max_entries: 1000,
+            history_ignore_space: true,
             compress_threshold: 2000,
+            compress_target_tokens: None,
+            compress_keep_turns: 0,
             summarize_prompt: None,
             summary_prompt: None,
+            repeat_guard: RepeatGuard::Ask,
+            session_conflict: SessionConflict::Ask,
+            session_backups: SESSION_BACKUPS,
+            session_retention: Default::default(),
+            suggest_followups: false,
+            followup_model: None,
+            prefetch_followups: false,
             left_prompt: None,
             right_prompt: None,
+            banner: None,
+            variables: Default::default(),
+            locale: None,
+            currency: None,
+            exchange_rates: HashMap::new(),
+            serve: Default::default(),
+            repl_theme: Default::default(),
+            offline: None,
+            proxy: None,
+            no_proxy: None,
+            user_agent: None,
+            tags: HashMap::new(),
+            concurrency: None,
+            rpm: None,
+            tpm: None,
+            extra_body: Default::default(),
+            model_overrides: HashMap::new(),
+            middlewares: vec![],
             clients: vec![],
             roles: vec![],
             role: None,
@@ -118,6 +275,11 @@ impl Default for Config {
             model: Default::default(),
             working_mode: WorkingMode::Command,
             last_message: None,
+            followups: vec![],
+            session_lock: None,
+            offline_mode: false,
+            fetched_models: vec![],
+            schema: None,
         }
     }
 }
@@ -127,13 +289,14 @@ pub type GlobalConfig = Arc<RwLock<Config>>;
 impl Config {
     pub fn init(working_mode: WorkingMode) -> Result<Self> {
         let config_path = Self::config_file()?;
+        init_client_models(&Self::models_file()?)?;
 
         let platform = env::var(get_env_name("platform")).ok();
         if working_mode != WorkingMode::Command && platform.is_none() && !config_path.exists() {
             create_config_file(&config_path)?;
         }
-        let mut config = if platform.is_some() {
-            Self::load_config_env(&platform.unwrap())?
+        let mut config = if let Some(platform) = &platform {
+            Self::load_config_env(platform)?
         } else {
             Self::load_config_file(&config_path)?
         };
@@ -145,7 +308,10 @@ impl Config {
         config.working_mode = working_mode;
         config.load_roles()?;
 
+        config.setup_implicit_clients()?;
         config.setup_model()?;
+        config.check_fallback_models()?;
+        config.setup_offline()?;
         config.setup_highlight();
         config.setup_light_theme()?;
 
@@ -268,17 +434,38 @@ impl Config {
         )
     }
 
+    /// The dot-commands run when the REPL starts.
+    pub fn replrc_file() -> Result<PathBuf> {
+        let env_name = get_env_name("replrc_file");
+        env::var(env_name).map_or_else(
+            |_| Self::local_path(REPLRC_FILE_NAME),
+            |value| Ok(PathBuf::from(value)),
+        )
+    }
+
     pub fn messages_file() -> Result<PathBuf> {
         Self::local_path(MESSAGES_FILE_NAME)
     }
 
+    /// Overrides of the models in the embedded `models.yaml`, with the same layout.
+    pub fn models_file() -> Result<PathBuf> {
+        Self::local_path(MODELS_FILE_NAME)
+    }
+
+    pub fn history_file(&self) -> Result<PathBuf> {
+        match &self.history_file {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Self::local_path(HISTORY_FILE_NAME),
+        }
+    }
+
     pub fn sessions_dir() -> Result<PathBuf> {
         Self::local_path(SESSIONS_DIR_NAME)
     }
 
     pub fn session_file(name: &str) -> Result<PathBuf> {
         let mut path = Self::sessions_dir()?;
-        path.push(&format!("{name}.yaml"));
+        path.push(format!("{name}.yaml"));
         Ok(path)
     }
 
@@ -302,6 +489,35 @@ impl Config {
         Ok(())
     }
 
+    pub fn delete_role(&mut self, name: &str) -> Result<()> {
+        if self
+            .role
+            .as_ref()
+            .map(|v| v.name == name)
+            .unwrap_or_default()
+        {
+            bail!("Cannot delete the current role, please run '.exit role' first.");
+        }
+        let index = self
+            .roles
+            .iter()
+            .position(|v| v.name == name)
+            .ok_or_else(|| anyhow!("Unknown role `{name}`"))?;
+        let ans = Confirm::new(&format!("Delete role '{name}'?"))
+            .with_default(false)
+            .prompt()?;
+        if !ans {
+            return Ok(());
+        }
+        self.roles.remove(index);
+        let path = Self::roles_file()?;
+        let content =
+            serde_yaml::to_string(&self.roles).with_context(|| "Failed to serde roles")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write roles to {}", path.display()))?;
+        Ok(())
+    }
+
     pub fn clear_role(&mut self) -> Result<()> {
         self.role = None;
         Ok(())
@@ -361,18 +577,8 @@ impl Config {
         }
     }
 
-    pub fn echo_messages(&self, input: &Input) -> String {
-        if let Some(session) = input.session(&self.session) {
-            session.echo_messages(input)
-        } else if let Some(role) = input.role() {
-            role.echo_messages(input)
-        } else {
-            input.render()
-        }
-    }
-
     pub fn build_messages(&self, input: &Input) -> Result<Vec<Message>> {
-        let messages = if let Some(session) = input.session(&self.session) {
+        let mut messages = if let Some(session) = input.session(&self.session) {
             session.build_emssages(input)
         } else if let Some(role) = input.role() {
             role.build_messages(input)
@@ -380,6 +586,15 @@ impl Config {
             let message = Message::new(input);
             vec![message]
         };
+        if let Some(suffix) = self
+            .model_override()
+            .and_then(|v| v.system_prompt_suffix.as_deref())
+        {
+            append_system_prompt(&mut messages, suffix);
+        }
+        if self.include_datetime {
+            inject_datetime(&mut messages);
+        }
         Ok(messages)
     }
 
@@ -398,27 +613,123 @@ impl Config {
     }
 
     pub fn set_model(&mut self, value: &str) -> Result<()> {
-        let models = list_models(self);
-        let model = Model::find(&models, value);
+        let model = self.find_model(value);
         match model {
             None => bail!("No model '{}'", value),
             Some(model) => {
+                self.ensure_offline_model(&model)?;
                 if let Some(session) = self.session.as_mut() {
                     session.set_model(model.clone())?;
                 }
+                self.maybe_warn_deprecated_model(&model);
                 self.model = model;
                 Ok(())
             }
         }
     }
 
+    /// The model of the id, or the unlisted model of a client that discovers its models.
+    pub fn find_model(&self, value: &str) -> Option<Model> {
+        let models = self.models();
+        Model::find(&models, value).or_else(|| self.unlisted_model(value))
+    }
+
+    /// A model of `lmstudio`, or of an openai-compatible client without models, takes any name
+    /// since its models are listed by the server's `/models`.
+    fn unlisted_model(&self, value: &str) -> Option<Model> {
+        let (client_name, name) = value.split_once(':')?;
+        if name.is_empty() {
+            return None;
+        }
+        let (client_type, _) = find_client_endpoint(self, client_name)?;
+        let discovers_models = IMPLICIT_PLATFORMS.contains(&client_name)
+            || (client_type == "openai-compatible"
+                && !self.models().iter().any(|v| v.client_name == client_name));
+        if !discovers_models {
+            return None;
+        }
+        Some(Model::new(client_name, name))
+    }
+
+    /// Warn once about each deprecated model that is used.
+    fn maybe_warn_deprecated_model(&self, model: &Model) {
+        let Some(warning) = model.deprecation_warning() else {
+            return;
+        };
+        if WARNED_MODELS.lock().insert(model.id()) {
+            self.print_warning(&warning);
+        }
+    }
+
+    pub fn print_warning(&self, warning: &str) {
+        let warning = format!("⚠️  {warning}");
+        if self.highlight {
+            eprintln!("{}", Color::Yellow.paint(warning));
+        } else {
+            eprintln!("{warning}");
+        }
+    }
+
+    /// The API base of the model's client is on this machine or the local network.
+    pub fn is_local_model(&self, model: &Model) -> bool {
+        find_client_endpoint(self, &model.client_name)
+            .and_then(|(_, api_base)| api_base)
+            .map(|v| is_local_url(&v))
+            .unwrap_or_default()
+    }
+
+    pub fn ensure_offline_model(&self, model: &Model) -> Result<()> {
+        if self.offline_mode && !self.is_local_model(model) {
+            bail!(
+                "Cannot use the cloud model '{}' offline, choose a local model (ollama, or a client whose api_base is local) or run `.set offline false`",
+                model.id()
+            );
+        }
+        Ok(())
+    }
+
+    /// Restrict the models to the local ones, the current model is switched to the first local
+    /// model if it is a cloud model.
+    pub fn set_offline_mode(&mut self, value: bool) -> Result<()> {
+        self.offline_mode = value;
+        if !value || self.is_local_model(&self.model) {
+            return Ok(());
+        }
+        let local_model = self
+            .models()
+            .into_iter()
+            .find(|v| self.is_local_model(v))
+            .map(|v| v.id());
+        match local_model {
+            Some(id) => {
+                self.print_warning(&format!("Offline, switched to the local model '{id}'"));
+                self.set_model(&id)?;
+            }
+            None => self.print_warning("Offline, but no local model is configured"),
+        }
+        Ok(())
+    }
+
     pub fn system_info(&self) -> Result<String> {
         let display_path = |path: &Path| path.display().to_string();
         let wrap = self
             .wrap
             .clone()
             .map_or_else(|| String::from("no"), |v| v.to_string());
+        let extra = find_client_extra_config(self);
+        let proxy = extra
+            .as_ref()
+            .and_then(|v| v.proxy.clone())
+            .or_else(|| self.proxy.clone());
+        let no_proxy = extra
+            .and_then(|v| v.no_proxy)
+            .or_else(|| self.no_proxy.clone());
+        let session_file = match &self.session {
+            Some(session) => display_path(&Self::session_file(session.name())?),
+            None => "-".into(),
+        };
         let items = vec![
+            ("client", self.model.client_name.clone()),
             ("model", self.model.id()),
             (
                 "max_output_tokens",
@@ -427,8 +738,55 @@ impl Config {
                     .map(|v| format!("{v} (current model)"))
                     .unwrap_or_else(|| "-".into()),
             ),
-            ("temperature", format_option_value(&self.temperature)),
-            ("top_p", format_option_value(&self.top_p)),
+            (
+                "role",
+                format_option_value(&self.role.as_ref().map(|v| &v.name)),
+            ),
+            (
+                "session",
+                format_option_value(&self.session.as_ref().map(|v| v.name())),
+            ),
+            (
+                "temperature",
+                format_option_value(&self.current_temperature()),
+            ),
+            ("top_p", format_option_value(&self.current_top_p())),
+            ("thinking", format_option_value(&self.thinking)),
+            ("seed", format_option_value(&self.seed)),
+            (
+                "frequency_penalty",
+                format_option_value(&self.frequency_penalty),
+            ),
+            (
+                "presence_penalty",
+                format_option_value(&self.presence_penalty),
+            ),
+            ("stop", format_stop(&self.stop)),
+            ("logprobs", format_option_value(&self.logprobs)),
+            (
+                "extra_body",
+                if self.extra_body.is_empty() {
+                    "-".into()
+                } else {
+                    serde_json::Value::Object(self.extra_body.clone()).to_string()
+                },
+            ),
+            ("stream", self.stream.to_string()),
+            (
+                "stream_chars_per_sec",
+                format_option_value(&self.stream_chars_per_sec),
+            ),
+            ("show_thinking", self.show_thinking.to_string()),
+            ("stream_flush", self.stream_flush.stringify().into()),
+            ("offline", self.offline_mode.to_string()),
+            ("proxy", format_option_value(&resolve_proxy(&proxy))),
+            (
+                "no_proxy",
+                format_option_value(&resolve_no_proxy(&no_proxy)),
+            ),
+            ("concurrency", format_option_value(&self.concurrency)),
+            ("rpm", format_option_value(&self.rpm)),
+            ("tpm", format_option_value(&self.tpm)),
             ("dry_run", self.dry_run.to_string()),
             ("save", self.save.to_string()),
             ("save_session", format_option_value(&self.save_session)),
@@ -437,13 +795,39 @@ impl Config {
             ("wrap", wrap),
             ("wrap_code", self.wrap_code.to_string()),
             ("auto_copy", self.auto_copy.to_string()),
+            ("pager", self.pager.to_string()),
+            ("include_datetime", self.include_datetime.to_string()),
+            ("run_code", self.run_code.to_string()),
             ("keybindings", self.keybindings.stringify().into()),
+            ("ctrl_c", self.ctrl_c.stringify().into()),
+            ("ctrl_d", self.ctrl_d.stringify().into()),
             ("prelude", format_option_value(&self.prelude)),
             ("compress_threshold", self.compress_threshold.to_string()),
+            (
+                "compress_target_tokens",
+                format_option_value(&self.compress_target_tokens),
+            ),
+            ("compress_keep_turns", self.compress_keep_turns.to_string()),
+            (
+                "prompt_compression",
+                format_option_value(&self.prompt_compression.ratio),
+            ),
+            ("repeat_guard", self.repeat_guard.stringify().into()),
+            ("session_conflict", self.session_conflict.stringify().into()),
+            ("session_backups", self.session_backups.to_string()),
+            ("locale", format_option_value(&self.locale)),
+            ("currency", self.currency().into()),
+            ("suggest_followups", self.suggest_followups.to_string()),
+            ("followup_model", format_option_value(&self.followup_model)),
+            ("fallback_models", self.fallback_models.join(", ")),
+            ("prefetch_followups", self.prefetch_followups.to_string()),
             ("config_file", display_path(&Self::config_file()?)),
             ("roles_file", display_path(&Self::roles_file()?)),
+            ("replrc_file", display_path(&Self::replrc_file()?)),
             ("messages_file", display_path(&Self::messages_file()?)),
+            ("models_file", display_path(&Self::models_file()?)),
             ("sessions_dir", display_path(&Self::sessions_dir()?)),
+            ("session_file", session_file),
         ];
         let output = items
             .iter()
@@ -453,6 +837,227 @@ impl Config {
         Ok(output)
     }
 
+    /// The models of `models.yaml` and the clients' configs, then the ones fetched from the providers.
+    pub fn models(&self) -> Vec<&Model> {
+        let mut models = list_models(self);
+        models.extend(self.fetched_models.iter());
+        models
+    }
+
+    /// Keep the fetched models that aren't known yet.
+    pub fn set_fetched_models(&mut self, models: Vec<Model>) {
+        let ids: HashSet<String> = list_models(self).into_iter().map(|v| v.id()).collect();
+        self.fetched_models = models
+            .into_iter()
+            .filter(|v| !ids.contains(&v.id()))
+            .collect();
+    }
+
+    /// The models with their context window, max output, prices and capabilities.
+    pub fn models_table(&self) -> String {
+        let models: Vec<_> = self
+            .models()
+            .into_iter()
+            .filter(|v| !self.offline_mode || self.is_local_model(v))
+            .collect();
+        let width = models
+            .iter()
+            .map(|v| v.id().len())
+            .max()
+            .unwrap_or_default();
+        let mut lines = vec![format!(
+            "{:<width$}  {:>8} / {:>8}  |  {:>6} / {:>6}",
+            "model", "input", "output", "$in", "$out"
+        )];
+        for model in models {
+            let mut line = format!("{:<width$}  {}", model.id(), model.description());
+            if model
+                .capabilities
+                .contains(ModelCapabilities::FunctionCalling)
+            {
+                line.push_str(" ⚒");
+            }
+            lines.push(line.trim_end().to_string());
+        }
+        lines.join("\n")
+    }
+
+    /// Everything known about the model, the current one by default.
+    pub fn model_info(&self, value: Option<&str>) -> Result<String> {
+        let model = match value {
+            Some(value) => self
+                .find_model(value)
+                .ok_or_else(|| anyhow!("No model '{}'", value))?,
+            None => self.model.clone(),
+        };
+        let (client_type, api_base) = find_client_endpoint(self, &model.client_name)
+            .ok_or_else(|| anyhow!("No client '{}'", model.client_name))?;
+        let mut modalities = vec![];
+        if model.capabilities.contains(ModelCapabilities::Text) {
+            modalities.push("text");
+        }
+        if model.capabilities.contains(ModelCapabilities::Vision) {
+            modalities.push("image");
+        }
+        if model.capabilities.contains(ModelCapabilities::Embedding) {
+            modalities.push("embedding");
+        }
+        let supports_tools = TOOL_CLIENT_TYPES.contains(&client_type)
+            && model
+                .capabilities
+                .contains(ModelCapabilities::FunctionCalling);
+        let locale = self.locale();
+        let price = |v: &Option<f64>| {
+            v.map(|v| format!("{} / 1M tokens", self.format_price(v)))
+                .unwrap_or_else(|| "-".into())
+        };
+        let count = |v: Option<u64>| format_option_value(&v.map(|v| locale.format_count(v)));
+        let status = match &model.deprecation {
+            Some(v) if v.removed => "removed",
+            Some(_) => "deprecated",
+            None => "available",
+        };
+        let replacement = model
+            .deprecation
+            .as_ref()
+            .and_then(|v| v.replacement.as_ref())
+            .map(|v| format!("{}:{v}", model.client_name));
+        let items = vec![
+            ("id", model.id()),
+            ("client", format!("{} ({client_type})", model.client_name)),
+            ("endpoint", format_option_value(&api_base)),
+            (
+                "max_input_tokens",
+                count(model.max_input_tokens.map(|v| v as u64)),
+            ),
+            (
+                "max_output_tokens",
+                count(model.show_max_output_tokens().map(|v| v as u64)),
+            ),
+            ("modalities", modalities.join(", ")),
+            ("tools", if supports_tools { "yes" } else { "no" }.into()),
+            ("input_price", price(&model.input_price)),
+            ("output_price", price(&model.output_price)),
+            (
+                "knowledge_cutoff",
+                format_option_value(&model.knowledge_cutoff),
+            ),
+            ("status", status.into()),
+            ("replacement", format_option_value(&replacement)),
+        ];
+        let output = items
+            .iter()
+            .map(|(name, value)| format!("{name:<20}{value}"))
+            .collect::<Vec<String>>()
+            .join("\n");
+        Ok(output)
+    }
+
+    /// The locale numbers are written in, the configured one or else the one of the environment.
+    pub fn locale(&self) -> Locale {
+        match &self.locale {
+            Some(locale) => Locale::parse(locale),
+            None => Locale::detect(),
+        }
+    }
+
+    pub fn currency(&self) -> &str {
+        self.currency.as_deref().unwrap_or("USD")
+    }
+
+    /// Format a price in USD in the configured currency, converted by `exchange_rates`.
+    pub fn format_price(&self, usd: f64) -> String {
+        let currency = self.currency();
+        let rate = match currency.eq_ignore_ascii_case("USD") {
+            true => Some(1.0),
+            false => self.exchange_rate(currency),
+        };
+        match rate {
+            Some(rate) => self.locale().format_money(usd * rate, currency),
+            None => self.locale().format_money(usd, "USD"),
+        }
+    }
+
+    /// The tokens and cost of the session, of this run per client and since the first run.
+    pub fn cost_report(&self) -> Result<String> {
+        let mut lines = vec![];
+        if let Some(session) = &self.session {
+            lines.push(format!(
+                "{:<20}{}",
+                "session",
+                self.format_usage(session.usage())
+            ));
+        }
+        let run_usage = run_usage();
+        let mut total = Usage::default();
+        for usage in run_usage.values() {
+            total.merge(usage);
+        }
+        lines.push(format!("{:<20}{}", "this run", self.format_usage(&total)));
+        for (client_name, usage) in &run_usage {
+            lines.push(format!("  {client_name:<18}{}", self.format_usage(usage)));
+        }
+        lines.push(String::new());
+        lines.push(self.usage_stats_report()?);
+        Ok(lines.join("\n"))
+    }
+
+    /// The tokens and cost since the first run, per client.
+    pub fn usage_stats_report(&self) -> Result<String> {
+        let stats = UsageStats::load()?;
+        let since = match &stats.since {
+            Some(since) => format!("since {since}"),
+            None => "all time".into(),
+        };
+        let mut lines = vec![format!("{since:<20}{}", self.format_usage(&stats.total))];
+        for (client_name, usage) in &stats.clients {
+            lines.push(format!("  {client_name:<18}{}", self.format_usage(usage)));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn format_usage(&self, usage: &Usage) -> String {
+        let locale = self.locale();
+        let mut output = format!(
+            "{} requests, {} tokens in, {} out",
+            locale.format_count(usage.requests),
+            locale.format_count(usage.input_tokens),
+            locale.format_count(usage.output_tokens),
+        );
+        // The models without a price cost nothing as far as we know
+        if usage.cost > 0.0 {
+            output.push_str(&format!(", {}", self.format_price(usage.cost)));
+        }
+        output
+    }
+
+    fn exchange_rate(&self, currency: &str) -> Option<f64> {
+        self.exchange_rates
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(currency))
+            .map(|(_, v)| *v)
+    }
+
+    pub fn current_temperature(&self) -> Option<f64> {
+        if let Some(session) = &self.session {
+            session.temperature()
+        } else if let Some(role) = &self.role {
+            role.temperature
+        } else {
+            self.temperature
+        }
+    }
+
+    pub fn current_top_p(&self) -> Option<f64> {
+        if let Some(session) = &self.session {
+            session.top_p()
+        } else if let Some(role) = &self.role {
+            role.top_p
+        } else {
+            self.top_p
+        }
+    }
+
     pub fn role_info(&self) -> Result<String> {
         if let Some(role) = &self.role {
             role.export()
@@ -461,11 +1066,33 @@ impl Config {
         }
     }
 
+    pub fn session_history(&self, show_compressed: bool) -> Result<String> {
+        if let Some(session) = &self.session {
+            let render_options = self.get_render_options()?;
+            let mut markdown_render = MarkdownRender::init(render_options)?;
+            session.history(show_compressed, &mut markdown_render)
+        } else {
+            bail!("No session")
+        }
+    }
+
+    pub fn session_inspect(&self) -> Result<String> {
+        if let Some(session) = &self.session {
+            session.inspect(
+                self.compress_threshold,
+                self.compress_keep_turns,
+                &self.locale(),
+            )
+        } else {
+            bail!("No session")
+        }
+    }
+
     pub fn session_info(&self) -> Result<String> {
         if let Some(session) = &self.session {
             let render_options = self.get_render_options()?;
             let mut markdown_render = MarkdownRender::init(render_options)?;
-            session.info(&mut markdown_render)
+            session.info(&mut markdown_render, &self.locale())
         } else {
             bail!("No session")
         }
@@ -496,8 +1123,10 @@ impl Config {
                     .iter()
                     .map(|v| (v.name.clone(), String::new()))
                     .collect(),
-                ".model" => list_models(self)
+                ".model" => self
+                    .models()
                     .into_iter()
+                    .filter(|v| !self.offline_mode || self.is_local_model(v))
                     .map(|v| (v.id(), v.description()))
                     .collect(),
                 ".session" => self
@@ -505,16 +1134,47 @@ impl Config {
                     .into_iter()
                     .map(|v| (v.clone(), String::new()))
                     .collect(),
+                ".vars" => vec!["set", "list"]
+                    .into_iter()
+                    .map(|v| (format!("{v} "), String::new()))
+                    .collect(),
                 ".set" => vec![
                     "max_output_tokens",
                     "temperature",
                     "top_p",
+                    "thinking",
+                    "seed",
+                    "frequency_penalty",
+                    "presence_penalty",
+                    "stop",
+                    "logprobs",
+                    "extra_body.",
                     "compress_threshold",
+                    "prompt_compression",
                     "save",
                     "save_session",
                     "highlight",
                     "dry_run",
+                    "stream",
+                    "stream_chars_per_sec",
+                    "show_thinking",
                     "auto_copy",
+                    "pager",
+                    "include_datetime",
+                    "run_code",
+                    "repeat_guard",
+                    "session_conflict",
+                    "locale",
+                    "currency",
+                    "suggest_followups",
+                    "prefetch_followups",
+                    "offline",
+                    "concurrency",
+                    "rpm",
+                    "tpm",
+                    "keybindings",
+                    "ctrl_c",
+                    "ctrl_d",
                 ]
                 .into_iter()
                 .map(|v| (format!("{v} "), String::new()))
@@ -523,11 +1183,47 @@ impl Config {
             };
             (values, args[0])
         } else if args.len() == 2 {
-            let values = match args[0] {
+            let values = match (cmd, args[0]) {
+                (".role", "delete") => {
+                    let values = self.roles.iter().map(|v| v.name.clone()).collect();
+                    return filter_complete_values(values, args[1]);
+                }
+                (".model", "info") => {
+                    let values = self
+                        .models()
+                        .into_iter()
+                        .filter(|v| !self.offline_mode || self.is_local_model(v))
+                        .map(|v| v.id())
+                        .collect();
+                    return filter_complete_values(values, args[1]);
+                }
+                (".session", "delete" | "recover" | "switch") => {
+                    return filter_complete_values(self.list_sessions(), args[1]);
+                }
+                (".tool", "call") => {
+                    let values = BUILTIN_FUNCTIONS.iter().map(|v| v.to_string()).collect();
+                    return filter_complete_values(values, args[1]);
+                }
+                (".vars", "set") => {
+                    let mut values: Vec<String> = self.variables.keys().cloned().collect();
+                    values.sort();
+                    return filter_complete_values(values, args[1]);
+                }
+                (_, key) => key,
+            };
+            let values = match values {
                 "max_output_tokens" => match self.model.show_max_output_tokens() {
                     Some(v) => vec![v.to_string()],
                     None => vec![],
                 },
+                "temperature" => self
+                    .temperature
+                    .map(|v| vec![v.to_string()])
+                    .unwrap_or_default(),
+                "top_p" => self.top_p.map(|v| vec![v.to_string()]).unwrap_or_default(),
+                "thinking" => vec!["low".into(), "medium".into(), "high".into()],
+                "compress_threshold" => vec![self.compress_threshold.to_string()],
+                "prompt_compression" => vec!["null".into(), "0.5".into()],
                 "save" => complete_bool(self.save),
                 "save_session" => {
                     let save_session = if let Some(session) = &self.session {
@@ -539,7 +1235,22 @@ impl Config {
                 }
                 "highlight" => complete_bool(self.highlight),
                 "dry_run" => complete_bool(self.dry_run),
+                "stream" => complete_bool(self.stream),
+                "show_thinking" => complete_bool(self.show_thinking),
                 "auto_copy" => complete_bool(self.auto_copy),
+                "pager" => complete_bool(self.pager),
+                "include_datetime" => complete_bool(self.include_datetime),
+                "run_code" => complete_bool(self.run_code),
+                "repeat_guard" => vec!["ask".into(), "reuse".into(), "off".into()],
+                "session_conflict" => vec!["ask".into(), "read_only".into(), "merge".into()],
+                "currency" => std::iter::once("USD".to_string())
+                    .chain(self.exchange_rates.keys().cloned())
+                    .collect(),
+                "suggest_followups" => complete_bool(self.suggest_followups),
+                "prefetch_followups" => complete_bool(self.prefetch_followups),
+                "offline" => complete_bool(self.offline_mode),
+                "keybindings" => vec!["emacs".into(), "vi".into()],
+                "ctrl_c" | "ctrl_d" => SignalAction::names().into_iter().map(Into::into).collect(),
                 _ => vec![],
             };
             (
@@ -556,12 +1267,22 @@ impl Config {
     }
 
     pub fn update(&mut self, data: &str) -> Result<()> {
-        let parts: Vec<&str> = data.split_whitespace().collect();
-        if parts.len() != 2 {
-            bail!("Usage: .set <key> <value>. If value is null, unset key.");
+        let usage = "Usage: .set <key> <value>. If value is null, unset key.";
+        let (key, value) = match data.trim().split_once(char::is_whitespace) {
+            Some((key, value)) if !value.trim().is_empty() => (key, value.trim()),
+            _ => bail!("{usage}"),
+        };
+        // A JSON value may contain spaces
+        if let Some(name) = key.strip_prefix("extra_body.") {
+            return self.set_extra_body(name, value);
+        }
+        if key == "stop" {
+            self.stop = parse_stop(value)?;
+            return Ok(());
+        }
+        if value.contains(char::is_whitespace) {
+            bail!("{usage}");
         }
-        let key = parts[0];
-        let value = parts[1];
         match key {
             "max_output_tokens" => {
                 let value = parse_value(value)?;
@@ -575,10 +1296,36 @@ impl Config {
                 let value = parse_value(value)?;
                 self.set_top_p(value);
             }
+            "thinking" => {
+                self.thinking = parse_value(value)?;
+            }
+            "seed" => {
+                self.seed = parse_value(value)?;
+            }
+            "frequency_penalty" => {
+                self.frequency_penalty = parse_value(value)?;
+            }
+            "presence_penalty" => {
+                self.presence_penalty = parse_value(value)?;
+            }
+            "logprobs" => {
+                let value: Option<u64> = parse_value(value)?;
+                if value.is_some_and(|v| v > MAX_TOP_LOGPROBS) {
+                    bail!("Invalid value, expect 0 to {MAX_TOP_LOGPROBS} or null");
+                }
+                self.logprobs = value;
+            }
             "compress_threshold" => {
                 let value = parse_value(value)?;
                 self.set_compress_threshold(value);
             }
+            "prompt_compression" => {
+                let value: Option<f64> = parse_value(value)?;
+                if value.is_some_and(|v| !(0.0..=1.0).contains(&v) || v == 0.0) {
+                    bail!("Invalid value, expect a ratio in (0, 1] or null");
+                }
+                self.prompt_compression.ratio = value;
+            }
             "save" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.save = value;
@@ -595,15 +1342,154 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.dry_run = value;
             }
+            "stream_chars_per_sec" => {
+                self.stream_chars_per_sec = parse_value(value)?.filter(|v| *v > 0);
+            }
+            "stream" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.stream = value;
+            }
+            "show_thinking" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.show_thinking = value;
+            }
             "auto_copy" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.auto_copy = value;
             }
+            "pager" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.pager = value;
+            }
+            "include_datetime" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.include_datetime = value;
+            }
+            "run_code" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.run_code = value;
+            }
+            "locale" => {
+                self.locale = parse_value(value)?;
+            }
+            "currency" => {
+                let currency: Option<String> = parse_value(value)?;
+                if let Some(currency) = &currency {
+                    if !currency.eq_ignore_ascii_case("USD")
+                        && self.exchange_rate(currency).is_none()
+                    {
+                        bail!("No exchange rate of '{currency}', add it to `exchange_rates`");
+                    }
+                }
+                self.currency = currency;
+            }
+            "repeat_guard" => {
+                self.repeat_guard = match value {
+                    "ask" => RepeatGuard::Ask,
+                    "reuse" => RepeatGuard::Reuse,
+                    "off" => RepeatGuard::Off,
+                    _ => bail!("Invalid value, expect ask, reuse or off"),
+                };
+            }
+            "session_conflict" => {
+                self.session_conflict = match value {
+                    "ask" => SessionConflict::Ask,
+                    "read_only" => SessionConflict::ReadOnly,
+                    "merge" => SessionConflict::Merge,
+                    _ => bail!("Invalid value, expect ask, read_only or merge"),
+                };
+            }
+            "suggest_followups" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.suggest_followups = value;
+            }
+            "prefetch_followups" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.prefetch_followups = value;
+            }
+            "offline" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.set_offline_mode(value)?;
+            }
+            "concurrency" => {
+                self.concurrency = parse_value(value)?.filter(|v| *v > 0);
+            }
+            "rpm" => {
+                self.rpm = parse_value(value)?.filter(|v| *v > 0);
+            }
+            "tpm" => {
+                self.tpm = parse_value(value)?.filter(|v| *v > 0);
+            }
+            "keybindings" => {
+                self.keybindings.mode = match value {
+                    "emacs" => KeybindingsMode::Emacs,
+                    "vi" => KeybindingsMode::Vi,
+                    _ => bail!("Invalid value, expect emacs or vi"),
+                };
+            }
+            "ctrl_c" => {
+                self.ctrl_c = value.parse()?;
+            }
+            "ctrl_d" => {
+                self.ctrl_d = value.parse()?;
+            }
             _ => bail!("Unknown key `{key}`"),
         }
         Ok(())
     }
 
+    /// Set a field of the request body, the value is JSON or else a string.
+    pub fn set_extra_body(&mut self, name: &str, value: &str) -> Result<()> {
+        if name.is_empty() {
+            bail!("Usage: .set extra_body.<name> <value>");
+        }
+        if value == "null" {
+            self.extra_body.remove(name);
+        } else {
+            let value = serde_json::from_str(value)
+                .unwrap_or_else(|_| serde_json::Value::String(value.into()));
+            self.extra_body.insert(name.into(), value);
+        }
+        Ok(())
+    }
+
+    pub fn set_variable(&mut self, data: &str) -> Result<()> {
+        match data.split_once(char::is_whitespace) {
+            Some((key, value)) if !value.trim().is_empty() => {
+                self.variables
+                    .insert(key.to_string(), value.trim().to_string());
+                Ok(())
+            }
+            _ => bail!("Usage: .vars set <key> <value>"),
+        }
+    }
+
+    pub fn list_variables(&self) -> String {
+        let mut names: Vec<&String> = self.variables.keys().collect();
+        names.sort_unstable();
+        names
+            .into_iter()
+            .map(|name| format!("{name:<20}{}", self.variables[name]))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Fill `%{name}` placeholders, asking the user for any variables that are not yet set
+    pub fn fill_input_variables(&mut self, input: &mut Input) -> Result<()> {
+        for name in input.variable_names() {
+            if self.variables.contains_key(&name) {
+                continue;
+            }
+            if !stdout().is_terminal() {
+                bail!("Missing variable '{name}'");
+            }
+            let value = Text::new(&format!("{name}:")).prompt()?;
+            self.variables.insert(name, value);
+        }
+        input.fill_variables(&self.variables);
+        Ok(())
+    }
+
     pub fn start_session(&mut self, session: Option<&str>) -> Result<()> {
         if self.session.is_some() {
             bail!(
@@ -623,10 +1509,30 @@ impl Config {
             }
             Some(name) => {
                 let session_path = Self::session_file(name)?;
+                let lock_path = session_path.with_extension("lock");
+                let read_only = match lock_session(&lock_path)? {
+                    Some(pid) => self.resolve_session_conflict(name, pid)?,
+                    None => {
+                        self.session_lock = Some(lock_path);
+                        false
+                    }
+                };
                 if !session_path.exists() {
-                    self.session = Some(Session::new(self, name));
+                    let mut session = Session::new(self, name);
+                    session.mark_synced(&session_path);
+                    session.read_only = read_only;
+                    self.session = Some(session);
                 } else {
-                    let session = Session::load(name, &session_path)?;
+                    let mut session = match Session::load(name, &session_path) {
+                        Ok(session) => session,
+                        Err(err) => {
+                            if let Some(lock_path) = self.session_lock.take() {
+                                unlock_session(&lock_path);
+                            }
+                            return Err(err);
+                        }
+                    };
+                    session.read_only = read_only;
                     let model_id = session.model().to_string();
                     self.session = Some(session);
                     self.set_model(&model_id)?;
@@ -650,10 +1556,48 @@ impl Config {
         Ok(())
     }
 
+    /// Returns whether to open the session held by another aichat read-only.
+    fn resolve_session_conflict(&mut self, name: &str, pid: u32) -> Result<bool> {
+        let conflict = match self.session_conflict {
+            SessionConflict::Ask if self.working_mode == WorkingMode::Repl => {
+                let read_only = "Open read-only";
+                let merge = "Open and merge my turns on save";
+                let ans = Select::new(
+                    &format!("Session '{name}' is open in another aichat (pid {pid})."),
+                    vec![read_only, merge, "Cancel"],
+                )
+                .prompt()?;
+                match ans {
+                    v if v == read_only => SessionConflict::ReadOnly,
+                    v if v == merge => SessionConflict::Merge,
+                    _ => bail!("Session '{name}' is open in another aichat (pid {pid})"),
+                }
+            }
+            SessionConflict::Ask => SessionConflict::Merge,
+            v => v,
+        };
+        Ok(conflict == SessionConflict::ReadOnly)
+    }
+
     pub fn end_session(&mut self) -> Result<()> {
+        if let Some(lock_path) = self.session_lock.take() {
+            unlock_session(&lock_path);
+        }
         if let Some(mut session) = self.session.take() {
             self.last_message = None;
+            if self.obsidian.auto_export && !session.is_empty() {
+                obsidian::export_session(&session, &self.obsidian, chrono::Local::now())?;
+            }
             let save_session = session.save_session();
+            if session.dirty && session.read_only {
+                if self.working_mode == WorkingMode::Repl {
+                    println!(
+                        "Session '{}' is read-only, its new messages are not saved.",
+                        session.name()
+                    );
+                }
+                return Ok(());
+            }
             if session.dirty && save_session != Some(false) {
                 if save_session.is_none() || session.is_temp() {
                     if self.working_mode != WorkingMode::Repl {
@@ -667,22 +1611,126 @@ impl Config {
                         session.name = Text::new("Session name:").prompt()?;
                     }
                 }
-                Self::save_session_to_file(&mut session)?;
+                Self::save_session_to_file(&mut session, self.session_backups)?;
             }
         }
         Ok(())
     }
 
+    /// Save the session without asking, as the process is terminated. A temp session is saved
+    /// with a name of the time, returns the name of the saved session.
+    pub fn autosave_session(&mut self) -> Result<Option<String>> {
+        if let Some(lock_path) = self.session_lock.take() {
+            unlock_session(&lock_path);
+        }
+        let Some(mut session) = self.session.take() else {
+            return Ok(None);
+        };
+        if !session.dirty || session.read_only || session.save_session() == Some(false) {
+            return Ok(None);
+        }
+        if session.is_temp() || session.name().is_empty() {
+            session.name = format!(
+                "{TEMP_SESSION_NAME}-{}",
+                chrono::Local::now().format("%Y%m%d-%H%M%S")
+            );
+        }
+        Self::save_session_to_file(&mut session, self.session_backups)?;
+        Ok(Some(session.name().to_string()))
+    }
+
     pub fn save_session(&mut self, name: &str) -> Result<()> {
         if let Some(session) = self.session.as_mut() {
             if !name.is_empty() {
+                // A read-only session can still be saved as a copy
+                if name != session.name {
+                    session.read_only = false;
+                }
                 session.name = name.to_string();
             }
-            Self::save_session_to_file(session)?;
+            Self::save_session_to_file(session, self.session_backups)?;
         }
         Ok(())
     }
 
+    pub fn export_session_obsidian(&self) -> Result<PathBuf> {
+        match &self.session {
+            Some(session) => {
+                obsidian::export_session(session, &self.obsidian, chrono::Local::now())
+            }
+            None => bail!("No session"),
+        }
+    }
+
+    pub fn delete_session(&mut self, name: &str) -> Result<()> {
+        if self
+            .session
+            .as_ref()
+            .map(|v| v.name() == name)
+            .unwrap_or_default()
+        {
+            bail!("Cannot delete the current session, please run '.exit session' first.");
+        }
+        // Only the files of the sessions directory can go
+        if name.contains(['/', '\\']) {
+            bail!("Invalid session name `{name}`");
+        }
+        let session_path = Self::session_file(name)?;
+        if !session_path.exists() {
+            bail!("Unknown session `{name}`");
+        }
+        if is_session_locked(&session_path.with_extension("lock")) {
+            bail!("Cannot delete the session `{name}`, another aichat has it open.");
+        }
+        let ans = Confirm::new(&format!("Delete session '{name}'?"))
+            .with_default(false)
+            .prompt()?;
+        if !ans {
+            return Ok(());
+        }
+        remove_session_files(&session_path)
+    }
+
+    /// Restore a session from its newest backup that loads, after a crash left it broken.
+    pub fn recover_session(&mut self, name: &str) -> Result<()> {
+        if self
+            .session
+            .as_ref()
+            .map(|v| v.name() == name)
+            .unwrap_or_default()
+        {
+            bail!("Cannot recover the current session, please run '.exit session' first.");
+        }
+        let session_path = Self::session_file(name)?;
+        let backups = backup_paths(&session_path);
+        if backups.is_empty() {
+            bail!("No backup of session `{name}`");
+        }
+        let Some(backup) = backups.into_iter().find(|v| Session::load(name, v).is_ok()) else {
+            bail!("No backup of session `{name}` can be loaded");
+        };
+        let ans = Confirm::new(&format!(
+            "Restore session '{name}' from '{}'?",
+            backup.display()
+        ))
+        .with_default(false)
+        .prompt()?;
+        if !ans {
+            return Ok(());
+        }
+        let content = read_to_string(&backup)
+            .with_context(|| format!("Failed to read {}", backup.display()))?;
+        // The backups are left as they are, the broken file is not worth keeping
+        write_file_atomic(&session_path, &content, 0)?;
+        println!("✓ Restored session '{name}' from '{}'", backup.display());
+        Ok(())
+    }
+
+    pub fn switch_session(&mut self, name: &str) -> Result<()> {
+        self.end_session()?;
+        self.start_session(Some(name))
+    }
+
     pub fn has_session(&self) -> bool {
         self.session.is_some()
     }
@@ -715,9 +1763,92 @@ impl Config {
         }
     }
 
+    /// Delete the sessions beyond `session_retention` with their backups, or just find them
+    /// with `dry_run`. The sessions open in a running aichat are kept.
+    pub fn prune_sessions(&self, dry_run: bool) -> Result<Vec<SessionFile>> {
+        let mut sessions = vec![];
+        for name in self.list_sessions() {
+            if self.session.as_ref().is_some_and(|v| v.name() == name) {
+                continue;
+            }
+            let path = Self::session_file(&name)?;
+            if is_session_locked(&path.with_extension("lock")) {
+                continue;
+            }
+            let Ok(metadata) = path.metadata() else {
+                continue;
+            };
+            let size = backup_paths(&path)
+                .iter()
+                .filter_map(|v| v.metadata().ok())
+                .map(|v| v.len())
+                .sum::<u64>()
+                + metadata.len();
+            sessions.push(SessionFile {
+                name,
+                modified: metadata.modified()?,
+                size,
+            });
+        }
+        let prunable = prunable_sessions(sessions, &self.session_retention, SystemTime::now());
+        if !dry_run {
+            for session in &prunable {
+                remove_session_files(&Self::session_file(&session.name)?)?;
+            }
+        }
+        Ok(prunable)
+    }
+
+    pub fn list_web_sessions(&self) -> Vec<String> {
+        self.list_sessions()
+            .into_iter()
+            .filter_map(|v| v.strip_prefix(WEB_SESSION_PREFIX).map(|v| v.to_string()))
+            .collect()
+    }
+
+    pub fn load_web_session(name: &str) -> Result<(String, Vec<Message>)> {
+        let name = web_session_name(name)?;
+        let session_path = Self::session_file(&name)?;
+        if !session_path.exists() {
+            bail!("Unknown session `{name}`");
+        }
+        let session = Session::load(&name, &session_path)?;
+        Ok((session.model().to_string(), session.messages().to_vec()))
+    }
+
+    /// Save a conversation from the playground so that it can be opened in the REPL later
+    pub fn save_web_session(name: &str, model_id: &str, messages: Vec<Message>) -> Result<()> {
+        let name = web_session_name(name)?;
+        let mut session: Session = serde_json::from_value(json!({
+            "model": model_id,
+            "messages": messages,
+        }))
+        .with_context(|| "Invalid session data")?;
+        session.name = name;
+        Self::save_session_to_file(&mut session, SESSION_BACKUPS)
+    }
+
+    /// Save a conversation imported from another tool as a session of the current model.
+    /// Returns false when the session exists, so that importing again doesn't overwrite it.
+    pub fn save_imported_session(&self, name: &str, messages: Vec<Message>) -> Result<bool> {
+        if Self::session_file(name)?.exists() {
+            return Ok(false);
+        }
+        let mut session: Session = serde_json::from_value(json!({
+            "model": self.model.id(),
+            "messages": messages,
+        }))
+        .with_context(|| "Invalid session data")?;
+        session.name = name.to_string();
+        Self::save_session_to_file(&mut session, 0)?;
+        Ok(true)
+    }
+
     pub fn should_compress_session(&mut self) -> bool {
         if let Some(session) = self.session.as_mut() {
-            if session.need_compress(self.compress_threshold) {
+            if session.need_compress(self.compress_threshold)
+                && session.can_compress(self.compress_keep_turns)
+            {
                 session.compressing = true;
                 return true;
             }
@@ -728,12 +1859,89 @@ impl Config {
     pub fn compress_session(&mut self, summary: &str) {
         if let Some(session) = self.session.as_mut() {
             let summary_prompt = self.summary_prompt.as_deref().unwrap_or(SUMMARY_PROMPT);
-            session.compress(format!("{}{}", summary_prompt, summary));
+            session.compress(
+                format!("{}{}", summary_prompt, summary),
+                self.compress_keep_turns,
+            );
+        }
+    }
+
+    pub fn summarize_prompt(&self) -> String {
+        let transcript = self
+            .session
+            .as_ref()
+            .map(|v| v.compress_transcript(self.compress_keep_turns))
+            .unwrap_or_default();
+        let mut prompt = self
+            .summarize_prompt
+            .as_deref()
+            .unwrap_or(SUMMARIZE_PROMPT)
+            .to_string();
+        if let Some(tokens) = self.compress_target_tokens {
+            prompt = format!("{prompt}\nKeep the summary within {tokens} tokens.");
+        }
+        format!("{transcript}\n\n{prompt}")
+    }
+
+    pub fn followup_prompt(&self, question: &str, answer: &str) -> String {
+        FOLLOWUP_PROMPT
+            .replace("__QUESTION__", question)
+            .replace("__ANSWER__", answer)
+    }
+
+    /// The prompt answering the follow-up in the context of the whole session, or of the last
+    /// question and answer without one, since the prefetched answer is saved as the reply.
+    pub fn prefetch_prompt(&self, question: &str, answer: &str, followup: &str) -> String {
+        let conversation = match &self.session {
+            Some(session) if !session.is_empty() => session.compress_transcript(0),
+            _ => format!("user: {question}\n\nassistant: {answer}"),
+        };
+        PREFETCH_PROMPT
+            .replace("__CONVERSATION__", &conversation)
+            .replace("__FOLLOWUP__", followup)
+    }
+
+    /// Create a standalone config used for generating follow-up suggestions, so that the
+    /// current role and session are not involved.
+    pub fn followup_config(&self) -> Result<Self> {
+        let mut config = Self {
+            clients: self.clients.clone(),
+            model: self.model.clone(),
+            ..Default::default()
+        };
+        if let Some(model_id) = &self.followup_model {
+            config.set_model(model_id)?;
+        }
+        Ok(config)
+    }
+
+    /// The config for sending the same input to one of `fallback_models`, which doesn't fail
+    /// over again.
+    pub fn fallback_config(&self, model_id: &str) -> Result<Self> {
+        let model = self
+            .find_model(model_id)
+            .ok_or_else(|| anyhow!("No model '{}'", model_id))?;
+        self.ensure_offline_model(&model)?;
+        Ok(Self {
+            model,
+            fallback_models: vec![],
+            ..self.clone()
+        })
+    }
+
+    pub fn add_session_usage(&mut self, details: &CompletionDetails) {
+        if let Some(session) = self.session.as_mut() {
+            session.add_usage(details);
         }
     }
 
-    pub fn summarize_prompt(&self) -> &str {
-        self.summarize_prompt.as_deref().unwrap_or(SUMMARIZE_PROMPT)
+    pub fn set_followups(&mut self, text: &str) {
+        self.followups = parse_followups(text);
+    }
+
+    pub fn pick_followup(&self, line: &str) -> Option<String> {
+        let index = line.trim().parse::<usize>().ok()?;
+        self.followups.get(index.checked_sub(1)?).cloned()
     }
 
     pub fn is_compressing_session(&self) -> bool {
@@ -774,11 +1982,12 @@ impl Config {
         } else {
             None
         };
-        let truecolor = matches!(
-            env::var("COLORTERM").as_ref().map(|v| v.as_str()),
-            Ok("truecolor")
-        );
-        Ok(RenderOptions::new(theme, wrap, self.wrap_code, truecolor))
+        Ok(RenderOptions::new(
+            theme,
+            wrap,
+            self.wrap_code,
+            ColorDepth::detect(),
+        ))
     }
 
     pub fn render_prompt_left(&self) -> String {
@@ -793,40 +2002,121 @@ impl Config {
         render_prompt(right_prompt, &variables)
     }
 
+    /// The welcome text of the REPL, empty when it is turned off.
+    pub fn render_banner(&self) -> String {
+        let mut variables = self.generate_prompt_context();
+        variables.insert("version", env!("CARGO_PKG_VERSION").into());
+        let banner = self.banner.as_deref().unwrap_or(BANNER);
+        render_prompt(banner, &variables)
+    }
+
     pub fn prepare_send_data(&self, input: &Input, stream: bool) -> Result<SendData> {
         let messages = self.build_messages(input)?;
-        let temperature = if let Some(session) = input.session(&self.session) {
+        let model_override = self.model_override();
+        let temperature = if let Some(v) = model_override.and_then(|v| v.temperature) {
+            Some(v)
+        } else if let Some(session) = input.session(&self.session) {
             session.temperature()
         } else if let Some(role) = input.role() {
             role.temperature
         } else {
             self.temperature
         };
-        let top_p = if let Some(session) = input.session(&self.session) {
+        let top_p = if let Some(v) = model_override.and_then(|v| v.top_p) {
+            Some(v)
+        } else if let Some(session) = input.session(&self.session) {
             session.top_p()
         } else if let Some(role) = input.role() {
             role.top_p
         } else {
             self.top_p
         };
+        let functions = match input.role() {
+            Some(role) if !role.functions.is_empty() => {
+                let names: Vec<String> = role
+                    .functions
+                    .iter()
+                    .filter(|v| !self.offline_mode || !WEB_FUNCTIONS.contains(&v.as_str()))
+                    .cloned()
+                    .collect();
+                if names.is_empty() {
+                    None
+                } else {
+                    Some(declare_functions(&names)?)
+                }
+            }
+            _ => None,
+        };
         self.model.max_input_tokens_limit(&messages)?;
         Ok(SendData {
             messages,
             temperature,
             top_p,
+            functions,
+            tool_rounds: input.tool_rounds().to_vec(),
+            seed: self.seed,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            stop: self.stop.clone(),
+            logprobs: self.logprobs,
+            thinking: self.thinking,
+            tags: self.tags.clone(),
+            schema: self.response_schema(input),
             stream,
         })
     }
 
+    /// The overrides of the current model, by its id.
+    pub fn model_override(&self) -> Option<&ModelOverride> {
+        self.model_overrides.get(&self.model.id())
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            concurrency: self.concurrency,
+            rpm: self.rpm,
+            tpm: self.tpm,
+        }
+    }
+
+    /// The JSON schema the answer must conform to, `--schema` taking precedence over the role.
+    pub fn response_schema(&self, input: &Input) -> Option<serde_json::Value> {
+        self.schema
+            .clone()
+            .or_else(|| input.role().and_then(|v| v.schema.clone()))
+    }
+
+    pub fn set_schema(&mut self, value: &str) -> Result<()> {
+        let text = match value.trim_start().starts_with('{') {
+            true => value.to_string(),
+            false => read_to_string(value)
+                .with_context(|| format!("Failed to read schema file '{value}'"))?,
+        };
+        let schema: serde_json::Value = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid JSON schema '{value}'"))?;
+        if !schema.is_object() {
+            bail!("Invalid JSON schema '{value}', expect an object");
+        }
+        self.schema = Some(schema);
+        Ok(())
+    }
+
     pub fn input_context(&self) -> InputContext {
         InputContext::new(self.role.clone(), self.has_session())
+            .with_prompt_compression(self.prompt_compression.clone())
     }
 
     pub fn maybe_print_send_tokens(&self, input: &Input) {
         if self.dry_run {
             if let Ok(messages) = self.build_messages(input) {
-                let tokens = self.model.total_tokens(&messages);
-                println!(">>> This message consumes {tokens} tokens. <<<");
+                let tokens = self.model.count_tokens(&messages) as u64;
+                let cost = self
+                    .model
+                    .estimate_cost(tokens, 0)
+                    .map(|v| format!(" (~{})", self.format_price(v)))
+                    .unwrap_or_default();
+                let tokens = self.locale().format_count(tokens);
+                println!(">>> This message consumes {tokens} tokens{cost}. <<<");
             }
         }
     }
@@ -911,7 +2201,7 @@ impl Config {
             .with_context(|| format!("Failed to create/append {}", path.display()))
     }
 
-    fn save_session_to_file(session: &mut Session) -> Result<()> {
+    fn save_session_to_file(session: &mut Session, backups: usize) -> Result<()> {
         let session_path = Self::session_file(session.name())?;
         let sessions_dir = session_path
             .parent()
@@ -921,7 +2211,7 @@ impl Config {
                 format!("Failed to create session_dir '{}'", sessions_dir.display())
             })?;
         }
-        session.save(&session_path)?;
+        session.save(&session_path, backups)?;
         Ok(())
     }
 
@@ -983,6 +2273,21 @@ impl Config {
         Ok(())
     }
 
+    /// Add the clients of the local platforms that work without config, unless a client has
+    /// their name.
+    fn setup_implicit_clients(&mut self) -> Result<()> {
+        for platform in IMPLICIT_PLATFORMS {
+            if find_client_endpoint(self, platform).is_some() {
+                continue;
+            }
+            let client = json!({ "type": "openai-compatible", "name": platform });
+            let client = serde_json::from_value(client)
+                .with_context(|| format!("Failed to add the client '{platform}'"))?;
+            self.clients.push(client);
+        }
+        Ok(())
+    }
+
     fn setup_model(&mut self) -> Result<()> {
         let model = match &self.model_id {
             Some(v) => v.clone(),
@@ -999,14 +2304,39 @@ impl Config {
         Ok(())
     }
 
-    fn setup_highlight(&mut self) {
-        if let Ok(value) = env::var("NO_COLOR") {
-            let mut no_color = false;
-            set_bool(&mut no_color, &value);
-            if no_color {
-                self.highlight = false;
+    fn check_fallback_models(&self) -> Result<()> {
+        for model_id in &self.fallback_models {
+            if self.find_model(model_id).is_none() {
+                bail!("No model '{}' in fallback_models", model_id);
             }
         }
+        Ok(())
+    }
+
+    fn setup_offline(&mut self) -> Result<()> {
+        let offline = match self.offline {
+            Some(offline) => offline,
+            // Behind a proxy, there may be no direct route to the internet
+            None if self.has_proxy() => false,
+            None => is_network_unreachable(),
+        };
+        if offline {
+            self.set_offline_mode(true)?;
+        }
+        Ok(())
+    }
+
+    fn has_proxy(&self) -> bool {
+        let proxy = find_client_extra_config(self)
+            .and_then(|v| v.proxy)
+            .or_else(|| self.proxy.clone());
+        resolve_proxy(&proxy).is_some()
+    }
+
+    fn setup_highlight(&mut self) {
+        if is_no_color() || is_dumb_terminal() {
+            self.highlight = false;
+        }
     }
 
     fn setup_light_theme(&mut self) -> Result<()> {
@@ -1025,27 +2355,200 @@ impl Config {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
-pub enum Keybindings {
-    #[serde(rename = "emacs")]
-    #[default]
-    Emacs,
-    #[serde(rename = "vi")]
-    Vi,
+/// Settings of a model that apply whenever it's the current model, whatever the role or the
+/// session, e.g. formatting instructions a local model needs.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ModelOverride {
+    /// Appended to the system prompt, which it becomes without one
+    pub system_prompt_suffix: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+}
+
+/// Sampling defaults and limits applied to the requests received in serve mode.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServeConfig {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<isize>,
+    pub min_temperature: Option<f64>,
+    pub max_temperature: Option<f64>,
+    pub min_top_p: Option<f64>,
+    pub max_top_p: Option<f64>,
+    pub max_tokens_limit: Option<isize>,
 }
 
-impl Keybindings {
-    pub fn is_vi(&self) -> bool {
-        matches!(self, Keybindings::Vi)
+impl ServeConfig {
+    /// Fill in the defaults for missing parameters, then clamp them into the configured ranges.
+    pub fn apply(
+        &self,
+        temperature: Option<f64>,
+        top_p: Option<f64>,
+        max_tokens: Option<isize>,
+    ) -> (Option<f64>, Option<f64>, Option<isize>) {
+        let temperature = temperature
+            .or(self.temperature)
+            .map(|v| clamp_value(v, self.min_temperature, self.max_temperature));
+        let top_p = top_p
+            .or(self.top_p)
+            .map(|v| clamp_value(v, self.min_top_p, self.max_top_p));
+        let max_tokens = match (max_tokens.or(self.max_tokens), self.max_tokens_limit) {
+            (Some(v), Some(limit)) => Some(v.min(limit)),
+            (None, Some(limit)) => Some(limit),
+            (v, None) => v,
+        };
+        (temperature, top_p, max_tokens)
     }
+}
+
+/// Limits of the `fetch_url` function.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    pub allow_domains: Vec<String>,
+    pub deny_domains: Vec<String>,
+    pub max_bytes: usize,
+    pub cache_ttl: u64,
+    /// Whether private, loopback and link-local addresses, e.g. cloud metadata, may be fetched
+    pub allow_private: bool,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            allow_domains: vec![],
+            deny_domains: vec![],
+            max_bytes: 1024 * 1024,
+            cache_ttl: 600,
+            allow_private: false,
+        }
+    }
+}
+
+/// Limits on the saved sessions, the least recently saved ones beyond them are pruned.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionRetention {
+    pub max_sessions: Option<usize>,
+    pub max_age_days: Option<u64>,
+    /// Of the session files and their backups together
+    pub max_size_mb: Option<u64>,
+    pub prune_on_startup: bool,
+}
+
+/// Prune the large files of the input to `ratio` of their words, see [`crate::utils::prune_text`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PromptCompression {
+    pub ratio: Option<f64>,
+    pub min_tokens: usize,
+}
+
+impl Default for PromptCompression {
+    fn default() -> Self {
+        Self {
+            ratio: None,
+            min_tokens: 2000,
+        }
+    }
+}
+
+/// Colors of the REPL input highlighting, unset ones follow `light_theme`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ReplTheme {
+    pub command: Option<String>,
+    pub unknown_command: Option<String>,
+    pub argument: Option<String>,
+    pub string: Option<String>,
+    pub fence: Option<String>,
+}
+
+/// What the REPL does when the prompt just answered is submitted again.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepeatGuard {
+    /// Ask before sending it again.
+    Ask,
+    /// Show the last reply instead of sending it.
+    Reuse,
+    Off,
+}
+
+impl RepeatGuard {
     pub fn stringify(&self) -> &str {
         match self {
-            Keybindings::Emacs => "emacs",
-            Keybindings::Vi => "vi",
+            RepeatGuard::Ask => "ask",
+            RepeatGuard::Reuse => "reuse",
+            RepeatGuard::Off => "off",
         }
     }
 }
 
+/// When the streamed reply is written out if stdout isn't a terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFlush {
+    /// Each piece as it arrives, which may split words.
+    Token,
+    /// Each complete line.
+    Line,
+    /// The whole reply once it's complete.
+    Off,
+}
+
+impl StreamFlush {
+    pub fn stringify(&self) -> &str {
+        match self {
+            StreamFlush::Token => "token",
+            StreamFlush::Line => "line",
+            StreamFlush::Off => "off",
+        }
+    }
+}
+
+impl std::str::FromStr for StreamFlush {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "token" => Ok(StreamFlush::Token),
+            "line" => Ok(StreamFlush::Line),
+            "off" => Ok(StreamFlush::Off),
+            _ => bail!("Invalid stream flush '{s}', expect token, line or off"),
+        }
+    }
+}
+
+/// What to do when the session to open is open in another aichat.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionConflict {
+    /// Ask in the REPL, merge otherwise.
+    Ask,
+    /// Open it without ever saving it.
+    ReadOnly,
+    /// Open it and put the new turns after the ones the other aichat saved.
+    Merge,
+}
+
+impl SessionConflict {
+    pub fn stringify(&self) -> &str {
+        match self {
+            SessionConflict::Ask => "ask",
+            SessionConflict::ReadOnly => "read_only",
+            SessionConflict::Merge => "merge",
+        }
+    }
+}
+
+fn clamp_value(value: f64, min: Option<f64>, max: Option<f64>) -> f64 {
+    let value = min.map_or(value, |min| value.max(min));
+    max.map_or(value, |max| value.min(max))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum WorkingMode {
     Command,
@@ -1137,6 +2640,17 @@ fn create_config_file(config_path: &Path) -> Result<()> {
     Ok(())
 }
 
+fn web_session_name(name: &str) -> Result<String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid session name `{name}`");
+    }
+    Ok(format!("{WEB_SESSION_PREFIX}{name}"))
+}
+
 fn ensure_parent_exists(path: &Path) -> Result<()> {
     if path.exists() {
         return Ok(());
@@ -1163,6 +2677,23 @@ fn set_bool(target: &mut bool, value: &str) {
     }
 }
 
+/// A JSON array of stop sequences, a single sequence or `null` for none.
+fn parse_stop(value: &str) -> Result<Vec<String>> {
+    match value {
+        "null" => Ok(vec![]),
+        _ if value.starts_with('[') => serde_json::from_str(value)
+            .with_context(|| format!("Invalid value '{value}', expect an array of strings")),
+        _ => Ok(vec![value.to_string()]),
+    }
+}
+
+fn format_stop(stop: &[String]) -> String {
+    match stop.is_empty() {
+        true => "-".into(),
+        false => serde_json::to_string(stop).unwrap_or_default(),
+    }
+}
+
 fn parse_value<T>(value: &str) -> Result<Option<T>>
 where
     T: std::str::FromStr,
@@ -1179,6 +2710,58 @@ where
     Ok(value)
 }
 
+/// Add the current date, time and timezone to the system message, regenerated for every request
+fn inject_datetime(messages: &mut Vec<Message>) {
+    let now = chrono::Local::now();
+    let timezone = env::var("TZ").unwrap_or_else(|_| now.format("UTC%:z").to_string());
+    let datetime = format!(
+        "Current date and time: {} ({timezone})",
+        now.format("%A, %Y-%m-%d %H:%M:%S %:z")
+    );
+    append_system_prompt(messages, &datetime);
+}
+
+fn append_system_prompt(messages: &mut Vec<Message>, text: &str) {
+    match messages.first_mut() {
+        Some(Message {
+            role: MessageRole::System,
+            content: MessageContent::Text(prompt),
+        }) => {
+            *prompt = format!("{prompt}\n\n{text}");
+        }
+        _ => messages.insert(
+            0,
+            Message {
+                role: MessageRole::System,
+                content: MessageContent::Text(text.to_string()),
+            },
+        ),
+    }
+}
+
+fn parse_followups(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| {
+            line.trim()
+                .trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*')
+                })
+                .trim()
+        })
+        .filter(|line| !line.is_empty())
+        .take(MAX_FOLLOWUPS)
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn filter_complete_values(values: Vec<String>, filter: &str) -> Vec<(String, String)> {
+    values
+        .into_iter()
+        .filter(|v| fuzzy_match(v, filter))
+        .map(|v| (v, String::new()))
+        .collect()
+}
+
 fn complete_bool(value: bool) -> Vec<String> {
     vec![(!value).to_string()]
 }
@@ -1190,3 +2773,238 @@ fn complete_option_bool(value: Option<bool>) -> Vec<String> {
         None => vec!["true".to_string(), "false".to_string()],
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn test_render_right_prompt() {
+        let mut variables = HashMap::from([("model", "openai:gpt-4o".to_string())]);
+        assert_eq!(render_prompt(RIGHT_PROMPT, &variables), "openai:gpt-4o");
+        variables.insert("session", "temp".into());
+        variables.insert("consume_tokens", "120".into());
+        variables.insert("consume_percent", "0".into());
+        assert_eq!(render_prompt(RIGHT_PROMPT, &variables), "120 openai:gpt-4o");
+        variables.insert("consume_percent", "1.5".into());
+        assert_eq!(
+            render_prompt(RIGHT_PROMPT, &variables),
+            "120(1.5%) openai:gpt-4o"
+        );
+    }
+
+    #[test]
+    fn test_serve_config_apply() {
+        let serve = ServeConfig {
+            temperature: Some(0.7),
+            max_temperature: Some(1.0),
+            min_top_p: Some(0.1),
+            max_tokens_limit: Some(1024),
+            ..Default::default()
+        };
+        assert_eq!(serve.apply(None, None, None), (Some(0.7), None, Some(1024)));
+        assert_eq!(
+            serve.apply(Some(1.5), Some(0.0), Some(4096)),
+            (Some(1.0), Some(0.1), Some(1024))
+        );
+        assert_eq!(
+            serve.apply(Some(0.2), Some(0.9), Some(256)),
+            (Some(0.2), Some(0.9), Some(256))
+        );
+    }
+
+    #[test]
+    fn test_model_info() {
+        let mut config = Config {
+            clients: vec![serde_yaml::from_str("type: openai").unwrap()],
+            locale: Some("en-US".into()),
+            ..Default::default()
+        };
+        let mut model = Model::new("openai", "gpt-4-turbo");
+        model
+            .set_max_input_tokens(Some(128000))
+            .set_supports_vision(true)
+            .set_input_price(Some(10.0))
+            .set_knowledge_cutoff(&Some("2023-12".into()));
+        config.model = model;
+        let info = config.model_info(None).unwrap();
+        assert!(info.contains("client              openai (openai)\n"));
+        assert!(info.contains("endpoint            https://api.openai.com/v1\n"));
+        assert!(info.contains("modalities          text, image\n"));
+        assert!(info.contains("tools               yes\n"));
+        assert!(info.contains("max_input_tokens    128,000\n"));
+        assert!(info.contains("input_price         $10.00 / 1M tokens\n"));
+        assert!(info.contains("knowledge_cutoff    2023-12\n"));
+
+        config.locale = Some("de-DE".into());
+        config.exchange_rates.insert("EUR".into(), 0.9);
+        config.update("currency eur").unwrap();
+        let info = config.model_info(None).unwrap();
+        assert!(info.contains("max_input_tokens    128.000\n"));
+        assert!(info.contains("input_price         9,00 € / 1M tokens\n"));
+        assert!(config.update("currency gbp").is_err());
+
+        config.model = Model::new("claude", "claude-3-haiku-20240307");
+        assert!(config.model_info(None).is_err());
+    }
+
+    #[test]
+    fn test_fallback_config() {
+        let config = Config {
+            clients: vec![serde_yaml::from_str("type: openai").unwrap()],
+            fallback_models: vec!["openai:gpt-4o".into()],
+            model: Model::new("openai", "gpt-4-turbo"),
+            ..Default::default()
+        };
+        assert!(config.check_fallback_models().is_ok());
+        let fallback_config = config.fallback_config("openai:gpt-4o").unwrap();
+        assert_eq!(fallback_config.model.id(), "openai:gpt-4o");
+        assert!(fallback_config.fallback_models.is_empty());
+        assert!(config
+            .fallback_config("claude:claude-3-haiku-20240307")
+            .is_err());
+    }
+
+    #[test]
+    fn test_set_extra_body() {
+        let mut config = Config::default();
+        config
+            .update(r#"extra_body.logit_bias {"50256": -100}"#)
+            .unwrap();
+        config.update("extra_body.service_tier auto").unwrap();
+        assert_eq!(
+            serde_json::Value::Object(config.extra_body.clone()),
+            json!({ "logit_bias": { "50256": -100 }, "service_tier": "auto" })
+        );
+        config.update("extra_body.service_tier null").unwrap();
+        assert_eq!(config.extra_body.len(), 1);
+        assert!(config.update("temperature 0.5 1").is_err());
+    }
+
+    #[test]
+    fn test_model_override() {
+        let mut config = Config {
+            temperature: Some(0.2),
+            model_overrides: serde_yaml::from_str(
+                r#"{ "ollama:llama3": { system_prompt_suffix: "Use markdown.", temperature: 0.6 } }"#,
+            )
+            .unwrap(),
+            model: Model::new("ollama", "llama3"),
+            ..Default::default()
+        };
+        let input = Input::from_str("hi", InputContext::new(None, false));
+        let data = config.prepare_send_data(&input, false).unwrap();
+        assert_eq!(data.temperature, Some(0.6));
+        assert_eq!(data.messages[0].role, MessageRole::System);
+        assert_eq!(data.messages[0].content.to_text(), "Use markdown.");
+
+        config.model = Model::new("openai", "gpt-4o");
+        let data = config.prepare_send_data(&input, false).unwrap();
+        assert_eq!(data.temperature, Some(0.2));
+        assert_eq!(data.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_unlisted_model() {
+        let mut config = Config {
+            clients: serde_json::from_value(json!([{ "type": "openai", "api_key": "x" }])).unwrap(),
+            ..Default::default()
+        };
+        config.setup_implicit_clients().unwrap();
+        let model = config
+            .unlisted_model("lmstudio:qwen2.5-7b-instruct")
+            .unwrap();
+        assert_eq!(model.id(), "lmstudio:qwen2.5-7b-instruct");
+        assert!(config.is_local_model(&model));
+        assert!(config.unlisted_model("lmstudio").is_none());
+        assert!(config.unlisted_model("unknown:model").is_none());
+        assert!(config.unlisted_model("openai:nonexistent").is_none());
+
+        config.setup_implicit_clients().unwrap();
+        assert_eq!(config.clients.len(), 2);
+    }
+
+    #[test]
+    fn test_set_stop() {
+        let mut config = Config::default();
+        config.update("stop END").unwrap();
+        assert_eq!(config.stop, ["END"]);
+        config.update(r#"stop ["\n\n", "STOP"]"#).unwrap();
+        assert_eq!(config.stop, ["\n\n", "STOP"]);
+        assert_eq!(format_stop(&config.stop), r#"["\n\n","STOP"]"#);
+        config.update("stop null").unwrap();
+        assert!(config.stop.is_empty());
+        assert!(config.update("stop [1]").is_err());
+    }
+
+    #[test]
+    fn test_render_banner() {
+        let mut config = Config::default();
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            config.render_banner(),
+            format!("Welcome to aichat {version}\nType \".help\" for additional help.")
+        );
+        config.banner = Some("aichat {version}{?role  as {role}}".into());
+        assert_eq!(config.render_banner(), format!("aichat {version}"));
+        config.banner = Some(String::new());
+        assert_eq!(config.render_banner(), "");
+    }
+
+    #[test]
+    fn test_parse_followups() {
+        assert_eq!(
+            parse_followups("1. What is Rust?\n2) How to install it?\n\n- Is it fast?\n* Extra"),
+            vec!["What is Rust?", "How to install it?", "Is it fast?"]
+        );
+        assert!(parse_followups("\n  \n").is_empty());
+    }
+
+    #[test]
+    fn test_prefetch_prompt() {
+        let mut config = Config::default();
+        let prompt = config.prefetch_prompt("Q2", "A2", "F");
+        assert!(prompt.contains("user: Q2\n\nassistant: A2\n\nFollow-up question:\nF"));
+        let mut session = Session::new(&config, "chat");
+        for (question, answer) in [("Q1", "A1"), ("Q2", "A2")] {
+            session
+                .add_message(&Input::from_str(question, Default::default()), answer)
+                .unwrap();
+        }
+        config.session = Some(session);
+        let prompt = config.prefetch_prompt("Q2", "A2", "F");
+        assert!(prompt.contains("user: Q1\n\nassistant: A1\n\nuser: Q2\n\nassistant: A2\n\n"));
+    }
+
+    #[test]
+    fn test_delete_session() {
+        let mut config = Config::default();
+        let err = config.delete_session("../config").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid session name `../config`");
+        assert!(config.delete_session("..\\config").is_err());
+    }
+
+    #[test]
+    fn test_autosave_session() {
+        let dir = TestDir::new("autosave");
+        let lock_path = dir.join("chat.lock");
+        let mut config = Config::default();
+        assert_eq!(lock_session(&lock_path).unwrap(), None);
+        config.session_lock = Some(lock_path.clone());
+        assert_eq!(config.autosave_session().unwrap(), None);
+        assert!(!lock_path.exists());
+        assert!(config.session_lock.is_none());
+
+        // A session that is unchanged, read-only or not to be saved is left alone
+        let mut session = Session::new(&config, "chat");
+        config.session = Some(session.clone());
+        assert_eq!(config.autosave_session().unwrap(), None);
+        assert!(config.session.is_none());
+        session.dirty = true;
+        session.read_only = true;
+        config.session = Some(session);
+        assert_eq!(config.autosave_session().unwrap(), None);
+        assert!(config.session.is_none());
+    }
+}
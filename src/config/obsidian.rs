@@ -0,0 +1,148 @@
+use super::session::Session;
+
+use crate::client::MessageRole;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use std::{
+    fs::{create_dir_all, read_to_string, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Export of sessions as notes of an Obsidian vault.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ObsidianConfig {
+    pub notes_dir: Option<String>,
+    pub folder: String,
+    pub daily_note_format: String,
+    pub auto_export: bool,
+}
+
+impl Default for ObsidianConfig {
+    fn default() -> Self {
+        Self {
+            notes_dir: None,
+            folder: "aichat".into(),
+            daily_note_format: "%Y-%m-%d".into(),
+            auto_export: false,
+        }
+    }
+}
+
+/// Write the session as a note with frontmatter, then link it from the daily note.
+/// Exporting the same session on the same day again updates the note.
+pub fn export_session(
+    session: &Session,
+    config: &ObsidianConfig,
+    time: DateTime<Local>,
+) -> Result<PathBuf> {
+    let Some(notes_dir) = &config.notes_dir else {
+        bail!("No notes directory, set `obsidian.notes_dir` in the config");
+    };
+    if session.is_empty() {
+        bail!("No messages in session '{}'", session.name());
+    }
+    let notes_dir = Path::new(notes_dir);
+    let daily_note = time.format(&config.daily_note_format).to_string();
+    let title = format!("{daily_note} {}", sanitize_name(session.name()));
+
+    let dir = notes_dir.join(&config.folder);
+    create_dir_all(&dir).with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    let note_path = dir.join(format!("{title}.md"));
+    std::fs::write(&note_path, render_note(session, &daily_note, time))
+        .with_context(|| format!("Failed to write '{}'", note_path.display()))?;
+
+    let daily_path = notes_dir.join(format!("{daily_note}.md"));
+    let link = format!("[[{title}]]");
+    let linked = read_to_string(&daily_path)
+        .map(|v| v.contains(&link))
+        .unwrap_or_default();
+    if !linked {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&daily_path)
+            .with_context(|| format!("Failed to open '{}'", daily_path.display()))?;
+        writeln!(file, "- {} {link}", time.format("%H:%M"))
+            .with_context(|| format!("Failed to write '{}'", daily_path.display()))?;
+    }
+    Ok(note_path)
+}
+
+fn render_note(session: &Session, daily_note: &str, time: DateTime<Local>) -> String {
+    let mut lines = vec![
+        "---".to_string(),
+        format!("session: {}", yaml_string(session.name())),
+        format!("model: {}", yaml_string(session.model())),
+        format!(
+            "date: {}",
+            time.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+        ),
+        format!("daily: \"[[{daily_note}]]\""),
+        "tags: [aichat]".into(),
+        "---".into(),
+        String::new(),
+    ];
+    for message in session.messages() {
+        let text = message.content.render_input(|v| v.to_string());
+        let heading = match message.role {
+            MessageRole::System => "## System",
+            MessageRole::User => "## User",
+            MessageRole::Assistant => "## Assistant",
+        };
+        lines.push(heading.into());
+        lines.push(String::new());
+        lines.push(text.trim().to_string());
+        lines.push(String::new());
+    }
+    lines.join("\n")
+}
+
+fn yaml_string(value: &str) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// Obsidian doesn't allow these characters in note names.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '[' | ']' | '#' | '^' | '|' | '\\' | '/' | ':' => '-',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Input, InputContext};
+    use crate::utils::TestDir;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_export_session() {
+        let dir = TestDir::new("obsidian");
+        let config = ObsidianConfig {
+            notes_dir: Some(dir.display().to_string()),
+            ..Default::default()
+        };
+        let mut session = Session::new(&Config::default(), "rust/help");
+        let input = Input::from_str("hello", InputContext::default());
+        session.add_message(&input, "hi there").unwrap();
+        let time = Local.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
+
+        let path = export_session(&session, &config, time).unwrap();
+        assert_eq!(path, dir.join("aichat/2024-05-06 rust-help.md"));
+        let note = read_to_string(&path).unwrap();
+        assert!(note.starts_with("---\nsession: \"rust/help\"\n"));
+        assert!(note.contains("daily: \"[[2024-05-06]]\"\n"));
+        assert!(note.contains("## User\n\nhello\n\n## Assistant\n\nhi there\n"));
+
+        export_session(&session, &config, time).unwrap();
+        let daily = read_to_string(dir.join("2024-05-06.md")).unwrap();
+        assert_eq!(daily, "- 07:08 [[2024-05-06 rust-help]]\n");
+    }
+}
@@ -6,6 +6,7 @@ use crate::{
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 pub const TEMP_ROLE: &str = "%%";
 pub const SHELL_ROLE: &str = "%shell%";
@@ -20,6 +21,11 @@ pub struct Role {
     pub prompt: String,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub functions: Vec<String>,
+    /// The JSON schema the answers must conform to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
 }
 
 impl Role {
@@ -29,6 +35,8 @@ impl Role {
             prompt: prompt.into(),
             temperature: None,
             top_p: None,
+            functions: vec![],
+            schema: None,
         }
     }
 
@@ -67,6 +75,8 @@ Output plain text only, without any markdown formatting."#
             ),
             temperature: None,
             top_p: None,
+            functions: vec![],
+            schema: None,
         }
     }
 
@@ -80,6 +90,8 @@ APPLY MARKDOWN formatting when possible."#
                 .into(),
             temperature: None,
             top_p: None,
+            functions: vec![],
+            schema: None,
         }
     }
 
@@ -91,6 +103,8 @@ If there is a lack of details, provide most logical solution, without requesting
                 .into(),
             temperature: None,
             top_p: None,
+            functions: vec![],
+            schema: None,
         }
     }
 
@@ -127,15 +141,6 @@ If there is a lack of details, provide most logical solution, without requesting
         }
     }
 
-    pub fn echo_messages(&self, input: &Input) -> String {
-        let input_markdown = input.render();
-        if self.embedded() {
-            self.prompt.replace(INPUT_PLACEHOLDER, &input_markdown)
-        } else {
-            format!("{}\n\n{}", self.prompt, input.render())
-        }
-    }
-
     pub fn build_messages(&self, input: &Input) -> Vec<Message> {
         let mut content = input.to_message_content();
 
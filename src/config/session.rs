@@ -1,15 +1,19 @@
 use super::input::resolve_data_url;
-use super::{Config, Input, Model};
+use super::{Config, Input, Model, SessionRetention};
 
-use crate::client::{Message, MessageContent, MessageRole};
+use crate::client::{CompletionDetails, Message, MessageContent, MessageRole, Usage};
 use crate::render::MarkdownRender;
+use crate::utils::{backup_paths, write_file_atomic, Locale};
 
 use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use std::fs::{self, read_to_string};
-use std::path::Path;
+use std::fs::{self, read_to_string, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
 
 pub const TEMP_SESSION_NAME: &str = "temp";
 
@@ -27,6 +31,8 @@ pub struct Session {
     #[serde(default)]
     compressed_messages: Vec<Message>,
     compress_threshold: Option<usize>,
+    #[serde(default, skip_serializing_if = "Usage::is_empty")]
+    usage: Usage,
     #[serde(skip)]
     pub name: String,
     #[serde(skip)]
@@ -37,6 +43,20 @@ pub struct Session {
     pub compressing: bool,
     #[serde(skip)]
     pub model: Model,
+    /// Opened while another aichat holds its lock, so it is never written.
+    #[serde(skip)]
+    pub read_only: bool,
+    #[serde(skip)]
+    synced: Option<SyncPoint>,
+}
+
+/// The session file as last read or written by this process, to tell the turns added by
+/// another process since then.
+#[derive(Debug, Clone)]
+struct SyncPoint {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    messages_len: usize,
 }
 
 impl Session {
@@ -49,12 +69,15 @@ impl Session {
             messages: vec![],
             compressed_messages: vec![],
             compress_threshold: None,
+            usage: Usage::default(),
             data_urls: Default::default(),
             name: name.to_string(),
             path: None,
             dirty: false,
             compressing: false,
             model: config.model.clone(),
+            read_only: false,
+            synced: None,
         }
     }
 
@@ -66,6 +89,7 @@ impl Session {
 
         session.name = name.to_string();
         session.path = Some(path.display().to_string());
+        session.mark_synced(path);
 
         Ok(session)
     }
@@ -78,6 +102,10 @@ impl Session {
         &self.model_id
     }
 
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
     pub fn temperature(&self) -> Option<f64> {
         self.temperature
     }
@@ -90,6 +118,15 @@ impl Session {
         self.save_session
     }
 
+    /// The tokens and cost of the completions of the session, saved with it.
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    pub fn add_usage(&mut self, details: &CompletionDetails) {
+        self.usage.add(details);
+    }
+
     pub fn need_compress(&self, current_compress_threshold: usize) -> bool {
         let threshold = self
             .compress_threshold
@@ -97,8 +134,13 @@ impl Session {
         threshold >= 1000 && self.tokens() > threshold
     }
 
+    /// Whether there are messages beyond the `keep_turns` latest turns to summarize.
+    pub fn can_compress(&self, keep_turns: usize) -> bool {
+        self.compress_boundary(keep_turns) > 0
+    }
+
     pub fn tokens(&self) -> usize {
-        self.model.total_tokens(&self.messages)
+        self.model.count_tokens(&self.messages)
     }
 
     pub fn user_messages_len(&self) -> usize {
@@ -137,7 +179,7 @@ impl Session {
         Ok(output)
     }
 
-    pub fn info(&self, render: &mut MarkdownRender) -> Result<String> {
+    pub fn info(&self, render: &mut MarkdownRender, locale: &Locale) -> Result<String> {
         let mut items = vec![];
 
         if let Some(path) = &self.path {
@@ -162,9 +204,15 @@ impl Session {
         }
 
         if let Some(max_input_tokens) = self.model.max_input_tokens {
-            items.push(("max_input_tokens", max_input_tokens.to_string()));
+            items.push((
+                "max_input_tokens",
+                locale.format_count(max_input_tokens as u64),
+            ));
         }
 
+        let (tokens, _) = self.tokens_and_percent();
+        items.push(("total_tokens", locale.format_count(tokens as u64)));
+
         let mut lines: Vec<String> = items
             .iter()
             .map(|(name, value)| format!("{name:<20}{value}"))
@@ -172,32 +220,175 @@ impl Session {
 
         if !self.is_empty() {
             lines.push("".into());
-            let resolve_url_fn = |url: &str| resolve_data_url(&self.data_urls, url.to_string());
+            lines.extend(self.render_messages(&self.messages, render));
+        }
 
-            for message in &self.messages {
-                match message.role {
-                    MessageRole::System => {
-                        lines.push(render.render(&message.content.render_input(resolve_url_fn)));
-                    }
-                    MessageRole::Assistant => {
-                        if let MessageContent::Text(text) = &message.content {
-                            lines.push(render.render(text));
-                        }
-                        lines.push("".into());
-                    }
-                    MessageRole::User => {
-                        lines.push(format!(
-                            "{}）{}",
-                            self.name,
-                            message.content.render_input(resolve_url_fn)
-                        ));
-                    }
+        let output = lines.join("\n");
+        Ok(output)
+    }
+
+    pub fn history(&self, show_compressed: bool, render: &mut MarkdownRender) -> Result<String> {
+        if self.is_empty() {
+            bail!("No messages in session '{}'", self.name)
+        }
+        let mut lines = vec![];
+        if show_compressed && !self.compressed_messages.is_empty() {
+            lines.push(format!(
+                "# Compressed messages ({})\n",
+                self.compressed_messages.len()
+            ));
+            lines.extend(self.render_messages(&self.compressed_messages, render));
+            lines.push("# Current messages\n".into());
+        }
+        lines.extend(self.render_messages(&self.messages, render));
+        Ok(lines.join("\n").trim_end().to_string())
+    }
+
+    /// List every message with its tokens, its age in turns, whether it's compressed, and
+    /// whether the next request sends it.
+    pub fn inspect(
+        &self,
+        compress_threshold: usize,
+        keep_turns: usize,
+        locale: &Locale,
+    ) -> Result<String> {
+        if self.is_empty() {
+            bail!("No messages in session '{}'", self.name)
+        }
+        let rows = self.inspect_rows(compress_threshold, keep_turns);
+        let mut lines = vec![format!(
+            "{:>3}  {:<9}  {:>7}  {:<12}  {:<11}  {:<11}  text",
+            "#", "role", "tokens", "age", "status", "next"
+        )];
+        for (i, row) in rows.iter().enumerate() {
+            let age = match row.turns_ago {
+                0 => "last turn".to_string(),
+                1 => "1 turn ago".to_string(),
+                n => format!("{n} turns ago"),
+            };
+            let mut text = row.message.content.to_text().replace('\n', " ");
+            if text.chars().count() > 40 {
+                text = format!("{}…", text.chars().take(39).collect::<String>());
+            }
+            lines.push(format!(
+                "{:>3}  {:<9}  {:>7}  {:<12}  {:<11}  {:<11}  {text}",
+                i + 1,
+                role_name(&row.message.role),
+                locale.format_count(row.tokens as u64),
+                age,
+                row.status,
+                row.next.as_str(),
+            ));
+        }
+        let sent: Vec<Message> = rows
+            .iter()
+            .filter(|v| v.next == Next::Sent)
+            .map(|v| v.message.clone())
+            .collect();
+        let mut next = format!(
+            "{} tokens",
+            locale.format_count(self.model.count_tokens(&sent) as u64)
+        );
+        if let Some(max_input_tokens) = self.model.max_input_tokens {
+            next.push_str(&format!(
+                " of {}",
+                locale.format_count(max_input_tokens as u64)
+            ));
+        }
+        next.push_str(", plus the new input");
+        lines.push("".into());
+        lines.push(format!("{:<20}{next}", "next_request"));
+        if rows.iter().any(|v| v.next == Next::OverBudget) {
+            lines.push(format!(
+                "{:<20}exceeded, `.clear messages` or compress the session",
+                "max_input_tokens"
+            ));
+        }
+        if self.need_compress(compress_threshold) {
+            lines.push(format!(
+                "{:<20}reached, the `to compress` messages are summarized first",
+                "compress_threshold"
+            ));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn inspect_rows(&self, compress_threshold: usize, keep_turns: usize) -> Vec<InspectRow<'_>> {
+        let compressed_len = self.compressed_messages.len();
+        let boundary = if self.need_compress(compress_threshold) {
+            self.compress_boundary(keep_turns)
+        } else {
+            0
+        };
+        let mut rows: Vec<InspectRow> = self
+            .compressed_messages
+            .iter()
+            .chain(self.messages.iter())
+            .enumerate()
+            .map(|(i, message)| {
+                let (status, next) = if i < compressed_len {
+                    // With only the summary left, the last compressed turn is sent along
+                    let sent =
+                        self.messages.len() == 1 && compressed_len >= 2 && i + 2 >= compressed_len;
+                    ("compressed", if sent { Next::Sent } else { Next::Dropped })
+                } else if i - compressed_len < boundary {
+                    ("to compress", Next::Dropped)
+                } else if i == compressed_len && compressed_len > 0 && message.role.is_system() {
+                    ("summary", Next::Sent)
+                } else {
+                    ("-", Next::Sent)
+                };
+                InspectRow {
+                    message,
+                    tokens: self.model.messages_tokens(std::slice::from_ref(message)),
+                    turns_ago: 0,
+                    status,
+                    next,
+                }
+            })
+            .collect();
+        let mut turns = 0;
+        let mut total = 0;
+        let max_input_tokens = self.model.max_input_tokens.unwrap_or(usize::MAX);
+        for row in rows.iter_mut().rev() {
+            row.turns_ago = turns;
+            if row.message.role.is_user() {
+                turns += 1;
+            }
+            if row.next == Next::Sent {
+                total += row.tokens;
+                if total >= max_input_tokens {
+                    row.next = Next::OverBudget;
                 }
             }
         }
+        rows
+    }
 
-        let output = lines.join("\n");
-        Ok(output)
+    fn render_messages(&self, messages: &[Message], render: &mut MarkdownRender) -> Vec<String> {
+        let mut lines = vec![];
+        let resolve_url_fn = |url: &str| resolve_data_url(&self.data_urls, url.to_string());
+        for message in messages {
+            match message.role {
+                MessageRole::System => {
+                    lines.push(render.render(&message.content.render_input(resolve_url_fn)));
+                }
+                MessageRole::Assistant => {
+                    if let MessageContent::Text(text) = &message.content {
+                        lines.push(render.render(text));
+                    }
+                    lines.push("".into());
+                }
+                MessageRole::User => {
+                    lines.push(format!(
+                        "{}）{}",
+                        self.name,
+                        message.content.render_input(resolve_url_fn)
+                    ));
+                }
+            }
+        }
+        lines
     }
 
     pub fn tokens_and_percent(&self) -> (usize, f32) {
@@ -250,21 +441,102 @@ impl Session {
         Ok(())
     }
 
-    pub fn compress(&mut self, prompt: String) {
+    /// Index of the first message that stays uncompressed, so the last `keep_turns` turns are kept.
+    fn compress_boundary(&self, keep_turns: usize) -> usize {
+        let mut boundary = self.messages.len();
+        let mut turns = 0;
+        while turns < keep_turns && boundary > 0 {
+            boundary -= 1;
+            if self.messages[boundary].role.is_user() {
+                turns += 1;
+            }
+        }
+        boundary
+    }
+
+    pub fn compress_transcript(&self, keep_turns: usize) -> String {
+        let boundary = self.compress_boundary(keep_turns);
+        self.messages[..boundary]
+            .iter()
+            .map(|message| {
+                format!(
+                    "{}: {}",
+                    role_name(&message.role),
+                    message.content.to_text()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n")
+    }
+
+    pub fn compress(&mut self, prompt: String, keep_turns: usize) {
+        let boundary = self.compress_boundary(keep_turns);
+        // The kept turns are all there is, a summary of nothing would only be put before them
+        if boundary == 0 {
+            return;
+        }
+        let kept = self.messages.split_off(boundary);
         self.compressed_messages.append(&mut self.messages);
         self.messages.push(Message {
             role: MessageRole::System,
             content: MessageContent::Text(prompt),
         });
+        self.messages.extend(kept);
         self.dirty = true;
+        // The messages were rewritten, there is no telling which turns are new anymore
+        self.synced = None;
+    }
+
+    /// Remember the state of the session file, so a new session saves over it like a loaded one.
+    pub fn mark_synced(&mut self, session_path: &Path) {
+        self.synced = Some(SyncPoint {
+            path: session_path.to_path_buf(),
+            modified: fs::metadata(session_path).and_then(|v| v.modified()).ok(),
+            messages_len: self.messages.len(),
+        });
+    }
+
+    /// Put the turns added here since the last sync after the ones another process saved to the
+    /// file in the meantime.
+    fn merge_from_disk(&mut self, session_path: &Path) -> Result<()> {
+        let Some(synced) = &self.synced else {
+            return Ok(());
+        };
+        if synced.path != session_path {
+            return Ok(());
+        }
+        let modified = fs::metadata(session_path).and_then(|v| v.modified()).ok();
+        if modified.is_none() || modified == synced.modified {
+            return Ok(());
+        }
+        let messages_len = synced.messages_len.min(self.messages.len());
+        let disk = Self::load(&self.name, session_path)?;
+        let mut messages = disk.messages;
+        messages.extend(self.messages.drain(messages_len..));
+        self.messages = messages;
+        let mut compressed_messages = disk.compressed_messages;
+        if compressed_messages.is_empty() {
+            compressed_messages = std::mem::take(&mut self.compressed_messages);
+        }
+        self.compressed_messages = compressed_messages;
+        self.data_urls.extend(disk.data_urls);
+        Ok(())
     }
 
-    pub fn save(&mut self, session_path: &Path) -> Result<()> {
+    /// Save the session, keeping `backups` previous versions of the file.
+    pub fn save(&mut self, session_path: &Path, backups: usize) -> Result<()> {
+        if self.read_only {
+            bail!(
+                "Session '{}' is read-only, it is open in another aichat",
+                self.name
+            );
+        }
+        self.merge_from_disk(session_path)?;
         self.path = Some(session_path.display().to_string());
 
         let content = serde_yaml::to_string(&self)
             .with_context(|| format!("Failed to serde session {}", self.name))?;
-        fs::write(session_path, content).with_context(|| {
+        write_file_atomic(session_path, &content, backups).with_context(|| {
             format!(
                 "Failed to write session {} to {}",
                 self.name,
@@ -273,6 +545,7 @@ impl Session {
         })?;
 
         self.dirty = false;
+        self.mark_synced(session_path);
 
         Ok(())
     }
@@ -320,11 +593,7 @@ impl Session {
         self.compressed_messages.clear();
         self.data_urls.clear();
         self.dirty = true;
-    }
-
-    pub fn echo_messages(&self, input: &Input) -> String {
-        let messages = self.build_emssages(input);
-        serde_yaml::to_string(&messages).unwrap_or_else(|_| "Unable to echo message".into())
+        self.synced = None;
     }
 
     pub fn build_emssages(&self, input: &Input) -> Vec<Message> {
@@ -349,3 +618,377 @@ impl Session {
         messages
     }
 }
+
+struct InspectRow<'a> {
+    message: &'a Message,
+    tokens: usize,
+    /// The turns that came after the message
+    turns_ago: usize,
+    status: &'static str,
+    next: Next,
+}
+
+/// Whether the next request sends a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Next {
+    Sent,
+    Dropped,
+    /// Sent, yet beyond `max_input_tokens`, counting from the newest message
+    OverBudget,
+}
+
+impl Next {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Next::Sent => "yes",
+            Next::Dropped => "no",
+            Next::OverBudget => "over budget",
+        }
+    }
+}
+
+fn role_name(role: &MessageRole) -> &'static str {
+    match role {
+        MessageRole::System => "system",
+        MessageRole::Assistant => "assistant",
+        MessageRole::User => "user",
+    }
+}
+
+/// Take the lock of a session for this process. Returns the pid of the live aichat that holds
+/// it instead, a lock left by a process that is gone is taken over.
+pub fn lock_session(lock_path: &Path) -> Result<Option<u32>> {
+    if let Some(dir) = lock_path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create session_dir '{}'", dir.display()))?;
+    }
+    let read_pid = || {
+        read_to_string(lock_path)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+    };
+    for _ in 0..MAX_LOCK_ATTEMPTS {
+        // Only one process can create the file, the others find the pid of the winner in it
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(lock_path)
+        {
+            Ok(mut file) => {
+                file.write_all(process::id().to_string().as_bytes())
+                    .with_context(|| format!("Failed to write lock {}", lock_path.display()))?;
+                return Ok(None);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to create lock {}", lock_path.display()))
+            }
+        }
+        match read_pid() {
+            Some(pid) if pid == process::id() => return Ok(None),
+            Some(pid) if is_process_alive(pid) => return Ok(Some(pid)),
+            Some(pid) => {
+                // Left by a process that is gone, unless another one took it over meanwhile
+                if read_pid() == Some(pid) {
+                    let _ = fs::remove_file(lock_path);
+                }
+            }
+            // Just created by another process, which is about to write its pid
+            None => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+    // An empty lock that stays empty is left by a process that crashed right after creating it
+    let _ = fs::remove_file(lock_path);
+    bail!(
+        "Failed to take the lock {}, please try again",
+        lock_path.display()
+    )
+}
+
+/// Tries to take a lock that is held by a process that is gone or still being written.
+const MAX_LOCK_ATTEMPTS: usize = 10;
+
+pub fn unlock_session(lock_path: &Path) {
+    let owned = read_to_string(lock_path)
+        .map(|v| v.trim() == process::id().to_string())
+        .unwrap_or_default();
+    if owned {
+        let _ = fs::remove_file(lock_path);
+    }
+}
+
+/// Whether a running aichat, this one included, has the session open.
+pub fn is_session_locked(lock_path: &Path) -> bool {
+    read_to_string(lock_path)
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .is_some_and(is_process_alive)
+}
+
+/// Remove the session file with its backups and its lock, once no running aichat has it open.
+pub fn remove_session_files(path: &Path) -> Result<()> {
+    let lock_path = path.with_extension("lock");
+    let paths = backup_paths(path)
+        .into_iter()
+        .chain([path.to_path_buf(), lock_path])
+        .filter(|v| v.exists());
+    for path in paths {
+        fs::remove_file(&path).with_context(|| format!("Failed to delete {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// A saved session, its size including its backups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionFile {
+    pub name: String,
+    pub modified: SystemTime,
+    pub size: u64,
+}
+
+/// The sessions beyond the limits of `retention`, keeping the most recently saved ones.
+pub fn prunable_sessions(
+    mut sessions: Vec<SessionFile>,
+    retention: &SessionRetention,
+    now: SystemTime,
+) -> Vec<SessionFile> {
+    sessions.sort_by_key(|v| std::cmp::Reverse(v.modified));
+    let max_age = retention
+        .max_age_days
+        .map(|v| Duration::from_secs(v * 24 * 60 * 60));
+    let max_size = retention.max_size_mb.map(|v| v * 1024 * 1024);
+    let mut total_size = 0;
+    let mut kept = 0;
+    let mut prunable = vec![];
+    for session in sessions {
+        let age = now.duration_since(session.modified).unwrap_or_default();
+        let prune = retention.max_sessions.is_some_and(|v| kept >= v)
+            || max_age.is_some_and(|v| age > v)
+            || max_size.is_some_and(|v| total_size + session.size > v);
+        if prune {
+            prunable.push(session);
+        } else {
+            kept += 1;
+            total_size += session.size;
+        }
+    }
+    prunable
+}
+
+#[cfg(target_os = "linux")]
+fn is_process_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_process_alive(pid: u32) -> bool {
+    process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(process::Stdio::null())
+        .status()
+        .map(|v| v.success())
+        .unwrap_or(true)
+}
+
+#[cfg(windows)]
+fn is_process_alive(pid: u32) -> bool {
+    process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {pid}"), "/NH"])
+        .output()
+        .map(|v| String::from_utf8_lossy(&v.stdout).contains(&pid.to_string()))
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    fn text_message(role: MessageRole, text: &str) -> Message {
+        Message {
+            role,
+            content: MessageContent::Text(text.into()),
+        }
+    }
+
+    #[test]
+    fn test_compress_keep_turns() {
+        let mut session = Session::new(&Config::default(), "test");
+        session.messages = vec![
+            text_message(MessageRole::User, "q1"),
+            text_message(MessageRole::Assistant, "a1"),
+            text_message(MessageRole::User, "q2"),
+            text_message(MessageRole::Assistant, "a2"),
+        ];
+        assert_eq!(session.compress_transcript(1), "user: q1\n\nassistant: a1");
+        session.compress("summary".into(), 1);
+        let texts: Vec<String> = session
+            .messages
+            .iter()
+            .map(|v| v.content.to_text())
+            .collect();
+        assert_eq!(texts, ["summary", "q2", "a2"]);
+        assert_eq!(session.compressed_messages.len(), 2);
+
+        // Nothing older than the kept turns, nothing to compress
+        assert!(!session.can_compress(2));
+        session.compress("summary again".into(), 2);
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(session.compressed_messages.len(), 2);
+    }
+
+    #[test]
+    fn test_inspect_rows() {
+        let mut session = Session::new(&Config::default(), "test");
+        session.messages = vec![
+            text_message(MessageRole::User, "q1"),
+            text_message(MessageRole::Assistant, "a1"),
+            text_message(MessageRole::User, "q2"),
+            text_message(MessageRole::Assistant, "a2"),
+            text_message(MessageRole::User, "q3"),
+            text_message(MessageRole::Assistant, "a3"),
+        ];
+        session.compress("summary".into(), 2);
+        let rows = session.inspect_rows(0, 1);
+        let view: Vec<(&str, usize, &str)> = rows
+            .iter()
+            .map(|v| (v.status, v.turns_ago, v.next.as_str()))
+            .collect();
+        assert_eq!(
+            view,
+            [
+                ("compressed", 2, "no"),
+                ("compressed", 2, "no"),
+                ("summary", 2, "yes"),
+                ("-", 1, "yes"),
+                ("-", 1, "yes"),
+                ("-", 0, "yes"),
+                ("-", 0, "yes"),
+            ]
+        );
+
+        // Room for the last turn only
+        let tokens = session.model.messages_tokens(&session.messages[3..]);
+        session.model.max_input_tokens = Some(tokens + 1);
+        let rows = session.inspect_rows(0, 1);
+        assert_eq!(rows[5].next, Next::Sent);
+        assert_eq!(rows[4].next, Next::OverBudget);
+    }
+
+    #[test]
+    fn test_merge_on_save() {
+        let dir = TestDir::new("session");
+        let path = dir.join("chat.yaml");
+        let mut first = Session::new(&Config::default(), "chat");
+        first.messages = vec![
+            text_message(MessageRole::User, "q1"),
+            text_message(MessageRole::Assistant, "a1"),
+        ];
+        first.save(&path, 0).unwrap();
+
+        let mut second = Session::load("chat", &path).unwrap();
+        first.messages.push(text_message(MessageRole::User, "q2"));
+        first
+            .messages
+            .push(text_message(MessageRole::Assistant, "a2"));
+        // Make sure the mtime moves even on coarse filesystems
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        first.save(&path, 0).unwrap();
+        second.messages.push(text_message(MessageRole::User, "q3"));
+        second
+            .messages
+            .push(text_message(MessageRole::Assistant, "a3"));
+        second.save(&path, 0).unwrap();
+
+        let texts: Vec<String> = Session::load("chat", &path)
+            .unwrap()
+            .messages
+            .iter()
+            .map(|v| v.content.to_text())
+            .collect();
+        assert_eq!(texts, ["q1", "a1", "q2", "a2", "q3", "a3"]);
+
+        second.read_only = true;
+        assert!(second.save(&path, 0).is_err());
+    }
+
+    #[test]
+    fn test_lock_session() {
+        let dir = TestDir::new("lock");
+        let path = dir.join("chat.lock");
+        assert_eq!(lock_session(&path).unwrap(), None);
+        assert_eq!(read_to_string(&path).unwrap(), process::id().to_string());
+        // A lock left by a process that is gone is taken over
+        fs::write(&path, u32::MAX.to_string()).unwrap();
+        assert_eq!(lock_session(&path).unwrap(), None);
+        assert_eq!(read_to_string(&path).unwrap(), process::id().to_string());
+        // The lock of a live process is not taken
+        fs::write(&path, "1").unwrap();
+        assert_eq!(lock_session(&path).unwrap(), Some(1));
+        fs::write(&path, process::id().to_string()).unwrap();
+        unlock_session(&path);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_remove_session_files() {
+        let dir = TestDir::new("remove");
+        let path = dir.join("chat.yaml");
+        for name in [
+            "chat.yaml",
+            "chat.yaml.1",
+            "chat.yaml.2",
+            "chat.lock",
+            "other.yaml",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        remove_session_files(&path).unwrap();
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|v| v.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["other.yaml"]);
+    }
+
+    #[test]
+    fn test_prunable_sessions() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let session = |name: &str, days: u32, size: u64| SessionFile {
+            name: name.into(),
+            modified: now - day * days,
+            size,
+        };
+        let sessions = vec![
+            session("old", 100, 10),
+            session("new", 0, 10),
+            session("big", 2, 3 * 1024 * 1024),
+            session("mid", 1, 10),
+        ];
+        let names = |retention: SessionRetention| -> Vec<String> {
+            prunable_sessions(sessions.clone(), &retention, now)
+                .into_iter()
+                .map(|v| v.name)
+                .collect()
+        };
+        assert!(names(SessionRetention::default()).is_empty());
+        let retention = SessionRetention {
+            max_sessions: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(names(retention), ["big", "old"]);
+        let retention = SessionRetention {
+            max_age_days: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(names(retention), ["old"]);
+        let retention = SessionRetention {
+            max_size_mb: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(names(retention), ["big"]);
+    }
+}
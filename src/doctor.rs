@@ -0,0 +1,369 @@
+use crate::client::{find_client_endpoint, init_client, list_models, Client, HttpError};
+use crate::config::{Config, WorkingMode};
+use crate::utils::{
+    get_env_name, is_clipboard_available, is_dumb_terminal, is_no_color, which, ColorDepth,
+};
+
+use anyhow::Result;
+use is_terminal::IsTerminal;
+use nu_ansi_term::Color;
+use parking_lot::RwLock;
+use std::{
+    env, fs,
+    io::stdout,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// The outcome of one check, with how to fix it unless it passed.
+#[derive(Debug)]
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warn(name: &str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Warn,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn fail(name: &str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Fail,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn render(&self, colored: bool) -> String {
+        let (symbol, color) = match self.status {
+            Status::Ok => ("✓", Color::Green),
+            Status::Warn => ("⚠", Color::Yellow),
+            Status::Fail => ("✗", Color::Red),
+        };
+        let symbol = if colored {
+            color.paint(symbol).to_string()
+        } else {
+            symbol.to_string()
+        };
+        let mut output = format!("{symbol} {}: {}", self.name, self.detail);
+        if let Some(fix) = &self.fix {
+            output.push_str(&format!("\n  → {fix}"));
+        }
+        output
+    }
+}
+
+/// Check the setup of aichat, print each check with how to fix it, and return whether none
+/// has failed.
+pub async fn run() -> Result<bool> {
+    let mut checks = vec![];
+    let (check, config) = check_config();
+    checks.push(check);
+    checks.extend(check_dirs()?);
+    checks.push(check_clipboard());
+    let editor = match &config {
+        Some(config) => config.buffer_editor(),
+        None => env::var("VISUAL").ok().or_else(|| env::var("EDITOR").ok()),
+    };
+    checks.push(check_editor(editor));
+    checks.push(check_terminal());
+    if let Some(config) = &config {
+        checks.extend(check_providers(config).await);
+    }
+
+    let colored = stdout().is_terminal() && !is_no_color() && !is_dumb_terminal();
+    for check in &checks {
+        println!("{}", check.render(colored));
+    }
+    Ok(checks.iter().all(|v| v.status != Status::Fail))
+}
+
+fn check_config() -> (Check, Option<Config>) {
+    let name = "Config";
+    let path = match Config::config_file() {
+        Ok(path) => path,
+        Err(err) => {
+            let fix = format!("Set {} to a valid dir", get_env_name("config_dir"));
+            return (Check::fail(name, format!("{err:#}"), fix), None);
+        }
+    };
+    if !path.exists() && env::var(get_env_name("platform")).is_err() {
+        let check = Check::warn(
+            name,
+            format!("no config at {}", path.display()),
+            "Run `aichat` to create one",
+        );
+        return (check, None);
+    }
+    match Config::init(WorkingMode::Command) {
+        Ok(config) => (Check::ok(name, path.display().to_string()), Some(config)),
+        Err(err) => {
+            let fix = format!("Fix {}, see config.example.yaml", path.display());
+            (Check::fail(name, format!("{err:#}"), fix), None)
+        }
+    }
+}
+
+fn check_dirs() -> Result<Vec<Check>> {
+    let config_dir = Config::config_dir()?;
+    let mut checks = vec![];
+    if !config_dir.exists() {
+        checks.push(Check::warn(
+            "Config dir",
+            format!("no {}", config_dir.display()),
+            "Run `aichat` to create it",
+        ));
+        return Ok(checks);
+    }
+    checks.push(check_writable("Config dir", &config_dir));
+    let sessions_dir = Config::sessions_dir()?;
+    if sessions_dir.exists() {
+        checks.push(check_writable("Sessions dir", &sessions_dir));
+    }
+    Ok(checks)
+}
+
+fn check_writable(name: &str, dir: &Path) -> Check {
+    let probe = dir.join(format!(".doctor-{}", std::process::id()));
+    match fs::write(&probe, "").and_then(|_| fs::remove_file(&probe)) {
+        Ok(_) => Check::ok(name, dir.display().to_string()),
+        Err(err) => Check::fail(
+            name,
+            format!("{} isn't writable ({err})", dir.display()),
+            format!(
+                "Fix the permissions of {}, or set {} to another dir",
+                dir.display(),
+                get_env_name("config_dir")
+            ),
+        ),
+    }
+}
+
+fn check_clipboard() -> Check {
+    let name = "Clipboard";
+    if is_clipboard_available() {
+        return Check::ok(name, "available");
+    }
+    let fix = if cfg!(target_os = "linux") {
+        "Run a graphical session, or install xclip (X11) or wl-clipboard (Wayland)"
+    } else {
+        "`.copy` and pasting images won't work on this platform"
+    };
+    Check::warn(name, "not available", fix)
+}
+
+fn check_editor(editor: Option<String>) -> Check {
+    let name = "Editor";
+    let Some(editor) = editor.filter(|v| !v.trim().is_empty()) else {
+        return Check::warn(
+            name,
+            "none for `.edit` and Ctrl+O",
+            "Set `buffer_editor` in the config, or $EDITOR",
+        );
+    };
+    let program = editor.split_whitespace().next().unwrap_or_default();
+    let found = if program.contains(std::path::MAIN_SEPARATOR) {
+        Some(PathBuf::from(program)).filter(|v| v.is_file())
+    } else {
+        which(program)
+    };
+    match found {
+        Some(_) => Check::ok(name, editor),
+        None => Check::fail(
+            name,
+            format!("'{program}' isn't in PATH"),
+            "Install it, or set `buffer_editor` or $EDITOR to an installed editor",
+        ),
+    }
+}
+
+fn check_terminal() -> Check {
+    let name = "Terminal";
+    if is_dumb_terminal() {
+        return Check::warn(
+            name,
+            "TERM=dumb, no colors, spinner or live markdown",
+            "Run aichat in a terminal emulator, or set TERM, e.g. to xterm-256color",
+        );
+    }
+    if !stdout().is_terminal() {
+        return Check::ok(name, "stdout isn't a terminal, the output is plain");
+    }
+    if is_no_color() {
+        return Check::ok(name, "colors off by NO_COLOR");
+    }
+    let colors = match ColorDepth::detect() {
+        ColorDepth::Ansi16 => "16 colors",
+        ColorDepth::Ansi256 => "256 colors",
+        ColorDepth::TrueColor => "true color",
+    };
+    Check::ok(name, colors)
+}
+
+/// Fetch the models of every client with its first model, which checks the network and the
+/// api key at once.
+async fn check_providers(config: &Config) -> Vec<Check> {
+    let mut checks = vec![];
+    let mut client_names: Vec<&str> = vec![];
+    for model in list_models(config) {
+        let client_name = model.client_name.as_str();
+        if client_names.contains(&client_name) {
+            continue;
+        }
+        client_names.push(client_name);
+        let name = format!("Client '{client_name}'");
+        if config.offline_mode && !config.is_local_model(model) {
+            checks.push(Check::ok(&name, "skipped in offline mode"));
+            continue;
+        }
+        let client = match config
+            .fallback_config(&model.id())
+            .and_then(|v| init_client(&Arc::new(RwLock::new(v))))
+        {
+            Ok(client) => client,
+            Err(err) => {
+                checks.push(Check::fail(
+                    &name,
+                    format!("{err:#}"),
+                    "Fix the client in the config",
+                ));
+                continue;
+            }
+        };
+        let api_base = find_client_endpoint(config, client_name).and_then(|(_, v)| v);
+        checks.push(check_provider(&name, client_name, api_base, client.as_ref()).await);
+    }
+    if checks.is_empty() {
+        checks.push(Check::warn(
+            "Clients",
+            "no models",
+            "Add a client to the config, see config.example.yaml",
+        ));
+    }
+    checks
+}
+
+async fn check_provider(
+    name: &str,
+    client_name: &str,
+    api_base: Option<String>,
+    client: &dyn Client,
+) -> Check {
+    let ret = match tokio::time::timeout(PROVIDER_TIMEOUT, client.fetch_models()).await {
+        Ok(ret) => ret,
+        Err(_) => {
+            return Check::fail(
+                name,
+                format!("no answer in {}s", PROVIDER_TIMEOUT.as_secs()),
+                "Check `api_base`, the proxy and the network",
+            )
+        }
+    };
+    let err = match ret {
+        Ok(models) => return Check::ok(name, format!("reachable, {} models", models.len())),
+        Err(err) => err,
+    };
+    if let Some(err) = err.chain().find_map(|v| v.downcast_ref::<HttpError>()) {
+        return match err.status {
+            401 | 403 => Check::fail(
+                name,
+                format!("the api key was rejected ({})", err.status),
+                format!(
+                    "Set `api_key` of the client or ${}_API_KEY",
+                    client_name.to_ascii_uppercase()
+                ),
+            ),
+            // The api has no route to list the models, yet it's reachable
+            404 | 405 | 501 => Check::ok(name, "reachable, doesn't list its models"),
+            status => Check::warn(
+                name,
+                format!("the models request failed ({status})"),
+                "Check `api_base` and the other settings of the client",
+            ),
+        };
+    }
+    if err.chain().any(|v| v.is::<reqwest::Error>()) {
+        return Check::fail(
+            name,
+            format!("unreachable ({:#})", err.root_cause()),
+            "Check `api_base`, the proxy and the network",
+        );
+    }
+    if err.to_string().contains("doesn't list its models") {
+        return check_api_base(name, api_base, client).await;
+    }
+    Check::fail(name, format!("{err:#}"), "Fix the client in the config")
+}
+
+/// For clients that don't list their models, any answer of `api_base` is enough.
+async fn check_api_base(name: &str, api_base: Option<String>, client: &dyn Client) -> Check {
+    let Some(api_base) = api_base else {
+        return Check::ok(name, "configured, doesn't list its models to check further");
+    };
+    let ret = match client.build_client() {
+        Ok(http_client) => {
+            tokio::time::timeout(PROVIDER_TIMEOUT, http_client.get(&api_base).send()).await
+        }
+        Err(err) => return Check::fail(name, format!("{err:#}"), "Fix the client in the config"),
+    };
+    match ret {
+        Ok(Ok(_)) => Check::ok(name, format!("{api_base} is reachable")),
+        Ok(Err(err)) => Check::fail(
+            name,
+            format!("{api_base} is unreachable ({err})"),
+            "Check `api_base`, the proxy and the network",
+        ),
+        Err(_) => Check::fail(
+            name,
+            format!(
+                "{api_base} didn't answer in {}s",
+                PROVIDER_TIMEOUT.as_secs()
+            ),
+            "Check `api_base`, the proxy and the network",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_check() {
+        let check = Check::ok("Editor", "vim");
+        assert_eq!(check.render(false), "✓ Editor: vim");
+        let check = Check::warn("Clipboard", "not available", "Install xclip");
+        assert_eq!(
+            check.render(false),
+            "⚠ Clipboard: not available\n  → Install xclip"
+        );
+    }
+}
@@ -0,0 +1,582 @@
+use crate::client::init_client;
+use crate::config::{GlobalConfig, Input, InputContext};
+use crate::utils::{extract_block, validate_schema};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use futures_util::{stream, StreamExt};
+use is_terminal::IsTerminal;
+use nu_ansi_term::Color;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{fs, io::stdout, path::Path, sync::Arc};
+
+const GRADE_PROMPT: &str = r#"You are grading the answer of an AI assistant against a rubric.
+
+Rubric:
+__RUBRIC__
+
+Question:
+__QUESTION__
+
+Answer:
+__ANSWER__
+
+Reply with PASS or FAIL on the first line, followed by a one-sentence reason."#;
+
+const JUDGE_PROMPT: &str = r#"Compare two answers of an AI assistant to the same question.
+
+Question:
+__QUESTION__
+
+First answer:
+__FIRST__
+
+Second answer:
+__SECOND__
+
+Reply with FIRST, SECOND or TIE on the first line, followed by a one-sentence reason."#;
+
+/// A set of test prompts loaded from the file given to `--eval`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct EvalSuite {
+    /// Models to test, defaults to the current model
+    models: Vec<String>,
+    /// Roles to test, defaults to the current role
+    roles: Vec<String>,
+    /// Model that grades the `rubric` assertions, defaults to the tested model
+    grader_model: Option<String>,
+    cases: Vec<EvalCase>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EvalCase {
+    name: String,
+    prompt: String,
+    #[serde(
+        default,
+        deserialize_with = "serde_yaml::with::singleton_map_recursive::deserialize"
+    )]
+    assertions: Vec<Assertion>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Assertion {
+    Contains(String),
+    NotContains(String),
+    Regex(String),
+    JsonSchema(Value),
+    Rubric(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct EvalResult {
+    model: String,
+    role: Option<String>,
+    case: String,
+    passed: bool,
+    failures: Vec<String>,
+    output: String,
+}
+
+impl EvalSuite {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read eval file at '{}'", path.display()))?;
+        let suite: Self = serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid eval file at '{}'", path.display()))?;
+        if suite.cases.is_empty() {
+            bail!("No cases in eval file at '{}'", path.display());
+        }
+        Ok(suite)
+    }
+}
+
+/// Run every case against every model/role combination, print a pass/fail table and
+/// optionally write a JSON report. Returns whether all cases passed.
+pub async fn run(config: &GlobalConfig, path: &str, report: Option<&str>) -> Result<bool> {
+    let suite = EvalSuite::load(Path::new(path))?;
+    let (models, roles) = {
+        let config = config.read();
+        let models = match suite.models.is_empty() {
+            true => vec![config.model.id()],
+            false => suite.models.clone(),
+        };
+        let roles: Vec<Option<String>> = match suite.roles.is_empty() {
+            true => vec![config.role.as_ref().map(|v| v.name.clone())],
+            false => suite.roles.iter().cloned().map(Some).collect(),
+        };
+        (models, roles)
+    };
+    let mut jobs = vec![];
+    for model in &models {
+        for role in &roles {
+            let case_config = eval_config(config, model, role.as_deref())?;
+            let grader_model = suite.grader_model.as_deref().unwrap_or(model);
+            let grader_config = eval_config(config, grader_model, None)?;
+            for case in &suite.cases {
+                jobs.push((
+                    model,
+                    role,
+                    case_config.clone(),
+                    grader_config.clone(),
+                    case,
+                ));
+            }
+        }
+    }
+    let concurrency = config.read().concurrency.unwrap_or(1);
+    let results: Vec<EvalResult> = stream::iter(jobs)
+        .map(
+            |(model, role, case_config, grader_config, case)| async move {
+                let result = run_case(&case_config, &grader_config, case).await;
+                let (passed, failures, output) = match result {
+                    Ok((output, failures)) => (failures.is_empty(), failures, output),
+                    Err(err) => (false, vec![format!("Error: {err}")], String::new()),
+                };
+                EvalResult {
+                    model: model.clone(),
+                    role: role.clone(),
+                    case: case.name.clone(),
+                    passed,
+                    failures,
+                    output,
+                }
+            },
+        )
+        .buffered(concurrency)
+        .collect()
+        .await;
+    print_table(&results);
+    let passed = results.iter().filter(|v| v.passed).count();
+    if let Some(report) = report {
+        let data = json!({
+            "file": path,
+            "passed": passed,
+            "failed": results.len() - passed,
+            "results": results,
+        });
+        fs::write(report, serde_json::to_string_pretty(&data)?)
+            .with_context(|| format!("Failed to write eval report to '{report}'"))?;
+    }
+    Ok(passed == results.len())
+}
+
+/// A standalone config for a model and role, so that the current session is not involved.
+fn eval_config(config: &GlobalConfig, model: &str, role: Option<&str>) -> Result<GlobalConfig> {
+    let mut config = config.read().clone();
+    config.session = None;
+    config.role = None;
+    config.set_model(model)?;
+    if let Some(role) = role {
+        config.set_role(role)?;
+    }
+    Ok(Arc::new(RwLock::new(config)))
+}
+
+async fn run_case(
+    config: &GlobalConfig,
+    grader_config: &GlobalConfig,
+    case: &EvalCase,
+) -> Result<(String, Vec<String>)> {
+    let output = send(config, &case.prompt).await?;
+    let mut failures = vec![];
+    for assertion in &case.assertions {
+        let ret = match assertion {
+            Assertion::Rubric(rubric) => {
+                let prompt = GRADE_PROMPT
+                    .replace("__RUBRIC__", rubric)
+                    .replace("__QUESTION__", &case.prompt)
+                    .replace("__ANSWER__", &output);
+                send(grader_config, &prompt)
+                    .await
+                    .and_then(|grade| check_grade(&grade))
+            }
+            _ => check_assertion(assertion, &output),
+        };
+        if let Err(err) = ret {
+            failures.push(err.to_string());
+        }
+    }
+    Ok((output, failures))
+}
+
+async fn send(config: &GlobalConfig, text: &str) -> Result<String> {
+    let input = Input::from_str(text, InputContext::new(config.read().role.clone(), false));
+    let client = init_client(config)?;
+    let (output, _) = client.send_message(input).await?;
+    Ok(output)
+}
+
+fn check_assertion(assertion: &Assertion, output: &str) -> Result<()> {
+    match assertion {
+        Assertion::Contains(text) => {
+            if !output.contains(text.as_str()) {
+                bail!("Expected to contain '{text}'");
+            }
+        }
+        Assertion::NotContains(text) => {
+            if output.contains(text.as_str()) {
+                bail!("Expected not to contain '{text}'");
+            }
+        }
+        Assertion::Regex(pattern) => {
+            let re =
+                Regex::new(pattern).map_err(|err| anyhow!("Invalid regex '{pattern}', {err}"))?;
+            if !re.is_match(output).unwrap_or_default() {
+                bail!("Expected to match /{pattern}/");
+            }
+        }
+        Assertion::JsonSchema(schema) => {
+            let text = match output.trim_start().starts_with("```") {
+                true => extract_block(output),
+                false => output.trim().to_string(),
+            };
+            let value: Value =
+                serde_json::from_str(&text).map_err(|err| anyhow!("Invalid JSON, {err}"))?;
+            let errors = validate_schema(schema, &value, "$");
+            if !errors.is_empty() {
+                bail!("Schema mismatch, {}", errors.join("; "));
+            }
+        }
+        Assertion::Rubric(_) => {}
+    }
+    Ok(())
+}
+
+fn check_grade(grade: &str) -> Result<()> {
+    let grade = grade.trim();
+    let verdict = grade
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .find(|v| !v.is_empty())
+        .unwrap_or_default()
+        .to_uppercase();
+    match verdict.as_str() {
+        "PASS" => Ok(()),
+        "FAIL" => {
+            let reason = grade.lines().skip(1).collect::<Vec<_>>().join(" ");
+            bail!("Rubric failed, {}", reason.trim())
+        }
+        _ => bail!("Unexpected grade '{grade}'"),
+    }
+}
+
+/// Compare the outputs of two roles over the same inputs, optionally judged by a model.
+pub async fn run_ab(
+    config: &GlobalConfig,
+    roles: &[String],
+    inputs_path: &str,
+    judge: Option<Option<&str>>,
+) -> Result<()> {
+    let [role_a, role_b] = roles else {
+        bail!("Usage: --ab <ROLE_A> <ROLE_B> --ab-inputs <FILE>");
+    };
+    let inputs = load_ab_inputs(Path::new(inputs_path))?;
+    let model = config.read().model.id();
+    let config_a = eval_config(config, &model, Some(role_a))?;
+    let config_b = eval_config(config, &model, Some(role_b))?;
+    let judge_config = match judge {
+        Some(judge_model) => Some(eval_config(config, judge_model.unwrap_or(&model), None)?),
+        None => None,
+    };
+    let colored = stdout().is_terminal();
+    let concurrency = config.read().concurrency.unwrap_or(1);
+    let (config_a, config_b, judge_config) = (&config_a, &config_b, &judge_config);
+    let mut cases = stream::iter(inputs.iter().enumerate())
+        .map(|(i, input)| async move {
+            let outputs = match (send(config_a, input).await, send(config_b, input).await) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(err), _) | (_, Err(err)) => return (i, input, Err(err)),
+            };
+            let verdict = match judge_config {
+                Some(judge_config) => {
+                    Some(judge_outputs(judge_config, input, &outputs, i % 2 == 1).await)
+                }
+                None => None,
+            };
+            (i, input, Ok((outputs, verdict)))
+        })
+        .buffered(concurrency);
+    let mut verdicts = vec![];
+    while let Some((i, input, ret)) = cases.next().await {
+        println!(
+            "## Case {}: {}",
+            i + 1,
+            input.lines().next().unwrap_or_default()
+        );
+        let ((output_a, output_b), verdict) = match ret {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error: {err}\n");
+                continue;
+            }
+        };
+        match verdict {
+            Some(Ok(verdict)) => {
+                println!("Winner: {}", verdict.label(role_a, role_b));
+                verdicts.push(verdict);
+            }
+            Some(Err(err)) => println!("Judge error: {err}"),
+            None => {}
+        }
+        if output_a == output_b {
+            println!("Identical outputs\n");
+        } else {
+            println!("--- {role_a}\n+++ {role_b}");
+            for line in diff_lines(&output_a, &output_b) {
+                match (line.chars().next(), colored) {
+                    (Some('-'), true) => println!("{}", Color::Red.paint(line)),
+                    (Some('+'), true) => println!("{}", Color::Green.paint(line)),
+                    _ => println!("{line}"),
+                }
+            }
+            println!();
+        }
+    }
+    if judge_config.is_some() {
+        let total = verdicts.len().max(1) as f64;
+        let count = |v: Verdict| verdicts.iter().filter(|x| **x == v).count();
+        let (wins_a, wins_b, ties) = (count(Verdict::A), count(Verdict::B), count(Verdict::Tie));
+        println!(
+            "{role_a}: {wins_a} wins ({:.0}%), {role_b}: {wins_b} wins ({:.0}%), {ties} ties, out of {} judged cases",
+            wins_a as f64 * 100.0 / total,
+            wins_b as f64 * 100.0 / total,
+            verdicts.len()
+        );
+    }
+    Ok(())
+}
+
+/// Let the judge pick the better output, `swapped` shows the second output first to offset the
+/// position bias of the judge over the cases.
+async fn judge_outputs(
+    config: &GlobalConfig,
+    input: &str,
+    (output_a, output_b): &(String, String),
+    swapped: bool,
+) -> Result<Verdict> {
+    let (first, second) = match swapped {
+        true => (output_b, output_a),
+        false => (output_a, output_b),
+    };
+    let prompt = JUDGE_PROMPT
+        .replace("__QUESTION__", input)
+        .replace("__FIRST__", first)
+        .replace("__SECOND__", second);
+    let verdict = parse_verdict(&send(config, &prompt).await?)?;
+    Ok(if swapped { verdict.swap() } else { verdict })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Verdict {
+    A,
+    B,
+    Tie,
+}
+
+impl Verdict {
+    fn swap(self) -> Self {
+        match self {
+            Verdict::A => Verdict::B,
+            Verdict::B => Verdict::A,
+            Verdict::Tie => Verdict::Tie,
+        }
+    }
+
+    fn label<'a>(&self, role_a: &'a str, role_b: &'a str) -> &'a str {
+        match self {
+            Verdict::A => role_a,
+            Verdict::B => role_b,
+            Verdict::Tie => "tie",
+        }
+    }
+}
+
+/// Each line is either a JSON string or an object with an `input` field.
+fn load_ab_inputs(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read inputs at '{}'", path.display()))?;
+    let mut inputs = vec![];
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(line)
+            .with_context(|| format!("Invalid JSON at line {} of '{}'", i + 1, path.display()))?;
+        match value.as_str().or_else(|| value["input"].as_str()) {
+            Some(input) => inputs.push(input.to_string()),
+            None => bail!("Missing input at line {} of '{}'", i + 1, path.display()),
+        }
+    }
+    if inputs.is_empty() {
+        bail!("No inputs in '{}'", path.display());
+    }
+    Ok(inputs)
+}
+
+fn parse_verdict(text: &str) -> Result<Verdict> {
+    let word = text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .find(|v| !v.is_empty())
+        .unwrap_or_default()
+        .to_uppercase();
+    match word.as_str() {
+        "1" | "FIRST" => Ok(Verdict::A),
+        "2" | "SECOND" => Ok(Verdict::B),
+        "TIE" => Ok(Verdict::Tie),
+        _ => bail!("Unexpected verdict '{}'", text.trim()),
+    }
+}
+
+/// Line diff based on the longest common subsequence, prefixed like a unified diff.
+fn diff_lines(a: &str, b: &str) -> Vec<String> {
+    let a: Vec<&str> = a.lines().collect();
+    let b: Vec<&str> = b.lines().collect();
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut output = vec![];
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            output.push(format!("  {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            output.push(format!("- {}", a[i]));
+            i += 1;
+        } else {
+            output.push(format!("+ {}", b[j]));
+            j += 1;
+        }
+    }
+    output
+}
+
+fn print_table(results: &[EvalResult]) {
+    let colored = stdout().is_terminal();
+    let rows: Vec<[String; 3]> = results
+        .iter()
+        .map(|v| {
+            [
+                v.model.clone(),
+                v.role.clone().unwrap_or_else(|| "-".into()),
+                v.case.clone(),
+            ]
+        })
+        .collect();
+    let width = |i: usize, title: &str| {
+        rows.iter()
+            .map(|v| v[i].chars().count())
+            .chain([title.len()])
+            .max()
+            .unwrap_or_default()
+    };
+    let (model_width, role_width, case_width) =
+        (width(0, "MODEL"), width(1, "ROLE"), width(2, "CASE"));
+    println!(
+        "{:<model_width$}  {:<role_width$}  {:<case_width$}  RESULT",
+        "MODEL", "ROLE", "CASE"
+    );
+    for (row, result) in rows.iter().zip(results) {
+        let status = match (result.passed, colored) {
+            (true, true) => Color::Green.paint("PASS").to_string(),
+            (false, true) => Color::Red.paint("FAIL").to_string(),
+            (true, false) => "PASS".into(),
+            (false, false) => "FAIL".into(),
+        };
+        println!(
+            "{:<model_width$}  {:<role_width$}  {:<case_width$}  {status}",
+            row[0], row[1], row[2]
+        );
+        for failure in &result.failures {
+            println!("    - {failure}");
+        }
+    }
+    let passed = results.iter().filter(|v| v.passed).count();
+    println!("\n{passed} passed, {} failed", results.len() - passed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_suite() {
+        let suite: EvalSuite = serde_yaml::from_str(
+            r#"
+models: [openai:gpt-4o]
+cases:
+  - name: greet
+    prompt: Say hi
+    assertions:
+      - contains: hi
+      - not_contains: bye
+      - regex: '(?i)^h'
+      - json_schema: { type: object }
+      - rubric: Friendly
+"#,
+        )
+        .unwrap();
+        assert_eq!(suite.models, ["openai:gpt-4o"]);
+        assert_eq!(suite.cases[0].assertions.len(), 5);
+        assert!(matches!(
+            suite.cases[0].assertions[1],
+            Assertion::NotContains(_)
+        ));
+    }
+
+    #[test]
+    fn test_check_assertion() {
+        let check = |assertion, output| check_assertion(&assertion, output).is_ok();
+        assert!(check(Assertion::Contains("hi".into()), "oh hi"));
+        assert!(!check(Assertion::Contains("bye".into()), "oh hi"));
+        assert!(check(Assertion::NotContains("bye".into()), "oh hi"));
+        assert!(check(Assertion::Regex(r"^\d+$".into()), "42"));
+        assert!(!check(Assertion::Regex(r"^\d+$".into()), "x42"));
+        let schema = json!({"type": "object", "required": ["a"]});
+        assert!(check(
+            Assertion::JsonSchema(schema.clone()),
+            "```json\n{\"a\": 1}\n```"
+        ));
+        assert!(!check(Assertion::JsonSchema(schema), "not json"));
+    }
+
+    #[test]
+    fn test_check_grade() {
+        assert!(check_grade("PASS\nGood").is_ok());
+        assert!(check_grade("**Pass** - fine").is_ok());
+        assert_eq!(
+            check_grade("FAIL\nToo rude").unwrap_err().to_string(),
+            "Rubric failed, Too rude"
+        );
+        assert!(check_grade("Maybe").is_err());
+    }
+
+    #[test]
+    fn test_parse_verdict() {
+        assert_eq!(parse_verdict("FIRST\nclearer").unwrap(), Verdict::A);
+        assert_eq!(parse_verdict("**Second**").unwrap(), Verdict::B);
+        assert_eq!(parse_verdict("tie").unwrap(), Verdict::Tie);
+        assert!(parse_verdict("both").is_err());
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nx\nc\nd"),
+            ["  a", "- b", "+ x", "  c", "+ d"]
+        );
+        assert_eq!(diff_lines("", "a"), ["+ a"]);
+    }
+}
@@ -0,0 +1,295 @@
+use super::{get_str_arg, FunctionDeclaration};
+
+use anyhow::{anyhow, bail, Result};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Pow, Signed, ToPrimitive, Zero};
+use serde_json::{json, Value};
+
+pub const NAME: &str = "calculator";
+
+const MAX_EXPONENT: i64 = 10000;
+/// The most bits of the numerator and the denominator of any intermediate result.
+const MAX_BITS: u64 = 100_000;
+const MAX_DECIMAL_DIGITS: usize = 30;
+
+pub fn declaration() -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: NAME.into(),
+        description: "Evaluate an arithmetic expression exactly, with arbitrary precision. Supports + - * / % ^, parentheses, and decimal or scientific numbers such as 1.5e-3. Use it instead of doing arithmetic yourself.".into(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "The expression, e.g. (2^64 - 1) / 3",
+                }
+            },
+            "required": ["expression"],
+        }),
+    }
+}
+
+pub fn eval(arguments: &Value) -> Result<Value> {
+    let expression = get_str_arg(arguments, "expression")?;
+    let value = evaluate(expression)?;
+    let (decimal, exact) = format_decimal(&value, MAX_DECIMAL_DIGITS);
+    let output = if exact {
+        json!({ "result": decimal })
+    } else {
+        json!({ "result": format!("{decimal}..."), "fraction": value.to_string() })
+    };
+    Ok(output)
+}
+
+/// Evaluate the expression with exact rational arithmetic.
+pub fn evaluate(expression: &str) -> Result<BigRational> {
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+    };
+    let value = parser.expr()?;
+    if let Some(c) = parser.peek() {
+        bail!("Unexpected '{c}' at position {}", parser.pos + 1);
+    }
+    Ok(value)
+}
+
+/// Render the value as a decimal, cut after `max_digits` fraction digits when it doesn't terminate.
+pub fn format_decimal(value: &BigRational, max_digits: usize) -> (String, bool) {
+    let sign = if value.is_negative() { "-" } else { "" };
+    let value = value.abs();
+    let denom = value.denom();
+    let int_part = value.numer() / denom;
+    let mut rem = value.numer() % denom;
+    let mut digits = String::new();
+    let ten = BigInt::from(10);
+    while !rem.is_zero() && digits.len() < max_digits {
+        rem *= &ten;
+        digits.push_str(&(&rem / denom).to_string());
+        rem %= denom;
+    }
+    let exact = rem.is_zero();
+    if digits.is_empty() {
+        (format!("{sign}{int_part}"), exact)
+    } else {
+        (format!("{sign}{int_part}.{digits}"), exact)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<BigRational> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+            check_size(&value)?;
+        }
+    }
+
+    fn term(&mut self) -> Result<BigRational> {
+        let mut value = self.unary()?;
+        loop {
+            if self.peek() == Some('*') && self.chars.get(self.pos + 1) != Some(&'*') {
+                self.pos += 1;
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                let rhs = self.unary()?;
+                if rhs.is_zero() {
+                    bail!("Division by zero");
+                }
+                value /= rhs;
+            } else if self.eat('%') {
+                let rhs = self.unary()?;
+                if rhs.is_zero() {
+                    bail!("Division by zero");
+                }
+                value %= rhs;
+            } else {
+                return Ok(value);
+            }
+            check_size(&value)?;
+        }
+    }
+
+    fn unary(&mut self) -> Result<BigRational> {
+        if self.eat('-') {
+            Ok(-self.unary()?)
+        } else if self.eat('+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<BigRational> {
+        let base = self.atom()?;
+        let is_power = if self.eat('^') {
+            true
+        } else if self.peek() == Some('*') && self.chars.get(self.pos + 1) == Some(&'*') {
+            self.pos += 2;
+            true
+        } else {
+            false
+        };
+        if !is_power {
+            return Ok(base);
+        }
+        let exponent = self.unary()?;
+        if !exponent.is_integer() {
+            bail!("Only integer exponents are supported");
+        }
+        let exponent = exponent
+            .to_integer()
+            .to_i64()
+            .filter(|v| v.abs() <= MAX_EXPONENT)
+            .ok_or_else(|| anyhow!("Exponent out of range"))?;
+        if base.is_zero() && exponent < 0 {
+            bail!("Division by zero");
+        }
+        let bits = base.numer().bits().max(base.denom().bits());
+        if bits.saturating_mul(exponent.unsigned_abs()) > MAX_BITS {
+            bail!("Result out of range");
+        }
+        Ok(Pow::pow(base, exponent as i32))
+    }
+
+    fn atom(&mut self) -> Result<BigRational> {
+        if self.eat('(') {
+            let value = self.expr()?;
+            if !self.eat(')') {
+                bail!("Missing ')'");
+            }
+            return Ok(value);
+        }
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            let is_exponent_sign = (c == '-' || c == '+')
+                && self.pos > start
+                && matches!(self.chars[self.pos - 1], 'e' | 'E');
+            if c.is_ascii_digit()
+                || c == '.'
+                || c == '_'
+                || c == 'e'
+                || c == 'E'
+                || is_exponent_sign
+            {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if start == self.pos {
+            match self.peek() {
+                Some(c) => bail!("Unexpected '{c}' at position {}", self.pos + 1),
+                None => bail!("Unexpected end of expression"),
+            }
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        parse_number(&text)
+    }
+}
+
+/// Bound the results, so that an expression like `9^9999 * 9^9999 * ...` can't exhaust memory.
+fn check_size(value: &BigRational) -> Result<()> {
+    if value.numer().bits() > MAX_BITS || value.denom().bits() > MAX_BITS {
+        bail!("Result out of range");
+    }
+    Ok(())
+}
+
+/// Parse a decimal number like `1_000.25` or `6.02e23` into an exact rational.
+pub fn parse_number(text: &str) -> Result<BigRational> {
+    let err = || anyhow!("Invalid number '{text}'");
+    let text = text.replace('_', "");
+    let (mantissa, exponent) = match text.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().map_err(|_| err())?),
+        None => (text.as_str(), 0),
+    };
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if int_part.is_empty() && frac_part.is_empty()
+        || !int_part.chars().all(|c| c.is_ascii_digit())
+        || !frac_part.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(err());
+    }
+    let digits: BigInt = format!("{int_part}{frac_part}")
+        .parse()
+        .map_err(|_| err())?;
+    let scale = exponent - frac_part.len() as i64;
+    if scale.abs() > MAX_EXPONENT {
+        bail!("Exponent out of range");
+    }
+    let factor = Pow::pow(BigInt::from(10), scale.unsigned_abs() as u32);
+    let value = if scale >= 0 {
+        BigRational::from_integer(digits * factor)
+    } else {
+        BigRational::new(digits, factor)
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calc(expression: &str) -> String {
+        format_decimal(&evaluate(expression).unwrap(), MAX_DECIMAL_DIGITS).0
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(calc("1 + 2 * 3"), "7");
+        assert_eq!(calc("(1 + 2) * 3"), "9");
+        assert_eq!(calc("0.1 + 0.2"), "0.3");
+        assert_eq!(calc("2^64 - 1"), "18446744073709551615");
+        assert_eq!(calc("2 ** -2"), "0.25");
+        assert_eq!(calc("-2^2"), "-4");
+        assert_eq!(calc("1.5e3 / 4"), "375");
+        assert_eq!(calc("7 % 3"), "1");
+        assert_eq!(calc("1/3"), "0.333333333333333333333333333333");
+        assert!(evaluate("1/0").is_err());
+        assert!(evaluate("2^0.5").is_err());
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("(1").is_err());
+        assert!(evaluate("9^9^9^9").is_err());
+        assert!(evaluate("(9^9999)^9999").is_err());
+        assert!(evaluate("2^10000 * 2^10000 * 2^10000 * 2^10000 * 2^10000 * 2^10000 * 2^10000 * 2^10000 * 2^10000 * 2^10000 * 2^10000").is_err());
+        assert_eq!(calc("2^10000 / 2^9999"), "2");
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(
+            eval(&json!({"expression": "10 / 4"})).unwrap(),
+            json!({"result": "2.5"})
+        );
+        assert_eq!(
+            eval(&json!({"expression": "2 / 3"})).unwrap(),
+            json!({"result": "0.666666666666666666666666666666...", "fraction": "2/3"})
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use super::{
+    calculator::{evaluate, format_decimal},
+    get_str_arg, FunctionDeclaration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use num_rational::BigRational;
+use serde_json::{json, Value};
+
+pub const NAME: &str = "convert_units";
+
+const MAX_DECIMAL_DIGITS: usize = 15;
+const CURRENCY_NOTE: &str =
+    "Currency rates come from an offline table, they are approximate and may be outdated";
+
+/// Names of a unit and the factor converting it into the first unit of its dimension.
+type UnitTable = &'static [(&'static [&'static str], &'static str)];
+
+const UNITS: [(&str, UnitTable); 10] = [
+    (
+        "length",
+        &[
+            (&["m", "meter", "meters", "metre", "metres"], "1"),
+            (&["km", "kilometer", "kilometers"], "1000"),
+            (&["cm", "centimeter", "centimeters"], "0.01"),
+            (&["mm", "millimeter", "millimeters"], "0.001"),
+            (&["um", "µm", "micrometer", "micrometers"], "1e-6"),
+            (&["nm", "nanometer", "nanometers"], "1e-9"),
+            (&["in", "inch", "inches"], "0.0254"),
+            (&["ft", "foot", "feet"], "0.3048"),
+            (&["yd", "yard", "yards"], "0.9144"),
+            (&["mi", "mile", "miles"], "1609.344"),
+            (&["nmi", "nautical_mile", "nautical_miles"], "1852"),
+        ],
+    ),
+    (
+        "mass",
+        &[
+            (&["kg", "kilogram", "kilograms"], "1"),
+            (&["g", "gram", "grams"], "0.001"),
+            (&["mg", "milligram", "milligrams"], "1e-6"),
+            (&["t", "tonne", "tonnes"], "1000"),
+            (&["lb", "lbs", "pound", "pounds"], "0.45359237"),
+            (&["oz", "ounce", "ounces"], "0.028349523125"),
+            (&["st", "stone", "stones"], "6.35029318"),
+        ],
+    ),
+    (
+        "time",
+        &[
+            (&["s", "sec", "second", "seconds"], "1"),
+            (&["ms", "millisecond", "milliseconds"], "0.001"),
+            (&["min", "minute", "minutes"], "60"),
+            (&["h", "hr", "hour", "hours"], "3600"),
+            (&["d", "day", "days"], "86400"),
+            (&["week", "weeks"], "604800"),
+            (&["year", "years"], "31557600"),
+        ],
+    ),
+    (
+        "volume",
+        &[
+            (&["l", "L", "liter", "liters", "litre", "litres"], "1"),
+            (&["ml", "mL", "milliliter", "milliliters"], "0.001"),
+            (&["m3", "cubic_meter", "cubic_meters"], "1000"),
+            (&["gal", "gallon", "gallons"], "3.785411784"),
+            (&["qt", "quart", "quarts"], "0.946352946"),
+            (&["pt", "pint", "pints"], "0.473176473"),
+            (&["cup", "cups"], "0.2365882365"),
+            (&["floz", "fluid_ounce", "fluid_ounces"], "0.0295735295625"),
+        ],
+    ),
+    (
+        "area",
+        &[
+            (&["m2", "square_meter", "square_meters"], "1"),
+            (&["km2", "square_kilometer", "square_kilometers"], "1e6"),
+            (&["cm2", "square_centimeter", "square_centimeters"], "1e-4"),
+            (&["ha", "hectare", "hectares"], "10000"),
+            (&["acre", "acres"], "4046.8564224"),
+            (&["ft2", "square_foot", "square_feet"], "0.09290304"),
+            (&["in2", "square_inch", "square_inches"], "0.00064516"),
+            (&["mi2", "square_mile", "square_miles"], "2589988.110336"),
+        ],
+    ),
+    (
+        "speed",
+        &[
+            (&["m/s", "mps"], "1"),
+            (&["km/h", "kph", "kmh"], "1000/3600"),
+            (&["mph"], "1609.344/3600"),
+            (&["knot", "knots", "kn"], "1852/3600"),
+            (&["ft/s", "fps"], "0.3048"),
+        ],
+    ),
+    (
+        "data",
+        &[
+            (&["B", "byte", "bytes"], "1"),
+            (&["bit", "bits"], "1/8"),
+            (&["KB", "kB", "kilobyte", "kilobytes"], "1e3"),
+            (&["MB", "megabyte", "megabytes"], "1e6"),
+            (&["GB", "gigabyte", "gigabytes"], "1e9"),
+            (&["TB", "terabyte", "terabytes"], "1e12"),
+            (&["KiB", "kibibyte", "kibibytes"], "2^10"),
+            (&["MiB", "mebibyte", "mebibytes"], "2^20"),
+            (&["GiB", "gibibyte", "gibibytes"], "2^30"),
+            (&["TiB", "tebibyte", "tebibytes"], "2^40"),
+        ],
+    ),
+    (
+        "energy",
+        &[
+            (&["J", "joule", "joules"], "1"),
+            (&["kJ", "kilojoule", "kilojoules"], "1000"),
+            (&["cal", "calorie", "calories"], "4.184"),
+            (&["kcal", "kilocalorie", "kilocalories"], "4184"),
+            (&["Wh", "watt_hour", "watt_hours"], "3600"),
+            (&["kWh", "kilowatt_hour", "kilowatt_hours"], "3.6e6"),
+        ],
+    ),
+    (
+        "pressure",
+        &[
+            (&["Pa", "pascal", "pascals"], "1"),
+            (&["kPa", "kilopascal", "kilopascals"], "1000"),
+            (&["bar"], "1e5"),
+            (&["atm", "atmosphere", "atmospheres"], "101325"),
+            (&["psi"], "0.45359237 * 9.80665 / 0.00064516"),
+            (&["mmHg"], "133.322387415"),
+        ],
+    ),
+    (
+        "currency",
+        &[
+            (&["USD"], "1"),
+            (&["EUR"], "1.08"),
+            (&["GBP"], "1.27"),
+            (&["JPY"], "0.0067"),
+            (&["CNY"], "0.138"),
+            (&["INR"], "0.012"),
+            (&["CAD"], "0.73"),
+            (&["AUD"], "0.66"),
+            (&["CHF"], "1.12"),
+            (&["HKD"], "0.128"),
+            (&["SGD"], "0.74"),
+            (&["KRW"], "0.00073"),
+            (&["BRL"], "0.18"),
+            (&["MXN"], "0.055"),
+            (&["SEK"], "0.095"),
+            (&["NOK"], "0.093"),
+        ],
+    ),
+];
+
+const TEMPERATURE_UNITS: [(&[&str], Temperature); 3] = [
+    (&["C", "celsius", "°C"], Temperature::Celsius),
+    (&["F", "fahrenheit", "°F"], Temperature::Fahrenheit),
+    (&["K", "kelvin"], Temperature::Kelvin),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Temperature {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+pub fn declaration() -> FunctionDeclaration {
+    let units: Vec<String> = UNITS
+        .iter()
+        .map(|(dimension, units)| {
+            let names: Vec<&str> = units.iter().map(|(names, _)| names[0]).collect();
+            format!("{dimension}: {}", names.join(", "))
+        })
+        .chain(Some("temperature: C, F, K".to_string()))
+        .collect();
+    FunctionDeclaration {
+        name: NAME.into(),
+        description: format!(
+            "Convert a value between units of the same dimension, exactly and offline. Currency rates are approximate. Known units, {}.",
+            units.join("; ")
+        ),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "value": {
+                    "type": "string",
+                    "description": "The value to convert, may be an arithmetic expression",
+                },
+                "from": {
+                    "type": "string",
+                    "description": "The unit of the value, e.g. km",
+                },
+                "to": {
+                    "type": "string",
+                    "description": "The target unit, e.g. mi",
+                },
+            },
+            "required": ["value", "from", "to"],
+        }),
+    }
+}
+
+pub fn eval(arguments: &Value) -> Result<Value> {
+    let value = match &arguments["value"] {
+        Value::Number(value) => value.to_string(),
+        _ => get_str_arg(arguments, "value")?.to_string(),
+    };
+    let from = get_str_arg(arguments, "from")?;
+    let to = get_str_arg(arguments, "to")?;
+    let (value, dimension) = convert(&evaluate(&value)?, from, to)?;
+    let (decimal, exact) = format_decimal(&value, MAX_DECIMAL_DIGITS);
+    let result = if exact {
+        format!("{decimal} {to}")
+    } else {
+        format!("{decimal}... {to}")
+    };
+    let output = if dimension == "currency" {
+        json!({ "result": result, "note": CURRENCY_NOTE })
+    } else {
+        json!({ "result": result })
+    };
+    Ok(output)
+}
+
+fn convert(value: &BigRational, from: &str, to: &str) -> Result<(BigRational, &'static str)> {
+    if let (Some(from), Some(to)) = (find_temperature(from), find_temperature(to)) {
+        return Ok((from_kelvin(&to_kelvin(value, from)?, to)?, "temperature"));
+    }
+    let (from_dimension, from_factor) =
+        find_unit(from).ok_or_else(|| anyhow!("Unknown unit '{from}'"))?;
+    let (to_dimension, to_factor) = find_unit(to).ok_or_else(|| anyhow!("Unknown unit '{to}'"))?;
+    if from_dimension != to_dimension {
+        bail!("Cannot convert {from_dimension} '{from}' to {to_dimension} '{to}'");
+    }
+    let value = value * evaluate(from_factor)? / evaluate(to_factor)?;
+    Ok((value, from_dimension))
+}
+
+/// Look up a unit, exact spelling first so that e.g. `MB` and `mB` stay distinct.
+fn find_unit(name: &str) -> Option<(&'static str, &'static str)> {
+    let find = |matches: &dyn Fn(&str) -> bool| {
+        UNITS.iter().find_map(|(dimension, units)| {
+            units
+                .iter()
+                .find(|(names, _)| names.iter().any(|v| matches(v)))
+                .map(|(_, factor)| (*dimension, *factor))
+        })
+    };
+    find(&|v| v == name).or_else(|| find(&|v| v.eq_ignore_ascii_case(name)))
+}
+
+fn find_temperature(name: &str) -> Option<Temperature> {
+    TEMPERATURE_UNITS.iter().find_map(|(names, unit)| {
+        names
+            .iter()
+            .any(|v| v.eq_ignore_ascii_case(name))
+            .then_some(*unit)
+    })
+}
+
+fn to_kelvin(value: &BigRational, unit: Temperature) -> Result<BigRational> {
+    let value = match unit {
+        Temperature::Celsius => value + evaluate("273.15")?,
+        Temperature::Fahrenheit => {
+            (value - evaluate("32")?) * evaluate("5/9")? + evaluate("273.15")?
+        }
+        Temperature::Kelvin => value.clone(),
+    };
+    Ok(value)
+}
+
+fn from_kelvin(value: &BigRational, unit: Temperature) -> Result<BigRational> {
+    let value = match unit {
+        Temperature::Celsius => value - evaluate("273.15")?,
+        Temperature::Fahrenheit => {
+            (value - evaluate("273.15")?) * evaluate("9/5")? + evaluate("32")?
+        }
+        Temperature::Kelvin => value.clone(),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conv(value: &str, from: &str, to: &str) -> String {
+        eval(&json!({"value": value, "from": from, "to": to})).unwrap()["result"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_convert_units() {
+        assert_eq!(conv("1", "mi", "km"), "1.609344 km");
+        assert_eq!(conv("12", "in", "ft"), "1 ft");
+        assert_eq!(conv("100", "C", "F"), "212 F");
+        assert_eq!(conv("-40", "fahrenheit", "celsius"), "-40 celsius");
+        assert_eq!(conv("1", "GiB", "MiB"), "1024 MiB");
+        assert_eq!(conv("2 * 50", "km/h", "m/s"), "27.777777777777777... m/s");
+        assert!(eval(&json!({"value": "1", "from": "kg", "to": "m"})).is_err());
+        assert!(eval(&json!({"value": "1", "from": "foo", "to": "m"})).is_err());
+        assert_eq!(
+            eval(&json!({"value": 10, "from": "EUR", "to": "USD"})).unwrap()["note"],
+            CURRENCY_NOTE
+        );
+    }
+}
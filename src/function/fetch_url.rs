@@ -0,0 +1,319 @@
+use super::{get_str_arg, FunctionDeclaration};
+
+use crate::client::{resolve_proxy, set_proxy};
+use crate::config::FetchConfig;
+
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use reqwest::{redirect, Client, Url};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+pub const NAME: &str = "fetch_url";
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+const MAX_CACHE_ENTRIES: usize = 100;
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<String, (Instant, Value)>> = Mutex::new(HashMap::new());
+    static ref SKIP_ELEMENTS_RE: Regex =
+        Regex::new(r"(?is)<(script|style|noscript|svg|head)\b.*?</\1\s*>|<!--.*?-->").unwrap();
+    static ref BLOCK_TAG_RE: Regex =
+        Regex::new(r"(?i)</?(p|div|br|li|tr|h[1-6]|section|article|pre|table|ul|ol)\b[^>]*>")
+            .unwrap();
+    static ref TAG_RE: Regex = Regex::new(r"(?s)<[^>]*>").unwrap();
+    static ref SPACES_RE: Regex = Regex::new(r"[ \t\r\f\v]+").unwrap();
+    static ref BLANK_LINES_RE: Regex = Regex::new(r"\n\s*\n\s*").unwrap();
+}
+
+pub fn declaration() -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: NAME.into(),
+        description: "Fetch a web page or API over HTTP(S). HTML pages are returned as plain text, JSON as is. Only some domains may be allowed.".into(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "url": {
+                    "type": "string",
+                    "description": "The http or https URL",
+                },
+            },
+            "required": ["url"],
+        }),
+    }
+}
+
+pub async fn eval(
+    config: &FetchConfig,
+    proxy: &Option<String>,
+    no_proxy: &Option<String>,
+    arguments: &Value,
+) -> Result<Value> {
+    let url = get_str_arg(arguments, "url")?;
+    let url = Url::parse(url).map_err(|_| anyhow!("Invalid url '{url}'"))?;
+    check_url(config, &url)?;
+    let key = url.to_string();
+    let ttl = Duration::from_secs(config.cache_ttl);
+    if let Some((time, value)) = CACHE.lock().get(&key) {
+        if time.elapsed() < ttl {
+            return Ok(value.clone());
+        }
+    }
+    let value = fetch(config, proxy, no_proxy, url).await?;
+    let mut cache = CACHE.lock();
+    cache.retain(|_, (time, _)| time.elapsed() < ttl);
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        if let Some(oldest) = cache
+            .iter()
+            .min_by_key(|(_, (time, _))| *time)
+            .map(|(k, _)| k.clone())
+        {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(key, (Instant::now(), value.clone()));
+    Ok(value)
+}
+
+/// The redirects are followed one at a time, so that each target is checked like the first.
+async fn fetch(
+    config: &FetchConfig,
+    proxy: &Option<String>,
+    no_proxy: &Option<String>,
+    mut url: Url,
+) -> Result<Value> {
+    let mut redirects = 0;
+    let res = loop {
+        check_url(config, &url)?;
+        let mut builder = Client::builder()
+            .redirect(redirect::Policy::none())
+            .timeout(TIMEOUT);
+        if resolve_proxy(proxy).is_some() {
+            builder = set_proxy(builder, proxy, no_proxy)?;
+        }
+        // Connect to the addresses that were checked, a second lookup could return others
+        let addrs = check_host(config, &url).await?;
+        if let Some(host) = url.domain() {
+            builder = builder.resolve_to_addrs(host, &addrs);
+        }
+        let client = builder.build().with_context(|| "Failed to build client")?;
+        let res = client.get(url.clone()).send().await?;
+        let location = res
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok());
+        match location {
+            Some(location) if res.status().is_redirection() => {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    bail!("Too many redirects");
+                }
+                url = url
+                    .join(location)
+                    .map_err(|_| anyhow!("Invalid redirect to '{location}'"))?;
+            }
+            _ => break res,
+        }
+    };
+    let status = res.status();
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    let (body, truncated) = read_body(res, config.max_bytes).await?;
+    let body = String::from_utf8_lossy(&body);
+    let content = if content_type.contains("json") && !truncated {
+        serde_json::from_str(&body).unwrap_or_else(|_| Value::String(body.to_string()))
+    } else if content_type.contains("html") {
+        Value::String(html_to_text(&body))
+    } else {
+        Value::String(body.to_string())
+    };
+    Ok(json!({
+        "url": url.to_string(),
+        "status": status.as_u16(),
+        "content": content,
+        "truncated": truncated,
+    }))
+}
+
+async fn read_body(res: reqwest::Response, max_bytes: usize) -> Result<(Vec<u8>, bool)> {
+    let mut body = vec![];
+    let mut stream = res.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > max_bytes {
+            body.extend_from_slice(&chunk[..max_bytes - body.len()]);
+            return Ok((body, true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, false))
+}
+
+/// Allow http(s) URLs whose host matches `allow_domains` (any when empty) and not `deny_domains`.
+fn check_url(config: &FetchConfig, url: &Url) -> Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Only http and https URLs are supported");
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Missing host in '{url}'"))?
+        .to_lowercase();
+    let matches = |domains: &[String]| domains.iter().any(|v| match_domain(&host, v));
+    if matches(&config.deny_domains)
+        || (!config.allow_domains.is_empty() && !matches(&config.allow_domains))
+    {
+        bail!("Access to '{host}' is not allowed");
+    }
+    Ok(())
+}
+
+/// Resolve the host and refuse the private, loopback, link-local and metadata addresses, unless
+/// `allow_private` is set, so that a model can't reach the services of the local network.
+async fn check_host(config: &FetchConfig, url: &Url) -> Result<Vec<SocketAddr>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Missing host in '{url}'"))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .with_context(|| format!("Failed to resolve '{host}'"))?
+            .collect(),
+    };
+    if !config.allow_private {
+        if let Some(addr) = addrs.iter().find(|v| is_private_ip(&v.ip())) {
+            bail!(
+                "Access to '{host}' is not allowed, it's a private address {}",
+                addr.ip()
+            );
+        }
+    }
+    Ok(addrs)
+}
+
+fn is_private_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_ip(&IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local and link-local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+/// `example.com` and `*.example.com` both match the domain and its subdomains.
+fn match_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches("*.").to_lowercase();
+    host == domain || host.ends_with(&format!(".{domain}"))
+}
+
+fn html_to_text(html: &str) -> String {
+    let text = SKIP_ELEMENTS_RE.replace_all(html, "");
+    let text = BLOCK_TAG_RE.replace_all(&text, "\n");
+    let text = TAG_RE.replace_all(&text, "");
+    let text = decode_entities(&text);
+    let text = SPACES_RE.replace_all(&text, " ");
+    let text = BLANK_LINES_RE.replace_all(&text, "\n\n");
+    text.lines()
+        .map(|v| v.trim())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        let config = FetchConfig {
+            allow_domains: vec!["docs.rs".into(), "*.python.org".into()],
+            deny_domains: vec!["internal.docs.rs".into()],
+            ..Default::default()
+        };
+        let check = |url: &str| check_url(&config, &Url::parse(url).unwrap()).is_ok();
+        assert!(check("https://docs.rs/serde"));
+        assert!(check("https://docs.python.org/3/"));
+        assert!(check("http://python.org"));
+        assert!(!check("https://internal.docs.rs/"));
+        assert!(!check("https://notdocs.rs/"));
+        assert!(!check("https://example.com/"));
+        assert!(!check("file:///etc/passwd"));
+    }
+
+    #[tokio::test]
+    async fn test_check_host() {
+        let config = FetchConfig::default();
+        let check = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            let config = config.clone();
+            async move { check_host(&config, &url).await.is_ok() }
+        };
+        assert!(check("https://93.184.215.14/").await);
+        assert!(!check("http://127.0.0.1:8080/").await);
+        assert!(!check("http://localhost/").await);
+        assert!(!check("http://169.254.169.254/latest/meta-data/").await);
+        assert!(!check("http://10.0.0.1/").await);
+        assert!(!check("http://[::1]/").await);
+        assert!(!check("http://[::ffff:192.168.1.1]/").await);
+        assert!(!check("http://[fd00::1]/").await);
+        let config = FetchConfig {
+            allow_private: true,
+            ..Default::default()
+        };
+        let url = Url::parse("http://127.0.0.1:8080/").unwrap();
+        assert!(check_host(&config, &url).await.is_ok());
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = r#"<html><head><title>T</title><style>p{}</style></head>
+<body><h1>Title</h1><script>alert(1)</script><p>Hello&nbsp;<b>world</b> &amp; more</p>
+<ul><li>one</li><li>two</li></ul></body></html>"#;
+        assert_eq!(
+            html_to_text(html),
+            "Title\n\nHello world & more\n\none\n\ntwo"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use super::{get_str_arg, FunctionDeclaration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use inquire::Confirm;
+use serde_json::{json, Value};
+use std::{
+    fs::{create_dir_all, metadata, read_dir, read_to_string, write},
+    path::{Component, Path, PathBuf},
+};
+
+pub const READ_NAME: &str = "fs_read";
+pub const WRITE_NAME: &str = "fs_write";
+pub const LIST_NAME: &str = "fs_list";
+pub const SEARCH_NAME: &str = "fs_search";
+
+const MAX_READ_BYTES: u64 = 256 * 1024;
+const MAX_LIST_ENTRIES: usize = 1000;
+const MAX_SEARCH_MATCHES: usize = 200;
+const MAX_SEARCH_FILE_BYTES: u64 = 1024 * 1024;
+
+pub fn read_declaration() -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: READ_NAME.into(),
+        description: "Read a text file. Relative paths are resolved against the first allowed root directory.".into(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "The path of the file",
+                },
+            },
+            "required": ["path"],
+        }),
+    }
+}
+
+pub fn write_declaration() -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: WRITE_NAME.into(),
+        description: "Create or overwrite a text file with the given content, after the user confirms it. Relative paths are resolved against the first allowed root directory.".into(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "The path of the file",
+                },
+                "content": {
+                    "type": "string",
+                    "description": "The full new content of the file",
+                },
+            },
+            "required": ["path", "content"],
+        }),
+    }
+}
+
+pub fn list_declaration() -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: LIST_NAME.into(),
+        description: "List the entries of a directory. Without a path, the allowed root directories are listed.".into(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "The path of the directory",
+                },
+            },
+        }),
+    }
+}
+
+pub fn search_declaration() -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: SEARCH_NAME.into(),
+        description:
+            "Search the text files under a directory for lines matching a regular expression."
+                .into(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "pattern": {
+                    "type": "string",
+                    "description": "The regular expression",
+                },
+                "path": {
+                    "type": "string",
+                    "description": "The directory or file to search, defaults to the first allowed root directory",
+                },
+            },
+            "required": ["pattern"],
+        }),
+    }
+}
+
+pub fn read(roots: &[String], arguments: &Value) -> Result<Value> {
+    let path = resolve_path(roots, get_str_arg(arguments, "path")?)?;
+    let size = metadata(&path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?
+        .len();
+    if size > MAX_READ_BYTES {
+        bail!("The file is too large ({size} bytes), the limit is {MAX_READ_BYTES} bytes");
+    }
+    let content =
+        read_to_string(&path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    Ok(json!({ "path": path.display().to_string(), "content": content }))
+}
+
+pub fn write_file(roots: &[String], arguments: &Value) -> Result<Value> {
+    let path = resolve_path(roots, get_str_arg(arguments, "path")?)?;
+    let content = get_str_arg(arguments, "content")?;
+    let verb = if path.exists() { "Overwrite" } else { "Create" };
+    let ans = Confirm::new(&format!(
+        "{verb} '{}' with {} bytes?",
+        path.display(),
+        content.len()
+    ))
+    .with_default(false)
+    .prompt()?;
+    if !ans {
+        bail!("The user declined to write the file");
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .with_context(|| format!("Failed to create '{}'", parent.display()))?;
+    }
+    write(&path, content).with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(json!({ "path": path.display().to_string(), "written_bytes": content.len() }))
+}
+
+pub fn list(roots: &[String], arguments: &Value) -> Result<Value> {
+    let path = match arguments["path"].as_str() {
+        Some(path) => resolve_path(roots, path)?,
+        None => return Ok(json!({ "roots": canonical_roots(roots)? })),
+    };
+    let mut entries = vec![];
+    let mut truncated = false;
+    for entry in read_dir(&path).with_context(|| format!("Failed to list '{}'", path.display()))? {
+        if entries.len() >= MAX_LIST_ENTRIES {
+            truncated = true;
+            break;
+        }
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if metadata.is_dir() {
+            entries.push(json!({ "name": name, "type": "dir" }));
+        } else {
+            entries.push(json!({ "name": name, "type": "file", "size": metadata.len() }));
+        }
+    }
+    entries.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Ok(json!({ "path": path.display().to_string(), "entries": entries, "truncated": truncated }))
+}
+
+pub fn search(roots: &[String], arguments: &Value) -> Result<Value> {
+    let pattern = get_str_arg(arguments, "pattern")?;
+    let re = Regex::new(pattern).map_err(|err| anyhow!("Invalid pattern, {err}"))?;
+    let path = resolve_path(roots, arguments["path"].as_str().unwrap_or("."))?;
+    let mut matches = vec![];
+    search_path(&path, &re, &mut matches)?;
+    let truncated = matches.len() >= MAX_SEARCH_MATCHES;
+    Ok(json!({ "matches": matches, "truncated": truncated }))
+}
+
+fn search_path(path: &Path, re: &Regex, matches: &mut Vec<String>) -> Result<()> {
+    if matches.len() >= MAX_SEARCH_MATCHES {
+        return Ok(());
+    }
+    let metadata = metadata(path)?;
+    if metadata.is_dir() {
+        let mut entries: Vec<PathBuf> = read_dir(path)?
+            .filter_map(|v| v.ok().map(|v| v.path()))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            let hidden = entry
+                .file_name()
+                .is_some_and(|v| v.to_string_lossy().starts_with('.'));
+            // Skip hidden entries and symlinks, which may point outside of the roots
+            if hidden || entry.is_symlink() {
+                continue;
+            }
+            search_path(&entry, re, matches)?;
+        }
+    } else if metadata.len() <= MAX_SEARCH_FILE_BYTES {
+        let Ok(content) = read_to_string(path) else {
+            return Ok(());
+        };
+        for (i, line) in content.lines().enumerate() {
+            if matches.len() >= MAX_SEARCH_MATCHES {
+                break;
+            }
+            if let Ok(true) = re.is_match(line) {
+                matches.push(format!("{}:{}: {}", path.display(), i + 1, line));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn canonical_roots(roots: &[String]) -> Result<Vec<PathBuf>> {
+    if roots.is_empty() {
+        bail!("No directory is allowed, add some to `fs_roots` in the config");
+    }
+    roots
+        .iter()
+        .map(|root| {
+            Path::new(root)
+                .canonicalize()
+                .with_context(|| format!("Invalid fs root '{root}'"))
+        })
+        .collect()
+}
+
+/// Resolve the path against the roots and make sure it stays inside one of them,
+/// following symlinks of the existing part of the path.
+fn resolve_path(roots: &[String], path: &str) -> Result<PathBuf> {
+    let roots = canonical_roots(roots)?;
+    let path = roots[0].join(path);
+    let mut existing = path.as_path();
+    let mut rest = vec![];
+    // Unlike `exists`, a symlink to nowhere counts, it must not be taken for a new file
+    while existing.symlink_metadata().is_err() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => bail!("Invalid path '{}'", path.display()),
+        }
+    }
+    let Ok(mut resolved) = existing.canonicalize() else {
+        bail!(
+            "Access denied, '{}' is a broken symlink",
+            existing.display()
+        );
+    };
+    for name in rest.into_iter().rev() {
+        resolved.push(name);
+    }
+    let escaped = resolved
+        .components()
+        .any(|v| matches!(v, Component::ParentDir));
+    if escaped || !roots.iter().any(|root| resolved.starts_with(root)) {
+        bail!(
+            "Access denied, '{}' is outside of the allowed directories",
+            path.display()
+        );
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn test_resolve_path() {
+        let dir = TestDir::new("fs-test");
+        create_dir_all(dir.join("sub")).unwrap();
+        let roots = vec![dir.display().to_string()];
+        let root = dir.canonicalize().unwrap();
+        assert_eq!(resolve_path(&roots, "sub").unwrap(), root.join("sub"));
+        assert_eq!(
+            resolve_path(&roots, "sub/new/file.txt").unwrap(),
+            root.join("sub/new/file.txt")
+        );
+        assert!(resolve_path(&roots, "..").is_err());
+        assert!(resolve_path(&roots, "sub/../../x").is_err());
+        assert!(resolve_path(&roots, "/etc/passwd").is_err());
+        assert!(resolve_path(&[], "x").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_path_dangling_symlink() {
+        let root = TestDir::new("fs-link");
+        let outside = TestDir::new("fs-outside");
+        let target = outside.join("target.txt");
+        std::os::unix::fs::symlink(&target, root.join("link")).unwrap();
+        let roots = vec![root.display().to_string()];
+        assert!(resolve_path(&roots, "link").is_err());
+        assert!(resolve_path(&roots, "link/new.txt").is_err());
+        assert!(!target.exists());
+    }
+}
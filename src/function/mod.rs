@@ -0,0 +1,145 @@
+mod calculator;
+mod convert_units;
+mod fetch_url;
+mod fs;
+mod run_code;
+
+use crate::config::GlobalConfig;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Builtin functions that a role can enable with `functions: [...]`.
+pub const BUILTIN_FUNCTIONS: [&str; 8] = [
+    calculator::NAME,
+    convert_units::NAME,
+    fetch_url::NAME,
+    fs::READ_NAME,
+    fs::WRITE_NAME,
+    fs::LIST_NAME,
+    fs::SEARCH_NAME,
+    run_code::NAME,
+];
+
+/// Builtin functions that need the internet, left out in offline mode.
+pub const WEB_FUNCTIONS: [&str; 1] = [fetch_url::NAME];
+
+/// Builtin functions that ask the user for confirmation on the terminal.
+pub const INTERACTIVE_FUNCTIONS: [&str; 2] = [fs::WRITE_NAME, run_code::NAME];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDeclaration {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    /// A tool call whose arguments came as a JSON string.
+    pub fn new(id: String, name: String, arguments: &str) -> Result<Self> {
+        let arguments = if arguments.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(arguments).map_err(|_| {
+                anyhow::anyhow!("Invalid arguments of the tool call '{name}': {arguments}")
+            })?
+        };
+        Ok(Self {
+            id,
+            name,
+            arguments,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolResult {
+    pub call: ToolCall,
+    pub output: Value,
+}
+
+/// The tool calls the model made at once, with what it said and thought along with them.
+#[derive(Debug, Clone, Default)]
+pub struct ToolRound {
+    /// The text of the reply next to the calls
+    pub text: String,
+    /// The blocks of thinking the provider wants back as they were, e.g. the signed ones of Claude
+    pub thinking: Vec<Value>,
+    pub results: Vec<ToolResult>,
+}
+
+pub fn declare_functions(names: &[String]) -> Result<Vec<FunctionDeclaration>> {
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            calculator::NAME => Ok(calculator::declaration()),
+            convert_units::NAME => Ok(convert_units::declaration()),
+            fetch_url::NAME => Ok(fetch_url::declaration()),
+            fs::READ_NAME => Ok(fs::read_declaration()),
+            fs::WRITE_NAME => Ok(fs::write_declaration()),
+            fs::LIST_NAME => Ok(fs::list_declaration()),
+            fs::SEARCH_NAME => Ok(fs::search_declaration()),
+            run_code::NAME => Ok(run_code::declaration()),
+            _ => bail!(
+                "Unknown function '{name}', available functions: {}",
+                BUILTIN_FUNCTIONS.join(", ")
+            ),
+        })
+        .collect()
+}
+
+/// Run the tool calls requested by the model, errors are reported back to the model.
+pub async fn eval_tool_calls(config: &GlobalConfig, calls: Vec<ToolCall>) -> Vec<ToolResult> {
+    let (fs_roots, fetch_config, proxy, offline, run_code_timeout, run_code_network) = {
+        let config = config.read();
+        (
+            config.fs_roots.clone(),
+            config.fetch.clone(),
+            (config.proxy.clone(), config.no_proxy.clone()),
+            config.offline_mode,
+            config.run_code_timeout,
+            config.run_code_network,
+        )
+    };
+    let mut results = vec![];
+    for call in calls {
+        let ret = match call.name.as_str() {
+            calculator::NAME => calculator::eval(&call.arguments),
+            convert_units::NAME => convert_units::eval(&call.arguments),
+            fetch_url::NAME if offline => Err(anyhow::anyhow!("Fetching URLs is disabled offline")),
+            fetch_url::NAME => {
+                fetch_url::eval(&fetch_config, &proxy.0, &proxy.1, &call.arguments).await
+            }
+            fs::READ_NAME => fs::read(&fs_roots, &call.arguments),
+            fs::WRITE_NAME => fs::write_file(&fs_roots, &call.arguments),
+            fs::LIST_NAME => fs::list(&fs_roots, &call.arguments),
+            fs::SEARCH_NAME => fs::search(&fs_roots, &call.arguments),
+            run_code::NAME => {
+                run_code::eval(run_code_timeout, run_code_network, &call.arguments).await
+            }
+            name => Err(anyhow::anyhow!("Unknown function '{name}'")),
+        };
+        debug!("ToolCall: {} {} => {:?}", call.name, call.arguments, ret);
+        let output = match ret {
+            Ok(output) => output,
+            Err(err) => json!({ "error": err.to_string() }),
+        };
+        results.push(ToolResult { call, output });
+    }
+    results
+}
+
+fn get_str_arg<'a>(arguments: &'a Value, name: &str) -> Result<&'a str> {
+    match arguments[name].as_str() {
+        Some(value) => Ok(value),
+        None => bail!("Missing argument '{name}'"),
+    }
+}
@@ -0,0 +1,53 @@
+use super::{get_str_arg, FunctionDeclaration};
+use crate::utils::{run_code, CodeLang, CodeSnippet};
+
+use anyhow::{anyhow, bail, Result};
+use inquire::Confirm;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+pub const NAME: &str = "run_code";
+
+pub fn declaration() -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: NAME.into(),
+        description: "Run a python, javascript or rust program in a fresh temp dir and return its stdout, stderr and exit code. The user confirms each run. HOME is the temp dir, but it is no sandbox: the program can read and write the files of the user.".into(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "language": {
+                    "type": "string",
+                    "enum": ["python", "javascript", "rust"],
+                },
+                "code": {
+                    "type": "string",
+                    "description": "The whole program, rust code needs a main function",
+                }
+            },
+            "required": ["language", "code"],
+        }),
+    }
+}
+
+pub async fn eval(timeout: u64, network: bool, arguments: &Value) -> Result<Value> {
+    let language = get_str_arg(arguments, "language")?;
+    let lang =
+        CodeLang::from_tag(language).ok_or_else(|| anyhow!("Unsupported language '{language}'"))?;
+    let code = get_str_arg(arguments, "code")?;
+    println!("```{}\n{}\n```", lang.name(), code.trim_end());
+    let ans = Confirm::new(&format!("Run the {} code?", lang.name()))
+        .with_default(false)
+        .prompt()?;
+    if !ans {
+        bail!("The user declined to run the code");
+    }
+    let snippet = CodeSnippet {
+        lang,
+        code: code.to_string(),
+    };
+    let output = tokio::task::spawn_blocking(move || {
+        run_code(&snippet, Duration::from_secs(timeout), network)
+    })
+    .await??;
+    Ok(json!({ "output": output }))
+}
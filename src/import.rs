@@ -0,0 +1,337 @@
+use crate::client::{Message, MessageContent, MessageRole};
+use crate::config::GlobalConfig;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+pub const IMPORT_FORMATS: [&str; 3] = ["chatgpt", "sgpt", "ollama"];
+
+/// A conversation of another tool, to be saved as a session.
+#[derive(Debug)]
+struct Conversation {
+    name: String,
+    messages: Vec<Message>,
+}
+
+/// Import the conversations of another tool as sessions of the current model:
+/// - `chatgpt`: the `export.zip` of ChatGPT or its `conversations.json`
+/// - `sgpt`: a chat file of shell-gpt or its `chat_cache` directory
+/// - `ollama`: a Modelfile saved by `/save` in `ollama run`, e.g. from `ollama show --modelfile`
+pub fn run(config: &GlobalConfig, format: &str, path: &str) -> Result<()> {
+    let path = Path::new(path);
+    let conversations = match format {
+        "chatgpt" => parse_chatgpt(&read_chatgpt_export(path)?)?,
+        "sgpt" => read_sgpt(path)?,
+        "ollama" => {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            vec![Conversation {
+                name: session_name("ollama", &file_name(path), ""),
+                messages: parse_modelfile(&text),
+            }]
+        }
+        _ => bail!(
+            "Unknown format '{format}', expect {}",
+            IMPORT_FORMATS.join(", ")
+        ),
+    };
+    let conversations: Vec<Conversation> = conversations
+        .into_iter()
+        .filter(|v| !v.messages.is_empty())
+        .collect();
+    if conversations.is_empty() {
+        bail!("No conversation found in {}", path.display());
+    }
+    let (mut imported, mut skipped) = (0, 0);
+    for conversation in conversations {
+        let Conversation { name, messages } = conversation;
+        if config.read().save_imported_session(&name, messages)? {
+            imported += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+    if skipped > 0 {
+        println!("✓ Imported {imported} sessions, {skipped} already imported");
+    } else {
+        println!("✓ Imported {imported} sessions");
+    }
+    Ok(())
+}
+
+fn read_chatgpt_export(path: &Path) -> Result<String> {
+    let ctx = || format!("Failed to read {}", path.display());
+    if path.extension().map(|v| v == "zip").unwrap_or_default() {
+        let file = File::open(path).with_context(ctx)?;
+        let mut archive = zip::ZipArchive::new(file).with_context(ctx)?;
+        let mut entry = archive
+            .by_name("conversations.json")
+            .map_err(|_| anyhow!("No conversations.json in {}", path.display()))?;
+        let mut text = String::new();
+        entry.read_to_string(&mut text).with_context(ctx)?;
+        Ok(text)
+    } else {
+        fs::read_to_string(path).with_context(ctx)
+    }
+}
+
+/// Each conversation is a tree of messages, the branch that was last shown is imported.
+fn parse_chatgpt(text: &str) -> Result<Vec<Conversation>> {
+    let data: Value =
+        serde_json::from_str(text).with_context(|| "Invalid conversations of ChatGPT")?;
+    let list = data
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid conversations of ChatGPT"))?;
+    let mut conversations = vec![];
+    for item in list {
+        let mapping = &item["mapping"];
+        let mut branch = vec![];
+        let mut node_id = item["current_node"].as_str();
+        while let Some(id) = node_id {
+            let node = &mapping[id];
+            branch.push(&node["message"]);
+            node_id = node["parent"].as_str();
+        }
+        let mut messages = vec![];
+        for message in branch.into_iter().rev() {
+            let role = match message["author"]["role"].as_str() {
+                Some("user") => MessageRole::User,
+                Some("assistant") => MessageRole::Assistant,
+                _ => continue,
+            };
+            let text = message["content"]["parts"]
+                .as_array()
+                .map(|parts| {
+                    parts
+                        .iter()
+                        .filter_map(|v| v.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n\n")
+                })
+                .unwrap_or_default();
+            push_message(&mut messages, role, text.trim());
+        }
+        let id = item["conversation_id"]
+            .as_str()
+            .or(item["id"].as_str())
+            .unwrap_or_default();
+        let title = item["title"].as_str().unwrap_or_default();
+        conversations.push(Conversation {
+            name: session_name("chatgpt", title, id),
+            messages,
+        });
+    }
+    Ok(conversations)
+}
+
+fn read_sgpt(path: &Path) -> Result<Vec<Conversation>> {
+    let mut paths = vec![];
+    if path.is_dir() {
+        let entries =
+            fs::read_dir(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        paths.extend(entries.flatten().map(|v| v.path()).filter(|v| v.is_file()));
+        paths.sort();
+    } else {
+        paths.push(path.to_path_buf());
+    }
+    let mut conversations = vec![];
+    for path in paths {
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let list: Vec<Value> = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid chat of shell-gpt at {}", path.display()))?;
+        let mut messages = vec![];
+        for item in list {
+            let role = match item["role"].as_str() {
+                Some("system") => MessageRole::System,
+                Some("user") => MessageRole::User,
+                Some("assistant") => MessageRole::Assistant,
+                _ => continue,
+            };
+            push_message(
+                &mut messages,
+                role,
+                item["content"].as_str().unwrap_or_default(),
+            );
+        }
+        conversations.push(Conversation {
+            name: session_name("sgpt", &file_name(&path), ""),
+            messages,
+        });
+    }
+    Ok(conversations)
+}
+
+/// Read the `SYSTEM` and `MESSAGE` instructions of a Modelfile, the other ones are skipped.
+fn parse_modelfile(text: &str) -> Vec<Message> {
+    let mut messages = vec![];
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let mut args = args.trim().to_string();
+        // A value in triple quotes may span lines
+        if args.matches(r#"""""#).count() == 1 {
+            for line in lines.by_ref() {
+                args.push('\n');
+                args.push_str(line);
+                if line.contains(r#"""""#) {
+                    break;
+                }
+            }
+        }
+        let (role, value) = match command.to_uppercase().as_str() {
+            "SYSTEM" => (MessageRole::System, args.as_str()),
+            "MESSAGE" => {
+                let (role, value) = args.split_once(char::is_whitespace).unwrap_or((&args, ""));
+                let role = match role.to_lowercase().as_str() {
+                    "system" => MessageRole::System,
+                    "user" => MessageRole::User,
+                    "assistant" => MessageRole::Assistant,
+                    _ => continue,
+                };
+                (role, value)
+            }
+            _ => continue,
+        };
+        let value = value.trim();
+        let value = value
+            .strip_prefix(r#"""""#)
+            .and_then(|v| v.strip_suffix(r#"""""#))
+            .or_else(|| value.strip_prefix('"').and_then(|v| v.strip_suffix('"')))
+            .unwrap_or(value);
+        push_message(&mut messages, role, value.trim());
+    }
+    messages
+}
+
+/// Add a message, the text of consecutive messages of the same role is joined.
+fn push_message(messages: &mut Vec<Message>, role: MessageRole, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    if let Some(Message {
+        role: last_role,
+        content: MessageContent::Text(last_text),
+    }) = messages.last_mut()
+    {
+        if *last_role == role {
+            last_text.push_str("\n\n");
+            last_text.push_str(text);
+            return;
+        }
+    }
+    messages.push(Message {
+        role,
+        content: MessageContent::Text(text.to_string()),
+    });
+}
+
+/// A session name made of the title, with the start of the id to tell apart the same titles.
+fn session_name(prefix: &str, title: &str, id: &str) -> String {
+    let mut name = prefix.to_string();
+    let mut slug = String::new();
+    for c in title.to_lowercase().chars() {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug: String = slug.chars().take(40).collect();
+    let id: String = id.chars().take(8).collect();
+    for part in [slug.trim_end_matches('-'), &id] {
+        if !part.is_empty() {
+            name.push('-');
+            name.push_str(part);
+        }
+    }
+    name
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(messages: &[Message]) -> Vec<(MessageRole, String)> {
+        messages
+            .iter()
+            .map(|v| (v.role, v.content.to_text()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_chatgpt() {
+        let text = r#"[{
+            "title": "Rust: lifetimes?",
+            "conversation_id": "6f1c2a9e-0000",
+            "current_node": "c",
+            "mapping": {
+                "root": { "message": null, "parent": null },
+                "s": { "message": { "author": { "role": "system" }, "content": { "parts": [""] } }, "parent": "root" },
+                "a": { "message": { "author": { "role": "user" }, "content": { "parts": ["What is 'a?"] } }, "parent": "s" },
+                "old": { "message": { "author": { "role": "assistant" }, "content": { "parts": ["An edited out answer"] } }, "parent": "a" },
+                "b": { "message": { "author": { "role": "assistant" }, "content": { "parts": ["A lifetime."] } }, "parent": "a" },
+                "c": { "message": { "author": { "role": "assistant" }, "content": { "parts": ["See the book."] } }, "parent": "b" }
+            }
+        }]"#;
+        let conversations = parse_chatgpt(text).unwrap();
+        assert_eq!(conversations[0].name, "chatgpt-rust-lifetimes-6f1c2a9e");
+        assert_eq!(
+            texts(&conversations[0].messages),
+            vec![
+                (MessageRole::User, "What is 'a?".into()),
+                (
+                    MessageRole::Assistant,
+                    "A lifetime.\n\nSee the book.".into()
+                ),
+            ]
+        );
+        assert!(parse_chatgpt("{}").is_err());
+    }
+
+    #[test]
+    fn test_parse_modelfile() {
+        let text = r#"FROM llama3
+TEMPLATE """{{ .System }}
+{{ .Prompt }}"""
+PARAMETER stop "<|eot_id|>"
+SYSTEM You are terse.
+MESSAGE user Hi
+message assistant """Hello,
+how can I help?"""
+"#;
+        assert_eq!(
+            texts(&parse_modelfile(text)),
+            vec![
+                (MessageRole::System, "You are terse.".into()),
+                (MessageRole::User, "Hi".into()),
+                (MessageRole::Assistant, "Hello,\nhow can I help?".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_name() {
+        assert_eq!(session_name("sgpt", "my_chat", ""), "sgpt-my-chat");
+        assert_eq!(
+            session_name("chatgpt", "", "abcdef123456"),
+            "chatgpt-abcdef12"
+        );
+        assert_eq!(
+            session_name("chatgpt", "Über Café!", "1"),
+            "chatgpt-über-café-1"
+        );
+    }
+}
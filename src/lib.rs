@@ -0,0 +1,20 @@
+//! The library behind the `aichat` binary, e.g. to run a REPL with custom dot-commands through
+//! `repl::ReplBuilder::add_command`.
+
+pub mod cli;
+pub mod client;
+pub mod config;
+pub mod doctor;
+pub mod eval;
+pub mod function;
+pub mod import;
+pub mod logger;
+pub mod render;
+pub mod repl;
+pub mod serve;
+pub mod tui;
+#[macro_use]
+pub mod utils;
+
+#[macro_use]
+extern crate log;
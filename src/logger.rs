@@ -4,23 +4,35 @@ use anyhow::Result;
 use log::LevelFilter;
 use simplelog::{format_description, Config as LogConfig, ConfigBuilder};
 
+/// With `trace`, the requests and the raw responses of the providers are logged too.
 #[cfg(debug_assertions)]
-pub fn setup_logger(working_mode: WorkingMode) -> Result<()> {
+pub fn setup_logger(working_mode: WorkingMode, trace: bool) -> Result<()> {
     let config = build_config();
+    let level = match trace {
+        true => LevelFilter::Trace,
+        false => LevelFilter::Debug,
+    };
     if working_mode == WorkingMode::Serve {
-        simplelog::SimpleLogger::init(LevelFilter::Debug, config)?;
+        simplelog::SimpleLogger::init(level, config)?;
     } else {
         let file = std::fs::File::create(crate::config::Config::local_path("debug.log")?)?;
-        simplelog::WriteLogger::init(LevelFilter::Debug, config, file)?;
+        simplelog::WriteLogger::init(level, config, file)?;
     }
     Ok(())
 }
 
 #[cfg(not(debug_assertions))]
-pub fn setup_logger(working_mode: WorkingMode) -> Result<()> {
+pub fn setup_logger(working_mode: WorkingMode, trace: bool) -> Result<()> {
     let config = build_config();
     if working_mode == WorkingMode::Serve {
-        simplelog::SimpleLogger::init(log::LevelFilter::Info, config)?;
+        let level = match trace {
+            true => LevelFilter::Trace,
+            false => LevelFilter::Info,
+        };
+        simplelog::SimpleLogger::init(level, config)?;
+    } else if trace {
+        let file = std::fs::File::create(crate::config::Config::local_path("debug.log")?)?;
+        simplelog::WriteLogger::init(LevelFilter::Trace, config, file)?;
     }
     Ok(())
 }
@@ -1,29 +1,23 @@
-mod cli;
-mod client;
-mod config;
-mod logger;
-mod render;
-mod repl;
-mod serve;
-#[macro_use]
-mod utils;
-
 #[macro_use]
 extern crate log;
 
-use crate::cli::Cli;
-use crate::client::{ensure_model_capabilities, init_client, list_models, send_stream};
-use crate::config::{
+use aichat::cli::Cli;
+use aichat::client::{
+    ensure_model_capabilities, flush_usage, init_client, list_models, print_response_meta,
+    send_stream, EmbeddingsData, ModelCapabilities, SchemaError,
+};
+use aichat::config::{
     Config, GlobalConfig, Input, WorkingMode, CODE_ROLE, EXPLAIN_ROLE, SHELL_ROLE,
 };
-use crate::render::{render_error, MarkdownRender};
-use crate::repl::Repl;
-use crate::utils::{
-    cl100k_base_singleton, create_abort_signal, extract_block, run_command, run_spinner,
+use aichat::render::{render_error, MarkdownRender};
+use aichat::repl::Repl;
+use aichat::utils::{
+    create_abort_signal, extract_block, print_paged, remove_temp_files, run_command, run_spinner,
     CODE_BLOCK_RE,
 };
+use aichat::{doctor, eval, import, logger, serve, tui};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
 use inquire::{Select, Text};
 use is_terminal::IsTerminal;
@@ -31,6 +25,7 @@ use parking_lot::RwLock;
 use std::io::{stderr, stdin, stdout, Read};
 use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::oneshot;
 
 #[tokio::main]
@@ -41,13 +36,25 @@ async fn main() -> Result<()> {
     let no_input = text.is_none() && file.is_empty();
     let working_mode = if cli.serve.is_some() {
         WorkingMode::Serve
-    } else if no_input {
+    } else if no_input && cli.eval.is_none() && cli.ab.is_empty() && cli.cmd.is_empty() {
         WorkingMode::Repl
     } else {
         WorkingMode::Command
     };
-    crate::logger::setup_logger(working_mode)?;
+    if cli.doctor {
+        if !doctor::run().await? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    logger::setup_logger(working_mode, cli.debug)?;
     let config = Arc::new(RwLock::new(Config::init(working_mode)?));
+    if cli.offline {
+        config.write().set_offline_mode(true)?;
+    }
+    if working_mode != WorkingMode::Serve {
+        tokio::spawn(watch_termination(config.clone()));
+    }
 
     if let Some(addr) = cli.serve {
         return serve::run(config, addr).await;
@@ -61,16 +68,44 @@ async fn main() -> Result<()> {
         return Ok(());
     }
     if cli.list_models {
-        for model in list_models(&config.read()) {
-            println!("{}", model.id());
+        let config = config.read();
+        for model in list_models(&config) {
+            if !config.offline_mode || config.is_local_model(model) {
+                println!("{}", model.id());
+            }
         }
         return Ok(());
     }
+    if cli.stats {
+        let report = config.read().usage_stats_report()?;
+        println!("{report}");
+        return Ok(());
+    }
     if cli.list_sessions {
         let sessions = config.read().list_sessions().join("\n");
         println!("{sessions}");
         return Ok(());
     }
+    if cli.prune_sessions {
+        let sessions = config.read().prune_sessions(cli.dry_run)?;
+        let action = if cli.dry_run { "Would prune" } else { "Pruned" };
+        for session in &sessions {
+            let size = session.size.div_ceil(1024);
+            println!("{action} session '{}' ({size} KB)", session.name);
+        }
+        if sessions.is_empty() {
+            println!("No session to prune");
+        }
+        return Ok(());
+    }
+    if config.read().session_retention.prune_on_startup {
+        if let Err(err) = config.read().prune_sessions(false) {
+            warn!("Failed to prune sessions: {err:#}");
+        }
+    }
+    if let [format, path] = cli.import.as_slice() {
+        return import::run(&config, format, path);
+    }
     if let Some(wrap) = &cli.wrap {
         config.write().set_wrap(wrap)?;
     }
@@ -80,6 +115,12 @@ async fn main() -> Result<()> {
     if cli.dry_run {
         config.write().dry_run = true;
     }
+    if cli.no_banner {
+        config.write().banner = Some(String::new());
+    }
+    if cli.no_stream {
+        config.write().stream = false;
+    }
     if let Some(prompt) = &cli.prompt {
         config.write().set_prompt(prompt)?;
     } else if let Some(name) = &cli.role {
@@ -97,6 +138,30 @@ async fn main() -> Result<()> {
     if let Some(model) = &cli.model {
         config.write().set_model(model)?;
     }
+    for tag in &cli.tag {
+        let (key, value) = tag
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid tag '{tag}', expect KEY=VALUE"))?;
+        config.write().tags.insert(key.into(), value.into());
+    }
+    if cli.concurrency.is_some() {
+        config.write().concurrency = cli.concurrency.filter(|v| *v > 0);
+    }
+    if cli.rpm.is_some() {
+        config.write().rpm = cli.rpm.filter(|v| *v > 0);
+    }
+    if cli.tpm.is_some() {
+        config.write().tpm = cli.tpm.filter(|v| *v > 0);
+    }
+    if let Some(schema) = &cli.schema {
+        config.write().set_schema(schema)?;
+    }
+    if let Some(flush) = &cli.stream_flush {
+        config.write().stream_flush = flush.parse()?;
+    }
+    if let Some(thinking) = &cli.think {
+        config.write().thinking = Some(thinking.parse()?);
+    }
     if cli.save_session {
         config.write().set_save_session(Some(true));
     }
@@ -108,7 +173,32 @@ async fn main() -> Result<()> {
         println!("{}", info);
         return Ok(());
     }
+    if let Some(path) = &cli.eval {
+        if !eval::run(&config, path, cli.eval_report.as_deref()).await? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if !cli.ab.is_empty() {
+        let Some(inputs) = &cli.ab_inputs else {
+            bail!("Missing --ab-inputs <FILE>");
+        };
+        let judge = cli.ab_judge.as_ref().map(|v| v.as_deref());
+        return eval::run_ab(&config, &cli.ab, inputs, judge).await;
+    }
     let text = aggregate_text(text)?;
+    if cli.embed {
+        return embed(&config, text, file).await;
+    }
+    if !cli.cmd.is_empty() {
+        config.write().apply_prelude()?;
+        if let Err(err) = start_commands(&config, &cli.cmd, text, file.clone()).await {
+            let highlight = stderr().is_terminal() && config.read().highlight;
+            render_error(err, highlight);
+            process::exit(1);
+        }
+        return Ok(());
+    }
     if cli.execute {
         if no_input {
             bail!("No input");
@@ -121,29 +211,96 @@ async fn main() -> Result<()> {
     if let Err(err) = match no_input {
         false => {
             let input = create_input(&config, text, file)?;
-            start_directive(&config, input, cli.no_stream, cli.code).await
+            start_directive(&config, input, cli.code).await
         }
-        true => start_interactive(&config).await,
+        true => match &cli.script {
+            Some(path) => start_script(&config, path).await,
+            None if cli.tui => tui::run(&config).await,
+            None => start_interactive(&config).await,
+        },
     } {
         let highlight = stderr().is_terminal() && config.read().highlight;
-        render_error(err, highlight)
+        render_error(err, highlight);
+        if cli.script.is_some() {
+            process::exit(1);
+        }
     }
     Ok(())
 }
 
-async fn start_directive(
-    config: &GlobalConfig,
-    input: Input,
-    no_stream: bool,
-    code_mode: bool,
-) -> Result<()> {
+/// Save the session, the usage and remove the temp files when the terminal is closed or the
+/// process is terminated, the conversation is kept instead of being lost.
+async fn watch_termination(config: GlobalConfig) {
+    let code = match wait_termination().await {
+        Ok(v) => v,
+        Err(err) => {
+            debug!("Failed to watch the termination signals: {err}");
+            return;
+        }
+    };
+    // Nothing can be asked anymore, don't wait for a config that stays locked
+    match config.try_write_for(Duration::from_secs(3)) {
+        Some(mut config) => match config.autosave_session() {
+            Ok(Some(name)) => info!("Saved the session '{name}' on termination"),
+            Ok(None) => {}
+            Err(err) => warn!("{err:#}"),
+        },
+        None => warn!("Failed to save the session on termination, the config is busy"),
+    }
+    flush_usage();
+    remove_temp_files();
+    let _ = crossterm::terminal::disable_raw_mode();
+    // The TUI may be on the alternate screen, a pipe must not get the escape sequence
+    if stdout().is_terminal() {
+        let _ = crossterm::execute!(stdout(), crossterm::terminal::LeaveAlternateScreen);
+    }
+    process::exit(code);
+}
+
+/// The exit code of the signal that terminates the process.
+#[cfg(unix)]
+async fn wait_termination() -> Result<i32> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let code = tokio::select! {
+        _ = terminate.recv() => 128 + 15,
+        _ = hangup.recv() => 128 + 1,
+    };
+    Ok(code)
+}
+
+/// The exit code of the console event that terminates the process.
+#[cfg(windows)]
+async fn wait_termination() -> Result<i32> {
+    use tokio::signal::windows::{ctrl_close, ctrl_shutdown};
+    let mut close = ctrl_close()?;
+    let mut shutdown = ctrl_shutdown()?;
+    tokio::select! {
+        _ = close.recv() => {}
+        _ = shutdown.recv() => {}
+    }
+    Ok(1)
+}
+
+async fn start_directive(config: &GlobalConfig, input: Input, code_mode: bool) -> Result<()> {
     let mut client = init_client(config)?;
     ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
     config.read().maybe_print_send_tokens(&input);
     let is_terminal_stdout = stdout().is_terminal();
     let extract_code = !is_terminal_stdout && code_mode;
+    let no_stream = !config.read().stream;
     let output = if no_stream || extract_code {
-        let (output, _) = client.send_message(input.clone()).await?;
+        let (output, details) = match client.send_message(input.clone()).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                // Still give the nonconforming answer, the stream mode has printed it too
+                if let Some(err) = err.downcast_ref::<SchemaError>() {
+                    println!("{}", err.output);
+                }
+                return Err(err);
+            }
+        };
         let output = if extract_code && output.trim_start().starts_with("```") {
             extract_block(&output)
         } else {
@@ -152,7 +309,9 @@ async fn start_directive(
         if is_terminal_stdout {
             let render_options = config.read().get_render_options()?;
             let mut markdown_render = MarkdownRender::init(render_options)?;
-            println!("{}", markdown_render.render(&output).trim());
+            let pager = config.read().pager;
+            print_paged(markdown_render.render(&output).trim(), pager);
+            print_response_meta(&details.meta, config);
         } else {
             println!("{}", output);
         }
@@ -168,9 +327,29 @@ async fn start_directive(
 }
 
 async fn start_interactive(config: &GlobalConfig) -> Result<()> {
-    cl100k_base_singleton();
+    config.read().model.encoding().singleton();
+    let mut repl: Repl = Repl::init(config)?;
+    let ret = repl.run().await;
+    // e.g. the images pasted but never sent
+    remove_temp_files();
+    ret
+}
+
+async fn start_commands(
+    config: &GlobalConfig,
+    commands: &[String],
+    text: Option<String>,
+    files: Vec<String>,
+) -> Result<()> {
+    config.read().model.encoding().singleton();
+    let mut repl: Repl = Repl::init(config)?;
+    repl.run_commands(commands, text, files).await
+}
+
+async fn start_script(config: &GlobalConfig, path: &str) -> Result<()> {
+    config.read().model.encoding().singleton();
     let mut repl: Repl = Repl::init(config)?;
-    repl.run().await
+    repl.run_script(path).await
 }
 
 #[async_recursion::async_recursion]
@@ -243,6 +422,26 @@ async fn execute(config: &GlobalConfig, mut input: Input) -> Result<()> {
     Ok(())
 }
 
+/// Print the embedding of the text and of each file, one JSON array per line.
+async fn embed(config: &GlobalConfig, text: Option<String>, files: &[String]) -> Result<()> {
+    let mut texts: Vec<String> = text.into_iter().collect();
+    for path in files {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read '{path}'"))?;
+        texts.push(text);
+    }
+    if texts.is_empty() {
+        bail!("No input");
+    }
+    let mut client = init_client(config)?;
+    ensure_model_capabilities(client.as_mut(), ModelCapabilities::Embedding)?;
+    let output = client.embeddings(EmbeddingsData::new(texts, false)).await?;
+    for vector in output.vectors {
+        println!("{}", serde_json::to_string(&vector)?);
+    }
+    Ok(())
+}
+
 fn aggregate_text(text: Option<String>) -> Result<Option<String>> {
     let text = if stdin().is_terminal() {
         text
@@ -260,11 +459,12 @@ fn aggregate_text(text: Option<String>) -> Result<Option<String>> {
 
 fn create_input(config: &GlobalConfig, text: Option<String>, file: &[String]) -> Result<Input> {
     let input_context = config.read().input_context();
-    let input = if file.is_empty() {
+    let mut input = if file.is_empty() {
         Input::from_str(&text.unwrap_or_default(), input_context)
     } else {
         Input::new(&text.unwrap_or_default(), file.to_vec(), input_context)?
     };
+    config.write().fill_input_variables(&mut input)?;
     if input.is_empty() {
         bail!("No input");
     }
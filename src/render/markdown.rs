@@ -1,3 +1,5 @@
+use crate::utils::ColorDepth;
+
 use ansi_colours::AsRGB;
 use anyhow::{anyhow, Context, Result};
 use crossterm::style::{Color, Stylize};
@@ -7,6 +9,10 @@ use std::collections::HashMap;
 use syntect::highlighting::{Color as SyntectColor, FontStyle, Style, Theme};
 use syntect::parsing::SyntaxSet;
 use syntect::{easy::HighlightLines, parsing::SyntaxReference};
+use textwrap::core::display_width;
+
+/// Rows of a streamed table that are held back for aligning, longer tables may not fit the screen.
+const MAX_TAIL_TABLE_ROWS: usize = 20;
 
 /// Comes from https://github.com/sharkdp/bat/raw/5e77ca37e89c873e4490b42ff556370dc5c6ba4f/assets/syntaxes.bin
 const SYNTAXES: &[u8] = include_bytes!("../../assets/syntaxes.bin");
@@ -28,6 +34,8 @@ pub struct MarkdownRender {
     code_syntax: Option<SyntaxReference>,
     prev_line_type: LineType,
     wrap_width: Option<u16>,
+    /// In the quoted thinking at the start of the reply, which is dimmed
+    thinking: bool,
 }
 
 impl MarkdownRender {
@@ -38,7 +46,7 @@ impl MarkdownRender {
         let code_color = options
             .theme
             .as_ref()
-            .map(|theme| get_code_color(theme, options.truecolor));
+            .map(|theme| get_code_color(theme, options.color_depth));
         let md_syntax = syntax_set.find_syntax_by_extension("md").unwrap().clone();
         let line_type = LineType::Normal;
         let wrap_width = match options.wrap.as_deref() {
@@ -64,18 +72,93 @@ impl MarkdownRender {
             code_syntax: None,
             prev_line_type: line_type,
             wrap_width,
+            thinking: false,
             options,
         })
     }
 
     pub fn render(&mut self, text: &str) -> String {
-        text.split('\n')
-            .map(|line| self.render_line_mut(line))
+        let lines: Vec<&str> = text.split('\n').collect();
+        let mut output = vec![];
+        let mut i = 0;
+        while i < lines.len() {
+            let in_code = matches!(
+                self.prev_line_type,
+                LineType::CodeBegin | LineType::CodeInner
+            );
+            let rows = match in_code {
+                true => 0,
+                false => lines[i..].iter().take_while(|v| is_table_line(v)).count(),
+            };
+            if rows > 1 {
+                for line in align_table(&lines[i..i + rows]) {
+                    output.push(self.render_line_mut(&line));
+                }
+                i += rows;
+            } else {
+                output.push(self.render_line_mut(lines[i]));
+                i += 1;
+            }
+        }
+        output.join("\n")
+    }
+
+    /// Render the trailing block of a stream, a single line or the rows of an unfinished table.
+    pub fn render_tail(&self, text: &str) -> String {
+        if !text.contains('\n') {
+            return self.render_line(text);
+        }
+        let lines: Vec<&str> = text.split('\n').collect();
+        align_table(&lines)
+            .iter()
+            .map(|line| self.highlight_line(line, &self.md_syntax, false))
             .collect::<Vec<String>>()
             .join("\n")
     }
 
+    /// Split streamed text into the complete lines and the trailing block that may still change.
+    /// The trailing block is the last line, plus the rows before it when they form a table,
+    /// so that the table can be aligned once all of its rows have arrived.
+    pub fn split_tail<'a>(&self, text: &'a str) -> (&'a str, &'a str) {
+        let Some(last_newline) = text.rfind('\n') else {
+            return ("", text);
+        };
+        let mut in_code = matches!(
+            self.prev_line_type,
+            LineType::CodeBegin | LineType::CodeInner
+        );
+        let mut table_start = None;
+        let mut offset = 0;
+        for line in text[..last_newline].split('\n') {
+            if detect_code_block(line).is_some() {
+                in_code = !in_code;
+                table_start = None;
+            } else if !in_code && is_table_line(line) {
+                table_start.get_or_insert(offset);
+            } else {
+                table_start = None;
+            }
+            offset += line.len() + 1;
+        }
+        let tail_start = match table_start {
+            Some(start) if text[start..].matches('\n').count() <= MAX_TAIL_TABLE_ROWS => start,
+            _ => last_newline + 1,
+        };
+        match tail_start {
+            0 => ("", text),
+            _ => (&text[..tail_start - 1], &text[tail_start..]),
+        }
+    }
+
+    /// The reply starts with the thinking of the model, quoted until its first other line.
+    pub fn start_thinking(&mut self) {
+        self.thinking = true;
+    }
+
     pub fn render_line(&self, line: &str) -> String {
+        if self.thinking && line.starts_with('>') {
+            return self.render_thinking_line(line);
+        }
         let (_, code_syntax, is_code) = self.check_line(line);
         if is_code {
             self.highlight_code_line(line, &code_syntax)
@@ -85,6 +168,12 @@ impl MarkdownRender {
     }
 
     fn render_line_mut(&mut self, line: &str) -> String {
+        if self.thinking {
+            if line.starts_with('>') {
+                return self.render_thinking_line(line);
+            }
+            self.thinking = false;
+        }
         let (line_type, code_syntax, is_code) = self.check_line(line);
         let output = if is_code {
             self.highlight_code_line(line, &code_syntax)
@@ -96,6 +185,14 @@ impl MarkdownRender {
         output
     }
 
+    fn render_thinking_line(&self, line: &str) -> String {
+        let line = match self.options.theme {
+            Some(_) => line.dim().to_string(),
+            None => line.to_string(),
+        };
+        self.wrap_line(line, false)
+    }
+
     fn check_line(&self, line: &str) -> (LineType, Option<SyntaxReference>, bool) {
         let mut line_type = self.prev_line_type;
         let mut code_syntax = self.code_syntax.clone();
@@ -147,7 +244,7 @@ impl MarkdownRender {
             if let Ok(ranges) = highlighter.highlight_line(trimmed_line, &self.syntax_set) {
                 line_highlighted = Some(format!(
                     "{ws}{}",
-                    as_terminal_escaped(&ranges, self.options.truecolor)
+                    as_terminal_escaped(&ranges, self.options.color_depth)
                 ))
             }
         }
@@ -202,7 +299,7 @@ pub struct RenderOptions {
     pub theme: Option<Theme>,
     pub wrap: Option<String>,
     pub wrap_code: bool,
-    pub truecolor: bool,
+    pub color_depth: ColorDepth,
 }
 
 impl RenderOptions {
@@ -210,13 +307,13 @@ impl RenderOptions {
         theme: Option<Theme>,
         wrap: Option<String>,
         wrap_code: bool,
-        truecolor: bool,
+        color_depth: ColorDepth,
     ) -> Self {
         Self {
             theme,
             wrap,
             wrap_code,
-            truecolor,
+            color_depth,
         }
     }
 }
@@ -229,11 +326,11 @@ pub enum LineType {
     CodeEnd,
 }
 
-fn as_terminal_escaped(ranges: &[(Style, &str)], truecolor: bool) -> String {
+fn as_terminal_escaped(ranges: &[(Style, &str)], color_depth: ColorDepth) -> String {
     let mut output = String::new();
     for (style, text) in ranges {
         let fg = blend_fg_color(style.foreground, style.background);
-        let mut text = text.with(convert_color(fg, truecolor));
+        let mut text = text.with(convert_color(fg, color_depth));
         if style.font_style.contains(FontStyle::BOLD) {
             text = text.bold();
         }
@@ -245,24 +342,54 @@ fn as_terminal_escaped(ranges: &[(Style, &str)], truecolor: bool) -> String {
     output
 }
 
-fn convert_color(c: SyntectColor, truecolor: bool) -> Color {
-    if truecolor {
-        Color::Rgb {
+fn convert_color(c: SyntectColor, color_depth: ColorDepth) -> Color {
+    match color_depth {
+        ColorDepth::TrueColor => Color::Rgb {
             r: c.r,
             g: c.g,
             b: c.b,
+        },
+        ColorDepth::Ansi256 => {
+            let value = (c.r, c.g, c.b).to_ansi256();
+            // lower contrast
+            let value = match value {
+                7 | 15 | 231 | 252..=255 => 252,
+                _ => value,
+            };
+            Color::AnsiValue(value)
         }
-    } else {
-        let value = (c.r, c.g, c.b).to_ansi256();
-        // lower contrast
-        let value = match value {
-            7 | 15 | 231 | 252..=255 => 252,
-            _ => value,
-        };
-        Color::AnsiValue(value)
+        ColorDepth::Ansi16 => to_ansi16(c),
     }
 }
 
+/// The nearest of the 16 colors, as xterm shows them.
+fn to_ansi16(c: SyntectColor) -> Color {
+    const COLORS: [(Color, (i32, i32, i32)); 16] = [
+        (Color::Black, (0, 0, 0)),
+        (Color::DarkRed, (205, 0, 0)),
+        (Color::DarkGreen, (0, 205, 0)),
+        (Color::DarkYellow, (205, 205, 0)),
+        (Color::DarkBlue, (0, 0, 238)),
+        (Color::DarkMagenta, (205, 0, 205)),
+        (Color::DarkCyan, (0, 205, 205)),
+        (Color::Grey, (229, 229, 229)),
+        (Color::DarkGrey, (127, 127, 127)),
+        (Color::Red, (255, 0, 0)),
+        (Color::Green, (0, 255, 0)),
+        (Color::Yellow, (255, 255, 0)),
+        (Color::Blue, (92, 92, 255)),
+        (Color::Magenta, (255, 0, 255)),
+        (Color::Cyan, (0, 255, 255)),
+        (Color::White, (255, 255, 255)),
+    ];
+    let (r, g, b) = (i32::from(c.r), i32::from(c.g), i32::from(c.b));
+    COLORS
+        .iter()
+        .min_by_key(|(_, (r2, g2, b2))| (r - r2).pow(2) + (g - g2).pow(2) + (b - b2).pow(2))
+        .map(|(color, _)| *color)
+        .unwrap_or(Color::Reset)
+}
+
 fn blend_fg_color(fg: SyntectColor, bg: SyntectColor) -> SyntectColor {
     if fg.a == 0xff {
         return fg;
@@ -291,7 +418,113 @@ fn detect_code_block(line: &str) -> Option<String> {
     Some(lang)
 }
 
-fn get_code_color(theme: &Theme, truecolor: bool) -> Color {
+/// A fenced code block of a reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    pub lang: String,
+    pub code: String,
+}
+
+/// Find the closed fenced code blocks, in order of appearance, the same way they are highlighted.
+pub fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = vec![];
+    let mut current: Option<CodeBlock> = None;
+    for line in text.lines() {
+        match (detect_code_block(line), current.take()) {
+            (Some(_), Some(block)) => blocks.push(block),
+            (Some(lang), None) => {
+                current = Some(CodeBlock {
+                    lang,
+                    code: String::new(),
+                })
+            }
+            (None, Some(mut block)) => {
+                block.code.push_str(line);
+                block.code.push('\n');
+                current = Some(block);
+            }
+            (None, None) => {}
+        }
+    }
+    blocks
+}
+
+fn is_table_line(line: &str) -> bool {
+    line.trim_start().starts_with('|')
+}
+
+/// Pad the cells of a pipe table so that the columns line up.
+fn align_table(lines: &[&str]) -> Vec<String> {
+    let rows: Vec<Vec<String>> = lines.iter().map(|v| split_table_row(v)).collect();
+    let is_separator = |row: &[String]| {
+        !row.is_empty()
+            && row.iter().all(|v| {
+                let v = v.trim_start_matches(':').trim_end_matches(':');
+                !v.is_empty() && v.chars().all(|c| c == '-')
+            })
+    };
+    let columns = rows.iter().map(|v| v.len()).max().unwrap_or_default();
+    let mut widths = vec![3; columns];
+    for row in rows.iter().filter(|v| !is_separator(v)) {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(display_width(cell));
+        }
+    }
+    rows.iter()
+        .zip(lines)
+        .map(|(row, line)| {
+            if !is_table_line(line) {
+                return line.to_string();
+            }
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let separator = is_separator(row);
+            let cells: Vec<String> = widths
+                .iter()
+                .enumerate()
+                .map(|(i, width)| {
+                    let cell = row.get(i).map(|v| v.as_str()).unwrap_or_default();
+                    if separator {
+                        let left = if cell.starts_with(':') { ":" } else { "-" };
+                        let right = if cell.len() > 1 && cell.ends_with(':') {
+                            ":"
+                        } else {
+                            "-"
+                        };
+                        format!("{left}{}{right}", "-".repeat(width - 2))
+                    } else {
+                        format!("{cell}{}", " ".repeat(width - display_width(cell)))
+                    }
+                })
+                .collect();
+            format!("{indent}| {} |", cells.join(" | "))
+        })
+        .collect()
+}
+
+fn split_table_row(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                cell.push(c);
+                if let Some(next) = chars.next() {
+                    cell.push(next);
+                }
+            }
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+fn get_code_color(theme: &Theme, color_depth: ColorDepth) -> Color {
     let scope = theme.scopes.iter().find(|v| {
         v.scope
             .selectors
@@ -300,7 +533,7 @@ fn get_code_color(theme: &Theme, truecolor: bool) -> Color {
     });
     scope
         .and_then(|v| v.style.foreground)
-        .map_or_else(|| Color::Yellow, |c| convert_color(c, truecolor))
+        .map_or_else(|| Color::Yellow, |c| convert_color(c, color_depth))
 }
 
 #[cfg(test)]
@@ -345,6 +578,23 @@ std::error::Error>> {
 ```
 "#;
 
+    #[test]
+    fn test_convert_color() {
+        let color = |r, g, b| SyntectColor { r, g, b, a: 255 };
+        assert_eq!(
+            convert_color(color(190, 10, 20), ColorDepth::Ansi16),
+            Color::DarkRed
+        );
+        assert_eq!(
+            convert_color(color(250, 250, 240), ColorDepth::Ansi16),
+            Color::White
+        );
+        assert_eq!(
+            convert_color(color(1, 2, 3), ColorDepth::TrueColor),
+            Color::Rgb { r: 1, g: 2, b: 3 }
+        );
+    }
+
     #[test]
     fn test_render() {
         let options = RenderOptions::default();
@@ -380,4 +630,58 @@ std::error::Error>> {
         let output = render.render(TEXT);
         assert_eq!(TEXT_WRAP_ALL, output);
     }
+
+    #[test]
+    fn render_thinking() {
+        let options = RenderOptions {
+            theme: Some(Theme::default()),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        render.start_thinking();
+        let output = render.render("> Let me\n> think\n\n> A quote");
+        let lines: Vec<&str> = output.split('\n').collect();
+        assert_eq!(lines[0], "> Let me".dim().to_string());
+        assert_eq!(lines[1], "> think".dim().to_string());
+        assert_ne!(lines[3], "> A quote".dim().to_string());
+    }
+
+    #[test]
+    fn render_table() {
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        let text = "Sizes:\n|a|bb|\n|:-|--:|\n|ccc|d \\| e|\ndone";
+        assert_eq!(
+            render.render(text),
+            "Sizes:\n| a   | bb     |\n| :-- | -----: |\n| ccc | d \\| e |\ndone"
+        );
+    }
+
+    #[test]
+    fn split_table_tail() {
+        let render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        assert_eq!(render.split_tail("a\nb"), ("a", "b"));
+        assert_eq!(render.split_tail("a"), ("", "a"));
+        assert_eq!(render.split_tail("a\n| x |\n| y"), ("a", "| x |\n| y"));
+        assert_eq!(render.split_tail("| x |\n"), ("", "| x |\n"));
+        assert_eq!(render.split_tail("| x |\nb\n"), ("| x |\nb", ""));
+        assert_eq!(render.split_tail("```\n| x |\n| y"), ("```\n| x |", "| y"));
+    }
+
+    #[test]
+    fn extract_blocks() {
+        let text = "Run:\n```sh\nls\n```\nthen\n```\na\n\nb\n```\n```py\nunclosed";
+        assert_eq!(
+            extract_code_blocks(text),
+            [
+                CodeBlock {
+                    lang: "sh".into(),
+                    code: "ls\n".into()
+                },
+                CodeBlock {
+                    lang: String::new(),
+                    code: "a\n\nb\n".into()
+                },
+            ]
+        );
+    }
 }
@@ -1,11 +1,15 @@
 mod markdown;
 mod stream;
 
-pub use self::markdown::{MarkdownRender, RenderOptions};
+pub use self::markdown::{extract_code_blocks, CodeBlock, MarkdownRender, RenderOptions};
 use self::stream::{markdown_stream, raw_stream};
+pub use self::stream::{take_pending_pipes, take_typeahead, ReplyText};
 
-use crate::utils::AbortSignal;
-use crate::{client::SseEvent, config::GlobalConfig};
+use crate::utils::{is_dumb_terminal, AbortSignal};
+use crate::{
+    client::SseEvent,
+    config::{GlobalConfig, KeyAction, WorkingMode},
+};
 
 use anyhow::Result;
 use is_terminal::IsTerminal;
@@ -18,12 +22,28 @@ pub async fn render_stream(
     config: &GlobalConfig,
     abort: AbortSignal,
 ) -> Result<()> {
-    if stdout().is_terminal() {
+    let reply_text = ReplyText::new(config.read().show_thinking);
+    // The markdown stream redraws its last lines, which needs to move the cursor
+    if stdout().is_terminal() && !is_dumb_terminal() {
         let render_options = config.read().get_render_options()?;
+        let chars_per_sec = match config.read().working_mode {
+            WorkingMode::Repl => config.read().stream_chars_per_sec,
+            _ => None,
+        };
+        let abort_keys = config.read().keybindings.keys(KeyAction::Abort);
         let mut render = MarkdownRender::init(render_options)?;
-        markdown_stream(rx, &mut render, &abort).await
+        markdown_stream(
+            rx,
+            &mut render,
+            reply_text,
+            &abort,
+            chars_per_sec,
+            &abort_keys,
+        )
+        .await
     } else {
-        raw_stream(rx, &abort).await
+        let flush = config.read().stream_flush;
+        raw_stream(rx, reply_text, flush, &abort).await
     }
 }
 
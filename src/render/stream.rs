@@ -1,6 +1,7 @@
 use super::{MarkdownRender, SseEvent};
 
-use crate::utils::{run_spinner, AbortSignal};
+use crate::config::{KeyCombo, StreamFlush};
+use crate::utils::{run_spinner, set_text, AbortSignal};
 
 use anyhow::Result;
 use crossterm::{
@@ -9,56 +10,202 @@ use crossterm::{
     queue, style,
     terminal::{self, disable_raw_mode, enable_raw_mode},
 };
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use std::{
     io::{self, stdout, Stdout, Write},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use textwrap::core::display_width;
 use tokio::sync::{mpsc::UnboundedReceiver, oneshot};
 
+lazy_static! {
+    static ref TYPEAHEAD: Mutex<String> = Mutex::new(String::new());
+    static ref PENDING_PIPES: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+}
+
+/// Take the text typed while a reply was streaming, to prefill the next input.
+pub fn take_typeahead() -> String {
+    std::mem::take(&mut TYPEAHEAD.lock())
+}
+
+/// Take the `.pipe` commands entered while a reply was streaming, with the partial reply
+/// at the time they were entered.
+pub fn take_pending_pipes() -> Vec<(String, String)> {
+    std::mem::take(&mut PENDING_PIPES.lock())
+}
+
+/// `.copy` and `.pipe <command>` typed during streaming act on a snapshot of the partial reply.
+/// The copy happens at once, the pipe runs after the stream ends so that it doesn't mess up the output.
+fn run_typeahead_command(reply: &str) {
+    let mut typeahead = TYPEAHEAD.lock();
+    let line = typeahead.trim();
+    if line == ".copy" {
+        let _ = set_text(reply);
+        typeahead.clear();
+    } else if let Some(command) = line.strip_prefix(".pipe ") {
+        PENDING_PIPES
+            .lock()
+            .push((command.trim().to_string(), reply.to_string()));
+        typeahead.clear();
+    }
+}
+
+/// Turn the events of a reply into the text shown, the thinking of the model as a quote
+/// before the reply unless it's hidden. The other events show nothing.
+#[derive(Debug)]
+pub struct ReplyText {
+    thinking: ThinkingState,
+    show_thinking: bool,
+}
+
+impl Default for ReplyText {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ThinkingState {
+    #[default]
+    None,
+    LineStart,
+    InLine,
+}
+
+impl ReplyText {
+    pub fn new(show_thinking: bool) -> Self {
+        Self {
+            thinking: ThinkingState::None,
+            show_thinking,
+        }
+    }
+
+    pub fn push(&mut self, event: &SseEvent) -> String {
+        match event {
+            SseEvent::TextDelta(text) => {
+                if self.thinking == ThinkingState::None {
+                    return text.clone();
+                }
+                self.thinking = ThinkingState::None;
+                format!("\n\n{text}")
+            }
+            SseEvent::ReasoningDelta(_) if !self.show_thinking => String::new(),
+            SseEvent::ReasoningDelta(text) => {
+                let mut output = String::new();
+                for c in text.chars() {
+                    if self.thinking != ThinkingState::InLine {
+                        output.push_str("> ");
+                    }
+                    output.push(c);
+                    self.thinking = match c {
+                        '\n' => ThinkingState::LineStart,
+                        _ => ThinkingState::InLine,
+                    };
+                }
+                output
+            }
+            SseEvent::Notice(text) => format!("⚠️  {text}\n\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Leaves the raw mode when dropped, so the terminal is restored even if rendering fails or panics.
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn new() -> Result<Self> {
+        enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+    }
+}
+
 pub async fn markdown_stream(
     rx: UnboundedReceiver<SseEvent>,
     render: &mut MarkdownRender,
+    reply_text: ReplyText,
     abort: &AbortSignal,
+    chars_per_sec: Option<usize>,
+    abort_keys: &[KeyCombo],
 ) -> Result<()> {
-    enable_raw_mode()?;
+    let _guard = RawModeGuard::new()?;
     let mut stdout = io::stdout();
 
-    let ret = markdown_stream_inner(rx, render, abort, &mut stdout).await;
-
-    disable_raw_mode()?;
-
-    ret
+    markdown_stream_inner(
+        rx,
+        render,
+        reply_text,
+        abort,
+        chars_per_sec,
+        abort_keys,
+        &mut stdout,
+    )
+    .await
 }
 
-pub async fn raw_stream(mut rx: UnboundedReceiver<SseEvent>, abort: &AbortSignal) -> Result<()> {
-    loop {
-        if abort.aborted() {
-            return Ok(());
+pub async fn raw_stream(
+    mut rx: UnboundedReceiver<SseEvent>,
+    mut reply_text: ReplyText,
+    flush: StreamFlush,
+    abort: &AbortSignal,
+) -> Result<()> {
+    let mut pending = String::new();
+    while !abort.aborted() {
+        let Some(evt) = rx.recv().await else {
+            break;
+        };
+        if evt == SseEvent::Done {
+            break;
         }
-        if let Some(evt) = rx.recv().await {
-            match evt {
-                SseEvent::Text(text) => {
-                    print!("{}", text);
-                    stdout().flush()?;
-                }
-                SseEvent::Done => {
-                    break;
-                }
-            }
+        pending.push_str(&reply_text.push(&evt));
+        let text = take_flushable(&mut pending, flush);
+        if !text.is_empty() {
+            print!("{}", text);
+            stdout().flush()?;
         }
     }
+    // What's held back is written even when aborted, it was received
+    if !pending.is_empty() {
+        print!("{}", pending);
+        stdout().flush()?;
+    }
     Ok(())
 }
 
+/// Take the pending text that is due to be written out.
+fn take_flushable(pending: &mut String, flush: StreamFlush) -> String {
+    match flush {
+        StreamFlush::Token => std::mem::take(pending),
+        StreamFlush::Line => match pending.rfind('\n') {
+            Some(i) => pending.drain(..=i).collect(),
+            None => String::new(),
+        },
+        StreamFlush::Off => String::new(),
+    }
+}
+
 async fn markdown_stream_inner(
     mut rx: UnboundedReceiver<SseEvent>,
     render: &mut MarkdownRender,
+    mut reply_text: ReplyText,
     abort: &AbortSignal,
+    chars_per_sec: Option<usize>,
+    abort_keys: &[KeyCombo],
     writer: &mut Stdout,
 ) -> Result<()> {
+    let mut reply = String::new();
     let mut buffer = String::new();
     let mut buffer_rows = 1;
+    let mut pending = String::new();
+    let mut done = false;
+    let mut pacer = chars_per_sec.map(StreamPacer::new);
 
     let columns = terminal::size()?.0;
 
@@ -66,76 +213,101 @@ async fn markdown_stream_inner(
     let mut spinner_tx = Some(spinner_tx);
     tokio::spawn(run_spinner(" Generating", spinner_rx));
 
-    'outer: loop {
+    loop {
         if abort.aborted() {
             return Ok(());
         }
-        for reply_event in gather_events(&mut rx).await {
-            if let Some(spinner_tx) = spinner_tx.take() {
-                let _ = spinner_tx.send(());
+        if !done {
+            for reply_event in gather_events(&mut rx).await {
+                match &reply_event {
+                    SseEvent::TextDelta(text) => reply.push_str(text),
+                    SseEvent::Done => done = true,
+                    _ => {}
+                }
+                let text = reply_text.push(&reply_event);
+                if matches!(reply_event, SseEvent::ReasoningDelta(_)) && !text.is_empty() {
+                    render.start_thinking();
+                }
+                if !text.is_empty() || done {
+                    if let Some(spinner_tx) = spinner_tx.take() {
+                        let _ = spinner_tx.send(());
+                    }
+                }
+                pending.push_str(&text);
             }
+        }
 
-            match reply_event {
-                SseEvent::Text(mut text) => {
-                    // tab width hacking
-                    text = text.replace('\t', "    ");
+        let text = match pacer.as_mut() {
+            Some(pacer) => pacer.take(&mut pending, Instant::now()),
+            None => std::mem::take(&mut pending),
+        };
 
-                    let (col, mut row) = cursor::position()?;
+        if !text.is_empty() {
+            // tab width hacking
+            let text = text.replace('\t', "    ");
 
-                    // Fix unexpected duplicate lines on kitty, see https://github.com/sigoden/aichat/issues/105
-                    if col == 0 && row > 0 && display_width(&buffer) == columns as usize {
-                        row -= 1;
-                    }
+            let (col, mut row) = cursor::position()?;
 
-                    if row + 1 >= buffer_rows {
-                        queue!(writer, cursor::MoveTo(0, row + 1 - buffer_rows),)?;
-                    } else {
-                        let scroll_rows = buffer_rows - row - 1;
-                        queue!(
-                            writer,
-                            terminal::ScrollUp(scroll_rows),
-                            cursor::MoveTo(0, 0),
-                        )?;
-                    }
+            // Fix unexpected duplicate lines on kitty, see https://github.com/sigoden/aichat/issues/105
+            if col == 0 && row > 0 && display_width(&buffer) == columns as usize {
+                row -= 1;
+            }
 
-                    // No guarantee that text returned by render will not be re-layouted, so it is better to clear it.
-                    queue!(writer, terminal::Clear(terminal::ClearType::FromCursorDown))?;
-
-                    if text.contains('\n') {
-                        let text = format!("{buffer}{text}");
-                        let (head, tail) = split_line_tail(&text);
-                        let output = render.render(head);
-                        print_block(writer, &output, columns)?;
-                        buffer = tail.to_string();
-                    } else {
-                        buffer = format!("{buffer}{text}");
-                    }
+            if row + 1 >= buffer_rows {
+                queue!(writer, cursor::MoveTo(0, row + 1 - buffer_rows),)?;
+            } else {
+                let scroll_rows = buffer_rows - row - 1;
+                queue!(
+                    writer,
+                    terminal::ScrollUp(scroll_rows),
+                    cursor::MoveTo(0, 0),
+                )?;
+            }
 
-                    let output = render.render_line(&buffer);
-                    if output.contains('\n') {
-                        let (head, tail) = split_line_tail(&output);
-                        buffer_rows = print_block(writer, head, columns)?;
-                        queue!(writer, style::Print(&tail),)?;
-
-                        // No guarantee the buffer width of the buffer will not exceed the number of columns.
-                        // So we calculate the number of rows needed, rather than setting it directly to 1.
-                        buffer_rows += need_rows(tail, columns);
-                    } else {
-                        queue!(writer, style::Print(&output))?;
-                        buffer_rows = need_rows(&output, columns);
-                    }
+            // No guarantee that text returned by render will not be re-layouted, so it is better to clear it.
+            queue!(writer, terminal::Clear(terminal::ClearType::FromCursorDown))?;
 
-                    writer.flush()?;
-                }
-                SseEvent::Done => {
-                    break 'outer;
+            if text.contains('\n') {
+                let text = format!("{buffer}{text}");
+                let (head, tail) = render.split_tail(&text);
+                if !head.is_empty() || text.starts_with('\n') {
+                    let output = render.render(head);
+                    print_block(writer, &output, columns)?;
                 }
+                buffer = tail.to_string();
+            } else {
+                buffer = format!("{buffer}{text}");
             }
+
+            let output = render.render_tail(&buffer);
+            if output.contains('\n') {
+                let (head, tail) = split_line_tail(&output);
+                buffer_rows = print_block(writer, head, columns)?;
+                queue!(writer, style::Print(&tail),)?;
+
+                // No guarantee the buffer width of the buffer will not exceed the number of columns.
+                // So we calculate the number of rows needed, rather than setting it directly to 1.
+                buffer_rows += need_rows(tail, columns);
+            } else {
+                queue!(writer, style::Print(&output))?;
+                buffer_rows = need_rows(&output, columns);
+            }
+
+            writer.flush()?;
+        }
+
+        if done && pending.is_empty() {
+            break;
         }
 
         if crossterm::event::poll(Duration::from_millis(25))? {
             if let Event::Key(key) = event::read()? {
                 match key.code {
+                    _ if abort_keys.iter().any(|v| v.matches(&key)) => {
+                        abort.set_ctrlc();
+                        break;
+                    }
+                    // Ctrl+C always aborts, raw mode keeps it from interrupting the process
                     KeyCode::Char('c') if key.modifiers == KeyModifiers::CONTROL => {
                         abort.set_ctrlc();
                         break;
@@ -144,6 +316,20 @@ async fn markdown_stream_inner(
                         abort.set_ctrld();
                         break;
                     }
+                    KeyCode::Char('f') if key.modifiers == KeyModifiers::CONTROL => {
+                        pacer = None;
+                    }
+                    KeyCode::Char(c)
+                        if !key
+                            .modifiers
+                            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+                    {
+                        TYPEAHEAD.lock().push(c);
+                    }
+                    KeyCode::Backspace => {
+                        TYPEAHEAD.lock().pop();
+                    }
+                    KeyCode::Enter => run_typeahead_command(&reply),
                     _ => {}
                 }
             }
@@ -156,30 +342,54 @@ async fn markdown_stream_inner(
     Ok(())
 }
 
+/// Token bucket that releases streamed text at a steady number of chars per second.
+struct StreamPacer {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl StreamPacer {
+    fn new(chars_per_sec: usize) -> Self {
+        Self {
+            rate: chars_per_sec as f64,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, pending: &mut String, now: Instant) -> String {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        // Allow at most one second of burst after the provider has been idle.
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        let count = self.tokens as usize;
+        let end = pending
+            .char_indices()
+            .nth(count)
+            .map(|(i, _)| i)
+            .unwrap_or(pending.len());
+        let text: String = pending.drain(..end).collect();
+        self.tokens -= text.chars().count() as f64;
+        text
+    }
+}
+
+/// The events received within 50ms, up to `Done`.
 async fn gather_events(rx: &mut UnboundedReceiver<SseEvent>) -> Vec<SseEvent> {
-    let mut texts = vec![];
-    let mut done = false;
+    let mut events = vec![];
     tokio::select! {
         _ = async {
             while let Some(reply_event) = rx.recv().await {
-                match reply_event {
-                    SseEvent::Text(v) => texts.push(v),
-                    SseEvent::Done => {
-                        done = true;
-                        break;
-                    }
+                let done = reply_event == SseEvent::Done;
+                events.push(reply_event);
+                if done {
+                    break;
                 }
             }
         } => {}
         _ = tokio::time::sleep(Duration::from_millis(50)) => {}
     };
-    let mut events = vec![];
-    if !texts.is_empty() {
-        events.push(SseEvent::Text(texts.join("")))
-    }
-    if done {
-        events.push(SseEvent::Done)
-    }
     events
 }
 
@@ -207,5 +417,66 @@ fn split_line_tail(text: &str) -> (&str, &str) {
 
 fn need_rows(text: &str, columns: u16) -> u16 {
     let buffer_width = display_width(text).max(1) as u16;
-    (buffer_width + columns - 1) / columns
+    buffer_width.div_ceil(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_flushable() {
+        let mut pending = "Hel".to_string();
+        assert_eq!(take_flushable(&mut pending, StreamFlush::Token), "Hel");
+        assert!(pending.is_empty());
+
+        let mut pending = "one\ntwo\nthr".to_string();
+        assert_eq!(
+            take_flushable(&mut pending, StreamFlush::Line),
+            "one\ntwo\n"
+        );
+        assert_eq!(pending, "thr");
+        assert_eq!(take_flushable(&mut pending, StreamFlush::Line), "");
+
+        assert_eq!(take_flushable(&mut pending, StreamFlush::Off), "");
+        assert_eq!(pending, "thr");
+    }
+
+    #[test]
+    fn test_reply_text() {
+        let mut reply_text = ReplyText::default();
+        let events = [
+            SseEvent::ReasoningDelta("Let me\nthink".into()),
+            SseEvent::ReasoningDelta(" more".into()),
+            SseEvent::Usage {
+                input_tokens: Some(10),
+                output_tokens: None,
+            },
+            SseEvent::TextDelta("Done".into()),
+            SseEvent::TextDelta(".".into()),
+        ];
+        let output: String = events.iter().map(|v| reply_text.push(v)).collect();
+        assert_eq!(output, "> Let me\n> think more\n\nDone.");
+
+        let mut reply_text = ReplyText::new(false);
+        let output: String = events.iter().map(|v| reply_text.push(v)).collect();
+        assert_eq!(output, "Done.");
+    }
+
+    #[test]
+    fn test_stream_pacer() {
+        let mut pacer = StreamPacer::new(10);
+        let start = pacer.last;
+        let mut pending = "hello world, 你好".to_string();
+        assert_eq!(pacer.take(&mut pending, start), "");
+        assert_eq!(
+            pacer.take(&mut pending, start + Duration::from_millis(500)),
+            "hello"
+        );
+        assert_eq!(
+            pacer.take(&mut pending, start + Duration::from_secs(5)),
+            " world, 你好"
+        );
+        assert_eq!(pending, "");
+    }
 }
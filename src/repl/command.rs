@@ -0,0 +1,236 @@
+use crate::client::{init_client, Client};
+use crate::config::{GlobalConfig, Session};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::{
+    MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLockReadGuard, RwLockWriteGuard,
+};
+
+/// A dot-command added to the REPL with `ReplBuilder::add_command`.
+///
+/// The usage and the help are generated from the args, flags and examples, which the
+/// completer and the check of the required args use as well.
+#[async_trait]
+pub trait Command: Send + Sync {
+    /// The name typed in the REPL, starting with a dot, e.g. `.jira`.
+    fn name(&self) -> &'static str;
+
+    /// One line shown by `.help`, it must not be empty.
+    fn description(&self) -> &'static str;
+
+    fn args(&self) -> Vec<Arg> {
+        vec![]
+    }
+
+    fn flags(&self) -> Vec<Flag> {
+        vec![]
+    }
+
+    fn examples(&self) -> Vec<Example> {
+        vec![]
+    }
+
+    /// The text of `.help <command>` between the usage and the args.
+    fn details(&self) -> &'static str {
+        ""
+    }
+
+    /// E.g. `.jira <issue> [--open]`.
+    fn usage(&self) -> String {
+        render_usage(self.name(), &self.args(), &self.flags())
+    }
+
+    /// Shown by `.help <command>`.
+    fn help(&self) -> String {
+        render_help(
+            &self.usage(),
+            self.details(),
+            &self.args(),
+            &self.flags(),
+            &self.examples(),
+        )
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>) -> Result<()>;
+}
+
+/// A positional argument of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arg {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub required: bool,
+    /// Takes the rest of the line
+    pub variadic: bool,
+}
+
+impl Arg {
+    pub fn required(name: &'static str, description: &'static str) -> Self {
+        Self {
+            name,
+            description,
+            required: true,
+            variadic: false,
+        }
+    }
+
+    pub fn optional(name: &'static str, description: &'static str) -> Self {
+        Self {
+            required: false,
+            ..Self::required(name, description)
+        }
+    }
+
+    pub fn variadic(mut self) -> Self {
+        self.variadic = true;
+        self
+    }
+}
+
+/// A flag of a command, e.g. `--yes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flag {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub required: bool,
+    /// The placeholder of the value after the flag, if it takes one
+    pub value: Option<&'static str>,
+}
+
+impl Flag {
+    pub fn new(name: &'static str, description: &'static str) -> Self {
+        Self {
+            name,
+            description,
+            required: false,
+            value: None,
+        }
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn with_value(mut self, value: &'static str) -> Self {
+        self.value = Some(value);
+        self
+    }
+}
+
+/// A line typed in the REPL and what it does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Example {
+    pub line: &'static str,
+    pub description: &'static str,
+}
+
+impl Example {
+    pub fn new(line: &'static str, description: &'static str) -> Self {
+        Self { line, description }
+    }
+}
+
+pub fn render_usage(name: &str, args: &[Arg], flags: &[Flag]) -> String {
+    let mut output = name.to_string();
+    for arg in args {
+        let arg_name = match arg.required {
+            true => format!("<{}>", arg.name),
+            false => format!("[{}]", arg.name),
+        };
+        let dots = if arg.variadic { "..." } else { "" };
+        output.push_str(&format!(" {arg_name}{dots}"));
+    }
+    for flag in flags {
+        let flag_name = match flag.value {
+            Some(value) => format!("{} <{value}>", flag.name),
+            None => flag.name.to_string(),
+        };
+        match flag.required {
+            true => output.push_str(&format!(" {flag_name}")),
+            false => output.push_str(&format!(" [{flag_name}]")),
+        }
+    }
+    output
+}
+
+pub fn render_help(
+    usage: &str,
+    details: &str,
+    args: &[Arg],
+    flags: &[Flag],
+    examples: &[Example],
+) -> String {
+    let mut sections = vec![format!("Usage: {usage}")];
+    if !details.is_empty() {
+        sections.push(details.to_string());
+    }
+    let list = |title: &str, items: Vec<(String, &str)>| {
+        let width = items.iter().map(|(v, _)| v.len()).max().unwrap_or_default();
+        let lines: Vec<String> = items
+            .iter()
+            .map(|(name, description)| format!("  {name:<width$}  {description}"))
+            .collect();
+        format!("{title}:\n{}", lines.join("\n"))
+    };
+    if !args.is_empty() {
+        let items = args
+            .iter()
+            .map(|v| (v.name.to_string(), v.description))
+            .collect();
+        sections.push(list("Arguments", items));
+    }
+    if !flags.is_empty() {
+        let items = flags
+            .iter()
+            .map(|v| match v.value {
+                Some(value) => (format!("{} <{value}>", v.name), v.description),
+                None => (v.name.to_string(), v.description),
+            })
+            .collect();
+        sections.push(list("Flags", items));
+    }
+    if !examples.is_empty() {
+        let items = examples
+            .iter()
+            .map(|v| (v.line.to_string(), v.description))
+            .collect();
+        sections.push(list("Examples", items));
+    }
+    sections.join("\n\n")
+}
+
+/// What a command can access while it runs.
+pub struct Context<'a> {
+    config: &'a GlobalConfig,
+    args: Option<&'a str>,
+}
+
+impl<'a> Context<'a> {
+    pub(super) fn new(config: &'a GlobalConfig, args: Option<&'a str>) -> Self {
+        Self { config, args }
+    }
+
+    /// The text typed after the command name.
+    pub fn args(&self) -> Option<&str> {
+        self.args
+    }
+
+    pub fn config(&self) -> &GlobalConfig {
+        self.config
+    }
+
+    pub fn session(&self) -> Option<MappedRwLockReadGuard<'_, Session>> {
+        RwLockReadGuard::try_map(self.config.read(), |v| v.session.as_ref()).ok()
+    }
+
+    pub fn session_mut(&self) -> Option<MappedRwLockWriteGuard<'_, Session>> {
+        RwLockWriteGuard::try_map(self.config.write(), |v| v.session.as_mut()).ok()
+    }
+
+    /// A client of the current model.
+    pub fn client(&self) -> Result<Box<dyn Client>> {
+        init_client(self.config)
+    }
+}
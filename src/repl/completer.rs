@@ -1,9 +1,9 @@
-use super::{ReplCommand, REPL_COMMANDS};
+use super::ReplCommand;
 
 use crate::config::GlobalConfig;
 
 use reedline::{Completer, Span, Suggestion};
-use std::collections::HashMap;
+use std::{collections::HashMap, fs::read_dir, path::Path};
 
 impl Completer for ReplCompleter {
     fn complete(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
@@ -46,6 +46,54 @@ impl Completer for ReplCompleter {
             })
             .collect();
 
+        if cmd == ".file" && parts_len > 1 {
+            let span = Span::new(parts[parts_len - 1].1, pos);
+            if parts[1..parts_len - 1].iter().any(|(v, _)| *v == "--") {
+                return suggestions;
+            }
+            return complete_path(parts[parts_len - 1].0)
+                .iter()
+                .map(|(value, description)| create_suggestion(value, description, span))
+                .collect();
+        }
+
+        if cmd == ".help" && parts_len == 2 {
+            let span = Span::new(parts[1].1, pos);
+            let filter = parts[1].0.trim_start_matches('.');
+            let mut names: Vec<&str> = vec![];
+            for cmd in self.commands.iter() {
+                let name = cmd.name.trim_start_matches('.');
+                let name = name.split(' ').next().unwrap_or(name);
+                if name.starts_with(filter) && !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+            return names
+                .into_iter()
+                .map(|name| create_suggestion(name, "", span))
+                .collect();
+        }
+
+        let (last, last_start) = parts[parts_len - 1];
+        if parts_len > 1 && last.starts_with('-') {
+            let typed: Vec<&str> = parts[1..parts_len - 1].iter().map(|(v, _)| *v).collect();
+            let subcommand = format!("{cmd} {}", parts[1].0);
+            let command = self
+                .commands
+                .iter()
+                .find(|v| v.name == subcommand)
+                .or_else(|| self.commands.iter().find(|v| v.name == cmd));
+            if let Some(command) = command {
+                let span = Span::new(last_start, pos);
+                return command
+                    .flags
+                    .iter()
+                    .filter(|v| v.name.starts_with(last) && !typed.contains(&v.name))
+                    .map(|v| create_suggestion(&format!("{} ", v.name), v.description, span))
+                    .collect();
+            }
+        }
+
         if parts_len > 1 {
             let span = Span::new(parts[parts_len - 1].1, pos);
             let args: Vec<&str> = parts.iter().skip(1).map(|(v, _)| *v).collect();
@@ -83,12 +131,12 @@ pub struct ReplCompleter {
 }
 
 impl ReplCompleter {
-    pub fn new(config: &GlobalConfig) -> Self {
+    pub fn new(config: &GlobalConfig, commands: &[ReplCommand]) -> Self {
         let mut groups = HashMap::new();
 
-        let commands: Vec<ReplCommand> = REPL_COMMANDS.to_vec();
+        let commands: Vec<ReplCommand> = commands.to_vec();
 
-        for cmd in REPL_COMMANDS.iter() {
+        for cmd in commands.iter() {
             let name = cmd.name;
             if let Some(count) = groups.get(name) {
                 groups.insert(name, count + 1);
@@ -121,6 +169,36 @@ fn create_suggestion(value: &str, description: &str, span: Span) -> Suggestion {
     }
 }
 
+/// Complete a filesystem path, directories end with `/` so that completion can continue into them.
+fn complete_path(word: &str) -> Vec<(String, String)> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let entries = match read_dir(if dir.is_empty() {
+        Path::new(".")
+    } else {
+        Path::new(dir)
+    }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut values: Vec<(String, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.file_type().map(|v| v.is_dir()).unwrap_or_default();
+            let suffix = if is_dir { "/" } else { " " };
+            Some((format!("{dir}{name}{suffix}"), String::new()))
+        })
+        .collect();
+    values.sort();
+    values
+}
+
 fn split_line(line: &str) -> Vec<(&str, usize)> {
     let mut parts = vec![];
     let mut part_start = None;
@@ -142,6 +220,22 @@ fn split_line(line: &str) -> Vec<(&str, usize)> {
     parts
 }
 
+#[test]
+fn test_complete_path() {
+    let dir = crate::utils::TestDir::new("complete");
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("notes.txt"), "").unwrap();
+    std::fs::write(dir.join(".hidden"), "").unwrap();
+    let base = format!("{}/", dir.display());
+    let values: Vec<String> = complete_path(&base).into_iter().map(|v| v.0).collect();
+    assert_eq!(values, [format!("{base}notes.txt "), format!("{base}sub/")]);
+    let values: Vec<String> = complete_path(&format!("{base}.h"))
+        .into_iter()
+        .map(|v| v.0)
+        .collect();
+    assert_eq!(values, [format!("{base}.hidden ")]);
+}
+
 #[test]
 fn test_split_line() {
     assert_eq!(split_line(".role coder"), vec![(".role", 0), ("coder", 6)],);
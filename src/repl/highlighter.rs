@@ -1,55 +1,243 @@
-use super::REPL_COMMANDS;
+use super::{resolve_command, ReplCommand};
 
-use crate::config::GlobalConfig;
+use crate::config::{GlobalConfig, ReplTheme};
 
 use nu_ansi_term::{Color, Style};
 use reedline::{Highlighter, StyledText};
 
 pub struct ReplHighlighter {
     config: GlobalConfig,
+    commands: Vec<ReplCommand>,
 }
 
 impl ReplHighlighter {
-    pub fn new(config: &GlobalConfig) -> Self {
+    pub fn new(config: &GlobalConfig, commands: &[ReplCommand]) -> Self {
         Self {
             config: config.clone(),
+            commands: commands.to_vec(),
         }
     }
 }
 
 impl Highlighter for ReplHighlighter {
     fn highlight(&self, line: &str, _cursor: usize) -> StyledText {
-        let color = Color::Default;
-        let match_color = if self.config.read().highlight {
-            Color::Green
+        let mut styled_text = StyledText::new();
+        let config = self.config.read();
+        if !config.highlight {
+            styled_text.push((Style::new(), line.to_string()));
+            return styled_text;
+        }
+        let palette = Palette::new(&config.repl_theme, config.light_theme);
+        highlight_input(&mut styled_text, line, &palette, &self.commands);
+        styled_text
+    }
+}
+
+struct Palette {
+    command: Style,
+    unknown_command: Style,
+    argument: Style,
+    string: Style,
+    fence: Style,
+}
+
+impl Palette {
+    fn new(theme: &ReplTheme, light_theme: bool) -> Self {
+        let style = |value: &Option<String>, default: Color| {
+            let color = value.as_deref().and_then(parse_color).unwrap_or(default);
+            Style::new().fg(color)
+        };
+        let (argument, string, fence) = if light_theme {
+            (Color::Blue, Color::Purple, Color::LightGray)
         } else {
-            color
+            (Color::Cyan, Color::Yellow, Color::DarkGray)
         };
+        Self {
+            command: style(&theme.command, Color::Green),
+            unknown_command: style(&theme.unknown_command, Color::Red),
+            argument: style(&theme.argument, argument),
+            string: style(&theme.string, string),
+            fence: style(&theme.fence, fence),
+        }
+    }
+}
 
-        let mut styled_text = StyledText::new();
+/// Parse a color name as used by the prompt templates (e.g. `light_green`) or a `#rrggbb` value.
+fn parse_color(value: &str) -> Option<Color> {
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+    }
+    let color = match value {
+        "black" => Color::Black,
+        "dark_gray" => Color::DarkGray,
+        "red" => Color::Red,
+        "light_red" => Color::LightRed,
+        "green" => Color::Green,
+        "light_green" => Color::LightGreen,
+        "yellow" => Color::Yellow,
+        "light_yellow" => Color::LightYellow,
+        "blue" => Color::Blue,
+        "light_blue" => Color::LightBlue,
+        "purple" => Color::Purple,
+        "light_purple" => Color::LightPurple,
+        "magenta" => Color::Magenta,
+        "light_magenta" => Color::LightMagenta,
+        "cyan" => Color::Cyan,
+        "light_cyan" => Color::LightCyan,
+        "white" => Color::White,
+        "light_gray" => Color::LightGray,
+        _ => return None,
+    };
+    Some(color)
+}
 
-        if REPL_COMMANDS.iter().any(|cmd| line.contains(cmd.name)) {
-            let matches: Vec<&str> = REPL_COMMANDS
-                .iter()
-                .filter(|cmd| line.contains(cmd.name))
-                .map(|cmd| cmd.name)
-                .collect();
-            let longest_match = matches.iter().fold(String::new(), |acc, &item| {
-                if item.len() > acc.len() {
-                    item.to_string()
-                } else {
-                    acc
-                }
-            });
-            let buffer_split: Vec<&str> = line.splitn(2, &longest_match).collect();
-
-            styled_text.push((Style::new().fg(color), buffer_split[0].to_string()));
-            styled_text.push((Style::new().fg(match_color), longest_match));
-            styled_text.push((Style::new().fg(color), buffer_split[1].to_string()));
-        } else {
-            styled_text.push((Style::new().fg(color), line.to_string()));
+fn highlight_input(
+    styled_text: &mut StyledText,
+    line: &str,
+    palette: &Palette,
+    commands: &[ReplCommand],
+) {
+    let trimmed = line.trim_start();
+    if let Some(body) = trimmed.strip_prefix(":::") {
+        push(
+            styled_text,
+            Style::new(),
+            &line[..line.len() - trimmed.len()],
+        );
+        push(styled_text, palette.fence, ":::");
+        let body_trimmed = body.trim_end();
+        match body_trimmed.strip_suffix(":::") {
+            Some(inner) => {
+                highlight_input(styled_text, inner, palette, commands);
+                push(styled_text, palette.fence, ":::");
+                push(styled_text, Style::new(), &body[body_trimmed.len()..]);
+            }
+            None => highlight_input(styled_text, body, palette, commands),
         }
+        return;
+    }
+    if !trimmed.starts_with('.') {
+        push(styled_text, Style::new(), line);
+        return;
+    }
+    push(
+        styled_text,
+        Style::new(),
+        &line[..line.len() - trimmed.len()],
+    );
+    let cmd_end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    let (cmd, rest) = trimmed.split_at(cmd_end);
+    let name = match resolve_command(commands, cmd) {
+        Ok(name) => {
+            push(styled_text, palette.command, cmd);
+            name
+        }
+        Err(_) => {
+            push(styled_text, palette.unknown_command, cmd);
+            push(styled_text, palette.argument, rest);
+            return;
+        }
+    };
+    let args = rest.trim_start();
+    push(styled_text, Style::new(), &rest[..rest.len() - args.len()]);
+    let sub_end = args.find(char::is_whitespace).unwrap_or(args.len());
+    let sub = &args[..sub_end];
+    let args = if !sub.is_empty()
+        && commands
+            .iter()
+            .any(|v| v.name.strip_prefix(name).and_then(|v| v.strip_prefix(' ')) == Some(sub))
+    {
+        push(styled_text, palette.command, sub);
+        &args[sub_end..]
+    } else {
+        args
+    };
+    highlight_args(styled_text, args, palette);
+}
+
+/// Highlight the arguments, giving quoted strings (even unterminated ones) their own color.
+fn highlight_args(styled_text: &mut StyledText, args: &str, palette: &Palette) {
+    let mut start = 0;
+    let mut quote: Option<(char, usize)> = None;
+    for (i, c) in args.char_indices() {
+        match quote {
+            Some((q, begin)) if c == q => {
+                push(styled_text, palette.string, &args[begin..i + 1]);
+                start = i + 1;
+                quote = None;
+            }
+            None if c == '"' || c == '\'' => {
+                push(styled_text, palette.argument, &args[start..i]);
+                quote = Some((c, i));
+            }
+            _ => {}
+        }
+    }
+    match quote {
+        Some((_, begin)) => push(styled_text, palette.string, &args[begin..]),
+        None => push(styled_text, palette.argument, &args[start..]),
+    }
+}
 
+fn push(styled_text: &mut StyledText, style: Style, text: &str) {
+    if !text.is_empty() {
+        styled_text.push((style, text.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repl::REPL_COMMANDS;
+
+    fn render(line: &str) -> Vec<(Color, String)> {
+        let palette = Palette::new(&ReplTheme::default(), false);
+        let mut styled_text = StyledText::new();
+        highlight_input(&mut styled_text, line, &palette, &REPL_COMMANDS[..]);
         styled_text
+            .buffer
+            .into_iter()
+            .map(|(style, text)| (style.foreground.unwrap_or(Color::Default), text))
+            .collect()
+    }
+
+    #[test]
+    fn test_highlight_input() {
+        assert_eq!(
+            render(".role delete \"my role\" x"),
+            [
+                (Color::Green, ".role".into()),
+                (Color::Default, " ".into()),
+                (Color::Green, "delete".into()),
+                (Color::Cyan, " ".into()),
+                (Color::Yellow, "\"my role\"".into()),
+                (Color::Cyan, " x".into()),
+            ]
+        );
+        assert_eq!(
+            render(".foo bar"),
+            [(Color::Red, ".foo".into()), (Color::Cyan, " bar".into()),]
+        );
+        assert_eq!(
+            render("::: hi\n:::"),
+            [
+                (Color::DarkGray, ":::".into()),
+                (Color::Default, " hi\n".into()),
+                (Color::DarkGray, ":::".into()),
+            ]
+        );
+        assert_eq!(render("hello"), [(Color::Default, "hello".into())]);
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("light_green"), Some(Color::LightGreen));
+        assert_eq!(parse_color("#ff8700"), Some(Color::Rgb(255, 135, 0)));
+        assert_eq!(parse_color("#ff87"), None);
+        assert_eq!(parse_color("orange"), None);
     }
 }
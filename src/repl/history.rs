@@ -0,0 +1,121 @@
+use reedline::{
+    CommandLineSearch, FileBackedHistory, History, HistoryItem, HistoryItemId, HistorySessionId,
+    Result, SearchQuery,
+};
+
+/// Keeps dot-commands and prompts apart in the history, in one file.
+///
+/// A search only finds entries of the kind of its text: Up on an empty line or after a
+/// question goes through the previous questions, after `.` through the previous commands.
+pub struct ReplHistory(FileBackedHistory);
+
+impl ReplHistory {
+    pub fn new(history: FileBackedHistory) -> Self {
+        Self(history)
+    }
+}
+
+fn is_command(line: &str) -> bool {
+    line.trim_start().starts_with('.')
+}
+
+impl History for ReplHistory {
+    fn save(&mut self, h: HistoryItem) -> Result<HistoryItem> {
+        self.0.save(h)
+    }
+
+    fn load(&self, id: HistoryItemId) -> Result<HistoryItem> {
+        self.0.load(id)
+    }
+
+    fn count(&self, query: SearchQuery) -> Result<i64> {
+        Ok(self.search(query)?.len() as i64)
+    }
+
+    fn count_all(&self) -> Result<i64> {
+        self.0.count_all()
+    }
+
+    fn search(&self, query: SearchQuery) -> Result<Vec<HistoryItem>> {
+        let commands = match &query.filter.command_line {
+            Some(CommandLineSearch::Prefix(text))
+            | Some(CommandLineSearch::Substring(text))
+            | Some(CommandLineSearch::Exact(text)) => is_command(text),
+            None => false,
+        };
+        let limit = query.limit;
+        let items = self.0.search(SearchQuery {
+            limit: None,
+            ..query
+        })?;
+        let items = items
+            .into_iter()
+            .filter(|v| is_command(&v.command_line) == commands);
+        Ok(match limit {
+            Some(limit) => items.take(limit.max(0) as usize).collect(),
+            None => items.collect(),
+        })
+    }
+
+    fn update(
+        &mut self,
+        id: HistoryItemId,
+        updater: &dyn Fn(HistoryItem) -> HistoryItem,
+    ) -> Result<()> {
+        self.0.update(id, updater)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.0.clear()
+    }
+
+    fn delete(&mut self, h: HistoryItemId) -> Result<()> {
+        self.0.delete(h)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.0.sync()
+    }
+
+    fn session(&self) -> Option<HistorySessionId> {
+        self.0.session()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reedline::{SearchDirection, SearchFilter};
+
+    fn search(history: &ReplHistory, text: Option<&str>) -> Vec<String> {
+        let filter = match text {
+            Some(text) => {
+                SearchFilter::from_text_search(CommandLineSearch::Prefix(text.into()), None)
+            }
+            None => SearchFilter::anything(None),
+        };
+        let query = SearchQuery {
+            limit: Some(2),
+            filter,
+            ..SearchQuery::everything(SearchDirection::Backward, None)
+        };
+        history
+            .search(query)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.command_line)
+            .collect()
+    }
+
+    #[test]
+    fn test_history_streams() {
+        let mut history = ReplHistory::new(FileBackedHistory::new(10).unwrap());
+        for line in ["what is rust", ".role coder", "why", ".info", ".model x"] {
+            history.save(HistoryItem::from_command_line(line)).unwrap();
+        }
+        assert_eq!(search(&history, None), vec!["why", "what is rust"]);
+        assert_eq!(search(&history, Some(".")), vec![".model x", ".info"]);
+        assert_eq!(search(&history, Some("w")), vec!["why", "what is rust"]);
+        assert_eq!(history.count_all().unwrap(), 5);
+    }
+}
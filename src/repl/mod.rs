@@ -1,27 +1,69 @@
+mod command;
 mod completer;
 mod highlighter;
+mod history;
+mod parse;
 mod prompt;
+mod script;
+mod tool_call;
+mod validator;
 
+pub use self::command::{Arg, Command, Context, Example, Flag};
+
+use self::command::{render_help, render_usage};
 use self::completer::ReplCompleter;
 use self::highlighter::ReplHighlighter;
+use self::history::ReplHistory;
+use self::parse::TokenIter;
 use self::prompt::ReplPrompt;
+use self::script::split_script;
+use self::tool_call::edit_arguments;
+use self::validator::ReplValidator;
 
-use crate::client::{ensure_model_capabilities, init_client, send_stream};
-use crate::config::{GlobalConfig, Input, InputContext, State};
-use crate::render::render_error;
-use crate::utils::{create_abort_signal, set_text, AbortSignal};
+use crate::client::{
+    ensure_model_capabilities, fetch_models, init_client, print_response_meta, send_stream,
+    ModelCapabilities,
+};
+use crate::config::{
+    self, Config, GlobalConfig, Input, InputContext, KeyAction, RepeatGuard, SignalAction, State,
+};
+use crate::function::{declare_functions, eval_tool_calls, ToolCall};
+use crate::render::{
+    extract_code_blocks, render_error, take_pending_pipes, take_typeahead, CodeBlock,
+    MarkdownRender,
+};
+use crate::utils::{
+    apply_hunk, create_abort_signal, extract_code_snippets, get_image, get_text, levenshtein,
+    parse_patch, pipe_command, print_paged, remove_temp_file, run_code, run_spinner, set_text,
+    temp_file, which, AbortSignal, FilePatch, Hunk,
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
+use chrono::Local;
+use crossterm::{
+    event,
+    terminal::{disable_raw_mode, enable_raw_mode},
+};
 use fancy_regex::Regex;
+use inquire::{Confirm, Select};
 use lazy_static::lazy_static;
 use nu_ansi_term::Color;
+use parking_lot::{Mutex, RwLock};
 use reedline::{
     default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
-    ColumnarMenu, EditCommand, EditMode, Emacs, KeyCode, KeyModifiers, Keybindings, Reedline,
-    ReedlineEvent, ReedlineMenu, ValidationResult, Validator, Vi,
+    ColumnarMenu, EditCommand, EditMode, Emacs, FileBackedHistory, KeyCode, KeyModifiers,
+    Keybindings, ListMenu, Reedline, ReedlineEvent, ReedlineMenu, Vi,
 };
 use reedline::{MenuBuilder, Signal};
-use std::{env, process};
+use std::{
+    collections::HashMap,
+    mem,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::oneshot;
 
 lazy_static! {
     static ref SPLIT_FILES_TEXT_ARGS_RE: Regex =
@@ -29,85 +71,299 @@ lazy_static! {
 }
 
 const MENU_NAME: &str = "completion_menu";
+const HISTORY_MENU_NAME: &str = "history_menu";
+const FOLLOWUP_TIMEOUT: Duration = Duration::from_secs(15);
 
 lazy_static! {
-    static ref REPL_COMMANDS: [ReplCommand; 16] = [
-        ReplCommand::new(".help", "Show this help message", State::all()),
-        ReplCommand::new(".info", "View system info", State::all()),
-        ReplCommand::new(".model", "Change the current LLM", State::all()),
+    static ref REPL_COMMANDS: [ReplCommand; 34] = [
+        ReplCommand::new(".help", "Show this help message", State::all())
+            .with_args(vec![Arg::optional("command", "E.g. `.session` or `.model info`")])
+            .with_details("Show all commands, or the detailed help of a command."),
+        ReplCommand::new(".info", "View system info", State::all())
+            .with_details("Show the active client, model, role, session, parameters and file paths."),
+        ReplCommand::new(".model", "Change the current LLM", State::all())
+            .with_args(vec![Arg::required("name", "The model as `<client>:<model>`")])
+            .with_details("Switch to another model.")
+            .with_examples(vec![Example::new(".model openai:gpt-4", "Switch to GPT-4")]),
+        ReplCommand::new(".model info", "View model details", State::all())
+            .with_args(vec![Arg::optional("name", "The model, the current one by default")])
+            .with_details("Show the context window, max output, modalities, tool support, pricing,\nknowledge cutoff, endpoint and client of the model."),
+        ReplCommand::new(".model list", "List the models", State::all())
+            .with_details("Show the context window, max output, prices and capabilities of the models:\n👁 vision, ⚒ function calling, `embedding` for embedding models.\nThe models the providers list now are fetched too, `.model` completes them afterwards."),
         ReplCommand::new(
             ".prompt",
             "Create a temporary role using a prompt",
             State::able_change_role()
-        ),
+        )
+        .with_args(vec![Arg::required("text", "The system prompt").variadic()])
+        .with_details("Use the text as the system prompt of a temporary role."),
         ReplCommand::new(
             ".role",
             "Switch to a specific role",
             State::able_change_role()
-        ),
-        ReplCommand::new(".info role", "View role info", State::in_role(),),
-        ReplCommand::new(".exit role", "Leave the role", State::in_role(),),
-        ReplCommand::new(".session", "Begin a chat session", State::not_in_session(),),
-        ReplCommand::new(".info session", "View session info", State::in_session(),),
+        )
+        .with_args(vec![
+            Arg::required("name", "The role"),
+            Arg::optional("text", "Send it with the role, staying in the current one").variadic(),
+        ])
+        .with_details("Switch to the role, or send the text with the role once.")
+        .with_examples(vec![Example::new(".role shell list the files", "Ask the `shell` role once")]),
+        ReplCommand::new(".role delete", "Delete a role", State::all())
+            .with_args(vec![Arg::required("name", "The role")])
+            .with_details("Remove the role from the roles file after confirmation."),
+        ReplCommand::new(".info role", "View role info", State::in_role(),)
+            .with_details("Show the prompt and parameters of the current role."),
+        ReplCommand::new(".exit role", "Leave the role", State::in_role(),)
+            .with_details("Stop using the current role."),
+        ReplCommand::new(".session", "Begin a chat session", State::not_in_session(),)
+            .with_args(vec![Arg::optional("name", "A saved session, or the name of a new one")])
+            .with_details("Start a new session or open a saved one. Without a name, a temporary session is used."),
+        ReplCommand::new(".session delete", "Delete a saved session", State::all())
+            .with_args(vec![Arg::required("name", "The saved session")])
+            .with_details("Remove the session file after confirmation."),
+        ReplCommand::new(".session recover", "Restore a session from its backup", State::all())
+            .with_args(vec![Arg::required("name", "The saved session")])
+            .with_details("Restore the session file from its newest backup that loads, e.g. after a crash while saving."),
+        ReplCommand::new(
+            ".session switch",
+            "End the current session and start another",
+            State::in_session()
+        )
+        .with_args(vec![Arg::required("name", "A saved session, or the name of a new one")])
+        .with_details("End the current session, then start or open another one."),
+        ReplCommand::new(".info session", "View session info", State::in_session(),)
+            .with_details("Show the parameters and messages of the current session."),
+        ReplCommand::new(
+            ".session history",
+            "View session messages",
+            State::in_session(),
+        )
+        .with_flags(vec![Flag::new("--show-compressed", "Include the messages replaced by the summary")])
+        .with_details("Show what the model currently remembers."),
+        ReplCommand::new(
+            ".session inspect",
+            "View the tokens of the session messages",
+            State::in_session(),
+        )
+        .with_details("List every message with its tokens, age in turns and compression status, and whether the next request sends it within `max_input_tokens`."),
+        ReplCommand::new(
+            ".session export",
+            "Export the session as a note of an Obsidian vault",
+            State::in_session(),
+        )
+        .with_flags(vec![Flag::new("--obsidian", "The only format so far").required()])
+        .with_details("Write the session as a note with frontmatter under `obsidian.notes_dir` and link it from the daily note."),
         ReplCommand::new(
             ".save session",
             "Save the chat to file",
             State::in_session(),
-        ),
+        )
+        .with_args(vec![Arg::optional("name", "Save it under this name instead")])
+        .with_details("Save the current session, optionally under a new name."),
         ReplCommand::new(
             ".clear messages",
             "Erase messages in the current session",
             State::unable_change_role()
-        ),
+        )
+        .with_details("Erase all messages, including compressed ones, in the current session."),
         ReplCommand::new(
             ".exit session",
             "End the current session",
             State::in_session(),
-        ),
-        ReplCommand::new(".file", "Include files with the message", State::all()),
-        ReplCommand::new(".set", "Adjust settings", State::all()),
-        ReplCommand::new(".copy", "Copy the last response", State::all()),
-        ReplCommand::new(".exit", "Exit the REPL", State::all()),
+        )
+        .with_details("End the current session, asking whether to save it if needed."),
+        ReplCommand::new(".file", "Include files with the message", State::all())
+            .with_args(vec![Arg::required("files", "The files to send").variadic()])
+            .with_flags(vec![Flag::new("--", "The text of the message, the rest of the line").with_value("text")])
+            .with_details("Send the files, and the optional text, as the message. Images are sent to vision models.")
+            .with_examples(vec![Example::new(".file src/main.rs -- explain it", "Ask about a file")]),
+        ReplCommand::new(".compose", "Write the next prompt in an editor", State::all())
+            .with_details("Open the editor on a markdown file headed by a summary of the context, and send what is saved as the next prompt, with the files attached by `.paste` or `.file`.\nUses `buffer_editor`, $VISUAL or $EDITOR, else nano, vim or vi. An empty file sends nothing."),
+        ReplCommand::new(".paste", "Paste an image or text from the clipboard", State::all())
+            .with_details("Attach the image of the clipboard to the next message, or insert the text of the clipboard.\nImages are saved as temporary PNG files and need a model that supports vision. Bound to Ctrl+V."),
+        ReplCommand::new(".set", "Adjust settings", State::all())
+            .with_args(vec![
+                Arg::required("key", "The setting"),
+                Arg::required("value", "`null` unsets it").variadic(),
+            ])
+            .with_details("Change a setting for the current run.")
+            .with_examples(vec![Example::new(".set temperature 0.2", "Make the replies less random")]),
+        ReplCommand::new(".vars", "Set or list prompt variables", State::all())
+            .with_args(vec![
+                Arg::required("set|list", "Set a variable, or list them"),
+                Arg::optional("key", "The variable to set"),
+                Arg::optional("value", "Its value").variadic(),
+            ])
+            .with_details("Values for `%{name}` placeholders in roles and prompts."),
+        ReplCommand::new(".copy", "Copy the last response", State::all())
+            .with_args(vec![Arg::optional("n", "A code block of the response")])
+            .with_details("Copy the last response, or its n-th code block, to the clipboard. Typed while a response is streaming, the partial response is copied."),
+        ReplCommand::new(".cost", "View the tokens and cost of the requests", State::all())
+            .with_details("Show the tokens and cost of the current session, of this run per client, and of all the\nruns so far, which are kept in `usage.jsonl` of the config dir. The cost is by the prices of\nthe models, in `currency`. The file gets a line per request, remove it to start the counts over."),
+        ReplCommand::new(".pipe", "Pipe the last response to a shell command", State::all())
+            .with_args(vec![Arg::required("command", "The shell command").variadic()])
+            .with_details("Run the command with the last response as its input. While a response is streaming, `.pipe <command>` and `.copy` act on the partial response.")
+            .with_examples(vec![Example::new(".pipe wc -w", "Count the words of the response")]),
+        ReplCommand::new(".save-code", "Save a code block of the last response", State::all())
+            .with_args(vec![
+                Arg::required("n", "The code block, as listed after the response"),
+                Arg::required("file", "Where to write it"),
+            ])
+            .with_details("Write the n-th code block of the last response to the file."),
+        ReplCommand::new(".apply", "Review and apply a patch of the last response", State::all())
+            .with_args(vec![Arg::optional("n", "A code block of the response, all of them by default")])
+            .with_flags(vec![Flag::new("--yes", "Accept all the hunks")])
+            .with_details("Apply the diff code blocks of the last response to the files in the current directory.\nEach hunk is shown first to accept, skip or edit it."),
+        ReplCommand::new(".tool call", "Call a builtin function with arguments typed in", State::all())
+            .with_args(vec![Arg::required("name", "The builtin function")])
+            .with_details("Edit the arguments of the function as JSON or YAML, starting from the defaults of its\nschema, then run it and show the result. The arguments are checked against the schema as\nthey are typed, Ctrl+C gives up. Helps to debug the functions without a model."),
+        ReplCommand::new(".source", "Run the REPL lines of a file", State::all())
+            .with_args(vec![Arg::required("file", "The script")])
+            .with_details("Run each line of the file as if it was typed, `:::` blocks included.\nBlank lines and lines starting with `#` are skipped. Stops at the first error."),
+        ReplCommand::new(".exit", "Exit the REPL", State::all())
+            .with_details("Exit the REPL, same as Ctrl+D by default."),
     ];
     static ref COMMAND_RE: Regex = Regex::new(r"^\s*(\.\S*)\s*").unwrap();
     static ref MULTILINE_RE: Regex = Regex::new(r"(?s)^\s*:::\s*(.*)\s*:::\s*$").unwrap();
 }
 
+/// Scripts may source other scripts, but not forever.
+const MAX_SOURCE_DEPTH: usize = 8;
+
 pub struct Repl {
     config: GlobalConfig,
     editor: Reedline,
     prompt: ReplPrompt,
     abort: AbortSignal,
+    commands: Vec<ReplCommand>,
+    custom_commands: Vec<Box<dyn Command>>,
+    source_depth: usize,
+    pending_files: Vec<String>,
+    /// The images of `.paste` among the pending files, removed once they are read
+    pasted_files: Vec<PathBuf>,
+    /// `.file` without text attaches the files to the next message instead of sending them.
+    defer_files: bool,
+    /// The line submitted last at the prompt, to catch a prompt sent twice by accident.
+    last_line: Option<String>,
+    prefetch: Arc<Mutex<Prefetch>>,
 }
 
-impl Repl {
-    pub fn init(config: &GlobalConfig) -> Result<Self> {
-        let editor = Self::create_editor(config)?;
+/// Answers to the suggested follow-ups generated ahead by `prefetch_followups`, until the
+/// next message is sent.
+#[derive(Debug, Default)]
+struct Prefetch {
+    generation: u64,
+    answers: HashMap<String, String>,
+}
+
+/// Builds a REPL with extra dot-commands next to the built-in ones.
+pub struct ReplBuilder {
+    config: GlobalConfig,
+    custom_commands: Vec<Box<dyn Command>>,
+}
 
-        let prompt = ReplPrompt::new(config);
+impl ReplBuilder {
+    pub fn new(config: &GlobalConfig) -> Self {
+        Self {
+            config: config.clone(),
+            custom_commands: vec![],
+        }
+    }
+
+    pub fn add_command(mut self, command: Box<dyn Command>) -> Self {
+        self.custom_commands.push(command);
+        self
+    }
+
+    pub fn build(self) -> Result<Repl> {
+        let mut commands = REPL_COMMANDS.to_vec();
+        for command in &self.custom_commands {
+            let name = command.name();
+            if !name.starts_with('.') || name.contains(char::is_whitespace) {
+                bail!("Invalid command name '{name}', expect a dot and no spaces, e.g. '.foo'");
+            }
+            if commands.iter().any(|v| v.name == name) {
+                bail!("Command '{name}' already exists");
+            }
+            if command.description().trim().is_empty() {
+                bail!("Command '{name}' has no description");
+            }
+            let mut repl_command = ReplCommand::new(name, command.description(), State::all())
+                .with_args(command.args())
+                .with_flags(command.flags());
+            repl_command.help = Some(command.help());
+            commands.push(repl_command);
+        }
+
+        let editor = Repl::create_editor(&self.config, &commands)?;
+
+        let prompt = ReplPrompt::new(&self.config);
 
         let abort = create_abort_signal();
 
-        Ok(Self {
-            config: config.clone(),
+        Ok(Repl {
+            config: self.config,
             editor,
             prompt,
             abort,
+            commands,
+            custom_commands: self.custom_commands,
+            source_depth: 0,
+            pending_files: vec![],
+            pasted_files: vec![],
+            defer_files: false,
+            last_line: None,
+            prefetch: Default::default(),
         })
     }
+}
+
+impl Repl {
+    pub fn init(config: &GlobalConfig) -> Result<Self> {
+        ReplBuilder::new(config).build()
+    }
 
     pub async fn run(&mut self) -> Result<()> {
         self.banner();
 
+        if self.run_replrc().await {
+            self.handle(".exit session").await?;
+            return Ok(());
+        }
+
         loop {
-            if self.abort.aborted_ctrld() {
-                break;
+            for (command, text) in take_pending_pipes() {
+                if let Err(err) = pipe_command(&command, &text) {
+                    render_error(err, self.config.read().highlight);
+                }
+            }
+            let typeahead = take_typeahead();
+            if !typeahead.is_empty() {
+                self.editor
+                    .run_edit_commands(&[EditCommand::InsertString(typeahead)]);
             }
             let sig = self.editor.read_line(&self.prompt);
             match sig {
                 Ok(Signal::Success(line)) => {
                     self.abort.reset();
-                    match self.handle(&line).await {
+                    let previous = self.last_line.replace(line.clone());
+                    if previous.as_deref() == Some(line.as_str()) {
+                        match self.guard_repeat(&line) {
+                            Ok(true) => continue,
+                            Ok(false) => {}
+                            Err(err) => {
+                                render_error(err, self.config.read().highlight);
+                                println!();
+                                continue;
+                            }
+                        }
+                    }
+                    let ret = match self.handle(&line).await {
+                        Ok(true) => Ok(true),
+                        Ok(false) => self.after_abort().await,
+                        Err(err) => Err(err),
+                    };
+                    match ret {
                         Ok(exit) => {
                             if exit {
                                 break;
@@ -119,13 +375,19 @@ impl Repl {
                         }
                     }
                 }
-                Ok(Signal::CtrlC) => {
-                    self.abort.set_ctrlc();
-                    println!("(To exit, press Ctrl+D or enter \".exit\")\n");
-                }
-                Ok(Signal::CtrlD) => {
-                    self.abort.set_ctrld();
-                    break;
+                Ok(signal @ (Signal::CtrlC | Signal::CtrlD)) => {
+                    let action = match signal {
+                        Signal::CtrlC => self.config.read().ctrl_c,
+                        _ => self.config.read().ctrl_d,
+                    };
+                    match self.on_signal(action, true).await {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(err) => {
+                            render_error(err, self.config.read().highlight);
+                            println!()
+                        }
+                    }
                 }
                 _ => {}
             }
@@ -134,95 +396,368 @@ impl Repl {
         Ok(())
     }
 
-    async fn handle(&self, mut line: &str) -> Result<bool> {
+    /// Check a prompt submitted twice in a row, returns true if it should not be sent again.
+    fn guard_repeat(&self, line: &str) -> Result<bool> {
+        if parse_command(line).is_some() || !self.pending_files.is_empty() {
+            return Ok(false);
+        }
+        let repeat_guard = self.config.read().repeat_guard;
+        let answered = matches!(
+            &self.config.read().last_message,
+            Some((input, _)) if input.text() == line
+        );
+        if repeat_guard == RepeatGuard::Off || !answered {
+            return Ok(false);
+        }
+        if repeat_guard == RepeatGuard::Ask {
+            let ans = Confirm::new("Same prompt as the last one, send it again?")
+                .with_default(false)
+                .prompt()?;
+            return Ok(!ans);
+        }
+        let output = self.config.read().last_reply().to_string();
+        let render_options = self.config.read().get_render_options()?;
+        let mut markdown_render = MarkdownRender::init(render_options)?;
+        println!("(Same prompt as the last one, showing its reply)");
+        self.print(markdown_render.render(&output).trim());
+        println!();
+        Ok(true)
+    }
+
+    /// Finish the action of the key that aborted the reply, if any.
+    async fn after_abort(&mut self) -> Result<bool> {
+        let action = if self.abort.aborted_ctrlc() {
+            self.config.read().ctrl_c
+        } else if self.abort.aborted_ctrld() {
+            self.config.read().ctrl_d
+        } else {
+            return Ok(false);
+        };
+        self.on_signal(action, false).await
+    }
+
+    /// Run the action bound to Ctrl+C or Ctrl+D, returns true to leave the REPL.
+    async fn on_signal(&mut self, action: SignalAction, at_prompt: bool) -> Result<bool> {
+        match action {
+            SignalAction::Abort => Ok(false),
+            SignalAction::ClearLine => {
+                if at_prompt {
+                    let exit_keys: Vec<&str> = [
+                        ("Ctrl+C", self.config.read().ctrl_c),
+                        ("Ctrl+D", self.config.read().ctrl_d),
+                    ]
+                    .into_iter()
+                    .filter(|(_, action)| *action == SignalAction::Exit)
+                    .map(|(key, _)| key)
+                    .collect();
+                    match exit_keys.first() {
+                        Some(key) => println!("(To exit, press {key} or enter \".exit\")\n"),
+                        None => println!("(To exit, enter \".exit\")\n"),
+                    }
+                }
+                Ok(false)
+            }
+            SignalAction::ExitSession => {
+                if self.config.read().has_session() {
+                    self.handle(".exit session").await?;
+                }
+                Ok(false)
+            }
+            SignalAction::Exit => Ok(true),
+        }
+    }
+
+    /// Run the commands of `--cmd`, then send the text and the files as one message.
+    pub async fn run_commands(
+        &mut self,
+        commands: &[String],
+        text: Option<String>,
+        files: Vec<String>,
+    ) -> Result<()> {
+        let has_message = text.is_some() || !files.is_empty();
+        self.defer_files = has_message;
+        for command in commands {
+            if parse_command(command).is_none() {
+                bail!("Invalid command '{command}', expect a dot-command like '.role coder'");
+            }
+            if self.handle(command).await? {
+                return Ok(());
+            }
+        }
+        if has_message {
+            self.pending_files.extend(files);
+            self.ask_text(&text.unwrap_or_default()).await?;
+        }
+        self.handle(".exit session").await?;
+        Ok(())
+    }
+
+    /// Run the lines of the replrc file, reporting the errors without stopping.
+    /// Returns true when one of them exits the REPL.
+    async fn run_replrc(&mut self) -> bool {
+        let highlight = self.config.read().highlight;
+        let entries = match Config::replrc_file().and_then(|path| {
+            if !path.exists() {
+                return Ok(vec![]);
+            }
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read '{}'", path.display()))?;
+            split_script(&text).with_context(|| format!("Invalid replrc '{}'", path.display()))
+        }) {
+            Ok(entries) => entries,
+            Err(err) => {
+                render_error(err, highlight);
+                return false;
+            }
+        };
+        for entry in entries {
+            match self.handle(&entry).await {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(err) => render_error(err.context(format!("replrc: {entry}")), highlight),
+            }
+        }
+        false
+    }
+
+    /// Run a script without the interactive editor, then end the session like `.exit`.
+    pub async fn run_script(&mut self, path: &str) -> Result<()> {
+        self.source(path).await?;
+        self.handle(".exit session").await?;
+        Ok(())
+    }
+
+    /// Run the lines of a file in order, returns true when one of them exits the REPL.
+    async fn source(&mut self, path: &str) -> Result<bool> {
+        if self.source_depth >= MAX_SOURCE_DEPTH {
+            bail!("Too many nested scripts, stopped at '{path}'");
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read script '{path}'"))?;
+        let entries = split_script(&text).with_context(|| format!("Invalid script '{path}'"))?;
+        self.source_depth += 1;
+        let mut ret = Ok(false);
+        for entry in entries {
+            println!("{}{entry}", self.config.read().render_prompt_left());
+            ret = Box::pin(self.handle(&entry)).await;
+            if !matches!(ret, Ok(false)) {
+                break;
+            }
+        }
+        self.source_depth -= 1;
+        ret
+    }
+
+    async fn handle(&mut self, mut line: &str) -> Result<bool> {
         if let Ok(Some(captures)) = MULTILINE_RE.captures(line) {
             if let Some(text_match) = captures.get(1) {
                 line = text_match.as_str();
             }
         }
         match parse_command(line) {
-            Some((cmd, args)) => match cmd {
-                ".help" => {
-                    dump_repl_help();
-                }
+            Some((cmd, args)) => match resolve_command(&self.commands, cmd)? {
+                ".help" => match args {
+                    Some(name) => self.print(&command_help(&self.commands, name)?),
+                    None => self.print(&repl_help(&self.commands)),
+                },
                 ".info" => match args {
                     Some("role") => {
                         let info = self.config.read().role_info()?;
-                        println!("{}", info);
+                        self.print(&info);
                     }
                     Some("session") => {
                         let info = self.config.read().session_info()?;
-                        println!("{}", info);
+                        self.print(&info);
                     }
                     Some(_) => unknown_command()?,
                     None => {
                         let output = self.config.read().system_info()?;
-                        println!("{}", output);
+                        self.print(&output);
                     }
                 },
-                ".model" => match args {
-                    Some(name) => {
-                        self.config.write().set_model(name)?;
+                ".model" => {
+                    let mut tokens = TokenIter::new(args.unwrap_or_default());
+                    match tokens.next().as_deref() {
+                        Some("info") => {
+                            let name = tokens.next();
+                            let output = self.config.read().model_info(name.as_deref())?;
+                            self.print(&output);
+                        }
+                        Some("list") => {
+                            let models = fetch_models(&self.config).await;
+                            self.config.write().set_fetched_models(models);
+                            let output = self.config.read().models_table();
+                            self.print(&output);
+                        }
+                        Some(_) => {
+                            self.config.write().set_model(args.unwrap_or_default())?;
+                        }
+                        None => self.print_usage(".model"),
                     }
-                    None => println!("Usage: .model <name>"),
-                },
+                }
                 ".prompt" => match args {
                     Some(text) => {
                         self.config.write().set_prompt(text)?;
                     }
-                    None => println!("Usage: .prompt <text>..."),
+                    None => self.print_usage(".prompt"),
                 },
-                ".role" => match args {
-                    Some(args) => match args.split_once(|c| c == '\n' || c == ' ') {
-                        Some((name, text)) => {
-                            let role = self.config.read().retrieve_role(name.trim())?;
-                            let input =
-                                Input::from_str(text.trim(), InputContext::new(Some(role), false));
-                            self.ask(input).await?;
+                ".role" => {
+                    let mut tokens = TokenIter::new(args.unwrap_or_default());
+                    match tokens.next().as_deref() {
+                        Some("delete") => match tokens.next() {
+                            Some(name) => self.config.write().delete_role(&name)?,
+                            None => self.print_usage(".role delete"),
+                        },
+                        Some(name) => {
+                            let text = tokens.rest();
+                            if text.is_empty() {
+                                self.config.write().set_role(name)?;
+                            } else {
+                                let role = self.config.read().retrieve_role(name)?;
+                                let input =
+                                    Input::from_str(text, InputContext::new(Some(role), false));
+                                self.ask(input).await?;
+                            }
                         }
-                        None => {
-                            self.config.write().set_role(args)?;
+                        None => self.print_usage(".role"),
+                    }
+                }
+                ".session" => match args
+                    .map(|v| TokenIter::new(v).collect::<Vec<_>>())
+                    .as_ref()
+                    .map(|v| v.iter().map(|v| v.as_str()).collect::<Vec<_>>())
+                    .as_deref()
+                {
+                    // Without a session they fail, rather than start a session of their name
+                    Some(["history", flags @ ..]) => match flags {
+                        [] => self.print(&self.config.read().session_history(false)?),
+                        ["--show-compressed"] => {
+                            self.print(&self.config.read().session_history(true)?)
+                        }
+                        _ => self.print_usage(".session history"),
+                    },
+                    Some(["inspect"]) => self.print(&self.config.read().session_inspect()?),
+                    Some(["export", flags @ ..]) => match flags {
+                        ["--obsidian"] => {
+                            let path = self.config.read().export_session_obsidian()?;
+                            println!("Exported to '{}'", path.display());
                         }
+                        _ => self.print_usage(".session export"),
                     },
-                    None => println!(r#"Usage: .role <name> [text]..."#),
+                    Some(["delete", name]) => {
+                        self.config.write().delete_session(name)?;
+                    }
+                    Some(["recover", name]) => {
+                        self.config.write().recover_session(name)?;
+                    }
+                    Some(["switch", name]) => {
+                        self.config.write().switch_session(name)?;
+                    }
+                    Some([name]) => {
+                        self.config.write().start_session(Some(name))?;
+                    }
+                    _ => {
+                        self.config.write().start_session(args)?;
+                    }
                 },
-                ".session" => {
-                    self.config.write().start_session(args)?;
-                }
                 ".save" => {
-                    match args.map(|v| match v.split_once(' ') {
-                        Some((subcmd, args)) => (subcmd, args.trim()),
-                        None => (v, ""),
-                    }) {
-                        Some(("session", name)) => {
-                            self.config.write().save_session(name)?;
-                        }
-                        _ => {
-                            println!(r#"Usage: .save session [name]"#)
+                    let mut tokens = TokenIter::new(args.unwrap_or_default());
+                    match tokens.next().as_deref() {
+                        Some("session") => {
+                            let name = tokens.next().unwrap_or_default();
+                            self.config.write().save_session(&name)?;
                         }
+                        _ => self.print_usage(".save session"),
                     }
                 }
                 ".set" => match args {
                     Some(args) => {
                         self.config.write().update(args)?;
+                        if args.split_whitespace().next() == Some("keybindings") {
+                            self.reload_edit_mode();
+                        }
                     }
-                    _ => {
-                        println!("Usage: .set <key> <value>...")
+                    _ => self.print_usage(".set"),
+                },
+                ".vars" => {
+                    let mut tokens = TokenIter::new(args.unwrap_or_default());
+                    match tokens.next().as_deref() {
+                        Some("set") => {
+                            self.config.write().set_variable(tokens.rest())?;
+                        }
+                        Some("list") => {
+                            println!("{}", self.config.read().list_variables());
+                        }
+                        _ => self.print_usage(".vars"),
+                    }
+                }
+                ".copy" => match args {
+                    Some(index) => {
+                        let block = self.code_block(index)?;
+                        self.copy(&block.code)
+                            .with_context(|| "Failed to copy the code block")?;
+                    }
+                    None => {
+                        let config = self.config.read();
+                        self.copy(config.last_reply())
+                            .with_context(|| "Failed to copy the last output")?;
+                    }
+                },
+                ".save-code" => match args.and_then(|v| v.split_once(' ')) {
+                    Some((index, path)) => {
+                        let block = self.code_block(index)?;
+                        let path = path.trim();
+                        std::fs::write(path, &block.code)
+                            .with_context(|| format!("Failed to write '{path}'"))?;
+                        println!("✓ Saved code block {} to '{path}'", index.trim());
                     }
+                    None => self.print_usage(".save-code"),
                 },
-                ".copy" => {
-                    let config = self.config.read();
-                    self.copy(config.last_reply())
-                        .with_context(|| "Failed to copy the last output")?;
+                ".apply" => self.apply(args)?,
+                ".tool" => {
+                    let mut tokens = TokenIter::new(args.unwrap_or_default());
+                    match (tokens.next().as_deref(), tokens.next()) {
+                        (Some("call"), Some(name)) => self.call_tool(&name).await?,
+                        _ => self.print_usage(".tool call"),
+                    }
+                }
+                ".compose" => self.compose().await?,
+                ".paste" => self.paste()?,
+                ".cost" => {
+                    let report = self.config.read().cost_report()?;
+                    self.print(&report);
                 }
+                ".pipe" => match args {
+                    Some(command) => {
+                        let text = self.config.read().last_reply().to_string();
+                        if text.is_empty() {
+                            bail!("Empty text");
+                        }
+                        pipe_command(command, &text)?;
+                    }
+                    None => self.print_usage(".pipe"),
+                },
                 ".file" => match args {
                     Some(args) => {
                         let (files, text) = split_files_text(args);
-                        let files = shell_words::split(files).with_context(|| "Invalid args")?;
-                        let input = Input::new(text, files, self.config.read().input_context())?;
-                        self.ask(input).await?;
+                        let mut files =
+                            shell_words::split(files).with_context(|| "Invalid args")?;
+                        if self.defer_files && text.is_empty() {
+                            self.pending_files.append(&mut files);
+                        } else {
+                            let input = self.pending_input(text, files)?;
+                            self.ask(input).await?;
+                        }
                     }
-                    None => println!("Usage: .file <files>... [-- <text>...]"),
+                    None => self.print_usage(".file"),
+                },
+                ".source" => match args {
+                    Some(path) => {
+                        if self.source(path.trim()).await? {
+                            return Ok(true);
+                        }
+                    }
+                    None => self.print_usage(".source"),
                 },
                 ".exit" => match args {
                     Some("role") => {
@@ -242,11 +777,32 @@ impl Repl {
                     }
                     _ => unknown_command()?,
                 },
-                _ => unknown_command()?,
+                name => match self.custom_commands.iter().find(|v| v.name() == name) {
+                    Some(command)
+                        if args.is_none() && command.args().iter().any(|v| v.required) =>
+                    {
+                        println!("Usage: {}", command.usage())
+                    }
+                    Some(command) => {
+                        let mut ctx = Context::new(&self.config, args);
+                        command.run(&mut ctx).await?;
+                    }
+                    None => unknown_command()?,
+                },
             },
             None => {
-                let input = Input::from_str(line, self.config.read().input_context());
-                self.ask(input).await?;
+                let followup = self.config.read().pick_followup(line);
+                let prefetched = followup
+                    .as_ref()
+                    .filter(|_| self.pending_files.is_empty())
+                    .and_then(|v| self.prefetch.lock().answers.remove(v));
+                match (followup, prefetched) {
+                    (Some(text), Some(answer)) => self.serve_prefetched(&text, answer).await?,
+                    (followup, _) => {
+                        let text = followup.as_deref().unwrap_or(line);
+                        self.ask_text(text).await?;
+                    }
+                }
             }
         }
 
@@ -255,19 +811,92 @@ impl Repl {
         Ok(false)
     }
 
-    async fn ask(&self, input: Input) -> Result<()> {
+    /// Run a builtin function with the arguments edited by the user, without a model.
+    async fn call_tool(&self, name: &str) -> Result<()> {
+        let declaration = declare_functions(&[name.to_string()])?.remove(0);
+        println!("{}", declaration.description);
+        let highlight = self.config.read().highlight;
+        let Some(arguments) = edit_arguments(&declaration, highlight)? else {
+            return Ok(());
+        };
+        let call = ToolCall {
+            id: String::new(),
+            name: declaration.name,
+            arguments,
+        };
+        for result in eval_tool_calls(&self.config, vec![call]).await {
+            let output = match result.output {
+                serde_json::Value::String(text) => text,
+                output => serde_json::to_string_pretty(&output)?,
+            };
+            self.print(&output);
+        }
+        Ok(())
+    }
+
+    /// Send the text with the files attached by `.paste` or `.file`.
+    async fn ask_text(&mut self, text: &str) -> Result<()> {
+        let input = self.pending_input(text, vec![])?;
+        self.ask(input).await
+    }
+
+    /// The input of the text with the files, followed by the ones attached to the next message.
+    /// The pasted images are removed then, the input holds their data.
+    fn pending_input(&mut self, text: &str, mut files: Vec<String>) -> Result<Input> {
+        files.append(&mut self.pending_files);
+        let input = if files.is_empty() {
+            Ok(Input::from_str(text, self.config.read().input_context()))
+        } else {
+            Input::new(text, files, self.config.read().input_context())
+        };
+        for path in self.pasted_files.drain(..) {
+            remove_temp_file(&path);
+        }
+        input
+    }
+
+    async fn ask(&self, mut input: Input) -> Result<()> {
         if input.is_empty() {
             return Ok(());
         }
+        self.config.write().fill_input_variables(&mut input)?;
         while self.config.read().is_compressing_session() {
-            std::thread::sleep(std::time::Duration::from_millis(100));
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        self.config.write().followups.clear();
+        self.clear_prefetch();
         self.config.read().maybe_print_send_tokens(&input);
         let mut client = init_client(&self.config)?;
         ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
-        let output = send_stream(&input, client.as_ref(), &self.config, self.abort.clone()).await?;
+        let output = if self.config.read().stream {
+            send_stream(&input, client.as_ref(), &self.config, self.abort.clone()).await?
+        } else {
+            // Raw mode is off while waiting, so Ctrl+C comes as a signal instead of a key
+            let (output, details) = tokio::select! {
+                ret = client.send_message(input.clone()) => ret?,
+                _ = tokio::signal::ctrl_c() => {
+                    self.abort.set_ctrlc();
+                    return Ok(());
+                }
+            };
+            let render_options = self.config.read().get_render_options()?;
+            let mut markdown_render = MarkdownRender::init(render_options)?;
+            self.print(markdown_render.render(&output).trim());
+            print_response_meta(&details.meta, &self.config);
+            output
+        };
+        let question = input.text();
         self.config.write().save_message(input, &output)?;
         self.config.read().maybe_copy(&output);
+        self.list_code_blocks(&output);
+        if self.config.read().run_code && !self.abort.aborted() {
+            if let Some(input) = self.run_code_blocks(&output).await? {
+                return Box::pin(self.ask(input)).await;
+            }
+        }
+        if self.config.read().suggest_followups && !self.abort.aborted() {
+            self.suggest_followups(&question, &output).await;
+        }
         if self.config.write().should_compress_session() {
             let config = self.config.clone();
             let color = if config.read().light_theme {
@@ -291,24 +920,200 @@ impl Repl {
         Ok(())
     }
 
+    /// Offer to run the code blocks of the reply, returning their output as the next input.
+    async fn run_code_blocks(&self, output: &str) -> Result<Option<Input>> {
+        let (timeout, network) = {
+            let config = self.config.read();
+            (
+                Duration::from_secs(config.run_code_timeout),
+                config.run_code_network,
+            )
+        };
+        let mut outputs = vec![];
+        for snippet in extract_code_snippets(output) {
+            let lang = snippet.lang.name();
+            let ans = Confirm::new(&format!("Run the {lang} code block?"))
+                .with_default(false)
+                .prompt()?;
+            if !ans {
+                continue;
+            }
+            let result =
+                tokio::task::spawn_blocking(move || run_code(&snippet, timeout, network)).await??;
+            println!("{}", Color::DarkGray.paint(&result));
+            outputs.push(format!(
+                "Output of the {lang} code:
+```
+{result}
+```"
+            ));
+        }
+        if outputs.is_empty() {
+            return Ok(None);
+        }
+        let text = outputs.join("\n\n");
+        Ok(Some(Input::from_str(
+            &text,
+            self.config.read().input_context(),
+        )))
+    }
+
+    async fn suggest_followups(&self, question: &str, answer: &str) {
+        if answer.is_empty() || self.config.read().dry_run {
+            return;
+        }
+        let prompt = self.config.read().followup_prompt(question, answer);
+        let config = match self.config.read().followup_config() {
+            Ok(config) => Arc::new(RwLock::new(config)),
+            Err(err) => {
+                debug!("Failed to suggest follow-ups, {err}");
+                return;
+            }
+        };
+        let mut task = tokio::spawn(async move { ask_standalone(&config, &prompt).await });
+        let (spinner_tx, spinner_rx) = oneshot::channel();
+        tokio::spawn(run_spinner(" Suggesting", spinner_rx));
+        // The suggestions are shown once ready, unless the user starts typing the next prompt
+        let watch_keys = enable_raw_mode().is_ok();
+        let ret = tokio::select! {
+            ret = tokio::time::timeout(FOLLOWUP_TIMEOUT, &mut task) => Some(ret),
+            _ = wait_keypress(), if watch_keys => None,
+        };
+        if watch_keys {
+            let _ = disable_raw_mode();
+        }
+        let _ = spinner_tx.send(());
+        match ret {
+            Some(Ok(Ok(Ok(text)))) => self.config.write().set_followups(&text),
+            Some(Ok(Ok(Err(err)))) => debug!("Failed to suggest follow-ups, {err}"),
+            Some(_) => debug!("Failed to suggest follow-ups, timeout"),
+            None => {
+                task.abort();
+                return;
+            }
+        }
+        let config = self.config.read();
+        if config.followups.is_empty() {
+            return;
+        }
+        let color = if config.light_theme {
+            Color::LightGray
+        } else {
+            Color::DarkGray
+        };
+        println!(
+            "\n💡 {}",
+            color
+                .normal()
+                .paint("Suggested follow-ups (enter the number to ask):")
+        );
+        for (i, followup) in config.followups.iter().enumerate() {
+            println!(
+                "   {}",
+                color.normal().paint(format!("{}. {followup}", i + 1))
+            );
+        }
+        if config.prefetch_followups {
+            drop(config);
+            self.prefetch_followups(question, answer);
+        }
+    }
+
+    /// Generate the answers of the follow-ups in the background, with the follow-up model.
+    fn prefetch_followups(&self, question: &str, answer: &str) {
+        let (prompts, config) = {
+            let config = self.config.read();
+            let prompts: Vec<_> = config
+                .followups
+                .iter()
+                .map(|v| (v.clone(), config.prefetch_prompt(question, answer, v)))
+                .collect();
+            (prompts, config.followup_config())
+        };
+        let config = match config {
+            Ok(config) => Arc::new(RwLock::new(config)),
+            Err(err) => {
+                debug!("Failed to prefetch follow-ups, {err}");
+                return;
+            }
+        };
+        let generation = self.prefetch.lock().generation;
+        for (followup, prompt) in prompts {
+            let config = config.clone();
+            let prefetch = self.prefetch.clone();
+            tokio::spawn(async move {
+                match ask_standalone(&config, &prompt).await {
+                    Ok(text) => {
+                        let mut prefetch = prefetch.lock();
+                        if prefetch.generation == generation {
+                            prefetch.answers.insert(followup, text);
+                        }
+                    }
+                    Err(err) => debug!("Failed to prefetch '{followup}', {err}"),
+                }
+            });
+        }
+    }
+
+    fn clear_prefetch(&self) {
+        let mut prefetch = self.prefetch.lock();
+        prefetch.generation += 1;
+        prefetch.answers.clear();
+    }
+
+    /// Show the answer prefetched for the follow-up, labeled as such, and save it as the reply.
+    async fn serve_prefetched(&self, text: &str, answer: String) -> Result<()> {
+        let input = Input::from_str(text, self.config.read().input_context());
+        self.config.write().followups.clear();
+        self.clear_prefetch();
+        let (model, color) = {
+            let config = self.config.read();
+            let model = config
+                .followup_config()
+                .map(|v| v.model.id())
+                .unwrap_or_default();
+            let color = if config.light_theme {
+                Color::LightGray
+            } else {
+                Color::DarkGray
+            };
+            (model, color)
+        };
+        println!(
+            "{}",
+            color
+                .italic()
+                .paint(format!("⚡ Prefetched answer by {model}"))
+        );
+        let render_options = self.config.read().get_render_options()?;
+        let mut markdown_render = MarkdownRender::init(render_options)?;
+        self.print(markdown_render.render(&answer).trim());
+        self.config.write().save_message(input, &answer)?;
+        self.config.read().maybe_copy(&answer);
+        self.list_code_blocks(&answer);
+        if self.config.read().suggest_followups {
+            self.suggest_followups(text, &answer).await;
+        }
+        Ok(())
+    }
+
     fn banner(&self) {
-        let version = env!("CARGO_PKG_VERSION");
-        print!(
-            r#"Welcome to aichat {version}
-Type ".help" for additional help.
-"#
-        )
+        let banner = self.config.read().render_banner();
+        if !banner.trim().is_empty() {
+            println!("{}", banner.trim_end());
+        }
     }
 
-    fn create_editor(config: &GlobalConfig) -> Result<Reedline> {
-        let completer = ReplCompleter::new(config);
-        let highlighter = ReplHighlighter::new(config);
+    fn create_editor(config: &GlobalConfig, commands: &[ReplCommand]) -> Result<Reedline> {
+        let completer = ReplCompleter::new(config, commands);
+        let highlighter = ReplHighlighter::new(config, commands);
         let menu = Self::create_menu();
         let edit_mode = Self::create_edit_mode(config);
         let mut editor = Reedline::create()
             .with_completer(Box::new(completer))
             .with_highlighter(Box::new(highlighter))
             .with_menu(menu)
+            .with_menu(Self::create_history_menu())
             .with_edit_mode(edit_mode)
             .with_quick_completions(true)
             .with_partial_completions(true)
@@ -316,9 +1121,24 @@ Type ".help" for additional help.
             .with_validator(Box::new(ReplValidator))
             .with_ansi_colors(true);
 
+        let (history_max_entries, history_ignore_space) = {
+            let config = config.read();
+            (config.history_max_entries, config.history_ignore_space)
+        };
+        if history_max_entries > 0 {
+            let history_file = config.read().history_file()?;
+            let history = FileBackedHistory::with_file(history_max_entries, history_file.clone())
+                .with_context(|| {
+                format!("Failed to load history from '{}'", history_file.display())
+            })?;
+            editor = editor.with_history(Box::new(ReplHistory::new(history)));
+        }
+        if history_ignore_space {
+            editor = editor.with_history_exclusion_prefix(Some(" ".into()));
+        }
+
         if let Some(cmd) = config.read().buffer_editor() {
-            let temp_file =
-                env::temp_dir().join(format!("aichat-{}.txt", chrono::Utc::now().timestamp()));
+            let temp_file = temp_file(&format!("{}.txt", chrono::Utc::now().timestamp()));
             let command = process::Command::new(cmd);
             editor = editor.with_buffer_editor(command, temp_file);
         }
@@ -326,7 +1146,13 @@ Type ".help" for additional help.
         Ok(editor)
     }
 
-    fn extra_keybindings(keybindings: &mut Keybindings) {
+    /// Swap the edit mode after the keybindings changed, keeping the history of the editor.
+    fn reload_edit_mode(&mut self) {
+        let editor = mem::replace(&mut self.editor, Reedline::create());
+        self.editor = editor.with_edit_mode(Self::create_edit_mode(&self.config));
+    }
+
+    fn extra_keybindings(keybindings: &mut Keybindings, custom: &config::Keybindings) {
         keybindings.add_binding(
             KeyModifiers::NONE,
             KeyCode::Tab,
@@ -340,23 +1166,61 @@ Type ".help" for additional help.
             KeyCode::BackTab,
             ReedlineEvent::MenuPrevious,
         );
+        keybindings.add_binding(
+            KeyModifiers::CONTROL,
+            KeyCode::Char('r'),
+            ReedlineEvent::UntilFound(vec![
+                ReedlineEvent::Menu(HISTORY_MENU_NAME.to_string()),
+                ReedlineEvent::MenuPageNext,
+            ]),
+        );
+        keybindings.add_binding(
+            KeyModifiers::CONTROL,
+            KeyCode::Char('v'),
+            ReedlineEvent::ExecuteHostCommand(".paste".into()),
+        );
         keybindings.add_binding(
             KeyModifiers::CONTROL,
             KeyCode::Enter,
             ReedlineEvent::Edit(vec![EditCommand::InsertNewline]),
         );
+        for (action, keys) in custom.bindings() {
+            let event = match action {
+                KeyAction::Submit => ReedlineEvent::Enter,
+                KeyAction::Newline => ReedlineEvent::Edit(vec![EditCommand::InsertNewline]),
+                KeyAction::Editor => ReedlineEvent::OpenEditor,
+                KeyAction::MenuNext => ReedlineEvent::UntilFound(vec![
+                    ReedlineEvent::Menu(MENU_NAME.to_string()),
+                    ReedlineEvent::MenuNext,
+                ]),
+                KeyAction::MenuPrevious => ReedlineEvent::MenuPrevious,
+                KeyAction::HistoryMenu => ReedlineEvent::UntilFound(vec![
+                    ReedlineEvent::Menu(HISTORY_MENU_NAME.to_string()),
+                    ReedlineEvent::MenuPageNext,
+                ]),
+                // Aborting a generation is handled while the reply streams
+                KeyAction::Abort => continue,
+            };
+            for key in action.default_keys() {
+                keybindings.remove_binding(key.modifiers, key.code);
+            }
+            for key in keys {
+                keybindings.add_binding(key.modifiers, key.code, event.clone());
+            }
+        }
     }
 
     fn create_edit_mode(config: &GlobalConfig) -> Box<dyn EditMode> {
-        let edit_mode: Box<dyn EditMode> = if config.read().keybindings.is_vi() {
+        let custom = config.read().keybindings.clone();
+        let edit_mode: Box<dyn EditMode> = if custom.is_vi() {
             let mut normal_keybindings = default_vi_normal_keybindings();
             let mut insert_keybindings = default_vi_insert_keybindings();
-            Self::extra_keybindings(&mut normal_keybindings);
-            Self::extra_keybindings(&mut insert_keybindings);
+            Self::extra_keybindings(&mut normal_keybindings, &custom);
+            Self::extra_keybindings(&mut insert_keybindings, &custom);
             Box::new(Vi::new(insert_keybindings, normal_keybindings))
         } else {
             let mut keybindings = default_emacs_keybindings();
-            Self::extra_keybindings(&mut keybindings);
+            Self::extra_keybindings(&mut keybindings, &custom);
             Box::new(Emacs::new(keybindings))
         };
         edit_mode
@@ -367,6 +1231,321 @@ Type ".help" for additional help.
         ReedlineMenu::EngineCompleter(Box::new(completion_menu))
     }
 
+    /// Lists the history entries containing the typed text, pressing Ctrl+R again pages on.
+    /// Commands are only listed once the text starts with `.`.
+    fn create_history_menu() -> ReedlineMenu {
+        let history_menu = ListMenu::default()
+            .with_name(HISTORY_MENU_NAME)
+            .with_page_size(10);
+        ReedlineMenu::HistoryMenu(Box::new(history_menu))
+    }
+
+    /// Show the numbers to use with `.copy <n>` and `.save-code <n> <file>`.
+    fn list_code_blocks(&self, output: &str) {
+        let blocks = extract_code_blocks(output);
+        if blocks.is_empty() {
+            return;
+        }
+        let color = if self.config.read().light_theme {
+            Color::LightGray
+        } else {
+            Color::DarkGray
+        };
+        let list: Vec<String> = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| match block.lang.as_str() {
+                "" => format!("{}. text", i + 1),
+                lang => format!("{}. {lang}", i + 1),
+            })
+            .collect();
+        println!(
+            "\n{}",
+            color.paint(format!("📋 Code blocks: {}", list.join(", ")))
+        );
+    }
+
+    /// The code block of the last response with the 1-based index.
+    fn code_block(&self, index: &str) -> Result<CodeBlock> {
+        let index: usize = match index.trim().parse() {
+            Ok(v) if v > 0 => v,
+            _ => bail!("Invalid code block number '{}'", index.trim()),
+        };
+        let mut blocks = extract_code_blocks(self.config.read().last_reply());
+        if index > blocks.len() {
+            bail!(
+                "No code block {index}, the last response has {}",
+                blocks.len()
+            );
+        }
+        Ok(blocks.swap_remove(index - 1))
+    }
+
+    /// The usage of the command and of its subcommands, as in the help.
+    fn usage(&self, name: &str) -> String {
+        let prefix = format!("{name} ");
+        let lines: Vec<String> = self
+            .commands
+            .iter()
+            .filter(|cmd| cmd.name == name || cmd.name.starts_with(&prefix))
+            .map(|cmd| cmd.usage())
+            .collect();
+        format!("Usage: {}", lines.join("\n       "))
+    }
+
+    fn print_usage(&self, name: &str) {
+        println!("{}", self.usage(name));
+    }
+
+    /// Apply the patch of the last response, hunk by hunk after review.
+    fn apply(&self, args: Option<&str>) -> Result<()> {
+        let mut yes = false;
+        let mut index = None;
+        for arg in args.unwrap_or_default().split_whitespace() {
+            match arg {
+                "--yes" | "-y" => yes = true,
+                _ if index.is_none() => index = Some(arg),
+                _ => bail!("{}", self.usage(".apply")),
+            }
+        }
+        let text = match index {
+            Some(index) => self.code_block(index)?.code,
+            None => {
+                let reply = self.config.read().last_reply().to_string();
+                let blocks: Vec<String> = extract_code_blocks(&reply)
+                    .into_iter()
+                    .filter(|v| matches!(v.lang.as_str(), "diff" | "patch"))
+                    .map(|v| v.code)
+                    .collect();
+                if blocks.is_empty() {
+                    reply
+                } else {
+                    blocks.join("\n")
+                }
+            }
+        };
+        let mut patches = parse_patch(&text)?;
+
+        let mut accept_rest = yes;
+        let mut accepted: Vec<Vec<bool>> = vec![];
+        'review: for patch in patches.iter_mut() {
+            let path = patch.path().to_string();
+            let mut flags = vec![false; patch.hunks.len()];
+            for (i, hunk) in patch.hunks.iter_mut().enumerate() {
+                if accept_rest {
+                    flags[i] = true;
+                    continue;
+                }
+                loop {
+                    println!("{}", self.render_hunk(&path, hunk));
+                    let choice = Select::new(
+                        "Apply this hunk?",
+                        vec!["Accept", "Skip", "Edit", "Accept the rest", "Skip the rest"],
+                    )
+                    .prompt()?;
+                    match choice {
+                        "Accept" => flags[i] = true,
+                        "Skip" => {}
+                        "Edit" => {
+                            self.edit_hunk(hunk)?;
+                            continue;
+                        }
+                        "Accept the rest" => {
+                            flags[i] = true;
+                            accept_rest = true;
+                        }
+                        _ => {
+                            accepted.push(flags);
+                            break 'review;
+                        }
+                    }
+                    break;
+                }
+            }
+            accepted.push(flags);
+        }
+
+        for (patch, flags) in patches.iter().zip(accepted) {
+            let hunks: Vec<&Hunk> = patch
+                .hunks
+                .iter()
+                .zip(&flags)
+                .filter(|(_, accepted)| **accepted)
+                .map(|(hunk, _)| hunk)
+                .collect();
+            if hunks.is_empty() {
+                continue;
+            }
+            if let Err(err) = apply_file_patch(patch, &hunks) {
+                render_error(err, self.config.read().highlight);
+            }
+        }
+        Ok(())
+    }
+
+    fn render_hunk(&self, path: &str, hunk: &Hunk) -> String {
+        let highlight = self.config.read().highlight;
+        let paint = |color: Color, text: &str| match highlight {
+            true => color.paint(text).to_string(),
+            false => text.to_string(),
+        };
+        let mut lines = vec![
+            paint(Color::White, &format!("--- {path}")),
+            paint(Color::Cyan, &hunk.header),
+        ];
+        for line in &hunk.lines {
+            lines.push(match line.chars().next() {
+                Some('-') => paint(Color::Red, line),
+                Some('+') => paint(Color::Green, line),
+                _ => line.clone(),
+            });
+        }
+        lines.join("\n")
+    }
+
+    /// Edit the lines of the hunk with the buffer editor.
+    fn edit_hunk(&self, hunk: &mut Hunk) -> Result<()> {
+        let (mut command, editor) = self.editor_command()?;
+        let path = temp_file(&format!(
+            "hunk-{}.diff",
+            chrono::Utc::now().timestamp_micros()
+        ));
+        let text = format!(
+            "# Edit the hunk, lines start with ' ' to keep, '-' to remove or '+' to add.\n# {}\n{}\n",
+            hunk.header,
+            hunk.lines.join("\n")
+        );
+        std::fs::write(&path, text)
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+        let status = command.arg(&path).status();
+        let text = std::fs::read_to_string(&path);
+        remove_temp_file(&path);
+        if !status
+            .with_context(|| format!("Failed to run '{editor}'"))?
+            .success()
+        {
+            bail!("The editor failed, the hunk is unchanged");
+        }
+        hunk.set_lines(&text?)
+    }
+
+    fn print(&self, text: &str) {
+        let pager = self.config.read().pager;
+        print_paged(text, pager);
+    }
+
+    /// The command of the editor, `buffer_editor` or $EDITOR, and the setting it comes from.
+    fn editor_command(&self) -> Result<(process::Command, String)> {
+        let editor = self
+            .config
+            .read()
+            .buffer_editor()
+            .filter(|v| !v.trim().is_empty())
+            .or_else(default_editor)
+            .ok_or_else(|| anyhow!("No editor, set `buffer_editor` or $EDITOR"))?;
+        Ok((editor_command(&editor)?, editor))
+    }
+
+    /// Write the next prompt in the editor, on a markdown file headed by the current context.
+    async fn compose(&mut self) -> Result<()> {
+        let (mut command, editor) = self.editor_command()?;
+        let path = temp_file(&format!(
+            "compose-{}.md",
+            Local::now().format("%Y%m%d%H%M%S%3f")
+        ));
+        std::fs::write(&path, format!("{}\n\n", self.compose_header()))
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+        let file = path.clone();
+        let status = tokio::task::spawn_blocking(move || command.arg(file).status()).await?;
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(_) => bail!(
+                "The editor failed, the draft is kept at '{}'",
+                path.display()
+            ),
+            Err(err) => {
+                remove_temp_file(&path);
+                bail!("Failed to run '{editor}', {err}");
+            }
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+        remove_temp_file(&path);
+        let text = strip_compose_header(&text);
+        if text.is_empty() {
+            println!("Nothing sent, the prompt is empty");
+            return Ok(());
+        }
+        self.ask_text(text).await
+    }
+
+    fn compose_header(&self) -> String {
+        let config = self.config.read();
+        let mut lines = vec![
+            "<!--".to_string(),
+            "Write the prompt below, then save and close the editor to send it.".into(),
+            "Leave it empty to cancel, this comment isn't sent.".into(),
+            "".into(),
+            format!("model: {}", config.model.id()),
+        ];
+        if let Some(role) = &config.role {
+            lines.push(format!("role: {}", role.name));
+        }
+        if let Some(session) = &config.session {
+            let (tokens, percent) = session.tokens_and_percent();
+            lines.push(format!(
+                "session: {}, {} turns, {tokens} tokens ({percent}%)",
+                session.name(),
+                session.user_messages_len()
+            ));
+        }
+        if !self.pending_files.is_empty() {
+            lines.push(format!("files: {}", self.pending_files.join(", ")));
+        }
+        if let Some(line) = config.last_reply().lines().find(|v| !v.trim().is_empty()) {
+            let mut line: String = line.replace("-->", "->").chars().take(60).collect();
+            if line.chars().count() == 60 {
+                line.push('…');
+            }
+            lines.push(format!("last reply: {line}"));
+        }
+        lines.push("-->".into());
+        lines.join("\n")
+    }
+
+    /// Attach the image of the clipboard to the next message, or insert its text.
+    fn paste(&mut self) -> Result<()> {
+        let supports_vision = self
+            .config
+            .read()
+            .model
+            .capabilities
+            .contains(ModelCapabilities::Vision);
+        if supports_vision {
+            if let Ok(data) = get_image() {
+                let path = temp_file(&format!(
+                    "paste-{}.png",
+                    Local::now().format("%Y%m%d%H%M%S%3f")
+                ));
+                std::fs::write(&path, data)
+                    .with_context(|| format!("Failed to write '{}'", path.display()))?;
+                println!("📎 Attached '{}' to the next message", path.display());
+                self.pending_files.push(path.display().to_string());
+                self.pasted_files.push(path);
+                return Ok(());
+            }
+        }
+        match get_text() {
+            Ok(text) if !text.is_empty() => {
+                self.editor
+                    .run_edit_commands(&[EditCommand::InsertString(text)]);
+                Ok(())
+            }
+            _ if supports_vision => bail!("Nothing to paste"),
+            _ => bail!("Nothing to paste, images need a model that supports vision"),
+        }
+    }
+
     fn copy(&self, text: &str) -> Result<()> {
         if text.is_empty() {
             bail!("Empty text")
@@ -380,6 +1559,12 @@ Type ".help" for additional help.
 pub struct ReplCommand {
     name: &'static str,
     description: &'static str,
+    details: &'static str,
+    args: Vec<Arg>,
+    flags: Vec<Flag>,
+    examples: Vec<Example>,
+    /// The help of a custom command, which may have its own
+    help: Option<String>,
     valid_states: Vec<State>,
 }
 
@@ -388,26 +1573,54 @@ impl ReplCommand {
         Self {
             name,
             description: desc,
+            details: "",
+            args: vec![],
+            flags: vec![],
+            examples: vec![],
+            help: None,
             valid_states,
         }
     }
 
-    fn is_valid(&self, state: &State) -> bool {
-        self.valid_states.contains(state)
+    fn with_details(mut self, details: &'static str) -> Self {
+        self.details = details;
+        self
     }
-}
 
-/// A default validator which checks for mismatched quotes and brackets
-struct ReplValidator;
+    fn with_args(mut self, args: Vec<Arg>) -> Self {
+        self.args = args;
+        self
+    }
 
-impl Validator for ReplValidator {
-    fn validate(&self, line: &str) -> ValidationResult {
-        let line = line.trim();
-        if line.starts_with(r#":::"#) && !line[3..].ends_with(r#":::"#) {
-            ValidationResult::Incomplete
-        } else {
-            ValidationResult::Complete
+    fn with_flags(mut self, flags: Vec<Flag>) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    fn with_examples(mut self, examples: Vec<Example>) -> Self {
+        self.examples = examples;
+        self
+    }
+
+    fn usage(&self) -> String {
+        render_usage(self.name, &self.args, &self.flags)
+    }
+
+    fn help(&self) -> String {
+        if let Some(help) = &self.help {
+            return help.clone();
         }
+        render_help(
+            &self.usage(),
+            self.details,
+            &self.args,
+            &self.flags,
+            &self.examples,
+        )
+    }
+
+    fn is_valid(&self, state: &State) -> bool {
+        self.valid_states.contains(state)
     }
 }
 
@@ -415,19 +1628,89 @@ fn unknown_command() -> Result<()> {
     bail!(r#"Unknown command. Type ".help" for additional help."#);
 }
 
-fn dump_repl_help() {
-    let head = REPL_COMMANDS
+fn repl_help(commands: &[ReplCommand]) -> String {
+    let head = commands
         .iter()
         .map(|cmd| format!("{:<24} {}", cmd.name, cmd.description))
         .collect::<Vec<String>>()
         .join("\n");
-    println!(
+    format!(
         r###"{head}
 
+Type ".help <command>" for the detailed help of a command.
 Type ::: to start multi-line editing, type ::: to finish it.
 Press Ctrl+O to open an editor for editing the input buffer.
-Press Ctrl+C to cancel the response, Ctrl+D to exit the REPL."###,
-    );
+Press Up to recall the previous prompts, type . first to recall the previous commands.
+Press Ctrl+R to search the input history.
+Press Ctrl+C to cancel the response, Ctrl+D to exit the REPL, see `ctrl_c` and `ctrl_d` to change them.
+Press Ctrl+F to flush the output smoothed by `stream_chars_per_sec`."###,
+    )
+}
+
+fn command_help(commands: &[ReplCommand], name: &str) -> Result<String> {
+    let name = if name.starts_with('.') {
+        name.to_string()
+    } else {
+        format!(".{name}")
+    };
+    let name = match name.split_once(' ') {
+        Some((cmd, sub)) => format!("{} {sub}", resolve_command(commands, cmd)?),
+        None => resolve_command(commands, &name)?.to_string(),
+    };
+    let prefix = format!("{name} ");
+    let commands: Vec<&ReplCommand> = commands
+        .iter()
+        .filter(|cmd| cmd.name == name || cmd.name.starts_with(&prefix))
+        .collect();
+    if commands.is_empty() {
+        unknown_command()?;
+    }
+    let output = commands
+        .iter()
+        .map(|cmd| format!("{} - {}\n\n{}", cmd.name, cmd.description, cmd.help()))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    Ok(output)
+}
+
+/// Resolve a command name typed by the user, accepting unambiguous prefixes such as `.se`.
+fn resolve_command(commands: &[ReplCommand], cmd: &str) -> Result<&'static str> {
+    let mut names: Vec<&'static str> = vec![];
+    for command in commands.iter() {
+        let name = command.name.split(' ').next().unwrap_or(command.name);
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    if let Some(name) = names.iter().find(|name| **name == cmd) {
+        return Ok(name);
+    }
+    if cmd.len() > 1 {
+        let candidates: Vec<&str> = names
+            .iter()
+            .filter(|name| name.starts_with(cmd))
+            .cloned()
+            .collect();
+        match candidates.as_slice() {
+            [name] => return Ok(name),
+            [] => {}
+            _ => bail!(
+                "Ambiguous command '{cmd}', could be {}",
+                candidates.join(", ")
+            ),
+        }
+    }
+    let nearest = names
+        .iter()
+        .map(|name| (levenshtein(cmd, name), name))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance);
+    match nearest {
+        Some((_, name)) => bail!(
+            r#"Unknown command '{cmd}'. Did you mean '{name}'? Type ".help" for additional help."#
+        ),
+        None => bail!(r#"Unknown command. Type ".help" for additional help."#),
+    }
 }
 
 fn parse_command(line: &str) -> Option<(&str, Option<&str>)> {
@@ -443,10 +1726,7 @@ fn parse_command(line: &str) -> Option<(&str, Option<&str>)> {
 }
 
 async fn compress_session(config: &GlobalConfig) -> Result<()> {
-    let input = Input::from_str(
-        config.read().summarize_prompt(),
-        config.read().input_context(),
-    );
+    let input = Input::from_str(&config.read().summarize_prompt(), InputContext::default());
     let mut client = init_client(config)?;
     ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
     let (summary, _) = client.send_message(input).await?;
@@ -454,6 +1734,57 @@ async fn compress_session(config: &GlobalConfig) -> Result<()> {
     Ok(())
 }
 
+/// An editor that is likely installed, for when none is configured.
+fn default_editor() -> Option<String> {
+    let editors: &[&str] = if cfg!(windows) {
+        &["notepad.exe"]
+    } else {
+        &["nano", "vim", "vi"]
+    };
+    editors
+        .iter()
+        .find(|v| which(v).is_some())
+        .map(|v| v.to_string())
+}
+
+/// The command of an editor setting, which may come with arguments, e.g. `code --wait`.
+fn editor_command(editor: &str) -> Result<process::Command> {
+    // A quoted path may have spaces, e.g. `"C:\Program Files\Microsoft VS Code\Code.exe" --wait`
+    let mut args =
+        shell_words::split(editor).with_context(|| format!("Invalid editor command '{editor}'"))?;
+    if args.is_empty() {
+        bail!("No editor, set `buffer_editor` or $EDITOR");
+    }
+    let mut command = process::Command::new(args.remove(0));
+    command.args(args);
+    Ok(command)
+}
+
+/// The prompt written with `.compose`, without the comment heading it.
+fn strip_compose_header(text: &str) -> &str {
+    text.trim_start()
+        .strip_prefix("<!--")
+        .and_then(|v| v.split_once("-->"))
+        .map(|(_, v)| v)
+        .unwrap_or(text)
+        .trim()
+}
+
+/// Send the prompt with the standalone config of `followup_config`, without streaming.
+async fn ask_standalone(config: &GlobalConfig, prompt: &str) -> Result<String> {
+    let input = Input::from_str(prompt, config.read().input_context());
+    let client = init_client(config)?;
+    let (text, _) = client.send_message(input).await?;
+    Ok(text)
+}
+
+/// Resolve once a key is pressed in raw mode, leaving it to the line editor.
+async fn wait_keypress() {
+    while !event::poll(Duration::ZERO).unwrap_or(true) {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
 fn split_files_text(args: &str) -> (&str, &str) {
     match SPLIT_FILES_TEXT_ARGS_RE.find(args).ok().flatten() {
         Some(mat) => {
@@ -469,6 +1800,51 @@ fn split_files_text(args: &str) -> (&str, &str) {
     }
 }
 
+/// Apply the accepted hunks to the file, the ones that don't match are reported and skipped.
+fn apply_file_patch(patch: &FilePatch, hunks: &[&Hunk]) -> Result<()> {
+    let path = patch.path();
+    let content = match &patch.old_path {
+        Some(_) => {
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read '{path}'"))?
+        }
+        None if Path::new(path).exists() => {
+            bail!("Refusing to create '{path}', it already exists")
+        }
+        None => String::new(),
+    };
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    let mut offset = 0;
+    let mut applied = 0;
+    for hunk in hunks {
+        match apply_hunk(&mut lines, hunk, &mut offset) {
+            Ok(()) => applied += 1,
+            Err(err) => eprintln!("✗ {err} '{path}'"),
+        }
+    }
+    if applied == 0 {
+        return Ok(());
+    }
+    if patch.new_path.is_none() && lines.is_empty() && applied == patch.hunks.len() {
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove '{path}'"))?;
+        println!("✓ Removed '{path}'");
+        return Ok(());
+    }
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create '{}'", parent.display()))?;
+    }
+    let mut output = lines.join("\n");
+    if content.is_empty() || content.ends_with('\n') {
+        output.push('\n');
+    }
+    std::fs::write(path, output).with_context(|| format!("Failed to write '{path}'"))?;
+    println!(
+        "✓ Applied {applied} of {} hunks to '{path}'",
+        patch.hunks.len()
+    );
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -492,6 +1868,119 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_resolve_command() {
+        assert_eq!(
+            resolve_command(&REPL_COMMANDS[..], ".session").unwrap(),
+            ".session"
+        );
+        assert_eq!(
+            resolve_command(&REPL_COMMANDS[..], ".ses").unwrap(),
+            ".session"
+        );
+        assert_eq!(resolve_command(&REPL_COMMANDS[..], ".h").unwrap(), ".help");
+        assert!(resolve_command(&REPL_COMMANDS[..], ".s").is_err());
+        assert!(resolve_command(&REPL_COMMANDS[..], ".")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Unknown command."));
+        assert!(resolve_command(&REPL_COMMANDS[..], ".sesion")
+            .unwrap_err()
+            .to_string()
+            .contains("Did you mean '.session'?"));
+    }
+
+    #[test]
+    fn test_command_help() {
+        assert!(REPL_COMMANDS.iter().all(|v| !v.description.is_empty()));
+        let apply = REPL_COMMANDS.iter().find(|v| v.name == ".apply").unwrap();
+        assert_eq!(apply.usage(), ".apply [n] [--yes]");
+        let help = command_help(&REPL_COMMANDS[..], "file").unwrap();
+        assert!(help.starts_with(
+            ".file - Include files with the message\n\nUsage: .file <files>... [-- <text>]\n\n"
+        ));
+        assert!(help.contains("Flags:\n  -- <text>  The text of the message, the rest of the line"));
+    }
+
+    struct Hello;
+
+    #[async_trait::async_trait]
+    impl Command for Hello {
+        fn name(&self) -> &'static str {
+            ".hello"
+        }
+
+        fn description(&self) -> &'static str {
+            "Say hello"
+        }
+
+        fn args(&self) -> Vec<Arg> {
+            vec![Arg::optional("name", "Who to greet")]
+        }
+
+        fn examples(&self) -> Vec<Example> {
+            vec![Example::new(".hello aichat", "Greet aichat")]
+        }
+
+        async fn run(&self, ctx: &mut Context<'_>) -> Result<()> {
+            let name = ctx.args().unwrap_or("world").to_string();
+            ctx.config().write().variables.insert("hello".into(), name);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_command() {
+        let config: GlobalConfig = Default::default();
+        let mut commands = REPL_COMMANDS.to_vec();
+        commands.push(ReplCommand::new(".hello", "Say hello", State::all()));
+        assert_eq!(resolve_command(&commands, ".hell").unwrap(), ".hello");
+        assert!(repl_help(&commands).contains(".hello                   Say hello"));
+
+        assert_eq!(Hello.usage(), ".hello [name]");
+        assert_eq!(
+            Hello.help(),
+            "Usage: .hello [name]\n\nArguments:\n  name  Who to greet\n\nExamples:\n  .hello aichat  Greet aichat"
+        );
+
+        let mut ctx = Context::new(&config, Some("aichat"));
+        Hello.run(&mut ctx).await.unwrap();
+        assert_eq!(config.read().variables["hello"], "aichat");
+        assert!(ctx.session().is_none());
+
+        let ret = ReplBuilder::new(&config)
+            .add_command(Box::new(Hello))
+            .add_command(Box::new(Hello))
+            .build();
+        assert_eq!(
+            ret.err().unwrap().to_string(),
+            "Command '.hello' already exists"
+        );
+    }
+
+    #[test]
+    fn test_editor_command() {
+        let command = editor_command("nvim -u NONE").unwrap();
+        assert_eq!(command.get_program(), "nvim");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["-u", "NONE"]);
+        let command = editor_command(r#""/opt/My Editor/edit" --wait"#).unwrap();
+        assert_eq!(command.get_program(), "/opt/My Editor/edit");
+        assert!(editor_command("  ").is_err());
+    }
+
+    #[test]
+    fn test_strip_compose_header() {
+        assert_eq!(
+            strip_compose_header("<!--\nmodel: openai:gpt-4o\n-->\n\nExplain\n\n- this\n"),
+            "Explain\n\n- this"
+        );
+        assert_eq!(strip_compose_header("<!--\nmodel\n-->\n\n"), "");
+        assert_eq!(
+            strip_compose_header("  No header <!-- -->\n"),
+            "No header <!-- -->"
+        );
+    }
+
     #[test]
     fn test_split_files_text() {
         assert_eq!(split_files_text("file.txt"), ("file.txt", ""));
@@ -0,0 +1,99 @@
+/// Shell-like tokenizer for command arguments.
+///
+/// Tokens are separated by any amount of whitespace. Single quotes keep everything literally,
+/// double quotes allow `\"` and `\\`, and outside of quotes a backslash escapes any character.
+/// An unterminated quote runs to the end of the text.
+pub struct TokenIter<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> TokenIter<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Self { text, pos: 0 }
+    }
+
+    /// The part that hasn't been tokenized yet, for free-text arguments.
+    pub fn rest(&self) -> &'a str {
+        self.text[self.pos..].trim()
+    }
+}
+
+impl<'a> Iterator for TokenIter<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.text[self.pos..];
+        let start = rest.len() - rest.trim_start().len();
+        self.pos += start;
+        if self.pos >= self.text.len() {
+            return None;
+        }
+        let mut token = String::new();
+        let mut quote: Option<char> = None;
+        let mut chars = self.text[self.pos..].char_indices().peekable();
+        let mut end = self.text.len() - self.pos;
+        while let Some((i, c)) = chars.next() {
+            match quote {
+                Some('\'') if c == '\'' => quote = None,
+                Some('"') if c == '"' => quote = None,
+                Some('"') if c == '\\' => match chars.peek() {
+                    Some((_, next @ ('"' | '\\'))) => {
+                        token.push(*next);
+                        chars.next();
+                    }
+                    _ => token.push(c),
+                },
+                Some(_) => token.push(c),
+                None if c == '\'' || c == '"' => quote = Some(c),
+                None if c == '\\' => {
+                    if let Some((_, next)) = chars.next() {
+                        token.push(next);
+                    }
+                }
+                None if c.is_whitespace() => {
+                    end = i;
+                    break;
+                }
+                None => token.push(c),
+            }
+        }
+        self.pos += end;
+        Some(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<String> {
+        TokenIter::new(text).collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(tokens(""), Vec::<String>::new());
+        assert_eq!(tokens("  a   b\n c  "), ["a", "b", "c"]);
+        assert_eq!(tokens(r#"create "my role" x"#), ["create", "my role", "x"]);
+        assert_eq!(
+            tokens(r#"'it''s' "a \"b\" \\ \n""#),
+            ["its", r#"a "b" \ \n"#]
+        );
+        assert_eq!(tokens(r"my\ role 'a\b'"), ["my role", r"a\b"]);
+        assert_eq!(tokens(r#"pre"fix"ed "open"#), ["prefixed", "open"]);
+        assert_eq!(tokens(r#""""#), [""]);
+    }
+
+    #[test]
+    fn test_rest() {
+        let mut iter = TokenIter::new(" \"my role\"  tell me\n  a joke ");
+        assert_eq!(iter.next().as_deref(), Some("my role"));
+        assert_eq!(iter.rest(), "tell me\n  a joke");
+        assert_eq!(iter.next().as_deref(), Some("tell"));
+        let mut iter = TokenIter::new("name");
+        assert_eq!(iter.next().as_deref(), Some("name"));
+        assert_eq!(iter.rest(), "");
+        assert_eq!(iter.next(), None);
+    }
+}
@@ -0,0 +1,69 @@
+use super::validator::is_complete;
+
+use anyhow::{bail, Result};
+
+/// Split a script into the lines the REPL would read one by one.
+/// `:::` blocks and commands with open quotes or brackets continue on the next lines,
+/// blank lines and `#` comments between them are skipped.
+pub fn split_script(text: &str) -> Result<Vec<String>> {
+    let mut entries = vec![];
+    let mut pending: Option<(usize, String)> = None;
+    for (index, line) in text.lines().enumerate() {
+        let (start, entry) = match pending.take() {
+            Some((start, mut entry)) => {
+                entry.push('\n');
+                entry.push_str(line);
+                (start, entry)
+            }
+            None => {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    continue;
+                }
+                (index + 1, line.to_string())
+            }
+        };
+        if is_complete(&entry) {
+            entries.push(entry);
+        } else {
+            pending = Some((start, entry));
+        }
+    }
+    if let Some((start, _)) = pending {
+        bail!("Unterminated input starting at line {start}");
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_script() {
+        let script = r#"# set up
+.model openai:gpt-4
+
+::: explain
+# not a comment
+
+:::
+.prompt "two
+lines"
+hello
+"#;
+        assert_eq!(
+            split_script(script).unwrap(),
+            vec![
+                ".model openai:gpt-4",
+                "::: explain\n# not a comment\n\n:::",
+                ".prompt \"two\nlines\"",
+                "hello",
+            ]
+        );
+        assert_eq!(
+            split_script("hi\n:::\nunclosed").unwrap_err().to_string(),
+            "Unterminated input starting at line 2"
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::function::FunctionDeclaration;
+use crate::utils::validate_schema;
+
+use anyhow::Result;
+use nu_ansi_term::{Color, Style};
+use reedline::{
+    DefaultPrompt, DefaultPromptSegment, EditCommand, Highlighter, Hinter, History, Reedline,
+    Signal, StyledText, ValidationResult, Validator,
+};
+use serde_json::{json, Map, Value};
+
+/// Edit the arguments of a tool call, starting from the defaults of its schema. The arguments
+/// are checked against the schema as they are typed, `None` if the user gives up.
+pub fn edit_arguments(declaration: &FunctionDeclaration, highlight: bool) -> Result<Option<Value>> {
+    let schema = &declaration.parameters;
+    let prompt = DefaultPrompt::new(
+        DefaultPromptSegment::Basic(declaration.name.clone()),
+        DefaultPromptSegment::Empty,
+    );
+    let mut text = serde_json::to_string_pretty(&default_arguments(schema))?;
+    loop {
+        let mut editor = Reedline::create()
+            .with_validator(Box::new(ArgumentsValidator))
+            .with_hinter(Box::new(ArgumentsHinter::new(schema, highlight)))
+            .with_highlighter(Box::new(ArgumentsHighlighter::new(schema, highlight)));
+        editor.run_edit_commands(&[EditCommand::InsertString(text)]);
+        match editor.read_line(&prompt)? {
+            Signal::Success(line) => match check_arguments(&line, schema) {
+                Ok(arguments) => return Ok(Some(arguments)),
+                Err(errors) => {
+                    for error in errors {
+                        println!("✗ {error}");
+                    }
+                    text = line;
+                }
+            },
+            Signal::CtrlC | Signal::CtrlD => return Ok(None),
+        }
+    }
+}
+
+/// The arguments with the required properties and the ones that have a default.
+fn default_arguments(schema: &Value) -> Value {
+    if let Some(value) = schema.get("default") {
+        return value.clone();
+    }
+    if let Some(value) = schema["enum"].as_array().and_then(|v| v.first()) {
+        return value.clone();
+    }
+    match schema["type"].as_str().unwrap_or("object") {
+        "string" => json!(""),
+        "integer" | "number" => json!(0),
+        "boolean" => json!(false),
+        "array" => json!([]),
+        "null" => Value::Null,
+        _ => {
+            let required: Vec<&str> = schema["required"]
+                .as_array()
+                .map(|v| v.iter().filter_map(|v| v.as_str()).collect())
+                .unwrap_or_default();
+            let mut arguments = Map::new();
+            if let Some(properties) = schema["properties"].as_object() {
+                for (name, property) in properties {
+                    if required.contains(&name.as_str()) || property.get("default").is_some() {
+                        arguments.insert(name.clone(), default_arguments(property));
+                    }
+                }
+            }
+            Value::Object(arguments)
+        }
+    }
+}
+
+/// Parse the arguments as JSON or YAML and validate them against the schema.
+fn check_arguments(text: &str, schema: &Value) -> Result<Value, Vec<String>> {
+    let arguments = parse_arguments(text).map_err(|err| vec![err])?;
+    let errors = validate_schema(schema, &arguments, "$");
+    if errors.is_empty() {
+        Ok(arguments)
+    } else {
+        Err(errors)
+    }
+}
+
+fn parse_arguments(text: &str) -> Result<Value, String> {
+    let text = text.trim();
+    if text.starts_with('{') {
+        serde_json::from_str(text).map_err(|err| format!("Invalid JSON, {err}"))
+    } else {
+        serde_yaml::from_str(text).map_err(|err| format!("Invalid YAML, {err}"))
+    }
+}
+
+/// Keep reading lines while a JSON object is unclosed.
+struct ArgumentsValidator;
+
+impl Validator for ArgumentsValidator {
+    fn validate(&self, line: &str) -> ValidationResult {
+        let line = line.trim();
+        match serde_json::from_str::<Value>(line) {
+            Err(err) if line.starts_with('{') && err.is_eof() => ValidationResult::Incomplete,
+            _ => ValidationResult::Complete,
+        }
+    }
+}
+
+/// Show the first problem of the arguments after the text.
+struct ArgumentsHinter {
+    schema: Value,
+    highlight: bool,
+}
+
+impl ArgumentsHinter {
+    fn new(schema: &Value, highlight: bool) -> Self {
+        Self {
+            schema: schema.clone(),
+            highlight,
+        }
+    }
+}
+
+impl Hinter for ArgumentsHinter {
+    fn handle(
+        &mut self,
+        line: &str,
+        pos: usize,
+        _history: &dyn History,
+        use_ansi_coloring: bool,
+    ) -> String {
+        // The hint is drawn at the cursor, it would split the text anywhere else
+        if pos < line.trim_end().len() {
+            return String::new();
+        }
+        let (hint, color) = match check_arguments(line, &self.schema) {
+            Ok(_) => ("  ✓".to_string(), Color::Green),
+            Err(errors) => (format!("  ✗ {}", errors[0]), Color::Red),
+        };
+        if use_ansi_coloring && self.highlight {
+            color.dimmed().paint(hint).to_string()
+        } else {
+            hint
+        }
+    }
+
+    fn complete_hint(&self) -> String {
+        String::new()
+    }
+
+    fn next_hint_token(&self) -> String {
+        String::new()
+    }
+}
+
+/// Color the arguments red while they are invalid.
+struct ArgumentsHighlighter {
+    schema: Value,
+    highlight: bool,
+}
+
+impl ArgumentsHighlighter {
+    fn new(schema: &Value, highlight: bool) -> Self {
+        Self {
+            schema: schema.clone(),
+            highlight,
+        }
+    }
+}
+
+impl Highlighter for ArgumentsHighlighter {
+    fn highlight(&self, line: &str, _cursor: usize) -> StyledText {
+        let style = match self.highlight && check_arguments(line, &self.schema).is_err() {
+            true => Style::new().fg(Color::Red),
+            false => Style::new(),
+        };
+        let mut styled_text = StyledText::new();
+        styled_text.push((style, line.to_string()));
+        styled_text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_arguments() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "pattern": { "type": "string" },
+                "limit": { "type": "integer", "default": 10 },
+                "mode": { "type": "string", "enum": ["a", "b"] },
+                "path": { "type": "string" },
+            },
+            "required": ["pattern", "mode"],
+        });
+        assert_eq!(
+            default_arguments(&schema),
+            json!({ "pattern": "", "limit": 10, "mode": "a" })
+        );
+    }
+
+    #[test]
+    fn test_check_arguments() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "path": { "type": "string" } },
+            "required": ["path"],
+        });
+        assert_eq!(
+            check_arguments(r#"{"path": "a"}"#, &schema),
+            Ok(json!({ "path": "a" }))
+        );
+        assert_eq!(
+            check_arguments("path: a", &schema),
+            Ok(json!({ "path": "a" }))
+        );
+        assert_eq!(
+            check_arguments("path: 1", &schema),
+            Err(vec!["$.path is not of type string".to_string()])
+        );
+        assert!(check_arguments(r#"{"path": "#, &schema).is_err());
+        assert!(matches!(
+            ArgumentsValidator.validate(r#"{"path": "#),
+            ValidationResult::Incomplete
+        ));
+    }
+}
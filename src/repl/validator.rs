@@ -0,0 +1,95 @@
+use reedline::{ValidationResult, Validator};
+
+/// Keep reading lines until a `:::` block is closed, or a command has balanced quotes and brackets.
+pub struct ReplValidator;
+
+impl Validator for ReplValidator {
+    fn validate(&self, line: &str) -> ValidationResult {
+        if is_complete(line) {
+            ValidationResult::Complete
+        } else {
+            ValidationResult::Incomplete
+        }
+    }
+}
+
+pub(super) fn is_complete(line: &str) -> bool {
+    let line = line.trim();
+    if let Some(body) = line.strip_prefix(":::") {
+        // A `:::` inside an unclosed code fence is part of the text, not the end of the block.
+        return match body.strip_suffix(":::") {
+            Some(body) => body.matches("```").count() % 2 == 0,
+            None => false,
+        };
+    }
+    if line.starts_with('.') {
+        return is_balanced(line);
+    }
+    true
+}
+
+/// Check the double quotes and brackets of a command, ignoring brackets within quotes.
+fn is_balanced(text: &str) -> bool {
+    let mut stack = vec![];
+    let mut quoting = false;
+    let mut escape = false;
+    for c in text.chars() {
+        if quoting {
+            if escape {
+                escape = false;
+            } else if c == '\\' {
+                escape = true;
+            } else if c == '"' {
+                quoting = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => quoting = true,
+            '(' => stack.push(')'),
+            '[' => stack.push(']'),
+            '{' => stack.push('}'),
+            // A stray closing bracket can't be fixed by reading more lines.
+            ')' | ']' | '}' if stack.pop() != Some(c) => return true,
+            _ => {}
+        }
+    }
+    !quoting && stack.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multiline_block() {
+        assert!(is_complete("hello"));
+        assert!(is_complete(""));
+        assert!(!is_complete(":::"));
+        assert!(!is_complete("::: hello\nworld"));
+        assert!(is_complete("::: hello\nworld\n:::"));
+        assert!(is_complete("  :::hello:::  "));
+        assert!(is_complete(":::\n:::"));
+    }
+
+    #[test]
+    fn test_multiline_block_with_code_fence() {
+        assert!(!is_complete(":::\n```\nlet s = \":::"));
+        assert!(!is_complete(":::\n```\n:::"));
+        assert!(is_complete(":::\n```\n:::\n```\n:::"));
+        assert!(is_complete(":::\n```rust\nfn main() {\n```\n:::"));
+    }
+
+    #[test]
+    fn test_command_balance() {
+        assert!(is_complete(".set temperature 0.5"));
+        assert!(!is_complete(".prompt \"you are"));
+        assert!(is_complete(".prompt \"you are\na poet\""));
+        assert!(is_complete(".prompt \"say \\\"hi\\\"\""));
+        assert!(!is_complete(".prompt reply with {\"a\": [1, 2"));
+        assert!(is_complete(".prompt reply with {\"a\": [1, 2]}"));
+        assert!(is_complete(".prompt \"(\""));
+        assert!(is_complete(".prompt smile :)"));
+        assert!(is_complete("don't (worry"));
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{
     client::{
-        init_client, ClientConfig, CompletionDetails, Message, Model, SendData, SseEvent,
-        SseHandler,
+        init_client, watch_abort, ClientConfig, CompletionDetails, Message, Model, SendData,
+        SseEvent, SseHandler,
     },
-    config::{Config, GlobalConfig},
-    utils::create_abort_signal,
+    config::{Config, GlobalConfig, ServeConfig},
+    render::ReplyText,
+    utils::{create_abort_signal, AbortSignal},
 };
 
 use anyhow::{anyhow, bail, Result};
@@ -18,10 +19,10 @@ use hyper::{
     service::service_fn,
 };
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::{convert::Infallible, net::IpAddr, sync::Arc};
+use std::{collections::HashMap, convert::Infallible, net::IpAddr, sync::Arc};
 use tokio::{
     net::TcpListener,
     sync::{
@@ -34,6 +35,7 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:8000";
 const DEFAULT_MODEL_NAME: &str = "default";
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
 
 type AppResponse = Response<BoxBody<Bytes, Infallible>>;
 
@@ -52,8 +54,18 @@ pub async fn run(config: GlobalConfig, addr: Option<String>) -> Result<()> {
     };
     let clients = config.read().clients.clone();
     let model = config.read().model.clone();
+    let serve_config = config.read().serve.clone();
+    let user_agent = config.read().user_agent.clone();
+    let tags = config.read().tags.clone();
     let listener = TcpListener::bind(&addr).await?;
-    let server = Arc::new(Server { clients, model });
+    let server = Arc::new(Server {
+        clients,
+        model,
+        serve_config,
+        user_agent,
+        tags,
+        running: Default::default(),
+    });
     let stop_server = server.run(listener).await?;
     println!("Access the chat completion API at: http://{addr}/v1/chat/completions");
     shutdown_signal().await;
@@ -61,9 +73,15 @@ pub async fn run(config: GlobalConfig, addr: Option<String>) -> Result<()> {
     Ok(())
 }
 
+type RunningRequests = Arc<Mutex<HashMap<String, AbortSignal>>>;
+
 struct Server {
     clients: Vec<ClientConfig>,
     model: Model,
+    serve_config: ServeConfig,
+    user_agent: Option<String>,
+    tags: HashMap<String, String>,
+    running: RunningRequests,
 }
 
 impl Server {
@@ -107,9 +125,25 @@ impl Server {
         let method = req.method().clone();
         let uri = req.uri().clone();
         let mut status = StatusCode::OK;
+        let session_name = uri
+            .path()
+            .strip_prefix("/v1/sessions/")
+            .map(|v| v.to_string());
+        let cancel_id = uri
+            .path()
+            .strip_prefix("/v1/cancel/")
+            .map(|v| v.to_string());
         let res = if method == Method::POST && uri == "/v1/chat/completions" {
             self.chat_completion(req).await
-        } else if method == Method::OPTIONS && uri == "/v1/chat/completions" {
+        } else if let (&Method::POST, Some(id)) = (&method, &cancel_id) {
+            self.cancel(id)
+        } else if method == Method::GET && uri == "/v1/sessions" {
+            self.list_sessions()
+        } else if let (&Method::GET, Some(name)) = (&method, &session_name) {
+            self.get_session(name)
+        } else if let (&Method::PUT, Some(name)) = (&method, &session_name) {
+            self.put_session(name, req).await
+        } else if method == Method::OPTIONS {
             status = StatusCode::NO_CONTENT;
             Ok(Response::default())
         } else {
@@ -132,7 +166,45 @@ impl Server {
         Ok(res)
     }
 
+    fn cancel(&self, id: &str) -> Result<AppResponse> {
+        match self.running.lock().get(id) {
+            Some(abort) => abort.set_ctrlc(),
+            None => bail!("No running request '{id}'"),
+        }
+        ret_json(json!({ "id": id, "cancelled": true }))
+    }
+
+    fn list_sessions(&self) -> Result<AppResponse> {
+        let config = Config::default();
+        let data = json!({ "sessions": config.list_web_sessions() });
+        ret_json(data)
+    }
+
+    fn get_session(&self, name: &str) -> Result<AppResponse> {
+        let (model, messages) = Config::load_web_session(name)?;
+        let data = json!({
+            "name": name,
+            "model": model,
+            "messages": messages,
+        });
+        ret_json(data)
+    }
+
+    async fn put_session(&self, name: &str, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: SessionReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let model = req_body.model.unwrap_or_else(|| self.model.id());
+        Config::save_web_session(name, &model, req_body.messages)?;
+        ret_json(json!({ "name": name }))
+    }
+
     async fn chat_completion(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
         let req_body = req.collect().await?.to_bytes();
         let req_body: ChatCompletionReqBody = serde_json::from_slice(&req_body)
             .map_err(|err| anyhow!("Invalid request body, {err}"))?;
@@ -142,13 +214,27 @@ impl Server {
             messages,
             temperature,
             top_p,
+            seed,
+            frequency_penalty,
+            presence_penalty,
+            stop,
+            logprobs,
+            top_logprobs,
             max_tokens,
+            user,
             stream,
         } = req_body;
+        let (temperature, top_p, max_tokens) =
+            self.serve_config.apply(temperature, top_p, max_tokens);
 
+        let mut tags = self.tags.clone();
+        if let Some(user) = user {
+            tags.insert("user".into(), user);
+        }
         let config = Config {
             clients: self.clients.to_vec(),
             model: self.model.clone(),
+            user_agent: self.user_agent.clone(),
             ..Default::default()
         };
         let config = Arc::new(RwLock::new(config));
@@ -172,51 +258,92 @@ impl Server {
         let abort = create_abort_signal();
         let http_client = client.build_client()?;
 
-        let completion_id = generate_completion_id();
+        let completion_id = request_id.unwrap_or_else(generate_completion_id);
+        self.running
+            .lock()
+            .insert(completion_id.clone(), abort.clone());
+        let _guard = RunningGuard {
+            running: self.running.clone(),
+            id: completion_id.clone(),
+            stream,
+        };
         let created = Utc::now().timestamp();
 
         let send_data: SendData = SendData {
             messages,
             temperature,
             top_p,
+            seed,
+            frequency_penalty,
+            presence_penalty,
+            stop,
+            logprobs: logprobs.then(|| top_logprobs.unwrap_or_default()),
+            functions: None,
+            tool_rounds: vec![],
+            thinking: None,
+            tags,
+            schema: None,
             stream,
         };
 
         if stream {
             let (tx, mut rx) = unbounded_channel();
+            let running = self.running.clone();
+            let id = completion_id.clone();
             tokio::spawn(async move {
                 let mut is_first = true;
                 let (tx2, rx2) = unbounded_channel();
-                let mut handler = SseHandler::new(tx2, abort);
+                let mut handler = SseHandler::new(tx2, abort.clone());
                 async fn map_event(
                     mut rx: UnboundedReceiver<SseEvent>,
                     tx: &UnboundedSender<ResEvent>,
                     is_first: &mut bool,
+                    abort: &AbortSignal,
                 ) {
+                    let mut reply_text = ReplyText::default();
                     while let Some(reply_event) = rx.recv().await {
                         if *is_first {
                             let _ = tx.send(ResEvent::First(None));
                             *is_first = false;
                         }
-                        match reply_event {
-                            SseEvent::Text(text) => {
-                                let _ = tx.send(ResEvent::Text(text));
-                            }
-                            SseEvent::Done => {
-                                let _ = tx.send(ResEvent::Done);
-                            }
-                        }
-                    }
-                }
-                tokio::select! {
-                    _ = map_event(rx2, &tx, &mut is_first) => {}
-                    ret = client.send_message_streaming_inner(&http_client, &mut handler, send_data) => {
-                        if let Err(err) = ret {
-                            send_first_event(&tx, Some(format!("{err:?}")), &mut is_first)
+                        let ret = match reply_event {
+                            SseEvent::Done => Ok(()),
+                            SseEvent::Logprobs(content) => tx.send(ResEvent::Logprobs(content)),
+                            _ => match reply_text.push(&reply_event) {
+                                text if text.is_empty() => Ok(()),
+                                text => tx.send(ResEvent::Text(text)),
+                            },
+                        };
+                        if ret.is_err() {
+                            // The web client has disconnected
+                            abort.set_ctrlc();
+                            break;
                         }
-                        let _ = tx.send(ResEvent::Done);
                     }
                 }
+                // The events are forwarded until the handler is dropped, a fast reply may be
+                // complete before any of them is
+                let send = async {
+                    let ret = client
+                        .send_message_streaming_inner(&http_client, &mut handler, send_data)
+                        .await;
+                    let details = handler.completion_details();
+                    drop(handler);
+                    ret.map(|_| details)
+                };
+                let ret = tokio::select! {
+                    (_, ret) = async {
+                        tokio::join!(map_event(rx2, &tx, &mut is_first, &abort), send)
+                    } => ret,
+                    _ = watch_abort(abort.clone()) => Ok(CompletionDetails::default()),
+                };
+                let (err, details) = match ret {
+                    Ok(details) => (None, details),
+                    Err(err) => (Some(format!("{err:?}")), CompletionDetails::default()),
+                };
+                send_first_event(&tx, err, &mut is_first);
+                let _ = tx.send(ResEvent::Done(details));
+                running.lock().remove(&id);
             });
 
             let first_event = rx.recv().await;
@@ -225,37 +352,48 @@ impl Server {
                 bail!("{err}");
             }
 
-            let shared: Arc<(String, String, i64)> = Arc::new((completion_id, model_name, created));
+            let shared: Arc<(String, String, i64)> =
+                Arc::new((completion_id.clone(), model_name, created));
             let stream = UnboundedReceiverStream::new(rx);
             let stream = stream.filter_map(move |res_event| {
                 let shared = shared.clone();
                 async move {
                     let (completion_id, model, created) = shared.as_ref();
                     match res_event {
-                        ResEvent::Text(text) => Some(Ok(create_frame(
+                        ResEvent::Text(text) => {
+                            Some(Ok(create_frame(completion_id, model, *created, &text)))
+                        }
+                        ResEvent::Logprobs(content) => Some(Ok(create_logprobs_frame(
                             completion_id,
                             model,
                             *created,
-                            &text,
-                            false,
+                            content,
+                        ))),
+                        ResEvent::Done(details) => Some(Ok(create_done_frame(
+                            completion_id,
+                            model,
+                            *created,
+                            &details,
                         ))),
-                        ResEvent::Done => {
-                            Some(Ok(create_frame(completion_id, model, *created, "", true)))
-                        }
                         _ => None,
                     }
                 }
             });
             let res = Response::builder()
                 .status(StatusCode::OK)
+                .header(REQUEST_ID_HEADER, &completion_id)
                 .header("Content-Type", "text/event-stream")
                 .header("Cache-Control", "no-cache")
                 .header("Connection", "keep-alive")
                 .body(BodyExt::boxed(StreamBody::new(stream)))?;
             Ok(res)
         } else {
-            let (content, details) = client.send_message_inner(&http_client, send_data).await?;
+            let (content, details) = tokio::select! {
+                ret = client.send_message_inner(&http_client, send_data) => ret?,
+                _ = watch_abort(abort.clone()) => bail!("The request was cancelled"),
+            };
             let res = Response::builder()
+                .header(REQUEST_ID_HEADER, &completion_id)
                 .header("Content-Type", "application/json")
                 .body(
                     Full::new(ret_non_stream(
@@ -278,16 +416,66 @@ struct ChatCompletionReqBody {
     messages: Vec<Message>,
     temperature: Option<f64>,
     top_p: Option<f64>,
+    seed: Option<u64>,
+    frequency_penalty: Option<f64>,
+    presence_penalty: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_stop")]
+    stop: Vec<String>,
+    #[serde(default)]
+    logprobs: bool,
+    top_logprobs: Option<u64>,
     max_tokens: Option<isize>,
+    user: Option<String>,
     #[serde(default)]
     stream: bool,
 }
 
+/// `stop` is a single sequence or an array of them.
+fn deserialize_stop<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stop {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<Stop>::deserialize(deserializer)? {
+        Some(Stop::One(v)) => vec![v],
+        Some(Stop::Many(v)) => v,
+        None => vec![],
+    })
+}
+
+/// Unregister a non-stream request once its handler finishes or is dropped because the web
+/// client disconnected. Stream requests are unregistered by their spawned task.
+struct RunningGuard {
+    running: RunningRequests,
+    id: String,
+    stream: bool,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        if !self.stream {
+            self.running.lock().remove(&self.id);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionReqBody {
+    model: Option<String>,
+    messages: Vec<Message>,
+}
+
 #[derive(Debug)]
 enum ResEvent {
     First(Option<String>),
     Text(String),
-    Done,
+    Logprobs(Value),
+    Done(CompletionDetails),
 }
 
 fn send_first_event(tx: &UnboundedSender<ResEvent>, data: Option<String>, is_first: &mut bool) {
@@ -319,20 +507,19 @@ fn set_cors_header(res: &mut AppResponse) {
     );
     res.headers_mut().insert(
         hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
-        hyper::header::HeaderValue::from_static("Content-Type,Authorization"),
+        hyper::header::HeaderValue::from_static("Content-Type,Authorization,X-Request-Id"),
+    );
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        hyper::header::HeaderValue::from_static(REQUEST_ID_HEADER),
     );
 }
 
-fn create_frame(id: &str, model: &str, created: i64, content: &str, done: bool) -> Frame<Bytes> {
-    let (delta, finish_reason) = if done {
-        (json!({}), "stop".into())
+fn create_frame(id: &str, model: &str, created: i64, content: &str) -> Frame<Bytes> {
+    let delta = if content.is_empty() {
+        json!({ "role": "assistant", "content": content })
     } else {
-        let delta = if content.is_empty() {
-            json!({ "role": "assistant", "content": content })
-        } else {
-            json!({ "content": content })
-        };
-        (delta, Value::Null)
+        json!({ "content": content })
     };
     let value = json!({
         "id": id,
@@ -343,16 +530,54 @@ fn create_frame(id: &str, model: &str, created: i64, content: &str, done: bool)
             {
                 "index": 0,
                 "delta": delta,
-                "finish_reason": finish_reason,
+                "finish_reason": null,
             },
         ],
     });
-    let output = if done {
-        format!("data: {value}\n\ndata: [DONE]\n\n")
-    } else {
-        format!("data: {value}\n\n")
-    };
-    Frame::data(Bytes::from(output))
+    Frame::data(Bytes::from(format!("data: {value}\n\n")))
+}
+
+/// The last chunk, with the usage reported by the client while streaming.
+fn create_done_frame(
+    id: &str,
+    model: &str,
+    created: i64,
+    details: &CompletionDetails,
+) -> Frame<Bytes> {
+    let value = json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [
+            {
+                "index": 0,
+                "delta": {},
+                "finish_reason": "stop",
+            },
+        ],
+        "usage": usage_json(details),
+    });
+    Frame::data(Bytes::from(format!("data: {value}\n\ndata: [DONE]\n\n")))
+}
+
+/// The log probabilities streamed by the client, sent in a chunk of their own after the text.
+fn create_logprobs_frame(id: &str, model: &str, created: i64, content: Value) -> Frame<Bytes> {
+    let value = json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [
+            {
+                "index": 0,
+                "delta": {},
+                "logprobs": { "content": content },
+                "finish_reason": null,
+            },
+        ],
+    });
+    Frame::data(Bytes::from(format!("data: {value}\n\n")))
 }
 
 fn ret_non_stream(
@@ -363,9 +588,6 @@ fn ret_non_stream(
     details: &CompletionDetails,
 ) -> Bytes {
     let id = details.id.as_deref().unwrap_or(id);
-    let input_tokens = details.input_tokens.unwrap_or_default();
-    let output_tokens = details.output_tokens.unwrap_or_default();
-    let total_tokens = input_tokens + output_tokens;
     let res_body = json!({
         "id": id,
         "object": "chat.completion",
@@ -378,19 +600,32 @@ fn ret_non_stream(
                     "role": "assistant",
                     "content": content,
                 },
-                "logprobs": null,
+                "logprobs": details.logprobs.as_ref().map(|v| json!({ "content": v })),
                 "finish_reason": "stop",
             },
         ],
-        "usage": {
-            "prompt_tokens": input_tokens,
-            "completion_tokens": output_tokens,
-            "total_tokens": total_tokens,
-        },
+        "usage": usage_json(details),
     });
     Bytes::from(res_body.to_string())
 }
 
+fn usage_json(details: &CompletionDetails) -> Value {
+    let input_tokens = details.input_tokens.unwrap_or_default();
+    let output_tokens = details.output_tokens.unwrap_or_default();
+    json!({
+        "prompt_tokens": input_tokens,
+        "completion_tokens": output_tokens,
+        "total_tokens": input_tokens + output_tokens,
+    })
+}
+
+fn ret_json(data: Value) -> Result<AppResponse> {
+    let res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+    Ok(res)
+}
+
 fn ret_err<T: std::fmt::Display>(err: T) -> AppResponse {
     let data = json!({
         "error": {
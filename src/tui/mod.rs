@@ -0,0 +1,705 @@
+use crate::client::{
+    ensure_model_capabilities, init_client, send_stream_with, CompletionDetails, SseEvent,
+};
+use crate::config::{GlobalConfig, Input};
+use crate::function::{eval_tool_calls, ToolCall, ToolResult, INTERACTIVE_FUNCTIONS};
+use crate::render::ReplyText;
+use crate::utils::{create_abort_signal, AbortSignal};
+
+use anyhow::{bail, Result};
+use crossterm::{
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+    },
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout, Position, Rect},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame, Terminal,
+};
+use std::io::{stdout, Stdout};
+use std::time::Duration;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tokio::task::JoinHandle;
+
+const SIDEBAR_WIDTH: u16 = 28;
+const MAX_INPUT_HEIGHT: u16 = 8;
+const SCROLL_STEP: usize = 3;
+
+/// Run the full-screen chat UI, sharing the config, and so the sessions, roles and clients,
+/// with the REPL.
+pub async fn run(config: &GlobalConfig) -> Result<()> {
+    let mut tui = Tui::new(config);
+    let mut terminal = enter_terminal()?;
+    let ret = tui.run(&mut terminal).await;
+    leave_terminal(&mut terminal)?;
+    ret?;
+    config.write().end_session()
+}
+
+type Backend = CrosstermBackend<Stdout>;
+
+fn enter_terminal() -> Result<Terminal<Backend>> {
+    enable_raw_mode()?;
+    execute!(stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+    terminal.clear()?;
+    Ok(terminal)
+}
+
+fn leave_terminal(terminal: &mut Terminal<Backend>) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Input,
+    Models,
+    Sessions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Speaker {
+    User,
+    Assistant(String),
+    Error,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    speaker: Speaker,
+    text: String,
+}
+
+/// The answer being streamed by a background task.
+struct Answering {
+    input: Input,
+    rx: UnboundedReceiver<SseEvent>,
+    reply: ReplyText,
+    /// The usage and the metadata reported so far, shown in the status line
+    details: CompletionDetails,
+    /// The tool calls that ask for confirmation, run on the plain terminal
+    tool_calls_rx: UnboundedReceiver<ToolCallsRequest>,
+    handle: JoinHandle<Result<String>>,
+}
+
+struct ToolCallsRequest {
+    calls: Vec<ToolCall>,
+    reply: oneshot::Sender<Vec<ToolResult>>,
+}
+
+struct Tui {
+    config: GlobalConfig,
+    abort: AbortSignal,
+    entries: Vec<Entry>,
+    input: String,
+    focus: Focus,
+    models: Vec<String>,
+    models_state: ListState,
+    sessions: Vec<String>,
+    sessions_state: ListState,
+    /// The first line of the conversation shown, and whether it follows the last line.
+    scroll: usize,
+    follow: bool,
+    max_scroll: usize,
+    answering: Option<Answering>,
+    status: String,
+    areas: Areas,
+    exit: bool,
+}
+
+/// Where the panes were drawn last, to route the mouse events.
+#[derive(Debug, Default, Clone, Copy)]
+struct Areas {
+    models: Rect,
+    sessions: Rect,
+    conversation: Rect,
+}
+
+impl Tui {
+    fn new(config: &GlobalConfig) -> Self {
+        let mut tui = Self {
+            config: config.clone(),
+            abort: create_abort_signal(),
+            entries: vec![],
+            input: String::new(),
+            focus: Focus::Input,
+            models: vec![],
+            models_state: ListState::default(),
+            sessions: vec![],
+            sessions_state: ListState::default(),
+            scroll: 0,
+            follow: true,
+            max_scroll: 0,
+            answering: None,
+            status: String::new(),
+            areas: Areas::default(),
+            exit: false,
+        };
+        tui.refresh_sidebar();
+        tui.load_session();
+        tui
+    }
+
+    async fn run(&mut self, terminal: &mut Terminal<Backend>) -> Result<()> {
+        while !self.exit {
+            self.poll_answer(terminal).await?;
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(Duration::ZERO)? {
+                match event::read()? {
+                    Event::Key(key) if key.kind != KeyEventKind::Release => {
+                        self.handle_key(key, terminal)?
+                    }
+                    Event::Mouse(mouse) => self.handle_mouse(mouse, terminal)?,
+                    _ => {}
+                }
+            } else {
+                // Yield to the task streaming the answer
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        if let Some(answering) = self.answering.take() {
+            self.abort.set_ctrlc();
+            answering.handle.abort();
+        }
+        Ok(())
+    }
+
+    fn refresh_sidebar(&mut self) {
+        let config = self.config.read();
+        self.models = config.models().into_iter().map(|v| v.id()).collect();
+        self.sessions = config.list_sessions();
+        let model_id = config.model.id();
+        self.models_state
+            .select(self.models.iter().position(|v| *v == model_id));
+        let session_name = config.session.as_ref().map(|v| v.name().to_string());
+        self.sessions_state
+            .select(session_name.and_then(|name| self.sessions.iter().position(|v| *v == name)));
+    }
+
+    /// Show the messages of the current session, if any.
+    fn load_session(&mut self) {
+        let config = self.config.read();
+        self.entries = match &config.session {
+            Some(session) => session
+                .messages()
+                .iter()
+                .filter(|v| !v.role.is_system())
+                .map(|v| Entry {
+                    speaker: match v.role.is_user() {
+                        true => Speaker::User,
+                        false => Speaker::Assistant(session.model().to_string()),
+                    },
+                    text: v.content.to_text(),
+                })
+                .collect(),
+            None => vec![],
+        };
+        self.follow = true;
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, terminal: &mut Terminal<Backend>) -> Result<()> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => match self.answering.is_some() {
+                true => self.abort.set_ctrlc(),
+                false => self.exit = true,
+            },
+            KeyCode::Char('d') if ctrl => self.exit = true,
+            KeyCode::Esc => {
+                if self.answering.is_some() {
+                    self.abort.set_ctrlc();
+                }
+            }
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Focus::Input => Focus::Models,
+                    Focus::Models => Focus::Sessions,
+                    Focus::Sessions => Focus::Input,
+                }
+            }
+            KeyCode::PageUp => self.scroll_up(self.areas.conversation.height as usize),
+            KeyCode::PageDown => self.scroll_down(self.areas.conversation.height as usize),
+            _ => match self.focus {
+                Focus::Input => self.handle_input_key(key, terminal),
+                Focus::Models | Focus::Sessions => self.handle_sidebar_key(key, terminal)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn handle_input_key(&mut self, key: KeyEvent, terminal: &mut Terminal<Backend>) {
+        match key.code {
+            KeyCode::Enter if key.modifiers.contains(KeyModifiers::ALT) => self.input.push('\n'),
+            KeyCode::Enter if self.answering.is_none() => {
+                let line = std::mem::take(&mut self.input);
+                if let Err(err) = self.submit(line.trim(), terminal) {
+                    self.push_error(err);
+                }
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Up => self.scroll_up(1),
+            KeyCode::Down => self.scroll_down(1),
+            KeyCode::Char(c) => self.input.push(c),
+            _ => {}
+        }
+    }
+
+    fn handle_sidebar_key(
+        &mut self,
+        key: KeyEvent,
+        terminal: &mut Terminal<Backend>,
+    ) -> Result<()> {
+        let (state, len) = match self.focus {
+            Focus::Models => (&mut self.models_state, self.models.len()),
+            _ => (&mut self.sessions_state, self.sessions.len()),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        match key.code {
+            KeyCode::Up => {
+                let index = state.selected().map(|v| v.saturating_sub(1)).unwrap_or(0);
+                state.select(Some(index));
+            }
+            KeyCode::Down => {
+                let index = state.selected().map(|v| (v + 1).min(len - 1)).unwrap_or(0);
+                state.select(Some(index));
+            }
+            KeyCode::Enter => self.activate(terminal)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_mouse(&mut self, mouse: MouseEvent, terminal: &mut Terminal<Backend>) -> Result<()> {
+        let position = Position::new(mouse.column, mouse.row);
+        match mouse.kind {
+            MouseEventKind::ScrollUp => self.scroll_up(SCROLL_STEP),
+            MouseEventKind::ScrollDown => self.scroll_down(SCROLL_STEP),
+            MouseEventKind::Down(MouseButton::Left) => {
+                for (focus, area) in [
+                    (Focus::Models, self.areas.models),
+                    (Focus::Sessions, self.areas.sessions),
+                ] {
+                    if !area.contains(position) || mouse.row == area.y {
+                        continue;
+                    }
+                    let (state, len) = match focus {
+                        Focus::Models => (&mut self.models_state, self.models.len()),
+                        _ => (&mut self.sessions_state, self.sessions.len()),
+                    };
+                    let index = state.offset() + (mouse.row - area.y - 1) as usize;
+                    if index < len {
+                        state.select(Some(index));
+                        self.focus = focus;
+                        self.activate(terminal)?;
+                    }
+                    return Ok(());
+                }
+                self.focus = Focus::Input;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Switch to the model or session selected in the sidebar.
+    fn activate(&mut self, terminal: &mut Terminal<Backend>) -> Result<()> {
+        if self.answering.is_some() {
+            self.status = "Wait for the answer to finish".into();
+            return Ok(());
+        }
+        let ret = match self.focus {
+            Focus::Models => match self.models_state.selected() {
+                Some(index) => {
+                    let id = self.models[index].clone();
+                    self.config.write().set_model(&id)
+                }
+                None => Ok(()),
+            },
+            Focus::Sessions => match self.sessions_state.selected() {
+                Some(index) => {
+                    let name = self.sessions[index].clone();
+                    // Ending or joining a session may ask questions on the plain terminal
+                    let config = self.config.clone();
+                    let ret = suspend(terminal, || {
+                        let mut config = config.write();
+                        match config.has_session() {
+                            true => config.switch_session(&name),
+                            false => config.start_session(Some(&name)),
+                        }
+                    });
+                    self.load_session();
+                    ret
+                }
+                None => Ok(()),
+            },
+            Focus::Input => Ok(()),
+        };
+        self.refresh_sidebar();
+        if let Err(err) = ret {
+            self.push_error(err);
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self, line: &str, terminal: &mut Terminal<Backend>) -> Result<()> {
+        if line.is_empty() {
+            return Ok(());
+        }
+        if let Some(command) = line.strip_prefix('.') {
+            let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+            match (name, args.trim()) {
+                ("exit", "") => self.exit = true,
+                ("role", "") => bail!("Usage: .role <name>"),
+                ("role", name) => self.config.write().set_role(name)?,
+                ("exit", "role") => self.config.write().clear_role()?,
+                _ => bail!("Unknown command `.{name}`, the TUI supports .role and .exit"),
+            }
+            return Ok(());
+        }
+        let mut input = Input::from_str(line, self.config.read().input_context());
+        // The variables of the role are asked on the plain terminal
+        let config = self.config.clone();
+        suspend(terminal, || config.write().fill_input_variables(&mut input))?;
+        let mut client = init_client(&self.config)?;
+        ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
+        self.entries.push(Entry {
+            speaker: Speaker::User,
+            text: line.to_string(),
+        });
+        self.entries.push(Entry {
+            speaker: Speaker::Assistant(self.config.read().model.id()),
+            text: String::new(),
+        });
+        self.follow = true;
+        self.abort.reset();
+        let (tx, rx) = unbounded_channel();
+        let (tool_calls_tx, tool_calls_rx) = unbounded_channel();
+        let handle = tokio::spawn(answer(
+            input.clone(),
+            self.config.clone(),
+            tx,
+            tool_calls_tx,
+            self.abort.clone(),
+        ));
+        self.answering = Some(Answering {
+            input,
+            rx,
+            reply: ReplyText::new(self.config.read().show_thinking),
+            details: CompletionDetails::default(),
+            tool_calls_rx,
+            handle,
+        });
+        self.status.clear();
+        Ok(())
+    }
+
+    /// Show the streamed events, and save the message once the answer is finished.
+    async fn poll_answer(&mut self, terminal: &mut Terminal<Backend>) -> Result<()> {
+        let Some(answering) = self.answering.as_mut() else {
+            return Ok(());
+        };
+        if let Ok(request) = answering.tool_calls_rx.try_recv() {
+            leave_terminal(terminal)?;
+            let results = eval_tool_calls(&self.config, request.calls).await;
+            resume_terminal(terminal)?;
+            let _ = request.reply.send(results);
+        }
+        while let Ok(event) = answering.rx.try_recv() {
+            let text = match &event {
+                SseEvent::ToolCall(call) => format!("\n`Call {} {}`\n", call.name, call.arguments),
+                SseEvent::Usage {
+                    input_tokens,
+                    output_tokens,
+                } => {
+                    let details = &mut answering.details;
+                    details.input_tokens = input_tokens.or(details.input_tokens);
+                    details.output_tokens = output_tokens.or(details.output_tokens);
+                    self.status = stats_line(details);
+                    continue;
+                }
+                SseEvent::Meta(meta) => {
+                    answering.details.meta = meta.clone();
+                    self.status = stats_line(&answering.details);
+                    continue;
+                }
+                event => answering.reply.push(event),
+            };
+            if let Some(entry) = self.entries.last_mut() {
+                entry.text.push_str(&text);
+            }
+        }
+        if !answering.handle.is_finished() {
+            return Ok(());
+        }
+        let Some(answering) = self.answering.take() else {
+            return Ok(());
+        };
+        let ret = match answering.handle.await {
+            Ok(ret) => ret,
+            Err(err) => Err(err.into()),
+        };
+        let ret = ret.and_then(|output| self.config.write().save_message(answering.input, &output));
+        if let Err(err) = ret {
+            self.push_error(err);
+        }
+        if self.abort.aborted() {
+            self.status = "Aborted".into();
+        }
+        Ok(())
+    }
+
+    fn push_error(&mut self, err: anyhow::Error) {
+        self.entries.push(Entry {
+            speaker: Speaker::Error,
+            text: format!("{err:?}"),
+        });
+        self.follow = true;
+    }
+
+    fn scroll_up(&mut self, lines: usize) {
+        self.follow = false;
+        self.scroll = self.scroll.min(self.max_scroll).saturating_sub(lines);
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.max_scroll);
+        self.follow = self.scroll == self.max_scroll;
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [sidebar, main] = split2(
+            frame.size(),
+            Direction::Horizontal,
+            [Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(20)],
+        );
+        let [models, sessions] = split2(
+            sidebar,
+            Direction::Vertical,
+            [Constraint::Percentage(60), Constraint::Percentage(40)],
+        );
+        let input_height = (self.input.lines().count().max(1) as u16 + 2).min(MAX_INPUT_HEIGHT);
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(3),
+                Constraint::Length(input_height),
+                Constraint::Length(1),
+            ])
+            .split(main);
+        let (conversation, input, status) = (chunks[0], chunks[1], chunks[2]);
+
+        let focus = self.focus;
+        let models_list = sidebar_list("Models", &self.models, focus == Focus::Models);
+        frame.render_stateful_widget(models_list, models, &mut self.models_state);
+        let sessions_list = sidebar_list("Sessions", &self.sessions, focus == Focus::Sessions);
+        frame.render_stateful_widget(sessions_list, sessions, &mut self.sessions_state);
+
+        let block = Block::default().borders(Borders::ALL).title(self.title());
+        let inner = block.inner(conversation);
+        let lines = self.conversation_lines(inner.width as usize);
+        let height = inner.height as usize;
+        self.max_scroll = lines.len().saturating_sub(height);
+        self.scroll = match self.follow {
+            true => self.max_scroll,
+            false => self.scroll.min(self.max_scroll),
+        };
+        let visible: Vec<Line> = lines.into_iter().skip(self.scroll).take(height).collect();
+        frame.render_widget(Paragraph::new(visible).block(block), conversation);
+        self.areas = Areas {
+            models,
+            sessions,
+            conversation: inner,
+        };
+
+        let title = match self.answering.is_some() {
+            true => "Answering, Esc to abort",
+            false => "Input",
+        };
+        let block = focused_block(title, focus == Focus::Input);
+        let inner = block.inner(input);
+        let input_lines: Vec<&str> = self.input.split('\n').collect();
+        let skip = input_lines.len().saturating_sub(inner.height as usize);
+        let text: Vec<Line> = input_lines[skip..]
+            .iter()
+            .map(|v| Line::raw(v.to_string()))
+            .collect();
+        frame.render_widget(Paragraph::new(text).block(block), input);
+        if focus == Focus::Input {
+            let last = input_lines.last().copied().unwrap_or_default();
+            let x = inner.x + (unicode_width::UnicodeWidthStr::width(last) as u16).min(inner.width);
+            let y = inner.y + (input_lines.len() - skip - 1) as u16;
+            frame.set_cursor(x, y);
+        }
+
+        let help = "Enter send · Alt+Enter newline · Tab focus · Ctrl+D quit";
+        let status_text = match self.status.is_empty() {
+            true => help.to_string(),
+            false => format!("{} · {help}", self.status),
+        };
+        frame.render_widget(Paragraph::new(status_text.dim()), status);
+    }
+
+    fn title(&self) -> String {
+        let config = self.config.read();
+        let mut parts = vec![config.model.id()];
+        if let Some(role) = &config.role {
+            parts.push(format!("role:{}", role.name));
+        }
+        if let Some(session) = &config.session {
+            parts.push(format!("session:{}", session.name()));
+        }
+        parts.join(" ")
+    }
+
+    fn conversation_lines(&self, width: usize) -> Vec<Line<'static>> {
+        let mut lines = vec![];
+        for entry in &self.entries {
+            let (name, style) = match &entry.speaker {
+                Speaker::User => ("You".to_string(), Style::new().cyan().bold()),
+                Speaker::Assistant(model) => (model.clone(), Style::new().green().bold()),
+                Speaker::Error => ("Error".to_string(), Style::new().red().bold()),
+            };
+            lines.push(Line::styled(name, style));
+            for line in wrap_text(&entry.text, width) {
+                lines.push(Line::raw(line));
+            }
+            lines.push(Line::default());
+        }
+        lines
+    }
+}
+
+fn split2(area: Rect, direction: Direction, constraints: [Constraint; 2]) -> [Rect; 2] {
+    let chunks = Layout::default()
+        .direction(direction)
+        .constraints(constraints)
+        .split(area);
+    [chunks[0], chunks[1]]
+}
+
+fn focused_block(title: &str, focused: bool) -> Block<'static> {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title.to_string());
+    match focused {
+        true => block.border_style(Style::new().yellow()),
+        false => block,
+    }
+}
+
+fn sidebar_list<'a>(title: &str, items: &'a [String], focused: bool) -> List<'a> {
+    List::new(items.iter().map(|v| ListItem::new(v.as_str())))
+        .block(focused_block(title, focused))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+}
+
+/// Wrap the text to the width, keeping its blank lines.
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    text.split('\n')
+        .flat_map(|line| match line.is_empty() {
+            true => vec![String::new()],
+            false => textwrap::wrap(line, width)
+                .into_iter()
+                .map(|v| v.into_owned())
+                .collect(),
+        })
+        .collect()
+}
+
+/// e.g. `tokens: 12 in, 34 out · provider: Groq, queue: 8ms`
+fn stats_line(details: &CompletionDetails) -> String {
+    let mut line = format!(
+        "tokens: {} in, {} out",
+        details.input_tokens.unwrap_or_default(),
+        details.output_tokens.unwrap_or_default()
+    );
+    if !details.meta.is_empty() {
+        line.push_str(&format!(" · {}", details.meta.summary()));
+    }
+    line
+}
+
+/// Run the function on the plain terminal, then go back to the full screen.
+fn suspend<T>(terminal: &mut Terminal<Backend>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    leave_terminal(terminal)?;
+    let ret = f();
+    resume_terminal(terminal)?;
+    ret
+}
+
+fn resume_terminal(terminal: &mut Terminal<Backend>) -> Result<()> {
+    enable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        EnterAlternateScreen,
+        EnableMouseCapture
+    )?;
+    terminal.clear()?;
+    Ok(())
+}
+
+/// Stream the answer with its rounds of tool calls, like `send_stream` without printing.
+///
+/// The tool calls that ask for confirmation are sent to the UI, which runs them on the plain
+/// terminal.
+async fn answer(
+    input: Input,
+    config: GlobalConfig,
+    tx: UnboundedSender<SseEvent>,
+    tool_calls_tx: UnboundedSender<ToolCallsRequest>,
+    abort: AbortSignal,
+) -> Result<String> {
+    let client = init_client(&config)?;
+    send_stream_with(&input, client.as_ref(), &config, abort, Some(tx), |calls| {
+        let config = config.clone();
+        let tool_calls_tx = tool_calls_tx.clone();
+        async move {
+            if !calls
+                .iter()
+                .any(|call| INTERACTIVE_FUNCTIONS.contains(&call.name.as_str()))
+            {
+                return eval_tool_calls(&config, calls).await;
+            }
+            let (reply, reply_rx) = oneshot::channel();
+            let _ = tool_calls_tx.send(ToolCallsRequest { calls, reply });
+            reply_rx.await.unwrap_or_default()
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_text() {
+        assert_eq!(
+            wrap_text("hello world foo\n\nbar", 11),
+            ["hello world", "foo", "", "bar"]
+        );
+        assert_eq!(wrap_text("", 10), [""]);
+    }
+}
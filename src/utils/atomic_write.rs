@@ -0,0 +1,73 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Write the file through a synced temporary file renamed over it, so a crash leaves either the
+/// old or the new content. The `backups` previous versions are kept, see [`backup_paths`].
+pub fn write_file_atomic(path: &Path, content: &str, backups: usize) -> Result<()> {
+    let tmp_path = with_suffix(path, "tmp");
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    file.write_all(content.as_bytes())
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    drop(file);
+
+    if backups > 0 && path.exists() {
+        for i in (1..backups).rev() {
+            let from = with_suffix(path, &i.to_string());
+            if from.exists() {
+                fs::rename(&from, with_suffix(path, &(i + 1).to_string()))
+                    .with_context(|| format!("Failed to rotate {}", from.display()))?;
+            }
+        }
+        let backup = with_suffix(path, "1");
+        fs::copy(path, &backup)
+            .with_context(|| format!("Failed to back up to {}", backup.display()))?;
+    }
+
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    // The rename is only durable once the directory is synced
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let _ = File::open(dir).and_then(|v| v.sync_all());
+    }
+    Ok(())
+}
+
+/// The backups of the file that exist, `<file>.1` being the newest.
+pub fn backup_paths(path: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|i| with_suffix(path, &i.to_string()))
+        .take_while(|v| v.exists())
+        .collect()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_file_atomic() {
+        let dir = crate::utils::TestDir::new("write");
+        let path = dir.join("chat.yaml");
+        for i in 1..=4 {
+            write_file_atomic(&path, &format!("v{i}"), 2).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "v4");
+        let backups = backup_paths(&path);
+        assert_eq!(backups.len(), 2);
+        assert_eq!(fs::read_to_string(&backups[0]).unwrap(), "v3");
+        assert_eq!(fs::read_to_string(&backups[1]).unwrap(), "v2");
+        assert!(!with_suffix(&path, "tmp").exists());
+    }
+}
@@ -4,6 +4,16 @@ lazy_static::lazy_static! {
         std::sync::Arc::new(std::sync::Mutex::new(arboard::Clipboard::new().ok()));
 }
 
+#[cfg(not(any(target_os = "android", target_os = "emscripten")))]
+pub fn is_clipboard_available() -> bool {
+    CLIPBOARD.lock().unwrap().is_some()
+}
+
+#[cfg(any(target_os = "android", target_os = "emscripten"))]
+pub fn is_clipboard_available() -> bool {
+    false
+}
+
 #[cfg(not(any(target_os = "android", target_os = "emscripten")))]
 pub fn set_text(text: &str) -> anyhow::Result<()> {
     let mut clipboard = CLIPBOARD.lock().unwrap();
@@ -18,3 +28,109 @@ pub fn set_text(text: &str) -> anyhow::Result<()> {
 pub fn set_text(_text: &str) -> anyhow::Result<()> {
     anyhow::bail!("No available clipboard")
 }
+
+#[cfg(not(any(target_os = "android", target_os = "emscripten")))]
+pub fn get_text() -> anyhow::Result<String> {
+    let mut clipboard = CLIPBOARD.lock().unwrap();
+    match clipboard.as_mut() {
+        Some(clipboard) => Ok(clipboard.get_text()?),
+        None => anyhow::bail!("No available clipboard"),
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "emscripten"))]
+pub fn get_text() -> anyhow::Result<String> {
+    anyhow::bail!("No available clipboard")
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Read the image of the clipboard as PNG with the clipboard tools of the platform,
+/// arboard is built without image support.
+pub fn get_image() -> anyhow::Result<Vec<u8>> {
+    let commands: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[
+            &["pngpaste", "-"],
+            &["osascript", "-e", "the clipboard as «class PNGf»"],
+        ]
+    } else if cfg!(target_os = "windows") {
+        &[&[
+            "powershell",
+            "-NoProfile",
+            "-Command",
+            "Add-Type -AssemblyName System.Windows.Forms; \
+             $img = [Windows.Forms.Clipboard]::GetImage(); \
+             if ($img) { $ms = New-Object IO.MemoryStream; \
+             $img.Save($ms, [Drawing.Imaging.ImageFormat]::Png); \
+             $out = [Console]::OpenStandardOutput(); $out.Write($ms.ToArray(), 0, $ms.Length) }",
+        ]]
+    } else {
+        &[
+            &["wl-paste", "--no-newline", "--type", "image/png"],
+            &[
+                "xclip",
+                "-selection",
+                "clipboard",
+                "-target",
+                "image/png",
+                "-out",
+            ],
+        ]
+    };
+    for args in commands {
+        let output = std::process::Command::new(args[0])
+            .args(&args[1..])
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .output();
+        let Ok(output) = output else {
+            continue;
+        };
+        if !output.status.success() {
+            continue;
+        }
+        let data = if args[0] == "osascript" {
+            match parse_applescript_data(&String::from_utf8_lossy(&output.stdout)) {
+                Some(data) => data,
+                None => continue,
+            }
+        } else {
+            output.stdout
+        };
+        if data.starts_with(PNG_SIGNATURE) {
+            return Ok(data);
+        }
+    }
+    anyhow::bail!("No image in the clipboard")
+}
+
+/// osascript prints data as `«data PNGf89504E47...»`.
+fn parse_applescript_data(text: &str) -> Option<Vec<u8>> {
+    let hex = text
+        .trim()
+        .strip_prefix("«data ")?
+        .strip_suffix('»')?
+        .get(4..)?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_applescript_data() {
+        assert_eq!(
+            parse_applescript_data("«data PNGf89504E47»\n"),
+            Some(vec![0x89, 0x50, 0x4e, 0x47])
+        );
+        assert_eq!(parse_applescript_data("«data PNGf8950E»"), None);
+        assert_eq!(parse_applescript_data("hello"), None);
+    }
+}
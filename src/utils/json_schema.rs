@@ -0,0 +1,233 @@
+use serde_json::Value;
+
+/// Validate a value against the common subset of JSON Schema: `type`, `enum`, `const`,
+/// `properties`, `required`, `additionalProperties`, `items`, the bounds of numbers, strings
+/// and arrays, `anyOf`, `oneOf` and `allOf`. The others, `$ref` included, are ignored.
+pub fn validate_schema(schema: &Value, value: &Value, path: &str) -> Vec<String> {
+    let mut errors = vec![];
+    if !schema.is_object() {
+        if *schema == Value::Bool(false) {
+            errors.push(format!("{path} is not allowed"));
+        }
+        return errors;
+    }
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(v) => vec![v.as_str()],
+        Value::Array(list) => list.iter().filter_map(|v| v.as_str()).collect(),
+        _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|v| match_type(v, value)) {
+        errors.push(format!("{path} is not of type {}", types.join("|")));
+        return errors;
+    }
+    if let Some(list) = schema["enum"].as_array() {
+        if !list.contains(value) {
+            errors.push(format!(
+                "{path} is not one of {}",
+                Value::Array(list.clone())
+            ));
+        }
+    }
+    if let Some(expect) = schema.get("const") {
+        if expect != value {
+            errors.push(format!("{path} is not {expect}"));
+        }
+    }
+    if let Value::Object(object) = value {
+        if let Some(required) = schema["required"].as_array() {
+            for name in required.iter().filter_map(|v| v.as_str()) {
+                if !object.contains_key(name) {
+                    errors.push(format!("{path}.{name} is required"));
+                }
+            }
+        }
+        let properties = schema["properties"].as_object();
+        for (name, item) in object {
+            match properties.and_then(|v| v.get(name)) {
+                Some(item_schema) => errors.extend(validate_schema(
+                    item_schema,
+                    item,
+                    &format!("{path}.{name}"),
+                )),
+                None => errors.extend(validate_schema(
+                    &schema["additionalProperties"],
+                    item,
+                    &format!("{path}.{name}"),
+                )),
+            }
+        }
+    }
+    if let Value::Array(list) = value {
+        if let Some(min) = schema["minItems"].as_u64() {
+            if (list.len() as u64) < min {
+                errors.push(format!("{path} has fewer than {min} items"));
+            }
+        }
+        if let Some(max) = schema["maxItems"].as_u64() {
+            if list.len() as u64 > max {
+                errors.push(format!("{path} has more than {max} items"));
+            }
+        }
+        if schema["items"].is_object() {
+            for (i, item) in list.iter().enumerate() {
+                errors.extend(validate_schema(
+                    &schema["items"],
+                    item,
+                    &format!("{path}[{i}]"),
+                ));
+            }
+        }
+    }
+    if let Value::String(text) = value {
+        let len = text.chars().count() as u64;
+        if let Some(min) = schema["minLength"].as_u64() {
+            if len < min {
+                errors.push(format!("{path} is shorter than {min} characters"));
+            }
+        }
+        if let Some(max) = schema["maxLength"].as_u64() {
+            if len > max {
+                errors.push(format!("{path} is longer than {max} characters"));
+            }
+        }
+    }
+    if let Some(number) = value.as_f64() {
+        if let Some(min) = schema["minimum"].as_f64() {
+            if number < min {
+                errors.push(format!("{path} is less than {min}"));
+            }
+        }
+        if let Some(max) = schema["maximum"].as_f64() {
+            if number > max {
+                errors.push(format!("{path} is greater than {max}"));
+            }
+        }
+        if let Some(min) = schema["exclusiveMinimum"].as_f64() {
+            if number <= min {
+                errors.push(format!("{path} is not greater than {min}"));
+            }
+        }
+        if let Some(max) = schema["exclusiveMaximum"].as_f64() {
+            if number >= max {
+                errors.push(format!("{path} is not less than {max}"));
+            }
+        }
+    }
+    let subschemas = |key: &str| {
+        schema[key]
+            .as_array()
+            .filter(|list| !list.is_empty())
+            .map(|list| {
+                list.iter()
+                    .map(|v| validate_schema(v, value, path).is_empty())
+                    .collect::<Vec<bool>>()
+            })
+    };
+    if let Some(list) = schema["allOf"].as_array() {
+        for item_schema in list {
+            errors.extend(validate_schema(item_schema, value, path));
+        }
+    }
+    if let Some(matches) = subschemas("anyOf") {
+        if !matches.contains(&true) {
+            errors.push(format!("{path} matches none of the anyOf schemas"));
+        }
+    }
+    if let Some(matches) = subschemas("oneOf") {
+        match matches.iter().filter(|v| **v).count() {
+            1 => {}
+            0 => errors.push(format!("{path} matches none of the oneOf schemas")),
+            n => errors.push(format!(
+                "{path} matches {n} of the oneOf schemas, expect one"
+            )),
+        }
+    }
+    errors
+}
+
+fn match_type(name: &str, value: &Value) -> bool {
+    match name {
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "type": "string" }, "minItems": 1 },
+                "kind": { "enum": ["a", "b"] },
+            },
+            "required": ["name"],
+            "additionalProperties": false,
+        });
+        let value = json!({ "name": "x", "tags": ["t"], "kind": "a" });
+        assert!(validate_schema(&schema, &value, "$").is_empty());
+        let value = json!({ "tags": [1], "kind": "c", "extra": true });
+        assert_eq!(
+            validate_schema(&schema, &value, "$"),
+            [
+                "$.name is required",
+                "$.tags[0] is not of type string",
+                "$.kind is not one of [\"a\",\"b\"]",
+                "$.extra is not allowed",
+            ]
+        );
+        assert!(validate_schema(&json!({"type": "integer"}), &json!(1.5), "$").len() == 1);
+    }
+
+    #[test]
+    fn test_validate_schema_bounds_and_combinators() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 3 },
+                "age": { "type": "integer", "minimum": 0, "exclusiveMaximum": 150 },
+                "id": { "anyOf": [{ "type": "string" }, { "type": "integer" }] },
+                "pick": { "oneOf": [{ "type": "number" }, { "type": "integer" }] },
+                "both": { "allOf": [{ "type": "string" }, { "const": "x" }] },
+            },
+            "additionalProperties": { "type": "boolean" },
+        });
+        let value =
+            json!({ "name": "Tom", "age": 3, "id": 1, "pick": 1.5, "both": "x", "ok": true });
+        assert!(validate_schema(&schema, &value, "$").is_empty());
+        let value = json!({
+            "name": "",
+            "age": 150,
+            "id": null,
+            "pick": 1,
+            "both": "y",
+            "ok": "yes",
+        });
+        assert_eq!(
+            validate_schema(&schema, &value, "$"),
+            [
+                "$.name is shorter than 1 characters",
+                "$.age is not less than 150",
+                "$.id matches none of the anyOf schemas",
+                "$.pick matches 2 of the oneOf schemas, expect one",
+                "$.both is not \"x\"",
+                "$.ok is not of type boolean",
+            ]
+        );
+        assert_eq!(
+            validate_schema(&json!({ "maximum": 2 }), &json!(2.5), "$"),
+            ["$ is greater than 2"]
+        );
+    }
+}
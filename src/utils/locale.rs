@@ -0,0 +1,136 @@
+use std::env;
+
+/// How numbers are written in a locale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Locale {
+    decimal: char,
+    group: char,
+    /// The currency goes after the amount, `1,50 €` rather than `€1.50`.
+    currency_after: bool,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self {
+            decimal: '.',
+            group: ',',
+            currency_after: false,
+        }
+    }
+}
+
+impl Locale {
+    /// Parse a locale like `de-DE`, `fr_FR.UTF-8` or `ja`, unknown ones are written like English.
+    pub fn parse(value: &str) -> Self {
+        let value = value.split(['.', '@']).next().unwrap_or_default();
+        let mut parts = value.split(['_', '-']);
+        let language = parts.next().unwrap_or_default().to_lowercase();
+        let region = parts.next().unwrap_or_default().to_uppercase();
+        let (decimal, group) = match (language.as_str(), region.as_str()) {
+            ("de" | "it" | "fr", "CH") => ('.', '\''),
+            ("es", "MX") => ('.', ','),
+            ("de" | "nl" | "it" | "es" | "pt" | "id" | "tr" | "da" | "el" | "ro" | "hr", _) => {
+                (',', '.')
+            }
+            (
+                "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "nb" | "no" | "fi" | "uk" | "hu" | "bg"
+                | "et" | "lt" | "lv",
+                _,
+            ) => (',', ' '),
+            _ => ('.', ','),
+        };
+        Self {
+            decimal,
+            group,
+            currency_after: decimal == ',',
+        }
+    }
+
+    /// The locale of the environment, from `LC_ALL`, `LC_NUMERIC` or `LANG`.
+    pub fn detect() -> Self {
+        ["LC_ALL", "LC_NUMERIC", "LANG"]
+            .into_iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|v| !v.is_empty())
+            .filter(|v| v != "C" && v != "POSIX")
+            .map(|v| Self::parse(&v))
+            .unwrap_or_default()
+    }
+
+    pub fn format_count(&self, value: u64) -> String {
+        self.group_digits(&value.to_string())
+    }
+
+    /// Format a number with at least `min_decimals` decimals and up to 4, dropping the trailing
+    /// zeros beyond the minimum.
+    pub fn format_decimal(&self, value: f64, min_decimals: usize) -> String {
+        let text = format!("{:.4}", value.abs());
+        let (integer, fraction) = text.split_once('.').unwrap_or((&text, ""));
+        let mut fraction = fraction.trim_end_matches('0').to_string();
+        while fraction.len() < min_decimals {
+            fraction.push('0');
+        }
+        let sign = if value < 0.0 { "-" } else { "" };
+        let integer = self.group_digits(integer);
+        if fraction.is_empty() {
+            format!("{sign}{integer}")
+        } else {
+            format!("{sign}{integer}{}{fraction}", self.decimal)
+        }
+    }
+
+    /// Format an amount of money in the currency, by its symbol if it has a common one.
+    pub fn format_money(&self, value: f64, currency: &str) -> String {
+        let amount = self.format_decimal(value, 2);
+        let symbol = match currency.to_uppercase().as_str() {
+            "USD" => "$",
+            "EUR" => "€",
+            "GBP" => "£",
+            "JPY" | "CNY" => "¥",
+            "INR" => "₹",
+            "KRW" => "₩",
+            _ => return format!("{amount} {}", currency.to_uppercase()),
+        };
+        if self.currency_after {
+            format!("{amount} {symbol}")
+        } else {
+            format!("{symbol}{amount}")
+        }
+    }
+
+    fn group_digits(&self, digits: &str) -> String {
+        let mut output = String::new();
+        for (i, c) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                output.push(self.group);
+            }
+            output.push(c);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locale() {
+        let en = Locale::parse("en_US.UTF-8");
+        assert_eq!(en.format_count(128000), "128,000");
+        assert_eq!(en.format_decimal(2.5, 2), "2.50");
+        assert_eq!(en.format_decimal(0.138, 2), "0.138");
+        assert_eq!(en.format_money(1234.5, "usd"), "$1,234.50");
+
+        let de = Locale::parse("de-DE");
+        assert_eq!(de.format_count(1000000), "1.000.000");
+        assert_eq!(de.format_money(0.15, "EUR"), "0,15 €");
+
+        let fr = Locale::parse("fr_FR");
+        assert_eq!(fr.format_count(4096), "4 096");
+        assert_eq!(fr.format_money(10.0, "CHF"), "10,00 CHF");
+
+        assert_eq!(Locale::parse("de_CH").format_count(12345), "12'345");
+        assert_eq!(Locale::parse("xx"), Locale::default());
+    }
+}
@@ -1,23 +1,47 @@
 mod abort_signal;
+mod atomic_write;
 mod clipboard;
 mod crypto;
+mod json_schema;
+mod locale;
+mod pager;
+mod patch;
 mod prompt_input;
+mod prune;
 mod render_prompt;
+mod run_code;
 mod spinner;
+mod temp_file;
+mod term;
+#[cfg(test)]
+mod test_dir;
 mod tiktoken;
 
 pub use self::abort_signal::{create_abort_signal, AbortSignal};
-pub use self::clipboard::set_text;
+pub use self::atomic_write::{backup_paths, write_file_atomic};
+pub use self::clipboard::{get_image, get_text, is_clipboard_available, set_text};
 pub use self::crypto::*;
+pub use self::json_schema::validate_schema;
+pub use self::locale::Locale;
+pub use self::pager::print_paged;
+pub use self::patch::{apply_hunk, parse_patch, FilePatch, Hunk};
 pub use self::prompt_input::*;
+pub use self::prune::prune_text;
 pub use self::render_prompt::render_prompt;
+pub use self::run_code::{extract_code_snippets, run_code, which, CodeLang, CodeSnippet};
 pub use self::spinner::run_spinner;
-pub use self::tiktoken::cl100k_base_singleton;
+pub use self::temp_file::{remove_temp_file, remove_temp_files, temp_file};
+pub use self::term::{is_dumb_terminal, is_no_color, ColorDepth};
+#[cfg(test)]
+pub use self::test_dir::TestDir;
+pub use self::tiktoken::Encoding;
 
 use fancy_regex::Regex;
 use lazy_static::lazy_static;
 use std::env;
-use std::process::Command;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::process::{Command, Stdio};
 
 lazy_static! {
     pub static ref CODE_BLOCK_RE: Regex = Regex::new(r"(?ms)```\w*(.*)```").unwrap();
@@ -38,12 +62,16 @@ pub fn get_env_name(key: &str) -> String {
 
 /// Split text to tokens
 pub fn tokenize(text: &str) -> Vec<String> {
-    let tokens = cl100k_base_singleton()
+    let tokens = tiktoken::cl100k_base_singleton()
         .lock()
         .encode_with_special_tokens(text);
     let token_bytes: Vec<Vec<u8>> = tokens
         .into_iter()
-        .map(|v| cl100k_base_singleton().lock().decode_bytes(vec![v]))
+        .map(|v| {
+            tiktoken::cl100k_base_singleton()
+                .lock()
+                .decode_bytes(vec![v])
+        })
         .collect();
     let mut output = vec![];
     let mut current_bytes = vec![];
@@ -57,12 +85,14 @@ pub fn tokenize(text: &str) -> Vec<String> {
     output
 }
 
-/// Count how many tokens a piece of text needs to consume
-pub fn count_tokens(text: &str) -> usize {
-    cl100k_base_singleton()
-        .lock()
-        .encode_with_special_tokens(text)
-        .len()
+/// No route to the internet, checked by connecting a UDP socket which sends no packet.
+pub fn is_network_unreachable() -> bool {
+    let reachable = |local: &str, remote: &str| {
+        UdpSocket::bind(local)
+            .and_then(|socket| socket.connect(remote))
+            .is_ok()
+    };
+    !reachable("0.0.0.0:0", "1.1.1.1:53") && !reachable("[::]:0", "[2606:4700:4700::1111]:53")
 }
 
 pub fn light_theme_from_colorfgbg(colorfgbg: &str) -> Option<bool> {
@@ -144,6 +174,22 @@ pub fn run_command(eval_str: &str) -> anyhow::Result<i32> {
     Ok(status.code().unwrap_or_default())
 }
 
+/// Run the command with the text as its stdin, the output goes to the terminal.
+pub fn pipe_command(eval_str: &str, text: &str) -> anyhow::Result<i32> {
+    let (_shell_name, shell_cmd, shell_arg) = detect_shell();
+    let mut child = Command::new(shell_cmd)
+        .arg(shell_arg)
+        .arg(eval_str)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may exit without reading all of its input
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    let status = child.wait()?;
+    Ok(status.code().unwrap_or_default())
+}
+
 pub fn extract_block(input: &str) -> String {
     let output: String = CODE_BLOCK_RE
         .captures_iter(input)
@@ -187,6 +233,21 @@ pub fn fuzzy_match(text: &str, pattern: &str) -> bool {
     pattern_index == pattern_chars.len()
 }
 
+/// Edit distance between two strings, counted in chars.
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b_chars.len() + 1];
+        for (j, cb) in b_chars.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b_chars.len()]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -199,7 +260,7 @@ mod tests {
 
     #[test]
     fn test_count_tokens() {
-        assert_eq!(count_tokens("😊 hello world"), 4);
+        assert_eq!(Encoding::Cl100kBase.count_tokens("😊 hello world"), 4);
     }
 
     #[test]
@@ -208,4 +269,11 @@ mod tests {
         assert!(fuzzy_match("openai:gpt-4-turbo", "oai4"));
         assert!(!fuzzy_match("openai:gpt-4-turbo", "4gpt"));
     }
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein(".sesion", ".session"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+    }
 }
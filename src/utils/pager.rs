@@ -0,0 +1,133 @@
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal::{self, disable_raw_mode, enable_raw_mode},
+};
+use is_terminal::IsTerminal;
+use std::{
+    env,
+    io::{stdout, Write},
+    process::{Command, Stdio},
+};
+use textwrap::core::display_width;
+
+const DEFAULT_PAGER: &str = "less -R";
+const MORE_PROMPT: &str = "-- More -- (Space: next page, Enter: next line, q: quit)";
+
+/// Print the text, through `$PAGER` or the built-in pager when it doesn't fit the terminal.
+pub fn print_paged(text: &str, enabled: bool) {
+    let rows = match terminal::size() {
+        Ok((columns, rows)) if enabled && stdout().is_terminal() => {
+            if count_rows(text, columns as usize) < rows as usize {
+                None
+            } else {
+                Some(rows as usize)
+            }
+        }
+        _ => None,
+    };
+    let Some(rows) = rows else {
+        println!("{text}");
+        return;
+    };
+    let pager = env::var("PAGER").unwrap_or_else(|_| DEFAULT_PAGER.into());
+    if !pager.trim().is_empty() && run_pager(&pager, text) {
+        return;
+    }
+    builtin_pager(text, rows);
+}
+
+/// The rows the text takes in a terminal of this width.
+fn count_rows(text: &str, columns: usize) -> usize {
+    text.lines()
+        .map(|line| display_width(line).div_ceil(columns.max(1)).max(1))
+        .sum()
+}
+
+fn run_pager(pager: &str, text: &str) -> bool {
+    let args = match shell_words::split(pager) {
+        Ok(args) if !args.is_empty() => args,
+        _ => return false,
+    };
+    let child = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .spawn();
+    let Ok(mut child) = child else {
+        return false;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may quit before reading all of its input
+        let _ = stdin.write_all(text.as_bytes());
+        let _ = stdin.write_all(b"\n");
+    }
+    child.wait().is_ok()
+}
+
+fn builtin_pager(text: &str, rows: usize) {
+    let lines: Vec<&str> = text.lines().collect();
+    let page = rows.saturating_sub(1).max(1);
+    let mut shown = page.min(lines.len());
+    for line in &lines[..shown] {
+        println!("{line}");
+    }
+    while shown < lines.len() {
+        let step = match wait_key() {
+            Some(PagerKey::Page) => page,
+            Some(PagerKey::Line) => 1,
+            None => break,
+        };
+        let end = (shown + step).min(lines.len());
+        for line in &lines[shown..end] {
+            println!("{line}");
+        }
+        shown = end;
+    }
+}
+
+enum PagerKey {
+    Page,
+    Line,
+}
+
+/// Show the prompt and wait for a key, `None` quits.
+fn wait_key() -> Option<PagerKey> {
+    print!("{MORE_PROMPT}");
+    let _ = stdout().flush();
+    if enable_raw_mode().is_err() {
+        println!();
+        return None;
+    }
+    let key = loop {
+        match event::read() {
+            Ok(Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press,
+                ..
+            })) => match code {
+                KeyCode::Char(' ') | KeyCode::PageDown => break Some(PagerKey::Page),
+                KeyCode::Enter | KeyCode::Down | KeyCode::Char('j') => break Some(PagerKey::Line),
+                KeyCode::Char('c') if modifiers == KeyModifiers::CONTROL => break None,
+                KeyCode::Char('q') | KeyCode::Esc => break None,
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(_) => break None,
+        }
+    };
+    let _ = disable_raw_mode();
+    print!("\r{}\r", " ".repeat(display_width(MORE_PROMPT)));
+    let _ = stdout().flush();
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_rows() {
+        assert_eq!(count_rows("ab\n\nabcde", 4), 4);
+        assert_eq!(count_rows("\x1b[1mab\x1b[0m", 2), 1);
+    }
+}
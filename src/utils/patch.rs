@@ -0,0 +1,299 @@
+use anyhow::{bail, Result};
+use std::path::{Component, Path};
+
+/// The changes of a unified diff to one file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilePatch {
+    /// `None` for a new file.
+    pub old_path: Option<String>,
+    /// `None` for a deleted file.
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    pub fn path(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// The `@@ -1,3 +1,4 @@` line.
+    pub header: String,
+    /// The 1-based line where the hunk starts in the old file.
+    pub old_start: usize,
+    /// The lines with their ` `, `-` or `+` prefix.
+    pub lines: Vec<String>,
+}
+
+impl Hunk {
+    /// Read the lines of a hunk edited by the user, `#` lines are comments.
+    pub fn set_lines(&mut self, text: &str) -> Result<()> {
+        let mut lines = vec![];
+        for line in text.lines() {
+            match line.chars().next() {
+                Some('#') => {}
+                Some(' ' | '-' | '+') => lines.push(line.to_string()),
+                None => lines.push(" ".into()),
+                Some(_) => bail!("Invalid line in the hunk, expect ' ', '-' or '+': {line}"),
+            }
+        }
+        while lines.last().map(|v| v == " ").unwrap_or_default() {
+            lines.pop();
+        }
+        self.lines = lines;
+        Ok(())
+    }
+
+    fn old_lines(&self) -> Vec<&str> {
+        self.side(['-', ' '])
+    }
+
+    fn new_lines(&self) -> Vec<&str> {
+        self.side(['+', ' '])
+    }
+
+    fn side(&self, prefixes: [char; 2]) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter(|v| v.starts_with(prefixes))
+            .map(|v| &v[1..])
+            .collect()
+    }
+}
+
+/// Parse the unified diffs of the text, `diff --git` and index lines are skipped.
+pub fn parse_patch(text: &str) -> Result<Vec<FilePatch>> {
+    let mut patches: Vec<FilePatch> = vec![];
+    let lines: Vec<&str> = text.lines().collect();
+    let is_file_header = |i: usize| {
+        lines[i].starts_with("--- ") && lines.get(i + 1).is_some_and(|v| v.starts_with("+++ "))
+    };
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        if let Some(old_path) = line.strip_prefix("--- ") {
+            if !is_file_header(i - 1) {
+                continue;
+            }
+            let new_path = lines[i];
+            i += 1;
+            patches.push(FilePatch {
+                old_path: parse_path(old_path, "a/"),
+                new_path: parse_path(&new_path[4..], "b/"),
+                hunks: vec![],
+            });
+        } else if line.starts_with("@@") {
+            let Some(patch) = patches.last_mut() else {
+                bail!("Hunk without file header: {line}");
+            };
+            let (old_start, old_count, new_count) = parse_hunk_header(line)
+                .ok_or_else(|| anyhow::anyhow!("Invalid hunk header: {line}"))?;
+            let mut hunk = Hunk {
+                header: line.to_string(),
+                old_start,
+                lines: vec![],
+            };
+            // The counts of the header tell where the hunk ends, a removed `-- ` line included.
+            // A count too large stops at the next file header.
+            let (mut old_left, mut new_left) = (old_count, new_count);
+            while (old_left > 0 || new_left > 0) && i < lines.len() {
+                let line = lines[i];
+                let fits = match line.chars().next() {
+                    // Models often drop the space of empty context lines
+                    None | Some(' ') => old_left > 0 && new_left > 0,
+                    Some('-') => old_left > 0 && !is_file_header(i),
+                    Some('+') => new_left > 0,
+                    Some('\\') => true,
+                    Some(_) => false,
+                };
+                if !fits {
+                    break;
+                }
+                i += 1;
+                match line.chars().next() {
+                    // `\ No newline at end of file`
+                    Some('\\') => continue,
+                    Some('-') => old_left -= 1,
+                    Some('+') => new_left -= 1,
+                    _ => {
+                        old_left -= 1;
+                        new_left -= 1;
+                    }
+                }
+                match line.is_empty() {
+                    true => hunk.lines.push(" ".into()),
+                    false => hunk.lines.push(line.to_string()),
+                }
+            }
+            if lines.get(i).is_some_and(|v| v.starts_with('\\')) {
+                i += 1;
+            }
+            while hunk.lines.last().map(|v| v == " ").unwrap_or_default() {
+                hunk.lines.pop();
+            }
+            patch.hunks.push(hunk);
+        }
+    }
+    patches.retain(|v| !v.hunks.is_empty());
+    if patches.is_empty() {
+        bail!("No patch found");
+    }
+    for patch in &patches {
+        let path = Path::new(patch.path());
+        if path.is_absolute()
+            || path
+                .components()
+                .any(|v| matches!(v, Component::ParentDir | Component::Prefix(_)))
+        {
+            bail!(
+                "Refusing to patch '{}' outside the current directory",
+                patch.path()
+            );
+        }
+    }
+    Ok(patches)
+}
+
+/// The old start and the line counts of `@@ -1,3 +1,4 @@`, a count is 1 when it is left out.
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut parts = line.split_whitespace().skip(1);
+    let range = |value: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let value = value?.strip_prefix(sign)?;
+        match value.split_once(',') {
+            Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+            None => Some((value.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_count) = range(parts.next(), '-')?;
+    let (_, new_count) = range(parts.next(), '+')?;
+    Some((old_start, old_count, new_count))
+}
+
+fn parse_path(value: &str, prefix: &str) -> Option<String> {
+    // A timestamp may follow the path after a tab
+    let value = value.split('\t').next().unwrap_or_default().trim();
+    if value == "/dev/null" {
+        return None;
+    }
+    Some(value.strip_prefix(prefix).unwrap_or(value).to_string())
+}
+
+/// Apply the hunk to the lines of a file, looking for its old lines around where it says they
+/// are, the nearest match wins. `offset` tracks how far the previous hunks moved the lines.
+pub fn apply_hunk(lines: &mut Vec<String>, hunk: &Hunk, offset: &mut isize) -> Result<()> {
+    let old = hunk.old_lines();
+    let new: Vec<String> = hunk.new_lines().into_iter().map(String::from).collect();
+    let expected = (hunk.old_start.saturating_sub(1) as isize + *offset).max(0) as usize;
+    let matches_at = |start: usize| {
+        start + old.len() <= lines.len()
+            && old
+                .iter()
+                .zip(&lines[start..])
+                .all(|(a, b)| a.trim_end() == b.trim_end())
+    };
+    let start = if old.is_empty() {
+        Some(expected.min(lines.len()))
+    } else {
+        (0..=lines.len()).find_map(|distance| {
+            [
+                expected.checked_sub(distance),
+                expected.checked_add(distance),
+            ]
+            .into_iter()
+            .flatten()
+            .find(|v| matches_at(*v))
+        })
+    };
+    let Some(start) = start else {
+        bail!("The hunk {} doesn't match the file", hunk.header);
+    };
+    let removed = old.len();
+    *offset += new.len() as isize - removed as isize;
+    lines.splice(start..start + removed, new);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &str = r#"diff --git a/src/main.rs b/src/main.rs
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,3 +1,3 @@
+ fn main() {
+-    println!("hello");
++    println!("hello, world");
+ }
+@@ -10,2 +10,3 @@
+ fn helper() {}
++fn other() {}
+
+--- /dev/null
++++ b/NOTES.md
+@@ -0,0 +1 @@
++# Notes
+"#;
+
+    #[test]
+    fn test_parse_patch() {
+        let patches = parse_patch(PATCH).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].path(), "src/main.rs");
+        assert_eq!(patches[0].hunks.len(), 2);
+        assert_eq!(patches[0].hunks[1].old_start, 10);
+        assert_eq!(
+            patches[0].hunks[1].lines,
+            vec![" fn helper() {}", "+fn other() {}"]
+        );
+        assert_eq!(patches[1].old_path, None);
+        assert_eq!(patches[1].path(), "NOTES.md");
+
+        let patches =
+            parse_patch("--- a/x.sql\n+++ b/x.sql\n@@ -1,2 +1 @@\n--- old comment\n select 1;\n")
+                .unwrap();
+        assert_eq!(
+            patches[0].hunks[0].lines,
+            vec!["--- old comment", " select 1;"]
+        );
+
+        assert!(parse_patch("no diff here").is_err());
+        let err = parse_patch("--- a/../x\n+++ b/../x\n@@ -1 +1 @@\n-a\n+b\n").unwrap_err();
+        assert!(err.to_string().starts_with("Refusing to patch '../x'"));
+    }
+
+    #[test]
+    fn test_apply_hunk() {
+        let patches = parse_patch(PATCH).unwrap();
+        let text = "fn main() {\n    println!(\"hello\");\n}\n\n// moved down\n\nfn helper() {}\n";
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        let mut offset = 0;
+        for hunk in &patches[0].hunks {
+            apply_hunk(&mut lines, hunk, &mut offset).unwrap();
+        }
+        assert_eq!(
+            lines.join("\n"),
+            "fn main() {\n    println!(\"hello, world\");\n}\n\n// moved down\n\nfn helper() {}\nfn other() {}"
+        );
+        assert!(apply_hunk(&mut lines, &patches[0].hunks[0], &mut offset).is_err());
+
+        let mut lines = vec![];
+        apply_hunk(&mut lines, &patches[1].hunks[0], &mut 0).unwrap();
+        assert_eq!(lines, vec!["# Notes"]);
+    }
+
+    #[test]
+    fn test_set_lines() {
+        let mut hunk = parse_patch(PATCH).unwrap()[0].hunks[0].clone();
+        hunk.set_lines("# comment\n fn main() {\n+    run();\n }\n\n")
+            .unwrap();
+        assert_eq!(hunk.lines, vec![" fn main() {", "+    run();", " }"]);
+        assert!(hunk.set_lines("oops").is_err());
+    }
+}
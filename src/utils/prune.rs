@@ -0,0 +1,103 @@
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+
+lazy_static! {
+    static ref WORD_RE: Regex = Regex::new(r"\S+\s*").unwrap();
+    static ref STOPWORDS: HashSet<&'static str> = [
+        "a", "about", "above", "after", "again", "all", "also", "am", "an", "and", "any", "are",
+        "as", "at", "be", "because", "been", "before", "being", "below", "between", "both", "but",
+        "by", "can", "could", "did", "do", "does", "doing", "down", "during", "each", "few", "for",
+        "from", "further", "had", "has", "have", "having", "he", "her", "here", "hers", "him",
+        "his", "how", "i", "if", "in", "into", "is", "it", "its", "itself", "just", "me", "more",
+        "most", "my", "of", "off", "on", "once", "only", "or", "other", "our", "ours", "out",
+        "over", "own", "same", "she", "should", "so", "some", "such", "than", "that", "the",
+        "their", "them", "then", "there", "these", "they", "this", "those", "through", "to", "too",
+        "under", "until", "up", "very", "was", "we", "were", "what", "when", "where", "which",
+        "while", "who", "whom", "why", "will", "with", "would", "you", "your",
+    ]
+    .into_iter()
+    .collect();
+}
+
+/// Keep about `ratio` of the words of the text, dropping the ones carrying the least
+/// information: stopwords and words repeated all over the text go first, rare words stay.
+/// The line breaks and the fenced code blocks are kept as is.
+pub fn prune_text(text: &str, ratio: f64) -> String {
+    let ratio = ratio.clamp(0.0, 1.0);
+    let mut words = vec![];
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        let fence = line.trim_start().starts_with("```") || line.trim_start().starts_with("~~~");
+        if in_code || fence {
+            words.push((line, None));
+            if fence {
+                in_code = !in_code;
+            }
+            continue;
+        }
+        for word in WORD_RE.find_iter(line).flatten() {
+            words.push((word.as_str(), Some(normalize(word.as_str()))));
+        }
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for key in words.iter().filter_map(|(_, key)| key.as_deref()) {
+        *counts.entry(key).or_default() += 1;
+    }
+    let total = counts.values().sum::<usize>().max(1) as f64;
+    let mut scored: Vec<(usize, f64)> = words
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (_, key))| {
+            let key = key.as_deref()?;
+            // The self-information of the word in the text
+            let mut score = -(counts[key] as f64 / total).ln();
+            if key.is_empty() || STOPWORDS.contains(key) {
+                score *= 0.1;
+            }
+            Some((i, score))
+        })
+        .collect();
+    let keep = (scored.len() as f64 * ratio).ceil() as usize;
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    let kept: HashSet<usize> = scored.into_iter().take(keep).map(|(i, _)| i).collect();
+
+    let mut output = String::new();
+    for (i, (word, key)) in words.iter().enumerate() {
+        if key.is_none() || kept.contains(&i) {
+            output.push_str(word);
+        } else if word.contains('\n') {
+            let trimmed = output.trim_end_matches([' ', '\t']).len();
+            output.truncate(trimmed);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+fn normalize(word: &str) -> String {
+    word.trim()
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_text() {
+        let text =
+            "The cat sat on the mat.\nThe quantum flux capacitor is the key to the engine.\n";
+        let output = prune_text(text, 0.5);
+        assert!(output.contains("quantum"));
+        assert!(output.contains("capacitor"));
+        assert!(!output.contains(" the "));
+        assert_eq!(output.lines().count(), 2);
+        assert_eq!(prune_text(text, 1.0), text);
+
+        let text = "Some words that may go away\n```\nlet x = 1;\n```\n";
+        assert!(prune_text(text, 0.0).ends_with("```\nlet x = 1;\n```\n"));
+    }
+}
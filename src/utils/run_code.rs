@@ -0,0 +1,258 @@
+use crate::render::extract_code_blocks;
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    env,
+    fs::{create_dir_all, remove_dir_all, write},
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Environment variables passed through to the code, everything else (e.g. API keys) is dropped.
+const KEEP_ENV_VARS: [&str; 5] = ["PATH", "LANG", "RUSTUP_HOME", "CARGO_HOME", "PYENV_ROOT"];
+
+/// The toolchains found in the real home by default, which the code gets a temp dir for.
+const TOOLCHAIN_HOMES: [(&str, &str); 3] = [
+    ("RUSTUP_HOME", ".rustup"),
+    ("CARGO_HOME", ".cargo"),
+    ("PYENV_ROOT", ".pyenv"),
+];
+const MAX_OUTPUT_CHARS: usize = 8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeLang {
+    Python,
+    JavaScript,
+    Rust,
+}
+
+impl CodeLang {
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag.to_lowercase().as_str() {
+            "python" | "py" | "python3" => Some(Self::Python),
+            "javascript" | "js" | "node" => Some(Self::JavaScript),
+            "rust" | "rs" => Some(Self::Rust),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Python => "python",
+            Self::JavaScript => "javascript",
+            Self::Rust => "rust",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeSnippet {
+    pub lang: CodeLang,
+    pub code: String,
+}
+
+/// Find the fenced code blocks that can be run, in order of appearance.
+pub fn extract_code_snippets(text: &str) -> Vec<CodeSnippet> {
+    extract_code_blocks(text)
+        .into_iter()
+        .filter_map(|block| {
+            let lang = CodeLang::from_tag(&block.lang)?;
+            Some(CodeSnippet {
+                lang,
+                code: block.code,
+            })
+        })
+        .collect()
+}
+
+/// Run the snippet in a fresh temp dir with a timeout and return its combined stdout/stderr.
+///
+/// Only a few environment variables are kept, and `HOME` and `TMPDIR` are the temp dir. Without
+/// `network`, the code runs in a new network namespace via `unshare`, which is only available on
+/// Linux. There is no isolation of the filesystem: the code can read and write anything the user
+/// can.
+pub fn run_code(snippet: &CodeSnippet, timeout: Duration, network: bool) -> Result<String> {
+    let dir = env::temp_dir().join(format!(
+        "aichat-run-code-{}-{}",
+        std::process::id(),
+        chrono::Utc::now().timestamp_micros()
+    ));
+    create_dir_all(&dir).with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    let ret = run_code_in(snippet, &dir, timeout, network);
+    let _ = remove_dir_all(&dir);
+    ret
+}
+
+fn run_code_in(
+    snippet: &CodeSnippet,
+    dir: &Path,
+    timeout: Duration,
+    network: bool,
+) -> Result<String> {
+    let script = match snippet.lang {
+        CodeLang::Python => {
+            write(dir.join("main.py"), &snippet.code)?;
+            "exec python3 main.py"
+        }
+        CodeLang::JavaScript => {
+            write(dir.join("main.js"), &snippet.code)?;
+            "exec node main.js"
+        }
+        CodeLang::Rust => {
+            write(dir.join("main.rs"), &snippet.code)?;
+            "rustc --edition 2021 -o main main.rs && exec ./main"
+        }
+    };
+    let mut command = if network {
+        Command::new("sh")
+    } else {
+        if !cfg!(target_os = "linux") || which("unshare").is_none() {
+            bail!("Running code without network access requires `unshare`, set `run_code_network` to true to allow network access");
+        }
+        if !can_unshare() {
+            bail!("Running code without network access requires user namespaces, which `unshare --map-root-user` can't create here, set `run_code_network` to true to allow network access");
+        }
+        let mut command = Command::new("unshare");
+        command.args(["--map-root-user", "--net", "sh"]);
+        command
+    };
+    command
+        .arg("-c")
+        .arg(script)
+        .current_dir(dir)
+        .env_clear()
+        .envs(
+            KEEP_ENV_VARS
+                .iter()
+                .filter_map(|k| env::var(k).ok().map(|v| (k, v))),
+        )
+        .envs(toolchain_homes())
+        .env("HOME", dir)
+        .env("TMPDIR", dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Own process group, so that a timeout kills the processes spawned by the code too.
+        command.process_group(0);
+    }
+    let mut child = command.spawn().with_context(|| "Failed to run code")?;
+
+    let mut stdout = child.stdout.take().ok_or_else(|| anyhow!("No stdout"))?;
+    let mut stderr = child.stderr.take().ok_or_else(|| anyhow!("No stderr"))?;
+    let stdout_reader = thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = stdout.read_to_string(&mut buf);
+        buf
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = stderr.read_to_string(&mut buf);
+        buf
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if start.elapsed() > timeout {
+            #[cfg(unix)]
+            let _ = Command::new("kill")
+                .args(["-9", &format!("-{}", child.id())])
+                .status();
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let stdout = stdout_reader.join().unwrap_or_default();
+    let stderr = stderr_reader.join().unwrap_or_default();
+    let mut output = String::new();
+    if !stdout.is_empty() {
+        output.push_str(&format!("stdout:\n{stdout}\n"));
+    }
+    if !stderr.is_empty() {
+        output.push_str(&format!("stderr:\n{stderr}\n"));
+    }
+    match status {
+        Some(status) => output.push_str(&format!("exit code: {}", status.code().unwrap_or(-1))),
+        None => output.push_str(&format!("timed out after {}s", timeout.as_secs())),
+    }
+    Ok(truncate_output(output))
+}
+
+/// Whether `unshare` can create the namespaces, user namespaces may be disabled, e.g. in a
+/// container.
+fn can_unshare() -> bool {
+    static CAN_UNSHARE: OnceLock<bool> = OnceLock::new();
+    *CAN_UNSHARE.get_or_init(|| {
+        Command::new("unshare")
+            .args(["--map-root-user", "--net", "true"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|v| v.success())
+    })
+}
+
+/// The toolchains of the real home, which `HOME` doesn't lead to anymore.
+fn toolchain_homes() -> Vec<(&'static str, PathBuf)> {
+    let Some(home) = env::var_os("HOME").map(PathBuf::from) else {
+        return vec![];
+    };
+    TOOLCHAIN_HOMES
+        .iter()
+        .filter(|(name, _)| env::var_os(name).is_none())
+        .map(|(name, dir)| (*name, home.join(dir)))
+        .filter(|(_, path)| path.exists())
+        .collect()
+}
+
+fn truncate_output(output: String) -> String {
+    match output.char_indices().nth(MAX_OUTPUT_CHARS) {
+        Some((i, _)) => format!("{}\n...(truncated)", &output[..i]),
+        None => output,
+    }
+}
+
+/// The path of the executable `name` in `PATH`.
+pub fn which(name: &str) -> Option<PathBuf> {
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_code_snippets() {
+        let text = "Try:\n```python\nprint(1)\n```\n\n```sh\nls\n```\n```js\nconsole.log(2)\n```\n";
+        assert_eq!(
+            extract_code_snippets(text),
+            [
+                CodeSnippet {
+                    lang: CodeLang::Python,
+                    code: "print(1)\n".into()
+                },
+                CodeSnippet {
+                    lang: CodeLang::JavaScript,
+                    code: "console.log(2)\n".into()
+                },
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use super::is_dumb_terminal;
+
 use anyhow::Result;
 use crossterm::{cursor, queue, style, terminal};
 use std::{
@@ -56,6 +58,10 @@ impl Spinner {
 }
 
 pub async fn run_spinner(message: &str, rx: oneshot::Receiver<()>) -> Result<()> {
+    if is_dumb_terminal() {
+        let _ = rx.await;
+        return Ok(());
+    }
     let mut writer = stdout();
     let mut spinner = Spinner::new(message);
     let mut interval = interval(Duration::from_millis(50));
@@ -0,0 +1,49 @@
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::env;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+}
+
+/// A path in the temp dir, `aichat-{name}`, that is removed if the process is terminated.
+pub fn temp_file(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("aichat-{name}"));
+    TEMP_FILES.lock().push(path.clone());
+    path
+}
+
+/// Remove a temp file once it's no longer needed, it's forgotten.
+pub fn remove_temp_file(path: &Path) {
+    TEMP_FILES.lock().retain(|v| v != path);
+    let _ = std::fs::remove_file(path);
+}
+
+/// Remove the temp files that still exist.
+pub fn remove_temp_files() {
+    for path in TEMP_FILES.lock().drain(..) {
+        if path.exists() {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_temp_files() {
+        let path = temp_file(&format!("test-{}.txt", std::process::id()));
+        std::fs::write(&path, "draft").unwrap();
+        remove_temp_files();
+        assert!(!path.exists());
+
+        let path = temp_file(&format!("test-{}-removed.txt", std::process::id()));
+        std::fs::write(&path, "draft").unwrap();
+        remove_temp_file(&path);
+        assert!(!path.exists());
+        assert!(!TEMP_FILES.lock().contains(&path));
+    }
+}
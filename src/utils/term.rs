@@ -0,0 +1,59 @@
+use std::env;
+
+/// How many colors the terminal shows, from `COLORTERM` and `TERM`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorDepth {
+    /// The 8 colors and their bright variants, e.g. the Linux console
+    Ansi16,
+    #[default]
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    pub fn detect() -> Self {
+        Self::from_env(
+            env::var("COLORTERM").ok().as_deref(),
+            env::var("TERM").ok().as_deref(),
+        )
+    }
+
+    fn from_env(colorterm: Option<&str>, term: Option<&str>) -> Self {
+        if matches!(colorterm, Some("truecolor" | "24bit")) {
+            return Self::TrueColor;
+        }
+        match term {
+            Some(term) if term.ends_with("-16color") || term.ends_with("-8color") => Self::Ansi16,
+            Some("linux" | "ansi" | "vt100" | "vt220" | "xterm-color" | "cons25") => Self::Ansi16,
+            _ => Self::Ansi256,
+        }
+    }
+}
+
+/// A terminal that can neither color the text nor move the cursor, e.g. the shell of an editor.
+pub fn is_dumb_terminal() -> bool {
+    env::var("TERM").is_ok_and(|v| v == "dumb")
+}
+
+/// `NO_COLOR` with any value but an empty one, see https://no-color.org.
+pub fn is_no_color() -> bool {
+    env::var("NO_COLOR").is_ok_and(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_depth() {
+        let depth = ColorDepth::from_env;
+        assert_eq!(
+            depth(Some("truecolor"), Some("xterm-256color")),
+            ColorDepth::TrueColor
+        );
+        assert_eq!(depth(None, Some("xterm-256color")), ColorDepth::Ansi256);
+        assert_eq!(depth(None, Some("linux")), ColorDepth::Ansi16);
+        assert_eq!(depth(None, Some("rxvt-16color")), ColorDepth::Ansi16);
+        assert_eq!(depth(None, None), ColorDepth::Ansi256);
+    }
+}
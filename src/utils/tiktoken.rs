@@ -10,15 +10,62 @@ use fancy_regex::Regex;
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 use rustc_hash::FxHashMap as HashMap;
+use serde::Deserialize;
 use std::collections::HashSet;
 use std::sync::Arc;
 use tokio::task;
 
 pub fn cl100k_base() -> Result<CoreBPE> {
-    let cl100k_base = include_str!("../../assets/cl100k_base.tiktoken");
+    load_bpe(
+        include_str!("../../assets/cl100k_base.tiktoken"),
+        &[
+            ("<|endoftext|>", 100257),
+            ("<|fim_prefix|>", 100258),
+            ("<|fim_middle|>", 100259),
+            ("<|fim_suffix|>", 100260),
+            ("<|endofprompt|>", 100276),
+        ],
+        "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+",
+    )
+}
+
+pub fn cl100k_base_singleton() -> Arc<Mutex<CoreBPE>> {
+    lazy_static! {
+        static ref CL100K_BASE: Arc<Mutex<CoreBPE>> = Arc::new(Mutex::new(cl100k_base().unwrap()));
+    }
+    CL100K_BASE.clone()
+}
 
+#[cfg(feature = "o200k")]
+pub fn o200k_base() -> Result<CoreBPE> {
+    load_bpe(
+        include_str!("../../assets/o200k_base.tiktoken"),
+        &[("<|endoftext|>", 199999), ("<|endofprompt|>", 200018)],
+        &[
+            "[^\\r\\n\\p{L}\\p{N}]?[\\p{Lu}\\p{Lt}\\p{Lm}\\p{Lo}\\p{M}]*[\\p{Ll}\\p{Lm}\\p{Lo}\\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+            "[^\\r\\n\\p{L}\\p{N}]?[\\p{Lu}\\p{Lt}\\p{Lm}\\p{Lo}\\p{M}]+[\\p{Ll}\\p{Lm}\\p{Lo}\\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+            "\\p{N}{1,3}",
+            " ?[^\\s\\p{L}\\p{N}]+[\\r\\n/]*",
+            "\\s*[\\r\\n]+",
+            "\\s+(?!\\S)",
+            "\\s+",
+        ]
+        .join("|"),
+    )
+}
+
+#[cfg(feature = "o200k")]
+pub fn o200k_base_singleton() -> Arc<Mutex<CoreBPE>> {
+    lazy_static! {
+        static ref O200K_BASE: Arc<Mutex<CoreBPE>> = Arc::new(Mutex::new(o200k_base().unwrap()));
+    }
+    O200K_BASE.clone()
+}
+
+/// Load the ranks of a `.tiktoken` file, one base64 token and its rank per line.
+fn load_bpe(ranks: &str, special_tokens: &[(&str, usize)], pattern: &str) -> Result<CoreBPE> {
     let mut encoder = HashMap::default();
-    for line in cl100k_base.lines() {
+    for line in ranks.lines() {
         let mut parts = line.split(' ');
         let raw = parts.next().unwrap();
         let token = &general_purpose::STANDARD.decode(raw)?;
@@ -26,25 +73,64 @@ pub fn cl100k_base() -> Result<CoreBPE> {
         encoder.insert(token.clone(), rank);
     }
 
-    let mut special_tokens = HashMap::default();
-    special_tokens.insert(String::from("<|endoftext|>"), 100257);
-    special_tokens.insert(String::from("<|fim_prefix|>"), 100258);
-    special_tokens.insert(String::from("<|fim_middle|>"), 100259);
-    special_tokens.insert(String::from("<|fim_suffix|>"), 100260);
-    special_tokens.insert(String::from("<|endofprompt|>"), 100276);
+    let special_tokens = special_tokens
+        .iter()
+        .map(|(token, rank)| (token.to_string(), *rank))
+        .collect();
 
-    CoreBPE::new(
-        encoder,
-        special_tokens,
-        "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}{1,3}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+",
-    )
+    CoreBPE::new(encoder, special_tokens, pattern)
 }
 
-pub fn cl100k_base_singleton() -> Arc<Mutex<CoreBPE>> {
-    lazy_static! {
-        static ref CL100K_BASE: Arc<Mutex<CoreBPE>> = Arc::new(Mutex::new(cl100k_base().unwrap()));
+/// The BPE encoding a model counts its tokens with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Cl100kBase,
+    O200kBase,
+}
+
+impl Encoding {
+    /// The encoding of the OpenAI model, e.g. `gpt-4o` or `openai/o3-mini` through a gateway.
+    /// cl100k_base is the closest one for the other models.
+    pub fn for_model(name: &str) -> Self {
+        let name = name.rsplit('/').next().unwrap_or(name).to_lowercase();
+        let o200k = [
+            "gpt-4o",
+            "chatgpt-4o",
+            "gpt-4.1",
+            "gpt-4.5",
+            "gpt-5",
+            "gpt-oss",
+        ]
+        .iter()
+        .any(|v| name.starts_with(v))
+            || ["o1", "o3", "o4"]
+                .iter()
+                .any(|v| name == *v || name.starts_with(&format!("{v}-")));
+        if o200k {
+            Encoding::O200kBase
+        } else {
+            Encoding::Cl100kBase
+        }
+    }
+
+    pub fn singleton(&self) -> Arc<Mutex<CoreBPE>> {
+        match self {
+            Encoding::Cl100kBase => cl100k_base_singleton(),
+            #[cfg(feature = "o200k")]
+            Encoding::O200kBase => o200k_base_singleton(),
+            // Built without it, cl100k_base is the closest
+            #[cfg(not(feature = "o200k"))]
+            Encoding::O200kBase => cl100k_base_singleton(),
+        }
+    }
+
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.singleton()
+            .lock()
+            .encode_with_special_tokens(text)
+            .len()
     }
-    CL100K_BASE.clone()
 }
 
 pub async fn decode_async(bpe: Arc<Mutex<CoreBPE>>, tokens: Vec<usize>) -> Result<String> {
@@ -380,7 +466,7 @@ impl CoreBPE {
         if unstable_bytes.len() > 1 {
             let last_decoded = bstr::decode_last_utf8(unstable_bytes.as_slice());
             if unstable_bytes.len() - last_decoded.1 > 0
-                && last_decoded.0.map_or(false, |c| c.is_whitespace())
+                && last_decoded.0.is_some_and(|c| c.is_whitespace())
             {
                 let mut reencoded = byte_pair_encode(
                     &unstable_bytes[..unstable_bytes.len() - last_decoded.1],
@@ -577,4 +663,21 @@ mod tests {
             vec![2028, 374, 264, 1296, 260, 449, 264, 2763, 315, 12908]
         );
     }
+
+    #[test]
+    #[cfg(feature = "o200k")]
+    fn o200k_base_test() {
+        let bpe = o200k_base().unwrap();
+        let tokens = bpe.encode_with_special_tokens("hello world");
+        assert_eq!(bpe.decode(tokens.clone()).unwrap(), "hello world");
+        assert_eq!(tokens, vec![24912, 2375]);
+    }
+
+    #[test]
+    fn encoding_for_model_test() {
+        assert_eq!(Encoding::for_model("gpt-4o-mini"), Encoding::O200kBase);
+        assert_eq!(Encoding::for_model("openai/o3-mini"), Encoding::O200kBase);
+        assert_eq!(Encoding::for_model("gpt-4-turbo"), Encoding::Cl100kBase);
+        assert_eq!(Encoding::for_model("llama3.1"), Encoding::Cl100kBase);
+    }
 }